@@ -0,0 +1,277 @@
+//!
+//! Connection handling benchmark.
+//!
+//!   Starts a portman server and opens a large number of connections, each
+//! holding an allocation, the way a busy system with many long-lived
+//! services would.  With those connections open it measures:
+//!
+//! -  The time to make the allocations.
+//! -  The latency of a LIST request.
+//! -  The rate at which short-lived connections (connect, LIST, close)
+//!    are served.
+//! -  The number of threads and the resident memory of the server.
+//!
+//!   The same is then measured for a baseline server that serves each
+//! connection from its own thread, the way portman used to, so the two can
+//! be compared.  The baseline shares portman's port pool responder and only
+//! knows the requests the benchmark makes.
+//!
+//!   The holder count is taken from PORTMAN_BENCH_HOLDERS (default 1000).
+//! Note that each holder uses a file descriptor in both this process and
+//! the server so the open file limit may need raising for large counts.
+//!
+use portman::error::error::PortmanError;
+use portman::protocol::request::{self, quote, ClientRequest};
+use portman::responder::responder::{self, ClientId, RequestMessage};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_HOLDERS: usize = 1000;
+const SHORT_CONNECTIONS: usize = 1000;
+const LIST_SAMPLES: usize = 20;
+const POOL_BASE: u16 = 40000;
+
+// Set when this benchmark is run again as the baseline server.  The value
+// is the port it listens on.
+
+const BASELINE_ENV: &str = "PORTMAN_BENCH_BASELINE";
+
+// Find a port nobody is listening on.
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start_server(listen: u16, base: u16, count: usize) -> Child {
+    let child = Command::new(env!("CARGO_BIN_EXE_portman"))
+        .args([
+            "-l",
+            &listen.to_string(),
+            "-p",
+            &base.to_string(),
+            "-n",
+            &count.to_string(),
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Unable to start portman");
+    wait_for(child, listen)
+}
+
+fn start_baseline(listen: u16, count: usize) -> Child {
+    let child = Command::new(env::current_exe().unwrap())
+        .env(BASELINE_ENV, listen.to_string())
+        .env("PORTMAN_BENCH_HOLDERS", count.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Unable to start the baseline server");
+    wait_for(child, listen)
+}
+
+fn wait_for(mut child: Child, listen: u16) -> Child {
+    // Wait for it to listen:
+
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", listen)).is_ok() {
+            return child;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("The server did not start listening");
+}
+
+// The baseline:  a thread per connection, each blocking on the responder.
+
+fn baseline_server(listen: u16, count: u16) {
+    let (requests, receiver) = mpsc::channel();
+    thread::spawn(move || responder::responder(POOL_BASE, count, receiver));
+    let listener = TcpListener::bind(("127.0.0.1", listen)).unwrap();
+    for (client, stream) in listener.incoming().enumerate() {
+        if let Ok(stream) = stream {
+            let requests = requests.clone();
+            thread::spawn(move || baseline_client(client as ClientId, stream, requests));
+        }
+    }
+}
+
+fn baseline_client(
+    client: ClientId,
+    mut stream: TcpStream,
+    requests: mpsc::Sender<RequestMessage>,
+) {
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    let mut held = Vec::new();
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let reply = match request::decode_request(&line) {
+            Ok(ClientRequest::Gimme(allocation)) => {
+                match responder::request_port(
+                    &allocation.service_name,
+                    &allocation.user_name,
+                    client,
+                    &requests,
+                ) {
+                    Ok(port) => {
+                        held.push(port);
+                        format!("OK {}\n", port)
+                    }
+                    Err(e) => e.reply(),
+                }
+            }
+            Ok(ClientRequest::List) => match responder::get_allocations(&requests) {
+                Ok(allocations) => {
+                    let mut reply = format!("OK {}\n", allocations.len());
+                    for a in allocations.iter() {
+                        reply += &format!(
+                            "{} {} {}\n",
+                            a.port(),
+                            quote(&a.service()),
+                            quote(&a.user())
+                        );
+                    }
+                    reply
+                }
+                Err(e) => e.reply(),
+            },
+            Ok(ClientRequest::Terminate) => process::exit(0),
+            Ok(_) => PortmanError::Invalid(String::from("Not served by the baseline")).reply(),
+            Err(e) => e.reply(),
+        };
+        if stream.write_all(reply.as_bytes()).is_err() {
+            break;
+        }
+    }
+    let _ = responder::release_ports(client, held, &requests);
+}
+
+// Send a request and read the first line of the reply.
+
+fn request(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, line: &str) -> String {
+    stream.write_all(line.as_bytes()).unwrap();
+    let mut reply = String::new();
+    reader.read_line(&mut reply).unwrap();
+    reply
+}
+
+// A LIST reply is "OK n" followed by n lines.
+
+fn list(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>) -> usize {
+    let reply = request(stream, reader, "LIST\n");
+    let count: usize = reply.trim()[3..].parse().unwrap();
+    let mut line = String::new();
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+    count
+}
+
+fn status_field(pid: u32, field: &str) -> String {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    status
+        .lines()
+        .find(|l| l.starts_with(field))
+        .map(|l| l[field.len()..].trim().to_string())
+        .unwrap_or_else(|| String::from("?"))
+}
+
+fn main() {
+    let holders: usize = env::var("PORTMAN_BENCH_HOLDERS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_HOLDERS);
+    if let Some(listen) = env::var(BASELINE_ENV).ok().and_then(|p| p.parse().ok()) {
+        baseline_server(listen, holders as u16);
+        return;
+    }
+
+    println!("portman (event loop):");
+    let listen = free_port();
+    measure(start_server(listen, POOL_BASE, holders), listen, holders);
+
+    println!("Baseline (thread per connection):");
+    let listen = free_port();
+    measure(start_baseline(listen, holders), listen, holders);
+}
+
+// Make the measurements on the server 'server' listening on 'listen'.
+
+fn measure(mut server: Child, listen: u16, holders: usize) {
+    // Open the holders:
+
+    let start = Instant::now();
+    let mut connections = Vec::with_capacity(holders);
+    for i in 0..holders {
+        let mut stream = TcpStream::connect(("127.0.0.1", listen)).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let reply = request(&mut stream, &mut reader, &format!("GIMME svc{} bench\n", i));
+        assert!(reply.starts_with("OK"), "Allocation failed: {}", reply);
+        connections.push(stream);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{} allocations in {:?} ({:?} each)",
+        holders,
+        elapsed,
+        elapsed / holders as u32
+    );
+
+    // LIST latency with all of those held:
+
+    let mut stream = TcpStream::connect(("127.0.0.1", listen)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let start = Instant::now();
+    for _ in 0..LIST_SAMPLES {
+        assert_eq!(list(&mut stream, &mut reader), holders);
+    }
+    println!(
+        "LIST of {} allocations: {:?}",
+        holders,
+        start.elapsed() / LIST_SAMPLES as u32
+    );
+    drop(reader);
+    drop(stream);
+
+    // Short lived connections:
+
+    let start = Instant::now();
+    for _ in 0..SHORT_CONNECTIONS {
+        let mut stream = TcpStream::connect(("127.0.0.1", listen)).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        list(&mut stream, &mut reader);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{} connect/LIST/close cycles in {:?} ({:.0}/s)",
+        SHORT_CONNECTIONS,
+        elapsed,
+        SHORT_CONNECTIONS as f64 / elapsed.as_secs_f64()
+    );
+
+    println!(
+        "Server threads: {}, VmRSS: {}",
+        status_field(server.id(), "Threads:"),
+        status_field(server.id(), "VmRSS:")
+    );
+
+    let mut stream = connections.pop().unwrap();
+    let _ = stream.write_all(b"TERMINATE\n");
+    let _ = server.wait();
+}
@@ -0,0 +1,93 @@
+//!
+//! Port pool benchmark.
+//!
+//!   Times the PortPool operations the server performs for every request
+//! with a large number of allocations (PORTMAN_BENCH_ENTRIES, default
+//! 20000) in a pool that covers most of the port space:
+//!
+//! -  Allocating, one port at a time and in blocks.
+//! -  Duplicate name checks against a full pool.
+//! -  Listing, both when nothing has changed and after every change.
+//! -  Freeing.
+//!
+use portman::portpool::ports::{PortPool, PortRequest, Protocol};
+use std::env;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const DEFAULT_ENTRIES: usize = 20000;
+const LISTS: usize = 100;
+
+fn report(what: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{:<40} {:>8} in {:>12?} ({:?} each)",
+        what,
+        count,
+        elapsed,
+        elapsed / count as u32
+    );
+}
+
+fn main() {
+    let entries: usize = env::var("PORTMAN_BENCH_ENTRIES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_ENTRIES)
+        .min(60000);
+    let mut pool = PortPool::new(1024, 64511);
+    let names: Vec<String> = (0..entries).map(|i| format!("service{}", i)).collect();
+
+    let start = Instant::now();
+    for name in &names {
+        black_box(pool.allocate(name, "bench").unwrap());
+    }
+    report("allocate", entries, start.elapsed());
+
+    let start = Instant::now();
+    for name in &names {
+        assert!(pool.allocate(name, "bench").is_err());
+    }
+    report("duplicate allocate", entries, start.elapsed());
+
+    pool.snapshot();
+    let start = Instant::now();
+    for _ in 0..LISTS {
+        black_box(pool.snapshot());
+    }
+    report("list (unchanged)", LISTS, start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..LISTS {
+        let port = pool.allocate("churn", "bench").unwrap().port();
+        black_box(pool.snapshot());
+        pool.free(port).unwrap();
+    }
+    report("list (after allocate)", LISTS, start.elapsed());
+
+    // Free every other port so blocks have to be searched for:
+
+    let start = Instant::now();
+    let mut freed = 0;
+    for port in (1024..1024 + entries as u16).step_by(2) {
+        pool.free(port).unwrap();
+        freed += 1;
+    }
+    report("free", freed, start.elapsed());
+
+    let blocks = 100;
+    let start = Instant::now();
+    for i in 0..blocks {
+        let name = format!("block{}", i);
+        black_box(
+            pool.allocate_block(4, &name, "bench", Protocol::Tcp)
+                .unwrap(),
+        );
+    }
+    report("allocate block of 4 (fragmented)", blocks, start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..blocks {
+        black_box(pool.satisfiable(PortRequest::Block(2), Protocol::Tcp));
+    }
+    report("satisfiable block of 2", blocks, start.elapsed());
+}
@@ -0,0 +1,919 @@
+use super::message::{self, put_labels, put_record, read_name, Record};
+use super::message::{CLASS_IN, TYPE_ANY, TYPE_PTR};
+use crate::log_debug;
+use crate::logging::audit::{Event, Recorder};
+use crate::portpool::ports::{Protocol, UsedPort};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Multicast DNS (RFC 6762) publication of the allocations as DNS-SD (RFC
+// 6763) services, so that programs on the LAN can browse for them without
+// knowing which host runs them.  Each advertised service is an instance of
+// the service type _portman._tcp (or _portman._udp for UDP ports) named
+// service.user, e.g. daq.fox._portman._tcp.local, with:
+//
+//   PTR - From the service type to the instance (and from
+//         _services._dns-sd._udp.local to the service type).
+//   SRV - One per port, to this host's .local name.
+//   TXT - service=, user= and protocol= strings.
+//
+// A grant is announced at once and again a second later; when a port is
+// freed its records are withdrawn with goodbye packets (the records with
+// a TTL of 0), as is everything still published when the server stops.
+// Queries for these names are answered, including unicast queries from
+// ordinary resolvers (sent from ports other than 5353).
+//
+// The host's own address record (host.local) is left to the system's mDNS
+// responder (e.g. avahi).  Instance names aren't probed for conflicts:  two
+// portman hosts with the same service advertised for the same user will
+// publish the same name.  A name too long for a DNS label (63 bytes) is
+// cut short and ends with a hash of the whole name, so it's still unique.
+//
+// The publisher runs in a thread of its own.  It's told of grants and
+// releases by being a Recorder of the audit records the responder makes.
+
+///
+/// The mDNS group and port.
+///
+pub const MDNS_GROUP: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353));
+
+const SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+
+// The TTLs RFC 6762 recommends for records naming a host (SRV) and for
+// the others, and the most a unicast resolver is told to cache them.
+
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+const UNICAST_TTL: u32 = 10;
+
+// Records that are unique to us (SRV and TXT) have the cache flush bit set
+// in their class.
+
+const CACHE_FLUSH: u16 = 0x8000;
+
+// Announcements are repeated after this long.  Packets are kept under the
+// Ethernet MTU.
+
+const REPEAT: Duration = Duration::from_secs(1);
+const MAX_PACKET: usize = 1400;
+
+// An instance:  its service, user and protocol and the first label of its
+// name.
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Instance {
+    service: String,
+    user: String,
+    udp: bool,
+    label: Vec<u8>,
+}
+
+impl Instance {
+    fn of(used: &UsedPort) -> Instance {
+        let (service, user) = (used.service(), used.user());
+        let label = instance_label(&format!("{}.{}", service, user));
+        Instance {
+            service,
+            user,
+            udp: used.protocol() == Protocol::Udp,
+            label,
+        }
+    }
+    fn name(&self) -> Vec<Vec<u8>> {
+        let mut name = vec![self.label.clone()];
+        name.extend(service_type(self.udp));
+        name
+    }
+}
+
+// The label for an instance named 'name'.  One too long is cut short, at a
+// character boundary, to leave room for a hyphen and the FNV-1a hash of
+// the whole name in hex.
+
+fn instance_label(name: &str) -> Vec<u8> {
+    if name.len() <= message::MAX_LABEL {
+        return name.as_bytes().to_vec();
+    }
+    let hash = name.bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    let mut end = message::MAX_LABEL - 9;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    log_debug!("Publishing {} as {}-{:08x}", name, &name[..end], hash);
+    format!("{}-{:08x}", &name[..end], hash).into_bytes()
+}
+
+fn service_type(udp: bool) -> Vec<Vec<u8>> {
+    let protocol: &[u8] = if udp { b"_udp" } else { b"_tcp" };
+    vec![b"_portman".to_vec(), protocol.to_vec(), b"local".to_vec()]
+}
+
+fn services_name() -> Vec<Vec<u8>> {
+    vec![
+        b"_services".to_vec(),
+        b"_dns-sd".to_vec(),
+        b"_udp".to_vec(),
+        b"local".to_vec(),
+    ]
+}
+
+fn same_name(a: &[Vec<u8>], b: &[Vec<u8>]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+// A question:  the name asked about and the record type wanted.
+
+type Question = (Vec<Vec<u8>>, u16);
+
+// A record to send:  its name, data, whether it's unique to us and TTL.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rr {
+    name: Vec<Vec<u8>>,
+    record: Record,
+    unique: bool,
+    ttl: u32,
+}
+
+///
+/// Services
+///    What's published:  the ports of each instance, whose SRV records
+/// point at *target*.  Its methods return the packets to send.
+///
+#[derive(Debug, Clone)]
+pub(crate) struct Services {
+    target: String,
+    published: BTreeMap<Instance, BTreeSet<u16>>,
+}
+
+impl Services {
+    pub(crate) fn new(target: &str) -> Services {
+        Services {
+            target: String::from(target),
+            published: BTreeMap::new(),
+        }
+    }
+    fn ptr(&self, instance: &Instance, ttl: u32) -> Rr {
+        Rr {
+            name: service_type(instance.udp),
+            record: Record::Ptr(instance.name()),
+            unique: false,
+            ttl,
+        }
+    }
+    fn srv(&self, instance: &Instance, port: u16, ttl: u32) -> Rr {
+        Rr {
+            name: instance.name(),
+            record: Record::Srv {
+                priority: 0,
+                weight: 0,
+                port,
+                target: self.target.clone(),
+            },
+            unique: true,
+            ttl,
+        }
+    }
+    fn txt(&self, instance: &Instance, ttl: u32) -> Rr {
+        Rr {
+            name: instance.name(),
+            record: Record::Txt(vec![
+                format!("service={}", instance.service),
+                format!("user={}", instance.user),
+                format!("protocol={}", if instance.udp { "udp" } else { "tcp" }),
+            ]),
+            unique: true,
+            ttl,
+        }
+    }
+    // All of an instance's records.
+
+    fn records(&self, instance: &Instance, ttl: Option<u32>) -> Vec<Rr> {
+        let mut records = vec![self.ptr(instance, ttl.unwrap_or(OTHER_TTL))];
+        for port in self.published.get(instance).into_iter().flatten() {
+            records.push(self.srv(instance, *port, ttl.unwrap_or(HOST_TTL)));
+        }
+        records.push(self.txt(instance, ttl.unwrap_or(OTHER_TTL)));
+        records
+    }
+    ///
+    /// grant
+    ///    Publish a port that's been granted.
+    ///
+    pub(crate) fn grant(&mut self, used: &UsedPort) -> Vec<Vec<u8>> {
+        let instance = Instance::of(used);
+        self.published
+            .entry(instance.clone())
+            .or_default()
+            .insert(used.port());
+        self.announce(used)
+    }
+    ///
+    /// announce
+    ///    Announce a port again, if it's still published.
+    ///
+    pub(crate) fn announce(&self, used: &UsedPort) -> Vec<Vec<u8>> {
+        let instance = Instance::of(used);
+        match self.published.get(&instance) {
+            Some(ports) if ports.contains(&used.port()) => packets(
+                0,
+                None,
+                &[
+                    self.ptr(&instance, OTHER_TTL),
+                    self.srv(&instance, used.port(), HOST_TTL),
+                    self.txt(&instance, OTHER_TTL),
+                ],
+                &[],
+            ),
+            _ => Vec::new(),
+        }
+    }
+    ///
+    /// release
+    ///    Withdraw a port that's been freed and, if it was the instance's
+    ///    last, the instance.
+    ///
+    pub(crate) fn release(&mut self, used: &UsedPort) -> Vec<Vec<u8>> {
+        let instance = Instance::of(used);
+        let last = match self.published.get_mut(&instance) {
+            Some(ports) if ports.contains(&used.port()) => {
+                ports.remove(&used.port());
+                ports.is_empty()
+            }
+            _ => return Vec::new(),
+        };
+        let mut goodbye = vec![self.srv(&instance, used.port(), 0)];
+        if last {
+            self.published.remove(&instance);
+            goodbye.push(self.ptr(&instance, 0));
+            goodbye.push(self.txt(&instance, 0));
+        }
+        packets(0, None, &goodbye, &[])
+    }
+    ///
+    /// goodbye
+    ///    Withdraw everything.
+    ///
+    pub(crate) fn goodbye(&mut self) -> Vec<Vec<u8>> {
+        let records: Vec<Rr> = self
+            .published
+            .keys()
+            .flat_map(|instance| self.records(instance, Some(0)))
+            .collect();
+        self.published.clear();
+        packets(0, None, &records, &[])
+    }
+    ///
+    /// answer
+    ///    The answers to an mDNS query.  A *unicast* query (from a port
+    ///    other than 5353) gets a conventional DNS response with its id and
+    ///    questions.
+    ///
+    pub(crate) fn answer(&self, packet: &[u8], unicast: bool) -> Vec<Vec<u8>> {
+        let questions = match questions(packet) {
+            Some(questions) => questions,
+            None => return Vec::new(),
+        };
+        let mut answers = Vec::new();
+        let mut additional = Vec::new();
+        for (name, qtype) in &questions {
+            let wants = |rtype| *qtype == rtype || *qtype == TYPE_ANY;
+            if same_name(name, &services_name()) && wants(TYPE_PTR) {
+                for udp in &[false, true] {
+                    if self.published.keys().any(|i| i.udp == *udp) {
+                        answers.push(Rr {
+                            name: services_name(),
+                            record: Record::Ptr(service_type(*udp)),
+                            unique: false,
+                            ttl: OTHER_TTL,
+                        });
+                    }
+                }
+            }
+            for instance in self.published.keys() {
+                if same_name(name, &service_type(instance.udp)) && wants(TYPE_PTR) {
+                    answers.push(self.ptr(instance, OTHER_TTL));
+                    additional.extend(self.records(instance, None).into_iter().skip(1));
+                } else if same_name(name, &instance.name()) {
+                    for rr in self.records(instance, None).into_iter().skip(1) {
+                        if wants(rr.record.rtype()) {
+                            answers.push(rr);
+                        }
+                    }
+                }
+            }
+        }
+        if answers.is_empty() {
+            return Vec::new();
+        }
+        if unicast {
+            for rr in answers.iter_mut().chain(additional.iter_mut()) {
+                rr.unique = false;
+                rr.ttl = rr.ttl.min(UNICAST_TTL);
+            }
+            let id = message_id(packet);
+            packets(id, Some(&questions), &answers, &additional)
+        } else {
+            packets(0, None, &answers, &additional)
+        }
+    }
+}
+
+fn message_id(packet: &[u8]) -> u16 {
+    u16::from_be_bytes([packet[0], packet[1]])
+}
+
+// The questions of a query:  their names and types.  None if it's not a
+// query or is malformed.
+
+fn questions(packet: &[u8]) -> Option<Vec<Question>> {
+    if packet.len() < 12 || packet[2] & 0x80 != 0 {
+        return None;
+    }
+    let count = u16::from_be_bytes([packet[4], packet[5]]);
+    let mut at = 12;
+    let mut questions = Vec::new();
+    for _ in 0..count {
+        let (name, end) = read_name(packet, at)?;
+        let qtype = u16::from_be_bytes([*packet.get(end)?, *packet.get(end + 1)?]);
+        questions.push((name, qtype));
+        at = end + 4;
+    }
+    Some(questions)
+}
+
+// Response packets holding 'answers' and then 'additional' records, as
+// many as it takes to keep each under MAX_PACKET.  Additional records
+// that don't fit in the last packet are dropped, as are records whose
+// names can't be sent.  A unicast response has 'questions', which it
+// repeats in each packet.
+
+fn packets(
+    id: u16,
+    questions: Option<&[Question]>,
+    answers: &[Rr],
+    additional: &[Rr],
+) -> Vec<Vec<u8>> {
+    let mut question = Vec::new();
+    let mut asked = 0;
+    for (name, qtype) in questions.unwrap_or(&[]) {
+        if put_labels(&mut question, name).is_ok() {
+            question.extend_from_slice(&qtype.to_be_bytes());
+            question.extend_from_slice(&CLASS_IN.to_be_bytes());
+            asked += 1;
+        }
+    }
+    let encode = |rr: &Rr| {
+        let mut out = Vec::new();
+        let class = if rr.unique {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        match put_record(&mut out, &rr.name, &rr.record, class, rr.ttl) {
+            Ok(()) => Some(out),
+            Err(e) => {
+                log_debug!("Not sending a record: {}", e);
+                None
+            }
+        }
+    };
+    let finish = |records: &[u8], counts: [u16; 2]| {
+        let mut packet = message::header(
+            id,
+            0,
+            false,
+            message::NOERROR,
+            false,
+            [asked, counts[0], 0, counts[1]],
+        );
+        packet.extend_from_slice(&question);
+        packet.extend_from_slice(records);
+        packet
+    };
+    let mut result = Vec::new();
+    let mut records = Vec::new();
+    let mut counts = [0u16; 2];
+    for (section, rrs) in [answers, additional].iter().enumerate() {
+        for rr in rrs.iter() {
+            let encoded = match encode(rr) {
+                Some(encoded) => encoded,
+                None => continue,
+            };
+            if 12 + question.len() + records.len() + encoded.len() > MAX_PACKET {
+                if section == 1 {
+                    break;
+                }
+                if counts[0] > 0 {
+                    result.push(finish(&records, counts));
+                    records.clear();
+                    counts = [0, 0];
+                }
+            }
+            records.extend_from_slice(&encoded);
+            counts[section] += 1;
+        }
+    }
+    if counts[0] > 0 {
+        result.push(finish(&records, counts));
+    }
+    result
+}
+
+///
+/// local_host
+///    This host's mDNS name:  the first label of its name in .local.
+///
+pub fn local_host() -> String {
+    let name = super::zone::host_name();
+    format!("{}.local", name.split('.').next().unwrap_or("localhost"))
+}
+
+// A grant or release to publish.
+
+enum Change {
+    Grant(UsedPort),
+    Release(UsedPort),
+}
+
+///
+/// MdnsControl
+///    Stops the publisher from another thread, which withdraws what it
+/// published.
+///
+pub(crate) struct MdnsControl {
+    stop: AtomicBool,
+    waker: Waker,
+}
+
+impl MdnsControl {
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+///
+/// Publisher
+///    The Recorder that passes grants and releases to the publisher's
+/// thread.
+///
+pub(crate) struct Publisher {
+    changes: mpsc::Sender<Change>,
+    control: Arc<MdnsControl>,
+}
+
+impl Recorder for Publisher {
+    fn record(&self, record: &crate::logging::audit::Record) {
+        let used = match (
+            &record.port,
+            &record.service,
+            &record.user,
+            &record.protocol,
+        ) {
+            (Some(port), Some(service), Some(user), Some(protocol)) => {
+                UsedPort::with_protocol(*port, service, user, *protocol)
+            }
+            _ => return,
+        };
+        let change = match record.event {
+            Event::Grant => Change::Grant(used),
+            Event::Release => Change::Release(used),
+            _ => return,
+        };
+        if self.changes.send(change).is_ok() {
+            let _ = self.control.waker.wake();
+        }
+    }
+}
+
+///
+/// Mdns
+///    The publisher:  it sends announcements and goodbyes to, and answers
+/// queries from, the mDNS group.
+///
+pub(crate) struct Mdns {
+    poll: Poll,
+    socket: UdpSocket,
+    group: SocketAddr,
+    services: Services,
+    changes: mpsc::Receiver<Change>,
+    repeats: Vec<(Instant, UsedPort)>,
+    control: Arc<MdnsControl>,
+}
+
+///
+/// mdns
+///    Create a publisher for the mDNS *group* (MDNS_GROUP but for tests)
+///    on the interface with address *interface* (or the system's choice
+///    if unspecified), whose SRV records point at *target*.  The Publisher
+///    is to be added to the audit's recorders and the Mdns run in a thread
+///    of its own.
+///
+pub(crate) fn mdns(
+    group: SocketAddr,
+    interface: Ipv4Addr,
+    target: &str,
+) -> io::Result<(Publisher, Mdns)> {
+    let socket = bind_shared(group.port())?;
+    if let IpAddr::V4(ip) = group.ip() {
+        socket.join_multicast_v4(&ip, &interface)?;
+    }
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    if !interface.is_unspecified() {
+        set_multicast_interface(&socket, interface)?;
+    }
+    let poll = Poll::new()?;
+    poll.registry().register(
+        &mut SourceFd(&socket.as_raw_fd()),
+        SOCKET,
+        Interest::READABLE,
+    )?;
+    let control = Arc::new(MdnsControl {
+        stop: AtomicBool::new(false),
+        waker: Waker::new(poll.registry(), WAKER)?,
+    });
+    let (send, receive) = mpsc::channel();
+    Ok((
+        Publisher {
+            changes: send,
+            control: Arc::clone(&control),
+        },
+        Mdns {
+            poll,
+            socket,
+            group,
+            services: Services::new(target),
+            changes: receive,
+            repeats: Vec::new(),
+            control,
+        },
+    ))
+}
+
+// A non-blocking UDP socket bound to 'port' of all interfaces that other
+// mDNS responders (or portman beacons) on this host can share.
+
+pub(crate) fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    for option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let on: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                *option,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: port.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        sin_zero: [0; 8],
+    };
+    let result = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(UdpSocket::from(fd))
+}
+
+// Send multicasts from the interface with address 'interface'.
+
+fn set_multicast_interface(socket: &UdpSocket, interface: Ipv4Addr) -> io::Result<()> {
+    let address = libc::in_addr {
+        s_addr: u32::from(interface).to_be(),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &address as *const libc::in_addr as *const libc::c_void,
+            std::mem::size_of::<libc::in_addr>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+impl Mdns {
+    pub(crate) fn control(&self) -> Arc<MdnsControl> {
+        Arc::clone(&self.control)
+    }
+    ///
+    /// Publish until stopped, then withdraw everything.
+    ///
+    pub(crate) fn run(mut self) {
+        let mut events = Events::with_capacity(16);
+        let mut buffer = [0u8; 9000];
+        while !self.control.stop.load(Ordering::SeqCst) {
+            let timeout = self
+                .repeats
+                .iter()
+                .map(|(when, _)| when.saturating_duration_since(Instant::now()))
+                .min();
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                break;
+            }
+            while let Ok(change) = self.changes.try_recv() {
+                let packets = match change {
+                    Change::Grant(used) => {
+                        self.repeats.push((Instant::now() + REPEAT, used.clone()));
+                        self.services.grant(&used)
+                    }
+                    Change::Release(used) => self.services.release(&used),
+                };
+                self.send(&packets, self.group);
+            }
+            let now = Instant::now();
+            let (due, later): (Vec<_>, Vec<_>) =
+                self.repeats.drain(..).partition(|(when, _)| *when <= now);
+            self.repeats = later;
+            for (_, used) in due {
+                let packets = self.services.announce(&used);
+                self.send(&packets, self.group);
+            }
+            loop {
+                match self.socket.recv_from(&mut buffer) {
+                    Ok((n, peer)) => {
+                        let unicast = peer.port() != self.group.port();
+                        let packets = self.services.answer(&buffer[..n], unicast);
+                        self.send(&packets, if unicast { peer } else { self.group });
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => break, // WouldBlock or e.g. ICMP unreachable.
+                }
+            }
+        }
+        let packets = self.services.goodbye();
+        self.send(&packets, self.group);
+    }
+    fn send(&self, packets: &[Vec<u8>], to: SocketAddr) {
+        for packet in packets {
+            if let Err(e) = self.socket.send_to(packet, to) {
+                log_debug!("Unable to send an mDNS packet to {}: {}", to, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::message::{TYPE_SRV, TYPE_TXT};
+    use std::thread;
+
+    fn names(packet: &[u8]) -> Vec<(String, u16, u32)> {
+        let count = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]) as usize;
+        let mut at = 12;
+        for _ in 0..count(4) {
+            at = read_name(packet, at).unwrap().1 + 4;
+        }
+        let mut result = Vec::new();
+        for _ in 0..count(6) + count(10) {
+            let (name, end) = read_name(packet, at).unwrap();
+            let name: Vec<String> = name
+                .iter()
+                .map(|l| String::from_utf8_lossy(l).into_owned())
+                .collect();
+            let ttl = u32::from_be_bytes([
+                packet[end + 4],
+                packet[end + 5],
+                packet[end + 6],
+                packet[end + 7],
+            ]);
+            result.push((name.join("/"), count(end) as u16, ttl));
+            at = end + 10 + count(end + 8);
+        }
+        result
+    }
+    fn query(name: &[&str], qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        let labels: Vec<Vec<u8>> = name.iter().map(|l| l.as_bytes().to_vec()).collect();
+        put_labels(&mut packet, &labels).unwrap();
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        let service = "x".repeat(60);
+        let label = instance_label(&format!("{}.fox", service));
+        assert_eq!(message::MAX_LABEL, label.len());
+        assert_eq!(label, instance_label(&format!("{}.fox", service)));
+        assert_ne!(label, instance_label(&format!("{}.owl", service)));
+        assert!(label.starts_with(&service.as_bytes()[..54]));
+        assert_eq!(b"daq.fox".to_vec(), instance_label("daq.fox"));
+
+        let mut services = Services::new("daq.local");
+        let packets = services.grant(&UsedPort::new(31000, &service, "fox"));
+        let name = format!("{}/_portman/_tcp/local", String::from_utf8_lossy(&label));
+        assert_eq!(name, names(&packets[0])[1].0);
+
+        // Multibyte characters aren't split:
+
+        let label = instance_label(&format!("{}é{}", "x".repeat(53), "y".repeat(20)));
+        assert!(String::from_utf8(label)
+            .unwrap()
+            .starts_with(&"x".repeat(53)));
+    }
+    #[test]
+    fn announces_and_withdraws() {
+        let mut services = Services::new("daq.local");
+        let first = UsedPort::new(31000, "daq", "fox");
+        let second = UsedPort::new(31001, "daq", "fox");
+        let packets = services.grant(&first);
+        assert_eq!(1, packets.len());
+        assert_eq!([0, 0, 0x84, 0, 0, 0, 0, 3, 0, 0, 0, 0], packets[0][..12]);
+        assert_eq!(
+            vec![
+                (String::from("_portman/_tcp/local"), TYPE_PTR, OTHER_TTL),
+                (
+                    String::from("daq.fox/_portman/_tcp/local"),
+                    TYPE_SRV,
+                    HOST_TTL
+                ),
+                (
+                    String::from("daq.fox/_portman/_tcp/local"),
+                    TYPE_TXT,
+                    OTHER_TTL
+                ),
+            ],
+            names(&packets[0])
+        );
+        services.grant(&second);
+
+        // Only the SRV record goes while the instance has other ports:
+
+        let packets = services.release(&first);
+        assert_eq!(
+            vec![(String::from("daq.fox/_portman/_tcp/local"), TYPE_SRV, 0)],
+            names(&packets[0])
+        );
+        assert!(services.announce(&first).is_empty());
+        assert!(services.release(&first).is_empty());
+        assert_eq!(
+            vec![
+                (String::from("daq.fox/_portman/_tcp/local"), TYPE_SRV, 0),
+                (String::from("_portman/_tcp/local"), TYPE_PTR, 0),
+                (String::from("daq.fox/_portman/_tcp/local"), TYPE_TXT, 0),
+            ],
+            names(&services.release(&second)[0])
+        );
+
+        services.grant(&first);
+        services.grant(&UsedPort::with_protocol(
+            31002,
+            "ring",
+            "owl",
+            Protocol::Udp,
+        ));
+        let packets = services.goodbye();
+        let goodbye = names(&packets[0]);
+        assert_eq!(6, goodbye.len());
+        assert!(goodbye.iter().all(|(_, _, ttl)| *ttl == 0));
+        assert!(services.goodbye().is_empty());
+    }
+    #[test]
+    fn answers() {
+        let mut services = Services::new("daq.local");
+        services.grant(&UsedPort::new(31000, "daq", "fox"));
+        services.grant(&UsedPort::with_protocol(
+            31001,
+            "ring",
+            "owl",
+            Protocol::Udp,
+        ));
+
+        let packets = services.answer(&query(&["_portman", "_TCP", "local"], TYPE_PTR), false);
+        assert_eq!([0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2], packets[0][..12]);
+        assert_eq!(
+            (String::from("_portman/_tcp/local"), TYPE_PTR, OTHER_TTL),
+            names(&packets[0])[0]
+        );
+        let packets = services.answer(
+            &query(&["_services", "_dns-sd", "_udp", "local"], TYPE_PTR),
+            false,
+        );
+        assert_eq!(2, names(&packets[0]).len());
+
+        // A unicast query gets its id and question back and short TTLs:
+
+        let packets = services.answer(
+            &query(&["ring.owl", "_portman", "_udp", "local"], TYPE_TXT),
+            true,
+        );
+        assert_eq!(
+            [0x12, 0x34, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 0],
+            packets[0][..12]
+        );
+        assert_eq!(
+            vec![(
+                String::from("ring.owl/_portman/_udp/local"),
+                TYPE_TXT,
+                UNICAST_TTL
+            )],
+            names(&packets[0])
+        );
+        assert!(services
+            .answer(
+                &query(&["bat.fox", "_portman", "_tcp", "local"], TYPE_ANY),
+                false
+            )
+            .is_empty());
+    }
+    #[test]
+    fn splits() {
+        let mut services = Services::new("daq.local");
+        for port in 31000..31100 {
+            services.grant(&UsedPort::new(port, &format!("service{}", port), "fox"));
+        }
+        let packets = services.goodbye();
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET));
+        let records: usize = packets.iter().map(|p| names(p).len()).sum();
+        assert_eq!(300, records);
+    }
+    #[test]
+    fn publishes() {
+        // Listen to a group of our own on a port other than mDNS's.
+
+        let group: SocketAddr = "224.0.0.251:35354".parse().unwrap();
+        let listener = bind_shared(group.port()).unwrap();
+        listener
+            .join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 251), &Ipv4Addr::UNSPECIFIED)
+            .unwrap();
+        listener.set_nonblocking(false).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        let (publisher, mdns) = mdns(group, Ipv4Addr::UNSPECIFIED, "daq.local").unwrap();
+        let control = mdns.control();
+        let thread = thread::spawn(move || mdns.run());
+        let used = UsedPort::new(31000, "daq", "fox");
+        publisher.record(
+            &crate::logging::audit::Record::new(Event::Grant, "allocated").with_port(&used),
+        );
+
+        let mut buffer = [0u8; 9000];
+        let n = listener.recv(&mut buffer).unwrap();
+        assert_eq!(3, names(&buffer[..n]).len());
+        control.stop();
+        thread.join().unwrap();
+
+        // The repeated announcement may come before the goodbye.
+
+        loop {
+            let n = listener.recv(&mut buffer).unwrap();
+            let records = names(&buffer[..n]);
+            if records.iter().all(|(_, _, ttl)| *ttl == 0) {
+                assert_eq!(3, records.len());
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,519 @@
+// The DNS wire format (RFC 1035), as much of it as answering SRV and TXT
+// queries needs:  parsing a query with its one question (and the EDNS OPT
+// record resolvers add to it) and building the response.
+//
+// Names are kept as their labels.  Labels are compared without regard to
+// ASCII case, as DNS requires.  A name with a label longer than MAX_LABEL
+// can't be sent.
+
+use crate::error::error::PortmanError;
+
+// Record types and classes:
+
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_ANY: u16 = 255;
+
+// Response codes:
+
+pub const NOERROR: u8 = 0;
+pub const FORMERR: u8 = 1;
+pub const SERVFAIL: u8 = 2;
+pub const NXDOMAIN: u8 = 3;
+pub const NOTIMP: u8 = 4;
+pub const REFUSED: u8 = 5;
+
+// Header flag bits:
+
+const QR: u16 = 0x8000;
+const AA: u16 = 0x0400;
+const TC: u16 = 0x0200;
+const RD: u16 = 0x0100;
+
+const HEADER: usize = 12;
+
+///
+/// The longest a label may be.
+///
+pub const MAX_LABEL: usize = 63;
+
+///
+/// The largest response that may be sent over UDP to a resolver that
+/// doesn't say (with EDNS) that it can take more, and the most we'll send
+/// to one that does.
+///
+pub const UDP_SIZE: usize = 512;
+pub const MAX_EDNS_SIZE: usize = 4096;
+
+///
+/// Query
+///    A query with its question.  *question* is the question section as
+/// received, to be echoed in the response.  *edns* is the UDP payload size
+/// the resolver said it can take, if it sent an OPT record.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub id: u16,
+    pub opcode: u8,
+    pub recursion_desired: bool,
+    pub labels: Vec<Vec<u8>>,
+    pub qtype: u16,
+    pub qclass: u16,
+    pub question: Vec<u8>,
+    pub edns: Option<u16>,
+}
+
+///
+/// Record
+///    The data of an answer.  A PTR record's name is given as its labels
+/// since DNS-SD instance names may have dots in their first label.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Ptr(Vec<Vec<u8>>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<String>),
+}
+
+impl Record {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            Record::Ptr(_) => TYPE_PTR,
+            Record::Srv { .. } => TYPE_SRV,
+            Record::Txt(_) => TYPE_TXT,
+        }
+    }
+    ///
+    /// The record's data in wire format.  Fails if a name in it has a
+    /// label that's too long.
+    ///
+    pub fn data(&self) -> Result<Vec<u8>, PortmanError> {
+        let mut data = Vec::new();
+        match self {
+            Record::Ptr(labels) => put_labels(&mut data, labels)?,
+            Record::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                for n in &[*priority, *weight, *port] {
+                    data.extend_from_slice(&n.to_be_bytes());
+                }
+                put_name(&mut data, target)?;
+            }
+            Record::Txt(strings) => {
+                for s in strings {
+                    let s = &s.as_bytes()[..s.len().min(255)];
+                    data.push(s.len() as u8);
+                    data.extend_from_slice(s);
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+// Append a dotted name in wire format (no compression).
+
+fn put_name(out: &mut Vec<u8>, name: &str) -> Result<(), PortmanError> {
+    let labels: Vec<Vec<u8>> = name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .map(|label| label.as_bytes().to_vec())
+        .collect();
+    put_labels(out, &labels)
+}
+
+///
+/// put_labels
+///    Append a name given as its labels in wire format (no compression).
+///    Nothing is appended if a label is longer than MAX_LABEL.
+///
+pub fn put_labels(out: &mut Vec<u8>, labels: &[Vec<u8>]) -> Result<(), PortmanError> {
+    if let Some(label) = labels.iter().find(|label| label.len() > MAX_LABEL) {
+        return Err(PortmanError::Invalid(format!(
+            "DNS label longer than {} bytes: {}",
+            MAX_LABEL,
+            String::from_utf8_lossy(label)
+        )));
+    }
+    for label in labels {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+    Ok(())
+}
+
+///
+/// put_record
+///    Append a resource record for *name* with *class* (which may have the
+///    mDNS cache flush bit set) and time to live *ttl*.  Nothing is
+///    appended if a name has a label that's too long.
+///
+pub fn put_record(
+    out: &mut Vec<u8>,
+    name: &[Vec<u8>],
+    record: &Record,
+    class: u16,
+    ttl: u32,
+) -> Result<(), PortmanError> {
+    let data = record.data()?;
+    put_labels(out, name)?;
+    out.extend_from_slice(&record.rtype().to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&ttl.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(&data);
+    Ok(())
+}
+
+///
+/// read_name
+///    Read the name at *at* in *packet*, following compression pointers.
+///    Returns its labels and where the name ends, or None if it's
+///    malformed.
+///
+pub fn read_name(packet: &[u8], mut at: usize) -> Option<(Vec<Vec<u8>>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(at)? as usize;
+        if len & 0xc0 == 0xc0 {
+            jumps += 1;
+            if jumps > 16 {
+                return None; // A loop.
+            }
+            let pointer = get_u16(packet, at)? as usize & 0x3fff;
+            end.get_or_insert(at + 2);
+            at = pointer;
+        } else if len > 63 {
+            return None;
+        } else if len == 0 {
+            return Some((labels, end.unwrap_or(at + 1)));
+        } else {
+            labels.push(packet.get(at + 1..at + 1 + len)?.to_vec());
+            at += 1 + len;
+        }
+    }
+}
+
+fn get_u16(packet: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(at)?, *packet.get(at + 1)?]))
+}
+
+///
+/// parse_query
+///    Parse a query.  Err is the response to send if the query is
+///    malformed but has a header we can answer, or None if it should be
+///    ignored (it's too short or is itself a response).
+///
+pub fn parse_query(packet: &[u8]) -> Result<Query, Option<Vec<u8>>> {
+    if packet.len() < HEADER {
+        return Err(None);
+    }
+    let id = get_u16(packet, 0).ok_or(None)?;
+    let flags = get_u16(packet, 2).ok_or(None)?;
+    if flags & QR != 0 {
+        return Err(None);
+    }
+    let opcode = ((flags >> 11) & 0xf) as u8;
+    let recursion_desired = flags & RD != 0;
+    let error = |rcode| Some(header(id, opcode, recursion_desired, rcode, false, [0; 4]));
+    if opcode != 0 {
+        return Err(error(NOTIMP));
+    }
+    if get_u16(packet, 4) != Some(1) {
+        return Err(error(FORMERR));
+    }
+
+    // The question's name is a list of labels.  Compression isn't
+    // expected in a query's one question so it's not accepted.
+
+    let mut at = HEADER;
+    let mut labels = Vec::new();
+    loop {
+        let len = *packet.get(at).ok_or_else(|| error(FORMERR))? as usize;
+        at += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 || at + len > packet.len() {
+            return Err(error(FORMERR));
+        }
+        labels.push(packet[at..at + len].to_vec());
+        at += len;
+    }
+    let qtype = get_u16(packet, at).ok_or_else(|| error(FORMERR))?;
+    let qclass = get_u16(packet, at + 2).ok_or_else(|| error(FORMERR))?;
+    at += 4;
+    let question = packet[HEADER..at].to_vec();
+
+    // An OPT record in the additional section says how big a UDP
+    // response the resolver can take.  Anything else there is ignored.
+
+    let mut edns = None;
+    if get_u16(packet, 6) == Some(0) && get_u16(packet, 8) == Some(0) {
+        let additional = get_u16(packet, 10).unwrap_or(0);
+        for _ in 0..additional {
+            let name = *packet.get(at).ok_or_else(|| error(FORMERR))?;
+            if name != 0 {
+                break; // Only OPT (whose name is the root) interests us.
+            }
+            let rtype = get_u16(packet, at + 1).ok_or_else(|| error(FORMERR))?;
+            let class = get_u16(packet, at + 3).ok_or_else(|| error(FORMERR))?;
+            let len = get_u16(packet, at + 9).ok_or_else(|| error(FORMERR))? as usize;
+            if rtype == TYPE_OPT {
+                edns = Some(class);
+            }
+            at += 11 + len;
+        }
+    }
+    Ok(Query {
+        id,
+        opcode,
+        recursion_desired,
+        labels,
+        qtype,
+        qclass,
+        question,
+        edns,
+    })
+}
+
+// A response header.  'counts' are the section counts.
+
+pub(crate) fn header(
+    id: u16,
+    opcode: u8,
+    recursion_desired: bool,
+    rcode: u8,
+    truncated: bool,
+    counts: [u16; 4],
+) -> Vec<u8> {
+    let mut flags = QR | AA | (u16::from(opcode) << 11) | u16::from(rcode);
+    if recursion_desired {
+        flags |= RD;
+    }
+    if truncated {
+        flags |= TC;
+    }
+    let mut out = Vec::with_capacity(UDP_SIZE);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&flags.to_be_bytes());
+    for count in &counts {
+        out.extend_from_slice(&count.to_be_bytes());
+    }
+    out
+}
+
+///
+/// response
+///    The response to *query*:  *rcode* and *answers*, each with time to
+///    live *ttl* seconds.  If it would be bigger than *max_size* the
+///    answers are left out and it's marked truncated so the resolver will
+///    ask again over TCP.  An answer whose name can't be sent (see
+///    put_labels) is left out.
+///
+pub fn response(
+    query: &Query,
+    rcode: u8,
+    answers: &[Record],
+    ttl: u32,
+    max_size: usize,
+) -> Vec<u8> {
+    let mut records = Vec::new();
+    let mut count = 0;
+    for answer in answers {
+        let data = match answer.data() {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        count += 1;
+        records.extend_from_slice(&[0xc0, HEADER as u8]); // The question's name.
+        records.extend_from_slice(&answer.rtype().to_be_bytes());
+        records.extend_from_slice(&CLASS_IN.to_be_bytes());
+        records.extend_from_slice(&ttl.to_be_bytes());
+        records.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        records.extend_from_slice(&data);
+    }
+
+    // A query with EDNS gets an OPT record back saying what we can take.
+
+    let mut opt = Vec::new();
+    if query.edns.is_some() {
+        opt.push(0);
+        opt.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        opt.extend_from_slice(&(MAX_EDNS_SIZE as u16).to_be_bytes());
+        opt.extend_from_slice(&[0; 6]); // Extended rcode, version, flags and no data.
+    }
+    let additional = if opt.is_empty() { 0 } else { 1 };
+
+    let size = HEADER + query.question.len() + records.len() + opt.len();
+    let truncated = size > max_size;
+    let answer_count = if truncated { 0 } else { count };
+    let mut out = header(
+        query.id,
+        query.opcode,
+        query.recursion_desired,
+        rcode,
+        truncated,
+        [1, answer_count, 0, additional],
+    );
+    out.extend_from_slice(&query.question);
+    if !truncated {
+        out.extend_from_slice(&records);
+    }
+    out.extend_from_slice(&opt);
+    out
+}
+
+///
+/// The most a UDP response to *query* may be.
+///
+pub fn udp_limit(query: &Query) -> usize {
+    match query.edns {
+        Some(size) => (size as usize).clamp(UDP_SIZE, MAX_EDNS_SIZE),
+        None => UDP_SIZE,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // A query for 'name' of type 'qtype', with an OPT record if 'edns'.
+
+    pub(crate) fn query(id: u16, name: &str, qtype: u16, edns: Option<u16>) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        put_name(&mut packet, name).unwrap();
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        if let Some(size) = edns {
+            packet[11] = 1;
+            packet.push(0);
+            packet.extend_from_slice(&TYPE_OPT.to_be_bytes());
+            packet.extend_from_slice(&size.to_be_bytes());
+            packet.extend_from_slice(&[0; 6]);
+        }
+        packet
+    }
+
+    #[test]
+    fn parses() {
+        let q = parse_query(&query(7, "_daq._fox.portman.local", TYPE_SRV, None)).unwrap();
+        assert_eq!(7, q.id);
+        assert!(q.recursion_desired);
+        assert_eq!(
+            vec![
+                b"_daq".to_vec(),
+                b"_fox".to_vec(),
+                b"portman".to_vec(),
+                b"local".to_vec()
+            ],
+            q.labels
+        );
+        assert_eq!(TYPE_SRV, q.qtype);
+        assert_eq!(CLASS_IN, q.qclass);
+        assert_eq!(None, q.edns);
+        assert_eq!(UDP_SIZE, udp_limit(&q));
+
+        let q = parse_query(&query(7, "portman.local", TYPE_TXT, Some(1232))).unwrap();
+        assert_eq!(Some(1232), q.edns);
+        assert_eq!(1232, udp_limit(&q));
+    }
+    #[test]
+    fn malformed() {
+        assert_eq!(Err(None), parse_query(&[0; 5]));
+        let mut response = query(7, "portman.local", TYPE_SRV, None);
+        response[2] |= 0x80;
+        assert_eq!(Err(None), parse_query(&response));
+
+        let truncated = query(7, "portman.local", TYPE_SRV, None);
+        let reply = parse_query(&truncated[..truncated.len() - 3])
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(FORMERR, reply[3] & 0xf);
+        assert_eq!([0, 7], reply[..2]);
+
+        let mut update = query(7, "portman.local", TYPE_SRV, None);
+        update[2] = 0x28; // Opcode 5.
+        let reply = parse_query(&update).unwrap_err().unwrap();
+        assert_eq!(NOTIMP, reply[3] & 0xf);
+    }
+    #[test]
+    fn names() {
+        let mut packet = vec![0; 12];
+        put_labels(&mut packet, &[b"daq.fox".to_vec(), b"local".to_vec()]).unwrap();
+        packet.extend_from_slice(&[3, b'w', b'e', b'b', 0xc0, 20]);
+        assert_eq!(
+            Some((vec![b"daq.fox".to_vec(), b"local".to_vec()], 27)),
+            read_name(&packet, 12)
+        );
+        assert_eq!(
+            Some((vec![b"web".to_vec(), b"local".to_vec()], 33)),
+            read_name(&packet, 27)
+        );
+        packet.extend_from_slice(&[0xc0, 33]);
+        assert_eq!(None, read_name(&packet, 33));
+        assert_eq!(None, read_name(&packet[..20], 12));
+
+        // A label that's too long isn't cut short:
+
+        let mut packet = Vec::new();
+        let long = vec![b'a'; MAX_LABEL + 1];
+        assert!(put_labels(&mut packet, &[long, b"local".to_vec()]).is_err());
+        assert!(put_name(&mut packet, &format!("{}.local", "a".repeat(MAX_LABEL + 1))).is_err());
+        assert!(packet.is_empty());
+    }
+    #[test]
+    fn responds() {
+        let q = parse_query(&query(9, "_daq._fox.portman.local", TYPE_SRV, None)).unwrap();
+        let answers = vec![
+            Record::Srv {
+                priority: 0,
+                weight: 0,
+                port: 31000,
+                target: String::from("daq.example."),
+            },
+            Record::Txt(vec![String::from("port=31000")]),
+        ];
+        let r = response(&q, NOERROR, &answers, 5, UDP_SIZE);
+        assert_eq!([0, 9, 0x85, 0x00, 0, 1, 0, 2, 0, 0, 0, 0], r[..12]);
+        let mut at = 12 + q.question.len();
+        assert_eq!(q.question, r[12..at]);
+        assert_eq!([0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 5, 0, 19], r[at..at + 12]);
+        at += 12;
+        assert_eq!([0, 0, 0, 0, 0x79, 0x18, 3], r[at..at + 7]);
+        assert_eq!(b"daq\x07example\x00", &r[at + 7..at + 19]);
+        at += 19;
+        assert_eq!([0xc0, 12, 0, 16], r[at..at + 4]);
+        assert_eq!(b"\x0aport=31000", &r[at + 12..]);
+
+        // Too big for UDP:
+
+        let r = response(&q, NOERROR, &answers, 5, 40);
+        assert_eq!([0x87, 0x00, 0, 1, 0, 0], r[2..8]);
+        assert_eq!(12 + q.question.len(), r.len());
+
+        // EDNS:
+
+        let q = parse_query(&query(9, "portman.local", TYPE_SRV, Some(1232))).unwrap();
+        let r = response(&q, NXDOMAIN, &[], 5, UDP_SIZE);
+        assert_eq!([0x85, 0x03, 0, 1, 0, 0, 0, 0, 0, 1], r[2..12]);
+        assert_eq!([0, 0, 41, 0x10, 0], r[r.len() - 11..r.len() - 6]);
+    }
+}
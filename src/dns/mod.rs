@@ -0,0 +1,6 @@
+// Contains module definitions that pull in specific files
+
+pub(crate) mod mdns;
+pub mod message;
+pub(crate) mod server;
+pub mod zone;
@@ -0,0 +1,406 @@
+use super::message::{self, parse_query, Query, Record};
+use super::zone::TTL;
+use crate::error::error::PortmanError;
+use crate::log_debug;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// A DNS server for the names of a Zone, over UDP and TCP on the same port.
+// Like the HTTP listener it's a single thread with one mio Poll and
+// non-blocking sockets:  UDP queries are answered as they come and each
+// TCP connection gets one answer (to the first query sent on it) before
+// it's closed.  TCP clients that are slow to send their query or take the
+// answer time out, and only so many are served at once, so they can't
+// hold up UDP or each other.  Responses too big for UDP are truncated,
+// which sends the resolver to TCP.
+
+const UDP: Token = Token(0);
+const LISTENER: Token = Token(1);
+const WAKER: Token = Token(2);
+
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The most TCP connections served at once.  Those over are closed.
+
+const MAX_CLIENTS: usize = 64;
+
+///
+/// DnsStop
+///    Stops a DnsServer from another thread.
+///
+pub(crate) struct DnsStop {
+    stop: AtomicBool,
+    waker: Waker,
+}
+
+impl DnsStop {
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+///
+/// DnsServer
+///    Answers queries with what the handler given to run returns:  the
+/// response code and the answers for a query from a peer.
+///
+pub(crate) struct DnsServer {
+    poll: Poll,
+    udp: UdpSocket,
+    listener: TcpListener,
+    clients: HashMap<Token, Client>,
+    next_token: usize,
+    stop: Arc<DnsStop>,
+}
+
+impl DnsServer {
+    ///
+    /// bind
+    ///    Bind UDP and TCP sockets to *address*.  If its port is 0, TCP
+    ///    gets the port the system picked for UDP.
+    ///
+    pub(crate) fn bind(address: SocketAddr) -> Result<DnsServer, PortmanError> {
+        let udp = UdpSocket::bind(address)?;
+        let listener = TcpListener::bind(udp.local_addr()?)?;
+        udp.set_nonblocking(true)?;
+        listener.set_nonblocking(true)?;
+        let poll = Poll::new()?;
+        poll.registry()
+            .register(&mut SourceFd(&udp.as_raw_fd()), UDP, Interest::READABLE)?;
+        poll.registry().register(
+            &mut SourceFd(&listener.as_raw_fd()),
+            LISTENER,
+            Interest::READABLE,
+        )?;
+        let stop = Arc::new(DnsStop {
+            stop: AtomicBool::new(false),
+            waker: Waker::new(poll.registry(), WAKER)?,
+        });
+        Ok(DnsServer {
+            poll,
+            udp,
+            listener,
+            clients: HashMap::new(),
+            next_token: 3,
+            stop,
+        })
+    }
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        self.udp.local_addr().ok()
+    }
+    pub(crate) fn stopper(&self) -> Arc<DnsStop> {
+        Arc::clone(&self.stop)
+    }
+    ///
+    /// Serve queries until stopped.
+    ///
+    pub(crate) fn run<F>(mut self, handler: F)
+    where
+        F: Fn(&Query, &SocketAddr) -> (u8, Vec<Record>),
+    {
+        let mut events = Events::with_capacity(64);
+        let mut buffer = [0u8; message::MAX_EDNS_SIZE];
+        while !self.stop.stop.load(Ordering::SeqCst) {
+            let timeout = self
+                .clients
+                .values()
+                .map(|c| c.deadline)
+                .min()
+                .map(|d| d.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    UDP => self.answer_udp(&mut buffer, &handler),
+                    LISTENER => self.accept(),
+                    WAKER => {}
+                    token => {
+                        let done = match self.clients.get_mut(&token) {
+                            Some(client) => client.serve(&handler),
+                            None => false,
+                        };
+                        if done {
+                            self.close(token);
+                        }
+                    }
+                }
+            }
+            let now = Instant::now();
+            let overdue: Vec<Token> = self
+                .clients
+                .iter()
+                .filter(|(_, c)| c.deadline <= now)
+                .map(|(token, _)| *token)
+                .collect();
+            for token in overdue {
+                self.close(token);
+            }
+        }
+    }
+    fn answer_udp<F>(&mut self, buffer: &mut [u8], handler: &F)
+    where
+        F: Fn(&Query, &SocketAddr) -> (u8, Vec<Record>),
+    {
+        loop {
+            match self.udp.recv_from(buffer) {
+                Ok((n, peer)) => {
+                    if let Some(response) = respond(&buffer[..n], &peer, false, handler) {
+                        let _ = self.udp.send_to(&response, peer);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => break, // E.g. ICMP unreachable from an earlier reply.
+            }
+        }
+    }
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    if self.clients.len() >= MAX_CLIENTS || stream.set_nonblocking(true).is_err() {
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    if self
+                        .poll
+                        .registry()
+                        .register(
+                            &mut SourceFd(&stream.as_raw_fd()),
+                            token,
+                            Interest::READABLE | Interest::WRITABLE,
+                        )
+                        .is_ok()
+                    {
+                        self.clients.insert(token, Client::new(stream, peer));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break, // WouldBlock or e.g. out of descriptors.
+            }
+        }
+    }
+    fn close(&mut self, token: Token) {
+        if let Some(client) = self.clients.remove(&token) {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&client.stream.as_raw_fd()));
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// The response to a query from 'peer' or None if it's to be ignored.
+
+fn respond<F>(packet: &[u8], peer: &SocketAddr, tcp: bool, handler: &F) -> Option<Vec<u8>>
+where
+    F: Fn(&Query, &SocketAddr) -> (u8, Vec<Record>),
+{
+    let query = match parse_query(packet) {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+    let (rcode, answers) = handler(&query, peer);
+    log_debug!(
+        "DNS {} type {} from {}: rcode {}, {} answers",
+        query
+            .labels
+            .iter()
+            .map(|l| String::from_utf8_lossy(l))
+            .collect::<Vec<_>>()
+            .join("."),
+        query.qtype,
+        peer,
+        rcode,
+        answers.len()
+    );
+    let limit = if tcp {
+        usize::from(u16::MAX)
+    } else {
+        message::udp_limit(&query)
+    };
+    Some(message::response(&query, rcode, &answers, TTL, limit))
+}
+
+// A TCP connection being served:  its query as it comes in, then the
+// answer as it goes out.  Messages over TCP are preceded by their length.
+
+struct Client {
+    stream: TcpStream,
+    peer: SocketAddr,
+    input: Vec<u8>,
+    output: Option<Vec<u8>>, // The answer, once there is one.
+    written: usize,
+    deadline: Instant,
+}
+
+impl Client {
+    fn new(stream: TcpStream, peer: SocketAddr) -> Client {
+        Client {
+            stream,
+            peer,
+            input: Vec::new(),
+            output: None,
+            written: 0,
+            deadline: Instant::now() + IO_TIMEOUT,
+        }
+    }
+    // Do what we can now.  True once the connection is done with.
+    //
+    fn serve<F>(&mut self, handler: &F) -> bool
+    where
+        F: Fn(&Query, &SocketAddr) -> (u8, Vec<Record>),
+    {
+        if self.output.is_none() {
+            let packet = match self.read_query() {
+                Some(Some(packet)) => packet,
+                Some(None) => return false,
+                None => return true,
+            };
+            let response = match respond(&packet, &self.peer, true, handler) {
+                Some(response) => response,
+                None => return true,
+            };
+            let mut out = (response.len() as u16).to_be_bytes().to_vec();
+            out.extend_from_slice(&response);
+            self.output = Some(out);
+            self.deadline = Instant::now() + IO_TIMEOUT;
+        }
+        self.flush()
+    }
+    // Read what's come of the query:  the query once it's all here,
+    // Some(None) if more is to come and None if the client went away.
+    //
+    fn read_query(&mut self) -> Option<Option<Vec<u8>>> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            if self.input.len() >= 2 {
+                let end = 2 + usize::from(u16::from_be_bytes([self.input[0], self.input[1]]));
+                if self.input.len() >= end {
+                    return Some(Some(self.input[2..end].to_vec()));
+                }
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return None,
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Some(None),
+                Err(_) => return None,
+            }
+        }
+    }
+    // Send what we can of the answer.  True once it's all gone or the
+    // client has.
+    //
+    fn flush(&mut self) -> bool {
+        let output = match &self.output {
+            Some(output) => output,
+            None => return false,
+        };
+        while self.written < output.len() {
+            match self.stream.write(&output[self.written..]) {
+                Ok(0) => return true,
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(_) => return true,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::message::tests::query;
+    use crate::dns::message::{NOERROR, NXDOMAIN, TYPE_SRV, TYPE_TXT};
+    use std::thread;
+
+    // A server answering every query with 'n' TXT records or, if there are
+    // none, NXDOMAIN.
+
+    fn start(n: usize) -> (SocketAddr, Arc<DnsStop>, thread::JoinHandle<()>) {
+        let server = DnsServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr().unwrap();
+        let stop = server.stopper();
+        let thread = thread::spawn(move || {
+            server.run(move |_, _| {
+                let answers = vec![Record::Txt(vec![String::from("x").repeat(200)]); n];
+                (if n == 0 { NXDOMAIN } else { NOERROR }, answers)
+            })
+        });
+        (address, stop, thread)
+    }
+
+    #[test]
+    fn udp_and_tcp() {
+        let (address, stop, thread) = start(0);
+
+        // A TCP client that's slow to send its query doesn't hold up UDP:
+
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(&[0]).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        socket
+            .send_to(
+                &query(3, "_daq._fox.portman.local", TYPE_SRV, None),
+                address,
+            )
+            .unwrap();
+        let mut buffer = [0u8; 512];
+        let n = socket.recv(&mut buffer).unwrap();
+        assert_eq!([0, 3, 0x85, NXDOMAIN], buffer[..4]);
+        assert_eq!(12 + 29, n);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let packet = query(4, "portman.local", TYPE_TXT, None);
+        let mut out = (packet.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(&packet);
+        stream.write_all(&out).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert_eq!([0, 12 + 19, 0, 4, 0x85, NXDOMAIN], response[..6]);
+
+        stop.stop();
+        thread.join().unwrap();
+    }
+    #[test]
+    fn truncates() {
+        let (address, stop, thread) = start(4);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        let mut buffer = [0u8; 4096];
+        socket
+            .send_to(&query(5, "portman.local", TYPE_TXT, None), address)
+            .unwrap();
+        socket.recv(&mut buffer).unwrap();
+        assert_eq!([0x87, NOERROR, 0, 1, 0, 0], buffer[2..8]);
+
+        socket
+            .send_to(&query(6, "portman.local", TYPE_TXT, Some(1232)), address)
+            .unwrap();
+        socket.recv(&mut buffer).unwrap();
+        assert_eq!([0x85, NOERROR, 0, 1, 0, 4], buffer[2..8]);
+
+        stop.stop();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use super::message::{
+    Query, Record, CLASS_ANY, CLASS_IN, NOERROR, NXDOMAIN, REFUSED, SERVFAIL, TYPE_ANY, TYPE_SRV,
+    TYPE_TXT,
+};
+use crate::portpool::ports::UsedPort;
+use crate::responder::responder::{self, RequestMessage};
+use crate::server::peer::may_list;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+
+// The names we answer for.  Under the domain (portman.local by default)
+// each advertised service has the name _service._user, as in DNS-SD, e.g.
+// _daq._fox.portman.local, with:
+//
+//   SRV - One per port:  priority 0, weight 0, the port and our host.
+//   TXT - One per port:  service=, user=, port= and protocol= strings.
+//
+// The domain itself and _user (for users with advertised services) exist
+// but have no records.  Anything else under the domain doesn't exist, so a
+// service that isn't advertised gets NXDOMAIN.  Names outside the domain
+// are REFUSED:  we're not a resolver.
+//
+// Labels are compared with service and user names without regard to ASCII
+// case, as DNS compares names.
+
+///
+/// How long resolvers may cache our answers, in seconds.  Allocations come
+/// and go so this is kept short.
+///
+pub const TTL: u32 = 5;
+
+///
+/// Zone
+///    The names under *domain*, whose SRV records point at *target*.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zone {
+    domain: Vec<String>,
+    target: String,
+}
+
+impl Zone {
+    pub fn new(domain: &str, target: &str) -> Zone {
+        Zone {
+            domain: domain
+                .trim_end_matches('.')
+                .split('.')
+                .filter(|l| !l.is_empty())
+                .map(str::to_ascii_lowercase)
+                .collect(),
+            target: String::from(target),
+        }
+    }
+    ///
+    /// in_zone
+    ///    If *query* is for a name under the domain, the labels in front
+    ///    of it.
+    ///
+    pub fn in_zone<'a>(&self, query: &'a Query) -> Option<&'a [Vec<u8>]> {
+        if query.labels.len() < self.domain.len() {
+            return None;
+        }
+        let (front, tail) = query
+            .labels
+            .split_at(query.labels.len() - self.domain.len());
+        let matches = tail
+            .iter()
+            .zip(&self.domain)
+            .all(|(label, domain)| label.eq_ignore_ascii_case(domain.as_bytes()));
+        if matches {
+            Some(front)
+        } else {
+            None
+        }
+    }
+    ///
+    /// answer
+    ///    The response code and answers for *query* given *allocations*.
+    ///
+    pub fn answer(&self, query: &Query, allocations: &[UsedPort]) -> (u8, Vec<Record>) {
+        let front = match self.in_zone(query) {
+            Some(front) if query.qclass == CLASS_IN || query.qclass == CLASS_ANY => front,
+            _ => return (REFUSED, Vec::new()),
+        };
+        let names: Vec<&[u8]> = match front.iter().map(|l| l.strip_prefix(b"_")).collect() {
+            Some(names) => names,
+            None => return (NXDOMAIN, Vec::new()),
+        };
+        let matching: Vec<&UsedPort> = match names.as_slice() {
+            [] => return (NOERROR, Vec::new()),
+            [user] => {
+                let exists = allocations.iter().any(|a| same(a.user(), user));
+                return (if exists { NOERROR } else { NXDOMAIN }, Vec::new());
+            }
+            [service, user] => allocations
+                .iter()
+                .filter(|a| same(a.service(), service) && same(a.user(), user))
+                .collect(),
+            _ => Vec::new(),
+        };
+        if matching.is_empty() {
+            return (NXDOMAIN, Vec::new());
+        }
+        let mut answers = Vec::new();
+        if query.qtype == TYPE_SRV || query.qtype == TYPE_ANY {
+            answers.extend(matching.iter().map(|a| Record::Srv {
+                priority: 0,
+                weight: 0,
+                port: a.port(),
+                target: self.target.clone(),
+            }));
+        }
+        if query.qtype == TYPE_TXT || query.qtype == TYPE_ANY {
+            answers.extend(matching.iter().map(|a| {
+                Record::Txt(vec![
+                    format!("service={}", a.service()),
+                    format!("user={}", a.user()),
+                    format!("port={}", a.port()),
+                    format!("protocol={}", a.protocol()),
+                ])
+            }));
+        }
+        (NOERROR, answers)
+    }
+}
+
+///
+/// resolve
+///    The response code and answers for *query* from *peer* given the
+///    allocations the responder reached through *requests* has.  Those who
+///    may not LIST the allocations are REFUSED, and if the responder
+///    doesn't answer it's a server failure.
+///
+pub fn resolve(
+    zone: &Zone,
+    requests: &mpsc::Sender<RequestMessage>,
+    query: &Query,
+    peer: &SocketAddr,
+) -> (u8, Vec<Record>) {
+    if zone.in_zone(query).is_none() || may_list(peer).is_err() {
+        return (REFUSED, Vec::new());
+    }
+    match responder::get_allocations(requests) {
+        Ok(allocations) => zone.answer(query, &allocations),
+        Err(_) => (SERVFAIL, Vec::new()),
+    }
+}
+
+///
+/// host_name
+///    This host's name, for SRV records to point at.
+///
+pub fn host_name() -> String {
+    let mut name = [0u8; 256];
+    let result = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    if result != 0 || end == 0 {
+        return String::from("localhost");
+    }
+    String::from_utf8_lossy(&name[..end]).into_owned()
+}
+
+// Whether a name is the one a label holds.
+
+fn same(name: String, label: &[u8]) -> bool {
+    name.as_bytes().eq_ignore_ascii_case(label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::message::parse_query;
+    use crate::dns::message::tests::query;
+    use crate::portpool::ports::Protocol;
+
+    fn allocations() -> Vec<UsedPort> {
+        vec![
+            UsedPort::new(31000, "daq", "fox"),
+            UsedPort::with_protocol(31001, "daq", "fox", Protocol::Udp),
+            UsedPort::new(31002, "WebUI", "owl"),
+        ]
+    }
+    fn answer(name: &str, qtype: u16) -> (u8, Vec<Record>) {
+        let zone = Zone::new("Portman.Local.", "daq.example");
+        zone.answer(
+            &parse_query(&query(1, name, qtype, None)).unwrap(),
+            &allocations(),
+        )
+    }
+    fn srv(port: u16) -> Record {
+        Record::Srv {
+            priority: 0,
+            weight: 0,
+            port,
+            target: String::from("daq.example"),
+        }
+    }
+
+    #[test]
+    fn services() {
+        assert_eq!(
+            (NOERROR, vec![srv(31000), srv(31001)]),
+            answer("_daq._fox.portman.local", TYPE_SRV)
+        );
+        assert_eq!(
+            (NOERROR, vec![srv(31002)]),
+            answer("_webui._OWL.portman.local.", TYPE_SRV)
+        );
+        let (rcode, answers) = answer("_webui._owl.portman.local", TYPE_ANY);
+        assert_eq!(NOERROR, rcode);
+        assert_eq!(
+            vec![
+                srv(31002),
+                Record::Txt(vec![
+                    String::from("service=WebUI"),
+                    String::from("user=owl"),
+                    String::from("port=31002"),
+                    String::from("protocol=tcp")
+                ])
+            ],
+            answers
+        );
+        assert_eq!((NOERROR, vec![]), answer("_daq._fox.portman.local", 1));
+    }
+    #[test]
+    fn names() {
+        assert_eq!((NOERROR, vec![]), answer("portman.local", TYPE_SRV));
+        assert_eq!((NOERROR, vec![]), answer("_fox.portman.local", TYPE_SRV));
+        assert_eq!((NXDOMAIN, vec![]), answer("_bat.portman.local", TYPE_SRV));
+        assert_eq!(
+            (NXDOMAIN, vec![]),
+            answer("_ring._fox.portman.local", TYPE_SRV)
+        );
+        assert_eq!(
+            (NXDOMAIN, vec![]),
+            answer("daq.fox.portman.local", TYPE_SRV)
+        );
+        assert_eq!(
+            (NXDOMAIN, vec![]),
+            answer("_x._daq._fox.portman.local", TYPE_SRV)
+        );
+        assert_eq!((REFUSED, vec![]), answer("_daq._fox.example.com", TYPE_SRV));
+        assert_eq!((REFUSED, vec![]), answer("local", TYPE_SRV));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::protocol::request::quote;
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::mpsc;
+
+// Contains the errors the port manager library reports.
+// Each error has a stable, machine readable code that's sent in FAIL
+// replies so that clients can tell failures apart without parsing the
+// human readable text that follows it, e.g.:
+//
+//     FAIL E_DUPLICATE - Duplicate port allocation attempted
+//
+
+///
+/// PortmanError
+///    The ways a port manager operation can fail.  Where the variant
+/// carries a string, it's the human readable description of the failure.
+///
+///  *   PoolExhausted - There are no free ports (or no block of them that fits).
+///  *   Duplicate     - The service name is already advertised for the user.
+///  *   NotAllocated  - The port being freed is not allocated.
+///  *   Unavailable   - The specific port asked for is allocated or not in the pool.
+///  *   NotLocal      - The request must come from the local host.
+///  *   NotAdvertised - No allocation matches the service and user names.
+///  *   Denied        - The request isn't permitted (see E_DENIED in the crate docs).
+///  *   QuotaExceeded - A limit on clients (e.g. connections) has been reached.
+///  *   TimedOut      - A wait for a port or for a request ran out.
+///  *   Framing       - A request line was too long, not UTF-8 or incomplete.
+///  *   Invalid       - A request or argument is malformed.
+///  *   Internal      - Something went wrong inside the server.
+///  *   Io            - A system call failed, e.g. binding the listen socket.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortmanError {
+    PoolExhausted(String),
+    Duplicate(String),
+    NotAllocated(u16),
+    Unavailable(String),
+    NotLocal,
+    NotAdvertised { service: String, user: String },
+    Denied(String),
+    QuotaExceeded(String),
+    TimedOut(String),
+    Framing(String),
+    Invalid(String),
+    Internal(String),
+    Io(String),
+}
+
+impl PortmanError {
+    ///
+    /// The machine readable code for the error.  These don't change
+    /// so clients can rely on them.
+    ///
+    pub fn code(&self) -> &'static str {
+        match self {
+            PortmanError::PoolExhausted(_) => "E_EXHAUSTED",
+            PortmanError::Duplicate(_) => "E_DUPLICATE",
+            PortmanError::NotAllocated(_) => "E_NOT_ALLOCATED",
+            PortmanError::Unavailable(_) => "E_UNAVAILABLE",
+            PortmanError::NotLocal => "E_NOT_LOCAL",
+            PortmanError::NotAdvertised { .. } => "E_NOT_FOUND",
+            PortmanError::Denied(_) => "E_DENIED",
+            PortmanError::QuotaExceeded(_) => "E_QUOTA",
+            PortmanError::TimedOut(_) => "E_TIMEOUT",
+            PortmanError::Framing(_) => "E_FRAMING",
+            PortmanError::Invalid(_) => "E_INVALID",
+            PortmanError::Internal(_) => "E_INTERNAL",
+            PortmanError::Io(_) => "E_IO",
+        }
+    }
+    ///
+    /// The FAIL reply line (including its newline) that reports the error
+    /// to a client.
+    ///
+    pub fn reply(&self) -> String {
+        format!("FAIL {} - {}\n", self.code(), self)
+    }
+}
+
+impl fmt::Display for PortmanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortmanError::NotAllocated(port) => write!(f, "Port {} is not allocated", port),
+            PortmanError::NotLocal => write!(f, "Can only allocate to local senders"),
+            PortmanError::NotAdvertised { service, user } => {
+                write!(f, "{} {} is not advertised", quote(service), quote(user))
+            }
+            PortmanError::PoolExhausted(msg)
+            | PortmanError::Duplicate(msg)
+            | PortmanError::Unavailable(msg)
+            | PortmanError::Denied(msg)
+            | PortmanError::QuotaExceeded(msg)
+            | PortmanError::TimedOut(msg)
+            | PortmanError::Framing(msg)
+            | PortmanError::Invalid(msg)
+            | PortmanError::Internal(msg)
+            | PortmanError::Io(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for PortmanError {}
+
+// Failures talking to the responder thread are internal errors:
+
+impl<T> From<mpsc::SendError<T>> for PortmanError {
+    fn from(_: mpsc::SendError<T>) -> PortmanError {
+        PortmanError::Internal(String::from("The port pool is not accepting requests"))
+    }
+}
+
+impl From<mpsc::RecvError> for PortmanError {
+    fn from(_: mpsc::RecvError) -> PortmanError {
+        PortmanError::Internal(String::from("No reply from the port pool"))
+    }
+}
+
+impl From<mpsc::RecvTimeoutError> for PortmanError {
+    fn from(e: mpsc::RecvTimeoutError) -> PortmanError {
+        match e {
+            mpsc::RecvTimeoutError::Timeout => {
+                PortmanError::Internal(String::from("Timed out waiting for the port pool"))
+            }
+            mpsc::RecvTimeoutError::Disconnected => mpsc::RecvError.into(),
+        }
+    }
+}
+
+// Failed system calls carry the system's description of the failure:
+
+impl From<io::Error> for PortmanError {
+    fn from(e: io::Error) -> PortmanError {
+        PortmanError::Io(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply() {
+        let e = PortmanError::Duplicate(String::from("Duplicate port allocation attempted"));
+        assert_eq!(
+            "FAIL E_DUPLICATE - Duplicate port allocation attempted\n",
+            e.reply()
+        );
+        assert_eq!(
+            "Port 1000 is not allocated",
+            PortmanError::NotAllocated(1000).to_string()
+        );
+        let e = PortmanError::NotAdvertised {
+            service: String::from("my service"),
+            user: String::from("fox"),
+        };
+        assert_eq!(
+            "FAIL E_NOT_FOUND - \"my service\" fox is not advertised\n",
+            e.reply()
+        );
+    }
+    #[test]
+    fn from_channel() {
+        let (sender, receiver) = mpsc::channel::<u32>();
+        drop(receiver);
+        let e: PortmanError = sender.send(1).unwrap_err().into();
+        assert_eq!("E_INTERNAL", e.code());
+    }
+}
@@ -33,26 +33,179 @@
 ///    -  --listen_port  - (required) The port on which our server listens for connections.
 ///    -  --port_base    - (required) The lowest port number in the allocation pool
 ///    -  --port_count   - (required) The number of ports to allocate to the pool.
+///    -  --udp-port-base, --udp-num-ports - (optional) If both are given, UDP ports
+///       are allocated from this separate range, which must not overlap the TCP
+///       range.  Otherwise TCP and UDP allocations share the same pool.
+///    -  --collision-policy - (optional) What to do when GIMME names a service
+///       that is already advertised for the user (see GIMME below).
+///    -  --max-connections, --max-connections-per-address - (optional) Limits on
+///       the number of client connections in total (default 4096) and from any
+///       one address (default 1024).  Connections over a limit get a FAIL reply
+///       and are closed.  Connections that hold ports don't count against
+///       the limits, so they don't limit the number of allocations.
+///    -  --first-request-timeout - (optional) Seconds a new connection has to
+///       send its first complete request (default 10).
+///    -  --idle-timeout - (optional) Seconds a connection that holds no ports may
+///       go without making a request (default 60).  Connections that hold or are
+///       waiting for ports never time out, so their allocations are not dropped.
+///       A connection that times out gets a FAIL reply and is closed.  For all
+///       of these limits, 0 means there is no limit.
+///    -  --log-level - (optional) The least important messages that are logged:
+///       error, warn (the default, so that normal operation logs nothing), info
+///       (connections, allocations, releases and failed requests) or debug
+///       (every request).  Messages about a client carry its connection id.
+///    -  --log-file, --syslog - (optional) Log to a file (appended to) or to the
+///       system log (syslog or journald) through /dev/log rather than to stderr.
+///       Log lines written to stderr or a file start with a UTC timestamp and
+///       the level.
+///    -  --audit-log - (optional) A file to which an audit record of every port
+///       granted, released and adopted (transfer), every rejected request and
+///       administrative action (start, stop and TERMINATE) is appended.  Each
+///       record is one line with the time, event, port, protocol, service,
+///       user, peer address, connection id and reason as key=value fields, so
+///       it's easy to grep for e.g. `port=31042`.  See portman::logging::audit.
+///    -  --audit-max-bytes, --audit-rotate-every, --audit-keep - (optional) Rotate
+///       the audit log before it passes a size or every so many seconds (86400
+///       rotates it at midnight UTC) keeping --audit-keep old logs (default 10)
+///       named audit-log.1 (the newest), audit-log.2 ...
+///    -  --http-listen - (optional) An address (e.g. 127.0.0.1:30001) on which to
+///       serve HTTP (GET and HEAD only):
+///       -  / - a page with the table of allocations.
+///       -  /allocations - the allocations as a JSON array of objects like
+///          `{"port":31000,"protocol":"tcp","service":"daq","user":"fox"}`.
+///       -  /allocations/{service}/{user} - those of one service, as FIND.  If
+///          there are none the status is 404 and the body is a JSON error,
+///          `{"code":"E_NOT_FOUND","message":"..."}`.
+///       -  /go/{user}/{service} - a redirect (302) to `http://host:port/`, where
+///          host is the one the request was sent to and port is the service's
+///          (lowest) TCP port, so web UIs can be browsed to by name.  If there's
+///          no such service the 404 page lists services with similar names.
+///       -  /status - JSON with the version, open connections and the HEALTH
+///          of the service thread (503 if it doesn't answer).
+///       -  /metrics - metrics in the Prometheus text format:  the
+///          size of the pool and the ports used and free, queued GIMME ... WAIT
+///          requests, ports granted and released, rejected requests by FAIL code,
+///          connections and how long requests to the service thread take.  See
+///          portman::metrics.
+///
+///       The allocations are shown to the same clients that may LIST them.
+///    -  --metrics-textfile - (optional) A file to which the metrics are written
+///       every 15 seconds for node_exporter's textfile collector.
+///    -  --relay-allow - (optional, may be repeated) Lets clients CONNECT to
+///       services (see CONNECT below).  Each rule is
+///       NETWORK[/PREFIX][=SERVICE,USER]:  clients in the network may CONNECT
+///       to services advertised under those names, either of which may be *
+///       for any; with no names, to any service.  E.g. 10.1.0.0/16=spectcl,*
+///       lets clients on 10.1 reach every user's spectcl.
+///    -  --dns-listen - (optional) An address (e.g. 127.0.0.1:30053) on which to
+///       answer DNS queries over UDP and TCP for the names of the allocations.
+///       Each advertised service is `_service._user.portman.local` with an SRV
+///       record (port and host) and a TXT record (`service=`, `user=`, `port=`
+///       and `protocol=`) for each of its ports, e.g.
+///       `dig @127.0.0.1 -p 30053 _daq._fox.portman.local SRV`.  Names of
+///       services that aren't advertised get NXDOMAIN, answers live 5 seconds
+///       and names outside the domain are refused.  Only those that may LIST
+///       the allocations are answered.  See portman::dns::zone.
+///    -  --dns-domain - (optional) The domain the names are in (default
+///       portman.local).
+///    -  --dns-target - (optional) The host name SRV records point at (default
+///       this host's name).
+///    -  --mdns - (optional) Publish the allocations on the LAN with mDNS/DNS-SD,
+///       each as an instance `service.user._portman._tcp.local` (`_udp` for UDP
+///       ports) with SRV records for its ports on this host's .local name and a
+///       TXT record (`service=`, `user=` and `protocol=`).  Grants are announced
+///       and freed ports are withdrawn with goodbye packets, as is everything
+///       still published when the server stops.
+///    -  --mdns-interface - (optional) The address of the interface to publish
+///       on (by default the system chooses).
+///    -  --peer - (optional, may be repeated) Another portman, as host or
+///       host:port (the port defaults to 30000), that LIST ALL and
+///       FIND ... ANYHOST also ask.
+///    -  --beacon - (optional) An address, usually the LAN's broadcast address
+///       (e.g. 192.168.1.255:30002), to which a UDP beacon saying where we
+///       listen is sent every 10 seconds.  Beacons from other instances heard
+///       on that port make them peers until they've been silent for 30 seconds.
+///    -  --replication-listen - (optional) The address (e.g. 0.0.0.0:30003) on
+///       which to serve the replication stream that replicas follow:  a
+///       snapshot of the allocations followed by each grant and release.
+///    -  --replica-of - (optional) Run as a read-only replica of the primary
+///       whose replication stream is at this host:port.  LIST and FIND are
+///       answered from a copy of the primary's allocations and GIMME is
+///       refused with E_DENIED.  Can't be combined with --replication-listen.
+///    -  --handoff-socket - (optional) A Unix socket path on which a new
+///       portman (e.g. after an upgrade) can take over from this one.
+///    -  --takeover - (requires --handoff-socket) Take over from the portman
+///       listening on the handoff socket:  it passes us its listen socket and
+///       every client connection with the ports it holds, then exits without
+///       closing them.
+///    -  --state-file - (optional) A file in which the allocations are kept so
+///       that, should portman stop without releasing them (e.g. it crashed),
+///       their holders can RECLAIM them when it's restarted.  GIMME replies
+///       then include a reclaim token.
+///    -  --reclaim-grace - (requires --state-file) The number of seconds
+///       (default 60) after a restart during which the allocations in the
+///       state file can be reclaimed.  Those that aren't are then freed.
 ///
 ///  ### Program structure:
 ///
-///    There will be n+2 threads (counting main), where n is the number of
-///    currently allocated ports:
+///    There are two threads (counting main) regardless of the number of
+///    connections or allocated ports:
 ///
-/// -  The main thread processes paramters and listens for connections
-///        on the listen_port value.  When connections come in they are
-///        decoded and processed by:
+/// -  The main thread processes paramters and then runs an event loop
+///    (epoll via the mio crate) that listens for connections on the
+///    listen_port value and services all client connections.  Sockets are
+///    non-blocking so one slow or idle client never holds up the others.
+///    Requests are decoded and processed with the help of:
 /// -  The service thread maintains the port pool.  It's given requests
-///        for allocations and allocation usage by the main thread via
-///        channels and, in some cases replies to those requests providing
-///        the desired information via a one-time reply channel that's
-///        provided by the request.   See the portman::resonder module for information
-///        about this thread.
+///    for allocations and allocation usage by the main thread via
+///    channels and, in some cases replies to those requests providing
+///    the desired information via a one-time reply channel that's
+///    provided by the request.   See the portman::resonder module for information
+///    about this thread.  When it answers a queued (GIMME ... WAIT) request
+///    it wakes the event loop so the reply can be sent to the client.
+///    The service thread is supervised: should it panic, the panic is logged
+///    and the pool is rebuilt from the ports the current holders have.  If it
+///    keeps failing the server exits rather than accept requests it can't
+///    serve.  Requests to it time out rather than hang.
+/// -  If --http-listen is given a third thread answers HTTP requests, one at
+///    a time, asking the service thread for what it needs just as the event
+///    loop does.
+/// -  If there are --relay-allow rules, connections that CONNECT are handed
+///    to a relay thread, which moves the data of all of the relay sessions
+///    from an event loop of its own.
+/// -  If --dns-listen is given another thread answers DNS queries, also
+///    asking the service thread for the allocations.
+/// -  If --mdns is given another thread publishes the allocations with
+///    mDNS.  It's told of grants and releases as they're audited.
+/// -  If --beacon is given another thread sends and listens for beacons.
+///    LIST ALL and FIND ... ANYHOST ask the peers from a short-lived thread
+///    of their own.
+/// -  If --replication-listen is given another thread serves the replication
+///    stream.  It's told of grants and releases as they're audited.  A
+///    replica (--replica-of) has a thread that follows its primary's stream
+///    instead.
+/// -  If --handoff-socket is given the event loop also listens on it.  When
+///    a portman run with --takeover connects, the listen socket and the
+///    client connections are passed to it (SCM_RIGHTS) along with their
+///    ports, buffered input and output and queued GIMME ... WAIT requests.
+///    The old process then stops without closing them and the new one
+///    restores the ports and carries on.  Relayed CONNECT sessions end
+///    with the old process.
+/// -  If --state-file is given the service thread rewrites it whenever the
+///    allocations change.  On startup, the allocations found there are held
+///    for their tokens until they're reclaimed or --reclaim-grace ends.
 /// -  In order to ensure ports are released, each application requesting a
-///        port must maintain a connection to this server (one connection per port
-///        allocation).  main spins off a thread to monitor this connection and,
-///        when it becomes readable, asks the service thread to drop the allocation.
-///        In this way, even if a service exits abnormally, its port is released.
+///    port must maintain a connection to this server (one connection per port
+///    allocation).  When the event loop sees that connection close, it
+///    asks the service thread to drop the allocation.
+///    In this way, even if a service exits abnormally, its port is released.
+///
+///    All of this lives in the library as portman::server::Server, a builder
+///    that sets the listen address, port ranges, collision policy and limits.
+///    The portman program just turns its command line into a Server and runs
+///    it.  Other programs and tests can start a server of their own (listening
+///    on port 0 lets the system pick a free port, which the RunningServer
+///    reports) and shut it down again, which closes all of its connections.
 ///
 /// ### Request and replies:
 ///
@@ -61,50 +214,280 @@
 /// (127.0.0.1 or its IPV6 equivalent).  The requests are ASCII strings
 /// terminated by a newline.  Replies will be described in the
 /// description of each request, however a common failure reply is of the form:
+/// ```text
+///        FAIL code - human readable reason for the failure.
 /// ```
-///        FAIL human readable reason for the failure.
-/// ```
+/// The code is a stable, machine readable name for the kind of failure
+/// (see portman::error::error::PortmanError) so clients need not parse the
+/// reason:
+///
+/// -  E_EXHAUSTED - no free port, or no free block of the size asked for.
+/// -  E_DUPLICATE - the service name is already advertised for the user.
+/// -  E_NOT_ALLOCATED - the port is not allocated.
+/// -  E_UNAVAILABLE - the specific port asked for is allocated or not in the pool.
+/// -  E_NOT_LOCAL - the request must come from the local host.
+/// -  E_NOT_FOUND - FIND matched nothing.
+/// -  E_DENIED - the request isn't permitted, e.g. the collision policy did
+///    not allow the takeover, no --relay-allow rule allows the CONNECT, GIMME
+///    was sent to a replica or a RECLAIM or ADOPT token isn't valid.
+/// -  E_QUOTA - a connection limit was reached.
+/// -  E_TIMEOUT - a WAIT ran out or the connection was idle too long.
+/// -  E_FRAMING - the request line was too long, not UTF-8 or unterminated.
+/// -  E_INVALID - the request is malformed.
+/// -  E_INTERNAL - something went wrong inside the server.
+///
+/// Words in a request are separated by spaces or tabs.  A word can be
+/// enclosed in double quotes so that it can contain spaces, and a backslash
+/// makes the character after it literal, so `"service 1"` and `service\ 1`
+/// are the same word.  Service and user names are 1 to 64 characters made
+/// up of ASCII letters, digits, spaces and the punctuation `._-:@+/`; they
+/// may not begin or end with a space.  Names are quoted the same way in
+/// replies when they contain spaces.  A request that is malformed in any
+/// way gets a FAIL reply that says what was wrong, and the connection is closed.
+///
+/// Requests are lines of UTF-8 terminated by a newline (a carriage return
+/// before the newline is ignored) and may be no longer than 1024 bytes.
+/// A client may send several requests without waiting for the replies
+/// (pipelining); they are processed, and replied to, in order.  A line that
+/// is too long or isn't valid UTF-8, a partial request left when the client
+/// closes its side of the connection, or more than 64KiB of requests
+/// waiting to be processed (e.g. behind a GIMME ... WAIT) get a FAIL reply
+/// and the connection is closed.
+///
 /// #### GIMME service-name user-name
 ///
 /// Requests a port allocation.  The service-name  and user-name are
-/// used to advertise the service.  The service-name must be unique
+/// used to advertise the service.  By default, the service-name must be unique
 /// for the user.   Note that this differs from the Tcl port manager
 /// which uniquifies the service-name if needed, much to the confusion
-/// of client applications.  This request must come from the
+/// of client applications.  The --collision-policy option selects
+/// what happens when the name is already in use:
+///
+/// -  reject - (default) the request fails.
+/// -  uniquify - as the Tcl port manager did, the service is advertised
+///    as service-name.n for the smallest n that is not in use, with
+///    service-name shortened if need be so the result is a valid name.
+/// -  takeover - the new request takes over the name.  The connection of
+///    the previous holder is closed and its port is given to the new request.
+///    This lets a crashed server restart before its old connection is noticed.
+///    A takeover never waits:  if the new request can't be granted at once,
+///    even with the previous holder's ports, it fails (WAIT notwithstanding)
+///    and the previous holder keeps its ports.  Likewise a request that
+///    didn't collide when it started to WAIT never becomes a takeover:  if
+///    the name has been taken by the time a port is free, it fails.
+/// -  takeover-same-uid - as takeover but only if both requests come from
+///    processes with the same uid; otherwise the request fails.
+///
+/// This request must come from the
 /// local host. On success, the reply is of the form:
 ///
-/// ```
-///     OK portnum
+/// ```text
+///     OK portnum transfer=token
 /// ```
 ///  
-/// Where *portnum* is the port that was allocated to the service.
+/// Where *portnum* is the port that was allocated to the service and
+/// *token* lets another connection take the allocation over (see ADOPT).
 /// The service provider must retain an open connection to the
 /// port manager as the port is released when the connection is dropped
 /// (or, for that matter, since additional messages on the socket are
 /// illegal, if the connection becomes readable).
 ///
+/// If the server keeps a state file (see --state-file) the reply also
+/// carries a token with which the allocation can be reclaimed after a
+/// restart (see RECLAIM):
+///
+/// ```text
+///     OK portnum reclaim=token transfer=token
+/// ```
+///
+/// All of a connection's allocations share its reclaim token; each
+/// allocation has a transfer token of its own.
+///
+/// #### GIMME service-name user-name WAIT seconds
+///
+/// As GIMME above, but if no port is free the request is queued for up
+/// to *seconds* rather than failing at once.  Queued requests are granted
+/// in the order they arrived as ports are released.  If the wait expires
+/// the reply is a FAIL.  Dropping the connection while waiting gives up
+/// the request's place in the queue.
+///
+/// #### GIMME service-name user-name PORT n
+///
+/// As GIMME above, but allocates port *n* itself.  The port must be a
+/// free port in the pool.
+///
+/// #### GIMME service-name user-name BLOCK k
+///
+/// Allocates *k* consecutive ports all advertised under the same
+/// service and user name.  The ports are allocated atomically (all or
+/// none) and are all released together when the connection is dropped.
+/// The reply lists every port in the block:
+///
+/// ```text
+///     OK port1 port2 ... portk transfer=token
+/// ```
+///
+/// LIST shows one line for each port of the block.  PORT and BLOCK
+/// can't both be given but either can be combined with WAIT, e.g.
+/// `GIMME service user BLOCK 2 WAIT 30`.
+///
+/// #### GIMME service-name user-name UDP
+///
+/// Any of the GIMME forms above can include the `UDP` flag to allocate
+/// UDP rather than TCP port(s).  The service-name need only be unique
+/// for the user within a protocol; a service can advertise both a TCP
+/// and a UDP port under the same name.
+///
+/// #### RECLAIM token
+///
+/// Takes back, after the server restarted, the allocations that were
+/// granted with the reclaim *token* on a connection of the previous server.
+/// The same ports are allocated under the same names and the reply is as
+/// for GIMME:
+///
+/// ```text
+///     OK port1 ... portk reclaim=token transfer=token
+/// ```
+///
+/// As with GIMME the connection must be kept open and must come from the
+/// local host.  RECLAIM must be the first allocating request on its
+/// connection.  Allocations can be reclaimed only for the --reclaim-grace
+/// seconds after the restart; after that they're returned to the pool and
+/// the reply, as for an unknown token, is a FAIL with the code E_DENIED.
+///
+/// #### ADOPT port token
+///
+/// Takes over the allocation that *port* is part of (all of the ports of
+/// a block) from the connection it was granted on, given the transfer
+/// *token* from the reply to that GIMME.  This lets, for example, a
+/// launcher ask for a port and hand it to the daemon it starts.  The ports
+/// are moved in one step, so they're never free for another request to
+/// take, and from then on they're released when this connection is
+/// dropped rather than the other.  The reply is as for GIMME with a new
+/// transfer token; the old one can't be used again:
+///
+/// ```text
+///     OK port1 ... portk transfer=token
+/// ```
+///
+/// As with GIMME the connection must come from the local host.  If the
+/// port isn't allocated or the token isn't its transfer token the reply
+/// is a FAIL with the code E_DENIED.
+///
 /// #### LIST
 ///    
 /// Lists the port usage.  This request cannot fail, unless there's some
 /// internal error.  The reply is  of the form:
 ///
-/// ```
+/// ```text
 ///    OK n
 /// ```
 /// Where *n* is the number of lines that follow.  Each subsequent line is of
 /// the form:
-/// ```
+/// ```text
 ///    port-number service-name user-name
 /// ```
 /// Where port-number is the number of the listen port allocated to the
-/// service-name, user-name pair.
-/// Once the list has been rendered to the client, the connection is closed
-/// by the server.
+/// service-name, user-name pair.  As after any other request, the
+/// connection stays open for more.
+///
+/// A replica (see --replica-of) lists its copy of the primary's allocations.
+/// While it's out of contact with the primary (its stream has closed or it
+/// has missed heartbeats for 15 seconds) the copy may be stale and the
+/// first line says for how many seconds it hasn't heard from the primary:
+///
+/// ```text
+///    OK n stale=s
+/// ```
+///
+/// LIST PROTOCOL and FIND replies on a replica are marked the same way.
+///
+/// #### LIST PROTOCOL
+///
+/// As LIST, but each line also says which protocol the port is for:
+/// ```text
+///    port-number service-name user-name protocol
+/// ```
+/// Where protocol is `tcp` or `udp`.  Plain LIST leaves it out so that its
+/// lines keep the three fields existing clients expect.
+///
+/// #### FIND service-name user-name
+///
+/// Looks up the port(s) advertised by service-name for user-name.  The
+/// reply has the same form as the LIST PROTOCOL reply but only includes the
+/// lines for that service and user, one for each protocol (and port of
+/// a block).  If nothing is advertised under that name, the reply is a FAIL.
+///
+/// #### LIST ALL
+///
+/// As LIST PROTOCOL, but for this host and all of its peers (see --peer and
+/// --beacon), which are asked at once.  Each line begins with the host the
+/// allocation is on:  its name and, if it doesn't listen on port 30000,
+/// its port:
+///
+/// ```text
+///    OK n [failed=host,...]
+///    host port-number service-name user-name protocol
+/// ```
+///
+/// Peers that can't be reached or don't answer within 2 seconds are listed
+/// after `failed=`.
+///
+/// #### FIND service-name user-name ANYHOST
+///
+/// As FIND, but on this host and all of its peers, with the reply of
+/// LIST ALL.  If nothing is advertised under that name on any host that
+/// answered, the reply is a FAIL with the code E_NOT_FOUND.
+///
+/// #### HEALTH
+///
+/// Asks whether the server can still serve requests.  If it can, the reply is:
+/// ```text
+///    OK restarts=r allocated=a available=f waiting=w relays=c
+/// ```
+/// Where r is the number of times the port pool's service thread has been
+/// restarted after failing, a and f are the number of ports allocated and
+/// available, w is the number of GIMME ... WAIT requests queued and c is
+/// the number of CONNECT relay sessions open.  If the
+/// service thread does not answer, the reply is a FAIL with the code E_INTERNAL.
+///
+/// A replica counts the allocations in its copy, has none available and
+/// adds `replica-of=host:port` and, if it's out of contact with the
+/// primary, `stale=s` as LIST does.
+///
+/// #### CONNECT service-name user-name
+///
+/// Makes the connection a relay to the service, so that a client that can
+/// reach only the port manager's listen port can still use it.  The port
+/// manager connects to the service's (lowest) TCP port on the local host
+/// and replies:
+///
+/// ```text
+///     OK portnum
+/// ```
+///
+/// From then on everything the client sends (including anything it sent
+/// after the CONNECT line) goes to the service and everything the service
+/// sends goes to the client, until both have closed.  The bytes are moved
+/// with splice(2) so they're not copied through the port manager.
+///
+/// Who may CONNECT to what is set by --relay-allow rules; without any,
+/// CONNECT is refused (E_DENIED).  A connection that holds ports can't be
+/// used for CONNECT.  If the service isn't advertised (E_NOT_FOUND) or
+/// can't be reached (E_IO) the reply is a FAIL and the connection is closed.
 ///
 /// #### TERMINATE
 ///     
-/// Requests the system to exit.  No reponse is given.
+/// Requests the system to exit.  No reponse is given.  Replies to requests
+/// sent ahead of it are sent and the connections of all clients are closed.
 ///
 pub mod aareadme {}
+pub mod dns;
+pub mod error;
+pub mod logging;
+pub mod metrics;
 pub mod portpool;
+pub mod protocol;
 pub mod responder;
+pub mod server;
+pub mod web;
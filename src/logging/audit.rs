@@ -0,0 +1,438 @@
+use super::logger;
+use crate::error::error::PortmanError;
+use crate::log_error;
+use crate::portpool::ports::{Protocol, UsedPort};
+use crate::protocol::request::quote;
+use crate::responder::responder::ClientId;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The audit log:  a record of every port granted and released, every
+// request that was rejected and every administrative action (starting
+// and stopping the server), so that we can answer questions like "who
+// held port 31042 last night?".
+//
+// The log is only ever appended to.  Each record is one line of
+// key=value fields after a UTC timestamp and the event, with - for the
+// fields that don't apply, so it's easy to grep or awk, e.g.:
+//
+//    2026-10-18T09:15:02.417Z grant port=31042 protocol=tcp service=daq user=fox \
+//        peer=127.0.0.1:40312 conn=12 reason=allocated
+//
+// (all on one line).  Values with spaces are quoted as names are in replies.
+// Blocks of ports get a record for each port.
+//
+// The log can be rotated when it reaches a size, at fixed intervals (e.g.
+// daily at midnight UTC) or both.  When it's rotated, audit.log becomes
+// audit.log.1, audit.log.1 becomes audit.log.2 and so on, keeping a fixed
+// number of old logs.
+//
+// Other parts of the server (e.g. the metrics) can watch the same records
+// by adding a Recorder to the Audit handle.
+//
+
+///
+/// Event
+///    What an audit record is about:
+///
+///  *   Grant    - A port was allocated.
+///  *   Release  - A port was freed; the reason says why.
+///  *   Transfer - A port was given to another client (see ADOPT).
+///  *   Reject   - A request (or connection) was refused.
+///  *   Admin    - An administrative action, e.g. the server stopped.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Grant,
+    Release,
+    Transfer,
+    Reject,
+    Admin,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Event::Grant => "grant",
+            Event::Release => "release",
+            Event::Transfer => "transfer",
+            Event::Reject => "reject",
+            Event::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+///
+/// Record
+///    One audit record.  Create it with the event and reason and then
+/// fill in what's known.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub event: Event,
+    pub port: Option<u16>,
+    pub protocol: Option<Protocol>,
+    pub service: Option<String>,
+    pub user: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub client: Option<ClientId>,
+    pub code: Option<&'static str>,
+    pub reason: String,
+}
+
+impl Record {
+    pub fn new(event: Event, reason: impl Into<String>) -> Record {
+        Record {
+            event,
+            port: None,
+            protocol: None,
+            service: None,
+            user: None,
+            peer: None,
+            client: None,
+            code: None,
+            reason: reason.into(),
+        }
+    }
+    /// A rejection because of *error*.  The reason starts with the
+    /// error's code, as in FAIL replies.
+    pub fn rejected(error: &PortmanError) -> Record {
+        let mut record = Record::new(Event::Reject, format!("{} - {}", error.code(), error));
+        record.code = Some(error.code());
+        record
+    }
+    /// The record is about an allocated port.
+    pub fn with_port(mut self, used: &UsedPort) -> Record {
+        self.port = Some(used.port());
+        self.with_names(&used.service(), &used.user(), used.protocol())
+    }
+    /// The record is about a service and user, e.g. a rejected GIMME.
+    pub fn with_names(mut self, service: &str, user: &str, protocol: Protocol) -> Record {
+        self.service = Some(String::from(service));
+        self.user = Some(String::from(user));
+        self.protocol = Some(protocol);
+        self
+    }
+    pub fn with_peer(mut self, peer: Option<SocketAddr>) -> Record {
+        self.peer = peer;
+        self
+    }
+    pub fn with_client(mut self, client: ClientId) -> Record {
+        self.client = Some(client);
+        self
+    }
+    // The record as a log line at 'time'.
+
+    fn line(&self, time: SystemTime) -> String {
+        fn field<T: ToString>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(|v| quote(&v.to_string()))
+                .unwrap_or_else(|| String::from("-"))
+        }
+        let reason: String = self
+            .reason
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        format!(
+            "{} {} port={} protocol={} service={} user={} peer={} conn={} reason={}\n",
+            logger::timestamp(time),
+            self.event,
+            field(&self.port),
+            field(&self.protocol),
+            field(&self.service),
+            field(&self.user),
+            field(&self.peer),
+            field(&self.client),
+            quote(&reason)
+        )
+    }
+}
+
+///
+/// Rotation
+///    When the audit log is rotated.  Either or both of:
+///
+///  *   max_bytes - Rotate before the log grows past this size.
+///  *   every     - Rotate when the time, counted in these intervals since
+///      the epoch, moves into a new interval.  For a day that's at midnight UTC.
+///  *   keep      - How many rotated logs to keep.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub every: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Rotation {
+        Rotation {
+            max_bytes: None,
+            every: None,
+            keep: 10,
+        }
+    }
+}
+
+// The open log file.
+
+struct AuditFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    started: SystemTime, // First record written (last, for a file we reopened).
+    rotation: Rotation,
+}
+
+impl AuditFile {
+    fn open(path: &Path, rotation: Rotation) -> std::io::Result<AuditFile> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let metadata = file.metadata()?;
+        let started = if metadata.len() > 0 {
+            metadata.modified().unwrap_or_else(|_| SystemTime::now())
+        } else {
+            SystemTime::now()
+        };
+        Ok(AuditFile {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            started,
+            rotation,
+        })
+    }
+    fn write(&mut self, time: SystemTime, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.due(time, line.len() as u64) {
+            self.rotate()?;
+        }
+        if self.size == 0 {
+            self.started = time;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+    // Whether the log must be rotated before writing 'more' bytes at 'time'.
+
+    fn due(&self, time: SystemTime, more: u64) -> bool {
+        let too_big = self
+            .rotation
+            .max_bytes
+            .is_some_and(|max| self.size + more > max);
+        let interval = |t: SystemTime, every: Duration| {
+            t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / every.as_secs().max(1)
+        };
+        let too_old = self
+            .rotation
+            .every
+            .is_some_and(|every| interval(time, every) != interval(self.started, every));
+        too_big || too_old
+    }
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        *self = AuditFile::open(&self.path, self.rotation)?;
+        Ok(())
+    }
+}
+
+///
+/// Recorder
+///    Something other than the log file that's given each audit record.
+///
+pub trait Recorder: Send + Sync {
+    fn record(&self, record: &Record);
+}
+
+///
+/// Audit
+///    A handle on the audit log that can be cloned and shared by the
+/// threads of the server.  Records are written to the log file, if there
+/// is one, and given to each Recorder.  The default handle has neither
+/// and records nothing.
+///
+#[derive(Clone, Default)]
+pub struct Audit {
+    log: Option<Arc<Mutex<AuditFile>>>,
+    recorders: Vec<Arc<dyn Recorder>>,
+}
+
+impl Audit {
+    ///
+    /// open
+    ///    Append to the audit log at *path*, creating it if need be, and
+    ///    rotate it as *rotation* says.
+    ///
+    pub fn open(path: &Path, rotation: Rotation) -> Result<Audit, PortmanError> {
+        let file = AuditFile::open(path, rotation)
+            .map_err(|e| PortmanError::Io(format!("{}: {}", path.display(), e)))?;
+        Ok(Audit {
+            log: Some(Arc::new(Mutex::new(file))),
+            recorders: Vec::new(),
+        })
+    }
+    ///
+    /// Also give each record to *recorder*.
+    ///
+    pub fn with_recorder(mut self, recorder: Arc<dyn Recorder>) -> Audit {
+        self.recorders.push(recorder);
+        self
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.log.is_some() || !self.recorders.is_empty()
+    }
+    ///
+    /// record
+    ///    Append a record to the log.  A failure to write it is logged
+    ///    but doesn't stop the server.
+    ///
+    pub fn record(&self, record: &Record) {
+        if let Some(log) = &self.log {
+            let now = SystemTime::now();
+            let mut log = log.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = log.write(now, &record.line(now)) {
+                log_error!("Unable to write the audit log: {}", e);
+            }
+        }
+        for recorder in &self.recorders {
+            recorder.record(record);
+        }
+    }
+}
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.log {
+            Some(log) => {
+                let log = log.lock().unwrap_or_else(|e| e.into_inner());
+                write!(f, "Audit({})", log.path.display())
+            }
+            None => write!(f, "Audit(no file)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("portman-{}-{}", process::id(), name));
+        for n in 0..4 {
+            let mut rotated = path.clone().into_os_string();
+            if n > 0 {
+                rotated.push(format!(".{}", n));
+            }
+            let _ = fs::remove_file(rotated);
+        }
+        path
+    }
+
+    #[test]
+    fn lines() {
+        let used = UsedPort::with_protocol(31042, "my daq", "fox", Protocol::Udp);
+        let record = Record::new(Event::Grant, "allocated")
+            .with_port(&used)
+            .with_peer(Some("127.0.0.1:4000".parse().unwrap()))
+            .with_client(12);
+        assert_eq!(
+            "1970-01-01T00:00:00.000Z grant port=31042 protocol=udp service=\"my daq\" \
+             user=fox peer=127.0.0.1:4000 conn=12 reason=allocated\n",
+            record.line(UNIX_EPOCH)
+        );
+        let record = Record::new(Event::Admin, "server\nstopped");
+        assert_eq!(
+            "1970-01-01T00:00:00.000Z admin port=- protocol=- service=- user=- peer=- \
+             conn=- reason=\"server stopped\"\n",
+            record.line(UNIX_EPOCH)
+        );
+    }
+    #[test]
+    fn appends() {
+        let path = temp_path("audit-append.log");
+        Audit::open(&path, Rotation::default())
+            .unwrap()
+            .record(&Record::new(Event::Admin, "one"));
+        let audit = Audit::open(&path, Rotation::default()).unwrap();
+        audit.record(&Record::new(Event::Admin, "two"));
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].ends_with("reason=one") && lines[1].ends_with("reason=two"));
+        assert!(!Audit::default().is_enabled());
+        Audit::default().record(&Record::new(Event::Admin, "nowhere"));
+        let _ = fs::remove_file(&path);
+    }
+    #[test]
+    fn rotate_by_size() {
+        let path = temp_path("audit-size.log");
+        let rotation = Rotation {
+            max_bytes: Some(250),
+            every: None,
+            keep: 2,
+        };
+        let mut file = AuditFile::open(&path, rotation).unwrap();
+        let line = Record::new(Event::Admin, "x").line(UNIX_EPOCH); // About 100 bytes.
+        for _ in 0..7 {
+            file.write(UNIX_EPOCH, &line).unwrap();
+        }
+
+        // 2 lines fit in a file; 7 lines make 3 full files and 1 over,
+        // but only 2 rotated files are kept:
+
+        let count = |suffix: &str| {
+            let mut name = path.clone().into_os_string();
+            name.push(suffix);
+            fs::read_to_string(name).map(|s| s.lines().count()).ok()
+        };
+        assert_eq!(Some(1), count(""));
+        assert_eq!(Some(2), count(".1"));
+        assert_eq!(Some(2), count(".2"));
+        assert_eq!(None, count(".3"));
+        let _ = temp_path("audit-size.log");
+    }
+    #[test]
+    fn rotate_by_time() {
+        let path = temp_path("audit-time.log");
+        let rotation = Rotation {
+            max_bytes: None,
+            every: Some(Duration::from_secs(86400)),
+            keep: 3,
+        };
+        let mut file = AuditFile::open(&path, rotation).unwrap();
+        let day = |d: u64, s: u64| UNIX_EPOCH + Duration::from_secs(d * 86400 + s);
+        let line = Record::new(Event::Admin, "x").line(UNIX_EPOCH);
+        file.write(day(1, 10), &line).unwrap();
+        file.write(day(1, 86000), &line).unwrap();
+        file.write(day(2, 5), &line).unwrap(); // After midnight.
+        let mut first = path.clone().into_os_string();
+        first.push(".1");
+        assert_eq!(2, fs::read_to_string(first).unwrap().lines().count());
+        assert_eq!(1, fs::read_to_string(&path).unwrap().lines().count());
+        let _ = temp_path("audit-time.log");
+    }
+}
@@ -0,0 +1,389 @@
+use crate::error::error::PortmanError;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The port manager's log.  Each message has a level and, if it's about a
+// client connection, the connection's id so that everything a client did
+// can be picked out.  Messages go to stderr, a file or the system log (syslog
+// or journald, through the local /dev/log socket).  Lines written to stderr
+// or a file look like:
+//
+//    2026-10-18T09:15:02.417Z INFO [conn 12] Connected from 127.0.0.1
+//
+// The system log adds its own timestamp so we don't.  Until init is called
+// warnings and errors go to stderr, so by default the log is quiet.
+//
+// Logging is done with the log_error!, log_warn!, log_info! and log_debug!
+// macros, which take format! arguments optionally preceded by
+// `client = id;`, e.g.:
+//
+//    log_info!(client = self.client; "Connected from {}", peer);
+//
+
+///
+/// The local socket through which syslog and journald accept messages.
+///
+pub const DEV_LOG: &str = "/dev/log";
+
+///
+/// Level
+///    How important a message is.  Setting the log level shows messages
+/// of that level and the levels above it.
+///
+///  *   Error - The server can't do something it should.
+///  *   Warn  - Something unusual happened, e.g. a connection limit was hit.
+///  *   Info  - Connections, allocations and releases.
+///  *   Debug - Everything, including each request.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    // The syslog severity of the level.
+    fn severity(&self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = PortmanError;
+    fn from_str(s: &str) -> Result<Level, PortmanError> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(PortmanError::Invalid(format!("Invalid log level: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+///
+/// Output
+///    Where log messages go.  Syslog gives the path of the system log's
+/// socket, normally DEV_LOG.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Stderr,
+    File(PathBuf),
+    Syslog(PathBuf),
+}
+
+// An opened Output:
+
+enum Sink {
+    Stderr,
+    File(fs::File),
+    Syslog(UnixDatagram, PathBuf),
+}
+
+impl Sink {
+    fn open(output: &Output) -> Result<Sink, PortmanError> {
+        match output {
+            Output::Stderr => Ok(Sink::Stderr),
+            Output::File(path) => fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(Sink::File)
+                .map_err(|e| PortmanError::Io(format!("{}: {}", path.display(), e))),
+            Output::Syslog(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket
+                    .connect(path)
+                    .map_err(|e| PortmanError::Io(format!("{}: {}", path.display(), e)))?;
+                Ok(Sink::Syslog(socket, path.clone()))
+            }
+        }
+    }
+    // Write a message.  Failures are ignored; there's nowhere to report
+    // them.  The system log may have been restarted, so if sending to it
+    // fails we reconnect and try once more.
+    //
+    fn write(&mut self, now: SystemTime, level: Level, client: Option<u64>, msg: &str) {
+        match self {
+            Sink::Stderr => {
+                let line = format_line(now, level, client, msg);
+                let _ = std::io::stderr().write_all(line.as_bytes());
+            }
+            Sink::File(file) => {
+                let _ = file.write_all(format_line(now, level, client, msg).as_bytes());
+            }
+            Sink::Syslog(socket, path) => {
+                let message = format_syslog(level, client, msg);
+                if socket.send(message.as_bytes()).is_err() {
+                    if let Ok(reconnected) = reconnect(path) {
+                        let _ = reconnected.send(message.as_bytes());
+                        *socket = reconnected;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn reconnect(path: &Path) -> std::io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+static SINK: Mutex<Option<Sink>> = Mutex::new(None); // None is stderr.
+
+///
+/// init
+///    Log messages of *level* and above to *output*.  An error is returned
+///    if the output can't be opened, in which case logging is unchanged.
+///
+pub fn init(level: Level, output: &Output) -> Result<(), PortmanError> {
+    let sink = Sink::open(output)?;
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(sink);
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+///
+/// enabled
+///    Whether messages of *level* are being logged.  The logging macros
+///    check this before formatting their message.
+///
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+///
+/// write
+///    Log a message.  Use the logging macros rather than calling this.
+///
+pub fn write(level: Level, client: Option<u64>, args: fmt::Arguments) {
+    let msg = args.to_string();
+    let now = SystemTime::now();
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    let sink = sink.get_or_insert(Sink::Stderr);
+    sink.write(now, level, client, &msg);
+}
+
+// The text of a message, including the connection id if it has one.
+
+fn format_message(client: Option<u64>, msg: &str) -> String {
+    match client {
+        Some(client) => format!("[conn {}] {}", client, msg),
+        None => String::from(msg),
+    }
+}
+
+// A line for stderr or a file.
+
+fn format_line(now: SystemTime, level: Level, client: Option<u64>, msg: &str) -> String {
+    format!(
+        "{} {} {}\n",
+        timestamp(now),
+        level,
+        format_message(client, msg)
+    )
+}
+
+// A message for the system log.  The priority is the daemon facility (3)
+// and the level's severity.
+
+fn format_syslog(level: Level, client: Option<u64>, msg: &str) -> String {
+    format!(
+        "<{}>portman[{}]: {}",
+        3 * 8 + level.severity(),
+        process::id(),
+        format_message(client, msg)
+    )
+}
+
+// Format a time as an ISO 8601 UTC timestamp with milliseconds.
+
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let seconds = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since.subsec_millis()
+    )
+}
+
+// The (year, month, day) of a count of days since 1970-01-01.  This is
+// Howard Hinnant's days-to-civil algorithm for the proleptic Gregorian
+// calendar.
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+///
+/// log_at!
+///    Log a message at a level for an optional client id.  The message
+///    is only formatted if the level is enabled.
+///
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $client:expr, $($arg:tt)+) => {
+        if $crate::logging::logger::enabled($level) {
+            $crate::logging::logger::write($level, $client, format_args!($($arg)+));
+        }
+    };
+}
+
+/// Log an error, optionally for a client: `log_error!(client = id; ...)`.
+#[macro_export]
+macro_rules! log_error {
+    (client = $client:expr; $($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Error, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Error, None, $($arg)+)
+    };
+}
+
+/// Log a warning, optionally for a client: `log_warn!(client = id; ...)`.
+#[macro_export]
+macro_rules! log_warn {
+    (client = $client:expr; $($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Warn, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Warn, None, $($arg)+)
+    };
+}
+
+/// Log information, optionally for a client: `log_info!(client = id; ...)`.
+#[macro_export]
+macro_rules! log_info {
+    (client = $client:expr; $($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Info, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Info, None, $($arg)+)
+    };
+}
+
+/// Log debugging detail, optionally for a client: `log_debug!(client = id; ...)`.
+#[macro_export]
+macro_rules! log_debug {
+    (client = $client:expr; $($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Debug, Some($client), $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::logging::logger::Level::Debug, None, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("portman-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!("1970-01-01T00:00:00.000Z", timestamp(UNIX_EPOCH));
+        let t = UNIX_EPOCH + Duration::from_millis(951_827_696_789); // Leap day.
+        assert_eq!("2000-02-29T12:34:56.789Z", timestamp(t));
+        let t = UNIX_EPOCH + Duration::from_secs(1_791_676_800);
+        assert_eq!("2026-10-11T00:00:00.000Z", timestamp(t));
+    }
+    #[test]
+    fn levels() {
+        assert!(Level::Error < Level::Warn && Level::Info < Level::Debug);
+        assert_eq!(Ok(Level::Info), "info".parse());
+        assert_eq!("E_INVALID", "loud".parse::<Level>().unwrap_err().code());
+        assert!(enabled(Level::Error));
+    }
+    #[test]
+    fn lines() {
+        assert_eq!(
+            "1970-01-01T00:00:00.000Z INFO [conn 12] Connected\n",
+            format_line(UNIX_EPOCH, Level::Info, Some(12), "Connected")
+        );
+        assert_eq!(
+            format!("<27>portman[{}]: Giving up", process::id()),
+            format_syslog(Level::Error, None, "Giving up")
+        );
+    }
+    #[test]
+    fn file_output() {
+        let path = temp_path("file.log");
+        let _ = fs::remove_file(&path);
+        let mut sink = Sink::open(&Output::File(path.clone())).unwrap();
+        sink.write(UNIX_EPOCH, Level::Warn, None, "one");
+        let mut sink = Sink::open(&Output::File(path.clone())).unwrap();
+        sink.write(UNIX_EPOCH, Level::Debug, Some(3), "two");
+        assert_eq!(
+            "1970-01-01T00:00:00.000Z WARN one\n1970-01-01T00:00:00.000Z DEBUG [conn 3] two\n",
+            fs::read_to_string(&path).unwrap()
+        );
+        let _ = fs::remove_file(&path);
+    }
+    #[test]
+    fn syslog_output() {
+        let path = temp_path("dev-log");
+        let _ = fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        let mut sink = Sink::open(&Output::Syslog(path.clone())).unwrap();
+        sink.write(UNIX_EPOCH, Level::Info, Some(1), "hello");
+        let mut buffer = [0u8; 256];
+        let n = server.recv(&mut buffer).unwrap();
+        assert_eq!(
+            format!("<30>portman[{}]: [conn 1] hello", process::id()),
+            String::from_utf8_lossy(&buffer[..n])
+        );
+        assert!(Sink::open(&Output::Syslog(temp_path("no-such-socket"))).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,4 @@
+// Contains module definitions that pull in specific files
+
+pub mod audit;
+pub mod logger;
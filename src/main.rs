@@ -1,19 +1,16 @@
+use clap::ArgAction;
 use clap::{command, value_parser, Arg};
-use portman::responder::responder;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::Write;
-use std::net;
-use std::net::SocketAddr;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use portman::log_debug;
+use portman::log_error;
+use portman::logging::audit::{Audit, Rotation};
+use portman::logging::logger::{self, Level, Output};
+use portman::responder::responder::CollisionPolicy;
+use portman::server::{Limits, RelayRule, Server};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::process;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::Duration;
 
-type RequestChannel = Arc<Mutex<mpsc::Sender<responder::RequestMessage>>>;
-type Socket = Arc<Mutex<TcpStream>>;
 //
 // Clap is kind of nice... with a few directives and
 // a struct it'll generate the code to do reasonable
@@ -26,6 +23,59 @@ type Socket = Arc<Mutex<TcpStream>>;
 //      value of the port_base member.
 // - -n, --num-ports has the default value of 1000 and sets the
 //       value of the num_ports member.
+// - --udp-port-base, --udp-num-ports are optional and, if given
+//       together, give UDP allocations their own range.  Otherwise
+//       TCP and UDP allocations share the port-base/num-ports range.
+// - --collision-policy says what to do when a service name is already
+//       advertised for the user: reject (default), uniquify, takeover
+//       or takeover-same-uid.
+// - --max-connections, --max-connections-per-address limit the number of
+//       client connections in total and from any one address
+//       (defaults 4096 and 1024, 0 means no limit).  Connections that
+//       hold ports don't count.
+// - --first-request-timeout, --idle-timeout are the seconds a connection
+//       may take to send its first request and may then be idle if it
+//       holds no ports (defaults 10 and 60, 0 means no limit).
+// - --log-level is the least important level of message logged:
+//       error, warn (default), info or debug.
+// - --log-file, --syslog send the log to a file or to the system log
+//       through /dev/log rather than to stderr.
+// - --audit-log is the file to which the audit log is appended (there is
+//       none by default).  --audit-max-bytes and --audit-rotate-every
+//       rotate it at a size or every so many seconds and --audit-keep is
+//       the number of rotated logs kept (default 10).
+// - --http-listen is the address (e.g. 127.0.0.1:30001) on which HTTP is
+//       served; /metrics has metrics for Prometheus.  There's no HTTP
+//       listener by default.
+// - --metrics-textfile is a file the metrics are written to every 15
+//       seconds for node_exporter's textfile collector.
+// - --relay-allow (repeatable) lets clients CONNECT to services:
+//       NETWORK[/PREFIX][=SERVICE,USER] with * matching any name.  With
+//       none CONNECT is refused.
+// - --dns-listen is the address (e.g. 127.0.0.1:30053) on which DNS SRV
+//       and TXT queries for _service._user.portman.local are answered over
+//       UDP and TCP.  --dns-domain replaces portman.local and --dns-target
+//       is the host SRV records name (by default this host's name).
+// - --mdns publishes the allocations with mDNS/DNS-SD as
+//       service.user._portman._tcp.local (or _udp).  --mdns-interface is
+//       the address of the interface to publish on.
+// - --peer (repeatable) is another portman (host or host:port) that
+//       LIST ALL and FIND ... ANYHOST ask as well.
+// - --beacon is an address (e.g. 192.168.1.255:30002) to which a beacon
+//       announcing us is sent; other instances' beacons heard on its port
+//       make them peers too.
+// - --replication-listen is an address (e.g. 0.0.0.0:30003) on which to serve
+//       the replication stream replicas follow.
+// - --replica-of is the primary's replication address (host:port); we become
+//       a read-only replica that answers LIST and FIND from a copy of its
+//       allocations.
+// - --handoff-socket is a Unix socket path on which a new portman can ask to
+//       take over from us.
+// - --takeover makes us that new portman:  we're handed the listen socket,
+//       the connections and their ports by the one on --handoff-socket.
+// - --state-file is a file in which the allocations are kept so that, after
+//       a restart, their holders can RECLAIM them with the tokens GIMME gave.
+// - --reclaim-grace is how many seconds after starting they can be (60).
 //
 // An impl Arguments is also automatically generated that, when
 // invoked will parse the command line and return an Arguments
@@ -40,21 +90,34 @@ type Socket = Arc<Mutex<TcpStream>>;
 // However at present, up to debian 11, we're restricted to 2.27.1 at highest
 // and that's a tiny bit more cumbersome.
 //
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 struct Arguments {
     listen_port: u16,
     port_base: u16,
     num_ports: u16,
-}
-#[derive(Debug)]
-enum ClientRequest {
-    Gimme {
-        service_name: String,
-        user_name: String,
-    },
-    List,
-    Terminate,
-    Invalid,
+    udp_port_base: Option<u16>,
+    udp_num_ports: Option<u16>,
+    collision_policy: CollisionPolicy,
+    limits: Limits,
+    log_level: Level,
+    log_output: Output,
+    audit_log: Option<PathBuf>,
+    audit_rotation: Rotation,
+    http_listen: Option<SocketAddr>,
+    metrics_textfile: Option<PathBuf>,
+    relay_rules: Vec<RelayRule>,
+    dns_listen: Option<SocketAddr>,
+    dns_domain: Option<String>,
+    dns_target: Option<String>,
+    mdns_interface: Option<Ipv4Addr>,
+    peers: Vec<String>,
+    beacon: Option<SocketAddr>,
+    replication_listen: Option<SocketAddr>,
+    replica_of: Option<String>,
+    handoff_socket: Option<PathBuf>,
+    takeover: bool,
+    state_file: Option<PathBuf>,
+    reclaim_grace: Option<u64>,
 }
 
 // Use clap to specify/process the command line arguments
@@ -62,23 +125,183 @@ enum ClientRequest {
 fn parse_arguments() -> Arguments {
     // set up the clap parser:
 
-    let parser =command!()
+    let parser = command!()
         .version("1.0")
         .author("Ron Fox")
         .about("Rust replacement for NSCLDAQ port manager - does not need container")
-        .arg(Arg::new("listen-port").short('l').long("listen-port")
+        .arg(
+            Arg::new("listen-port")
+                .short('l')
+                .long("listen-port")
                 .default_value("30000")
-                .value_parser(value_parser!(u16))
+                .value_parser(value_parser!(u16)),
         )
         .arg(
-            Arg::new("port-base").short('p').long("port-base")
+            Arg::new("port-base")
+                .short('p')
+                .long("port-base")
                 .default_value("31000")
-                .value_parser(value_parser!(u16))
+                .value_parser(value_parser!(u16)),
         )
         .arg(
-            Arg::new("num-ports").short('n').long("num-ports")
+            Arg::new("num-ports")
+                .short('n')
+                .long("num-ports")
                 .default_value("1000")
-                .value_parser(value_parser!(u16))
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            Arg::new("udp-port-base")
+                .long("udp-port-base")
+                .requires("udp-num-ports")
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            Arg::new("udp-num-ports")
+                .long("udp-num-ports")
+                .requires("udp-port-base")
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            Arg::new("collision-policy")
+                .long("collision-policy")
+                .default_value("reject")
+                .value_parser(["reject", "uniquify", "takeover", "takeover-same-uid"]),
+        )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .default_value("4096")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-connections-per-address")
+                .long("max-connections-per-address")
+                .default_value("1024")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("first-request-timeout")
+                .long("first-request-timeout")
+                .default_value("10")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .default_value("60")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .default_value("warn")
+                .value_parser(["error", "warn", "info", "debug"]),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .conflicts_with("syslog")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(Arg::new("syslog").long("syslog").action(ArgAction::SetTrue))
+        .arg(
+            Arg::new("audit-log")
+                .long("audit-log")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("audit-max-bytes")
+                .long("audit-max-bytes")
+                .requires("audit-log")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("audit-rotate-every")
+                .long("audit-rotate-every")
+                .requires("audit-log")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("audit-keep")
+                .long("audit-keep")
+                .default_value("10")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("http-listen")
+                .long("http-listen")
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("metrics-textfile")
+                .long("metrics-textfile")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("relay-allow")
+                .long("relay-allow")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("dns-listen")
+                .long("dns-listen")
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("dns-domain")
+                .long("dns-domain")
+                .requires("dns-listen"),
+        )
+        .arg(
+            Arg::new("dns-target")
+                .long("dns-target")
+                .requires("dns-listen"),
+        )
+        .arg(Arg::new("mdns").long("mdns").action(ArgAction::SetTrue))
+        .arg(
+            Arg::new("mdns-interface")
+                .long("mdns-interface")
+                .requires("mdns")
+                .value_parser(value_parser!(Ipv4Addr)),
+        )
+        .arg(Arg::new("peer").long("peer").action(ArgAction::Append))
+        .arg(
+            Arg::new("beacon")
+                .long("beacon")
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("replication-listen")
+                .long("replication-listen")
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("replica-of")
+                .long("replica-of")
+                .conflicts_with("replication-listen"),
+        )
+        .arg(
+            Arg::new("handoff-socket")
+                .long("handoff-socket")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("takeover")
+                .long("takeover")
+                .action(ArgAction::SetTrue)
+                .requires("handoff-socket"),
+        )
+        .arg(
+            Arg::new("state-file")
+                .long("state-file")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("reclaim-grace")
+                .long("reclaim-grace")
+                .value_parser(value_parser!(u64))
+                .requires("state-file"),
         )
         .get_matches();
 
@@ -88,248 +311,224 @@ fn parse_arguments() -> Arguments {
         listen_port: 30000,
         port_base: 31000,
         num_ports: 1000,
+        udp_port_base: None,
+        udp_num_ports: None,
+        collision_policy: CollisionPolicy::Reject,
+        limits: Limits::default(),
+        log_level: Level::Warn,
+        log_output: Output::Stderr,
+        audit_log: None,
+        audit_rotation: Rotation::default(),
+        http_listen: None,
+        metrics_textfile: None,
+        relay_rules: Vec::new(),
+        dns_listen: None,
+        dns_domain: None,
+        dns_target: None,
+        mdns_interface: None,
+        peers: Vec::new(),
+        beacon: None,
+        replication_listen: None,
+        replica_of: None,
+        handoff_socket: None,
+        takeover: false,
+        state_file: None,
+        reclaim_grace: None,
     };
 
     // Use clap's parser override the default values.
 
     if let Some(listen_value) = parser.get_one::<u16>("listen-port") {
-            result.listen_port = *listen_value;
+        result.listen_port = *listen_value;
     } else {
-            eprintln!("The listen port value must be a 16 bit unsigned integer");
-            process::exit(-1);
+        eprintln!("The listen port value must be a 16 bit unsigned integer");
+        process::exit(-1);
     }
 
     if let Some(base_value) = parser.get_one::<u16>("port-base") {
-            result.port_base = *base_value;
+        result.port_base = *base_value;
     } else {
         eprintln!("The port-base value must be a 16 bit unsigned integer");
         process::exit(-1);
     };
 
     if let Some(num_value) = parser.get_one::<u16>("num-ports") {
-            result.num_ports = *num_value;
+        result.num_ports = *num_value;
     } else {
         eprintln!("The num-ports value must be a 16 bit unsigned integer");
         process::exit(-1);
     }
 
-    // return the parsed parameters.
-    result
-}
-
-fn main() {
-    let args = parse_arguments();
-    println!("{:#?}", args);
-
-    // Create the request channel and start the resopnder.
-
-    let (request_send, request_receive) = mpsc::channel();
-    let safe_req = Arc::new(Mutex::new(request_send));
-    let _service_handle = thread::spawn(move || {
-        responder::responder(args.port_base, args.num_ports, request_receive)
-    });
-
-    // Now turn ourselves into a TCP/IP server that's
-    // processing client requests.
-
-    let server = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], args.listen_port))).unwrap();
+    result.udp_port_base = parser.get_one::<u16>("udp-port-base").copied();
+    result.udp_num_ports = parser.get_one::<u16>("udp-num-ports").copied();
 
-    for request in server.incoming() {
-        if let Ok(socket) = request {
-            let safe_socket = Arc::new(Mutex::new(socket));
-            let myreq = Arc::clone(&safe_req);
-            thread::spawn(move || process_request(Arc::clone(&myreq), Arc::clone(&safe_socket)));
-        } else {
-            // Fill in failure code here when we can figure out
-            // what it should look like.
+    if let Some(policy) = parser.get_one::<String>("collision-policy") {
+        match policy.parse() {
+            Ok(policy) => result.collision_policy = policy,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                process::exit(-1);
+            }
         }
     }
-}
 
-//  Given a connected socket, returns the line of text
-//  received from it.  WE don't really havfe
-fn read_request_line(socket: &Socket) -> String {
-    let mut line: Vec<u8> = vec![];
-    let so = socket.lock().unwrap();
-    let mut reader = BufReader::new(so.try_clone().unwrap());
-    if let Ok(_) = reader.read_until(b'\n', &mut line) {
-        String::from_utf8_lossy(&line).trim_end().to_string()
-    } else {
-        String::from("") // Illegal request
-    }
-}
-
-fn decode_request(request_line: &str) -> ClientRequest {
-    let request_words: Vec<&str> = request_line.split_ascii_whitespace().collect::<Vec<&str>>();
+    // For the limits, 0 means unlimited:
 
-    // Need a word:
+    let count = |name| parser.get_one::<usize>(name).copied().filter(|n| *n != 0);
+    let seconds = |name| {
+        parser
+            .get_one::<u64>(name)
+            .copied()
+            .filter(|n| *n != 0)
+            .map(Duration::from_secs)
+    };
+    result.limits = Limits {
+        max_connections: count("max-connections"),
+        max_per_address: count("max-connections-per-address"),
+        first_request: seconds("first-request-timeout"),
+        idle: seconds("idle-timeout"),
+    };
 
-    if request_words.len() >= 1 {
-        match request_words[0] {
-            "GIMME" => {
-                if request_words.len() == 3 {
-                    ClientRequest::Gimme {
-                        service_name: request_words[1].to_string(),
-                        user_name: request_words[2].to_string(),
-                    }
-                } else {
-                    ClientRequest::Invalid
-                }
+    if let Some(level) = parser.get_one::<String>("log-level") {
+        match level.parse() {
+            Ok(level) => result.log_level = level,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                process::exit(-1);
             }
-            "LIST" => ClientRequest::List,
-            "TERMINATE" => ClientRequest::Terminate,
-            _ => ClientRequest::Invalid,
         }
-    } else {
-        ClientRequest::Invalid
     }
-}
-// Release allocated ports back to the pool:
-
-fn release_ports(req_chan: &RequestChannel, ports: Vec<u16>) {
-    for port in ports {
-        responder::release_port(port, &req_chan.lock().unwrap()).unwrap();
+    if let Some(path) = parser.get_one::<PathBuf>("log-file") {
+        result.log_output = Output::File(path.clone());
+    } else if parser.get_flag("syslog") {
+        result.log_output = Output::Syslog(PathBuf::from(logger::DEV_LOG));
     }
-}
 
-fn process_request(req_chan: RequestChannel, so: Socket) {
-    let mut allocated_ports = Vec::<u16>::new();
-    println!("Connected from {:#?}", so.lock().unwrap().peer_addr());
-    loop {
-        let request_line = read_request_line(&so);
-        if request_line.len() == 0 {
-            break;
-        }
-        println!("Request: {}", request_line);
-        let request = decode_request(&request_line);
-        match request {
-            ClientRequest::Gimme {
-                service_name,
-                user_name,
-            } => {
-                match create_allocation(
-                    Arc::clone(&req_chan),
-                    Arc::clone(&so),
-                    &service_name,
-                    &user_name,
-                ) {
-                    Ok(port) => {
-                        allocated_ports.push(port);
-                        if so
-                            .lock()
-                            .unwrap()
-                            .write_all(format!("OK {}\n", port).as_bytes())
-                            .is_err()
-                        {
-                            // here if lost connection
-                            break;
-                        }
-                    }
-                    Err(msg) => {
-                        if so
-                            .lock()
-                            .unwrap()
-                            .write_all(format!("FAIL - {}\n", msg).as_bytes())
-                            .is_err()
-                        {
-                            break;
-                        }
-                        break; // /exit regardless...
-                    }
-                };
-            }
-            ClientRequest::List => {
-                list_allocations(&req_chan, &so);
-            }
-            ClientRequest::Terminate => {
-                println!("Client requesting shutdown");
-                process::exit(0);
-            }
-            ClientRequest::Invalid => {
-                invalid_request(&so);
-
-                break; // only allow one.
+    result.audit_log = parser.get_one::<PathBuf>("audit-log").cloned();
+    result.audit_rotation = Rotation {
+        max_bytes: parser.get_one::<u64>("audit-max-bytes").copied(),
+        every: seconds("audit-rotate-every"),
+        keep: parser.get_one::<usize>("audit-keep").copied().unwrap_or(10),
+    };
+    result.http_listen = parser.get_one::<SocketAddr>("http-listen").copied();
+    result.metrics_textfile = parser.get_one::<PathBuf>("metrics-textfile").cloned();
+    result.dns_listen = parser.get_one::<SocketAddr>("dns-listen").copied();
+    result.dns_domain = parser.get_one::<String>("dns-domain").cloned();
+    result.dns_target = parser.get_one::<String>("dns-target").cloned();
+    result.peers = parser
+        .get_many::<String>("peer")
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    result.beacon = parser.get_one::<SocketAddr>("beacon").copied();
+    result.replication_listen = parser.get_one::<SocketAddr>("replication-listen").copied();
+    result.replica_of = parser.get_one::<String>("replica-of").cloned();
+    result.handoff_socket = parser.get_one::<PathBuf>("handoff-socket").cloned();
+    result.takeover = parser.get_flag("takeover");
+    result.state_file = parser.get_one::<PathBuf>("state-file").cloned();
+    result.reclaim_grace = parser.get_one::<u64>("reclaim-grace").copied();
+    if parser.get_flag("mdns") {
+        result.mdns_interface = Some(
+            parser
+                .get_one::<Ipv4Addr>("mdns-interface")
+                .copied()
+                .unwrap_or(Ipv4Addr::UNSPECIFIED),
+        );
+    }
+    for rule in parser
+        .get_many::<String>("relay-allow")
+        .into_iter()
+        .flatten()
+    {
+        match rule.parse() {
+            Ok(rule) => result.relay_rules.push(rule),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                process::exit(-1);
             }
         }
     }
-    release_ports(&req_chan, allocated_ports);
-    let _ = so.lock().unwrap().shutdown(net::Shutdown::Both);
+
+    // return the parsed parameters.
+    result
 }
 
-///
-/// ## is_local
-///
-///   Determine if a socket is connected to a local peer.
-///
-fn is_local(so: &Socket) -> bool {
-    let socket = so.lock().unwrap();
-    if let Ok(peer) = socket.peer_addr() {
-        if peer.is_ipv4() {
-            peer.ip() == net::Ipv4Addr::new(127, 0, 0, 1)
-        } else if peer.is_ipv6() {
-            peer.ip() == net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)
-        } else {
-            false
-        }
-    } else {
-        false
+fn main() {
+    let args = parse_arguments();
+    if let Err(e) = logger::init(args.log_level, &args.log_output) {
+        eprintln!("Unable to open the log: {}", e);
+        process::exit(-1);
     }
-}
-//
-//  Functions to process individual requests
-//
+    log_debug!("{:?}", args);
 
-///
-/// ## invalid_request
-///    Report that a request was invalid.
-///
-fn invalid_request(sock: &Socket) {
-    sock.lock()
-        .unwrap()
-        .write_all(String::from("FAIL - invalid request\n").as_bytes())
-        .unwrap();
-    sock.lock().unwrap().flush().unwrap();
-}
+    // The server does all the work.  It returns when a client asks
+    // it to TERMINATE, or fails if it can't start or keep going.
 
-///
-/// ## list_allocations
-///    Produce a list of allocations to the output socket.
-///
-fn list_allocations(req_chan: &RequestChannel, so: &Socket) {
-    let allocations = responder::get_allocations(&req_chan.lock().unwrap()).unwrap();
-    let mut sock = so.lock().unwrap();
-    let result = sock.write_all(format!("OK {}\n", allocations.len()).as_bytes());
-    if result.is_err() {
-        return;
+    let mut server = Server::new()
+        .with_listen_address(SocketAddr::from(([0, 0, 0, 0], args.listen_port)))
+        .with_port_range(args.port_base, args.num_ports)
+        .with_collision_policy(args.collision_policy)
+        .with_limits(args.limits);
+    if let (Some(udp_base), Some(udp_num)) = (args.udp_port_base, args.udp_num_ports) {
+        server = server.with_udp_range(udp_base, udp_num);
     }
-    for aloc in allocations {
-        let result = sock.write_all(format!("{}\n", aloc).as_bytes());
-        if result.is_err() {
-            return;
+    if let Some(path) = &args.audit_log {
+        match Audit::open(path, args.audit_rotation) {
+            Ok(audit) => server = server.with_audit(audit),
+            Err(e) => {
+                log_error!("Unable to open the audit log: {}", e);
+                process::exit(-1);
+            }
         }
     }
-    let _ = sock.flush();
-    return;
-}
-
-///
-/// ## create_allocation
-///
-///    Given an allocation request, allocates a port from the
-///    service thread and spins off a thread to monitor the socket on which
-///    the service was requested - when the socket becomes readable,
-///    that thread drops the allocated port from the list of
-///    allocated port.  This request is only allowed from local connections.
-///
-fn create_allocation(
-    req_chan: RequestChannel,
-    so: Socket,
-    service: &str,
-    user: &str,
-) -> Result<u16, String> {
-    if !is_local(&so) {
-        Err(String::from("FAIL can only allocate to local senders\n"))
-    } else {
-        responder::request_port(service, user, &req_chan.lock().unwrap())
+    if let Some(address) = args.http_listen {
+        server = server.with_http_address(address);
+    }
+    if let Some(path) = &args.metrics_textfile {
+        server = server.with_metrics_textfile(path);
+    }
+    for rule in &args.relay_rules {
+        server = server.with_relay_rule(rule.clone());
+    }
+    if let Some(address) = args.dns_listen {
+        server = server.with_dns_address(address);
+    }
+    if let Some(domain) = &args.dns_domain {
+        server = server.with_dns_domain(domain);
+    }
+    if let Some(target) = &args.dns_target {
+        server = server.with_dns_target(target);
+    }
+    if let Some(interface) = args.mdns_interface {
+        server = server.with_mdns(interface);
+    }
+    for peer in &args.peers {
+        server = server.with_peer(peer);
+    }
+    if let Some(address) = args.beacon {
+        server = server.with_beacon(address);
+    }
+    if let Some(address) = args.replication_listen {
+        server = server.with_replication_address(address);
+    }
+    if let Some(primary) = &args.replica_of {
+        server = server.with_primary(primary);
+    }
+    if let Some(path) = &args.handoff_socket {
+        server = server.with_handoff_socket(path);
+    }
+    if args.takeover {
+        server = server.with_takeover();
+    }
+    if let Some(path) = &args.state_file {
+        server = server.with_state_file(path);
+    }
+    if let Some(seconds) = args.reclaim_grace {
+        server = server.with_reclaim_grace(Duration::from_secs(seconds));
+    }
+    if let Err(e) = server.run() {
+        log_error!("{}", e);
+        process::exit(-1);
     }
 }
@@ -0,0 +1,336 @@
+use crate::logging::audit::{Event, Record, Recorder};
+use crate::responder::responder::Health;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Metrics about the port manager in the Prometheus text exposition format,
+// for graphing pool usage and catching exhaustion before it bites.  They're
+// served from /metrics by the HTTP listener and can also be written to a
+// file for node_exporter's textfile collector.
+//
+// Counts of ports granted and released and of requests rejected come from
+// the audit records (a Metrics is a Recorder), so they count exactly what
+// the audit log shows.  Connection counts and the time taken by round trips
+// to the responder are counted by the event loop, CONNECT relay sessions by
+// the relay.  The state of the pool is
+// asked of the responder when the metrics are rendered.
+//
+// The port manager has no notion of quarantined ports (a released port can
+// be handed out again at once) so there's no metric for them.
+//
+
+// Upper bounds (seconds) of the responder round trip histogram buckets.
+
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 2.5,
+];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()], // Not cumulative.
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = BUCKETS.iter().position(|b| seconds <= *b) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    granted: BTreeMap<String, u64>, // By protocol.
+    released: BTreeMap<String, u64>,
+    rejected: BTreeMap<&'static str, u64>, // By error code.
+    connections: u64,
+    accepted: u64,
+    relays: u64,
+    round_trips: Histogram,
+}
+
+///
+/// Metrics
+///    The counters.  Share one between the threads of a server with an Arc.
+///
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+    fn counters(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// A client connection was accepted.
+    pub fn connected(&self) {
+        let mut counters = self.counters();
+        counters.connections += 1;
+        counters.accepted += 1;
+    }
+    /// A client connection was closed.
+    pub fn disconnected(&self) {
+        let mut counters = self.counters();
+        counters.connections = counters.connections.saturating_sub(1);
+    }
+    /// The number of client connections open.
+    pub fn connections(&self) -> u64 {
+        self.counters().connections
+    }
+    /// A CONNECT relay session started.
+    pub fn relay_started(&self) {
+        self.counters().relays += 1;
+    }
+    /// A CONNECT relay session ended.
+    pub fn relay_ended(&self) {
+        let mut counters = self.counters();
+        counters.relays = counters.relays.saturating_sub(1);
+    }
+    /// The number of CONNECT relay sessions open.
+    pub fn relays(&self) -> u64 {
+        self.counters().relays
+    }
+    /// A round trip to the responder took *elapsed*.
+    pub fn round_trip(&self, elapsed: Duration) {
+        self.counters().round_trips.observe(elapsed.as_secs_f64());
+    }
+    ///
+    /// render
+    ///    The metrics in the Prometheus text format.  *health* is what the
+    ///    responder said about the pool, or None if it didn't answer.
+    ///
+    pub fn render(&self, health: Option<&Health>) -> String {
+        let counters = self.counters();
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{}{} {}", name, labels, value);
+            }
+        };
+        let plain = |value: String| vec![(String::new(), value)];
+
+        metric(
+            "portman_up",
+            "gauge",
+            "Whether the port pool responder answered.",
+            &plain(String::from(if health.is_some() { "1" } else { "0" })),
+        );
+        if let Some(health) = health {
+            metric(
+                "portman_pool_size",
+                "gauge",
+                "Ports in the pool.",
+                &plain((health.allocated + health.available).to_string()),
+            );
+            metric(
+                "portman_pool_used",
+                "gauge",
+                "Ports allocated.",
+                &plain(health.allocated.to_string()),
+            );
+            metric(
+                "portman_pool_free",
+                "gauge",
+                "Ports free to allocate.",
+                &plain(health.available.to_string()),
+            );
+            metric(
+                "portman_pool_unclaimed",
+                "gauge",
+                "Ports allocated before a restart and not yet reclaimed.",
+                &plain(health.unclaimed.to_string()),
+            );
+            metric(
+                "portman_waiting_requests",
+                "gauge",
+                "GIMME ... WAIT requests queued for a free port.",
+                &plain(health.waiting.to_string()),
+            );
+            metric(
+                "portman_responder_restarts_total",
+                "counter",
+                "Times the port pool responder was restarted after failing.",
+                &plain(health.restarts.to_string()),
+            );
+        }
+        let by = |label: &str, map: &BTreeMap<String, u64>| -> Vec<(String, String)> {
+            map.iter()
+                .map(|(k, v)| (format!("{{{}=\"{}\"}}", label, k), v.to_string()))
+                .collect()
+        };
+        metric(
+            "portman_ports_granted_total",
+            "counter",
+            "Ports allocated to clients.",
+            &by("protocol", &counters.granted),
+        );
+        metric(
+            "portman_ports_released_total",
+            "counter",
+            "Ports released.",
+            &by("protocol", &counters.released),
+        );
+        let rejected: BTreeMap<String, u64> = counters
+            .rejected
+            .iter()
+            .map(|(k, v)| (String::from(*k), *v))
+            .collect();
+        metric(
+            "portman_requests_rejected_total",
+            "counter",
+            "Requests and connections refused, by the code of the FAIL reply.",
+            &by("code", &rejected),
+        );
+        metric(
+            "portman_connections",
+            "gauge",
+            "Client connections open.",
+            &plain(counters.connections.to_string()),
+        );
+        metric(
+            "portman_connections_total",
+            "counter",
+            "Client connections accepted.",
+            &plain(counters.accepted.to_string()),
+        );
+        metric(
+            "portman_relays",
+            "gauge",
+            "CONNECT relay sessions open.",
+            &plain(counters.relays.to_string()),
+        );
+
+        let histogram = &counters.round_trips;
+        let mut samples = Vec::new();
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            samples.push((
+                format!("_bucket{{le=\"{}\"}}", bound),
+                cumulative.to_string(),
+            ));
+        }
+        samples.push((
+            String::from("_bucket{le=\"+Inf\"}"),
+            histogram.count.to_string(),
+        ));
+        samples.push((String::from("_sum"), histogram.sum.to_string()));
+        samples.push((String::from("_count"), histogram.count.to_string()));
+        metric(
+            "portman_responder_round_trip_seconds",
+            "histogram",
+            "Time taken by requests to the port pool responder.",
+            &samples,
+        );
+        text
+    }
+    ///
+    /// write_textfile
+    ///    Write the metrics to *path* for node_exporter's textfile collector.
+    ///    The file is replaced atomically so the collector never sees part
+    ///    of it.
+    ///
+    pub fn write_textfile(&self, path: &Path, health: Option<&Health>) -> io::Result<()> {
+        let mut temporary = path.to_path_buf().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, self.render(health))?;
+        fs::rename(&temporary, path)
+    }
+}
+
+impl Recorder for Metrics {
+    fn record(&self, record: &Record) {
+        let protocol = || record.protocol.map(|p| p.to_string()).unwrap_or_default();
+        let mut counters = self.counters();
+        match record.event {
+            Event::Grant => *counters.granted.entry(protocol()).or_insert(0) += 1,
+            Event::Release => *counters.released.entry(protocol()).or_insert(0) += 1,
+            Event::Reject => {
+                let code = record.code.unwrap_or("E_UNKNOWN");
+                *counters.rejected.entry(code).or_insert(0) += 1;
+            }
+            Event::Transfer | Event::Admin => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::error::PortmanError;
+    use crate::portpool::ports::{Protocol, UsedPort};
+
+    #[test]
+    fn counts() {
+        let metrics = Metrics::new();
+        let used = UsedPort::with_protocol(31000, "daq", "fox", Protocol::Tcp);
+        metrics.record(&Record::new(Event::Grant, "allocated").with_port(&used));
+        metrics.record(&Record::new(Event::Grant, "allocated").with_port(&used));
+        metrics.record(&Record::new(Event::Release, "released").with_port(&used));
+        metrics.record(&Record::rejected(&PortmanError::NotLocal));
+        metrics.connected();
+        metrics.connected();
+        metrics.disconnected();
+        metrics.relay_started();
+        metrics.relay_started();
+        metrics.relay_ended();
+        metrics.round_trip(Duration::from_micros(200));
+        metrics.round_trip(Duration::from_secs(5));
+        let health = Health {
+            restarts: 0,
+            allocated: 3,
+            available: 7,
+            unclaimed: 2,
+            waiting: 2,
+        };
+        let text = metrics.render(Some(&health));
+        for line in &[
+            "portman_up 1",
+            "portman_pool_size 10",
+            "portman_pool_used 3",
+            "portman_pool_free 7",
+            "portman_pool_unclaimed 2",
+            "portman_waiting_requests 2",
+            "portman_ports_granted_total{protocol=\"tcp\"} 2",
+            "portman_ports_released_total{protocol=\"tcp\"} 1",
+            "portman_requests_rejected_total{code=\"E_NOT_LOCAL\"} 1",
+            "portman_connections 1",
+            "portman_connections_total 2",
+            "portman_relays 1",
+            "portman_responder_round_trip_seconds_bucket{le=\"0.0001\"} 0",
+            "portman_responder_round_trip_seconds_bucket{le=\"0.00025\"} 1",
+            "portman_responder_round_trip_seconds_bucket{le=\"2.5\"} 1",
+            "portman_responder_round_trip_seconds_bucket{le=\"+Inf\"} 2",
+            "portman_responder_round_trip_seconds_count 2",
+            "# TYPE portman_responder_round_trip_seconds histogram",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+        let text = metrics.render(None);
+        assert!(text.lines().any(|l| l == "portman_up 0"));
+        assert!(!text.contains("portman_pool_size"));
+    }
+    #[test]
+    fn textfile() {
+        let path = std::env::temp_dir().join(format!("portman-{}.prom", std::process::id()));
+        Metrics::new().write_textfile(&path, None).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("portman_up 0\n"));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,4 @@
+// Contains module definitions that pull in specific files
+
+#[allow(clippy::module_inception)]
+pub mod metrics;
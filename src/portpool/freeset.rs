@@ -0,0 +1,226 @@
+// Contains the set of free ports of a port pool.
+// The set is a bitmap over the entire 16 bit port space, one bit per
+// port, set when the port is free.  That's 8KiB regardless of the
+// size of the pool and gives constant time insert, remove and lookup.
+// Searches (the lowest free port, the lowest run of free ports)
+// skip 64 ports at a time where none are free so they're bounded by
+// the size of the port space rather than the number of allocations.
+
+const WORDS: usize = 65536 / 64;
+
+///
+/// FreeSet
+///    An ordered set of free port numbers.
+///
+#[derive(Clone)]
+pub struct FreeSet {
+    bits: Vec<u64>,
+    count: usize,
+}
+
+impl FreeSet {
+    ///
+    /// An empty set.
+    ///
+    pub fn new() -> FreeSet {
+        FreeSet {
+            bits: vec![0; WORDS],
+            count: 0,
+        }
+    }
+    ///
+    /// The set of the 'n' ports starting with 'start'.  Ports that would be
+    /// past 65535 are not included.
+    ///
+    pub fn with_range(start: u16, n: u16) -> FreeSet {
+        let mut set = FreeSet::new();
+        if let Some(end) = range_end(start, n) {
+            for port in start..=end {
+                set.insert(port);
+            }
+        }
+        set
+    }
+    ///
+    /// Add 'port' to the set.  Returns false if it was already there.
+    ///
+    pub fn insert(&mut self, port: u16) -> bool {
+        let (word, bit) = position(port);
+        if self.bits[word] & bit != 0 {
+            return false;
+        }
+        self.bits[word] |= bit;
+        self.count += 1;
+        true
+    }
+    ///
+    /// Remove 'port' from the set.  Returns false if it wasn't there.
+    ///
+    pub fn remove(&mut self, port: u16) -> bool {
+        let (word, bit) = position(port);
+        if self.bits[word] & bit == 0 {
+            return false;
+        }
+        self.bits[word] &= !bit;
+        self.count -= 1;
+        true
+    }
+    pub fn contains(&self, port: u16) -> bool {
+        let (word, bit) = position(port);
+        self.bits[word] & bit != 0
+    }
+    pub fn len(&self) -> usize {
+        self.count
+    }
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    ///
+    /// The lowest port in the set.
+    ///
+    pub fn first(&self) -> Option<u16> {
+        if self.count == 0 {
+            return None;
+        }
+        self.bits
+            .iter()
+            .position(|w| *w != 0)
+            .map(|word| (word * 64) as u16 + self.bits[word].trailing_zeros() as u16)
+    }
+    ///
+    /// The lowest port of the first run of 'count' consecutive ports
+    /// in the set.
+    ///
+    pub fn find_run(&self, count: u16) -> Option<u16> {
+        let count = u32::from(count);
+        if count == 0 || count as usize > self.count {
+            return None;
+        }
+        let mut run_start = 0u32;
+        let mut run_length = 0u32;
+        for (word, bits) in self.bits.iter().enumerate() {
+            match *bits {
+                0 => run_length = 0,
+                u64::MAX => {
+                    if run_length == 0 {
+                        run_start = word as u32 * 64;
+                    }
+                    run_length += 64;
+                }
+                bits => {
+                    for bit in 0..64 {
+                        if bits & (1 << bit) != 0 {
+                            if run_length == 0 {
+                                run_start = word as u32 * 64 + bit;
+                            }
+                            run_length += 1;
+                            if run_length >= count {
+                                return Some(run_start as u16);
+                            }
+                        } else {
+                            run_length = 0;
+                        }
+                    }
+                }
+            }
+            if run_length >= count {
+                return Some(run_start as u16);
+            }
+        }
+        None
+    }
+    ///
+    /// Return true if no port is in both sets.
+    ///
+    pub fn is_disjoint(&self, other: &FreeSet) -> bool {
+        self.bits.iter().zip(&other.bits).all(|(a, b)| a & b == 0)
+    }
+}
+
+impl Default for FreeSet {
+    fn default() -> FreeSet {
+        FreeSet::new()
+    }
+}
+
+///
+/// The last port of the 'n' ports starting at 'start', limited to
+/// the port space.  None if 'n' is zero.
+///
+pub fn range_end(start: u16, n: u16) -> Option<u16> {
+    if n == 0 {
+        None
+    } else {
+        Some((u32::from(start) + u32::from(n) - 1).min(u32::from(u16::MAX)) as u16)
+    }
+}
+
+fn position(port: u16) -> (usize, u64) {
+    (usize::from(port) / 64, 1 << (port % 64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_remove() {
+        let mut set = FreeSet::new();
+        assert!(set.is_empty());
+        assert!(set.insert(1000));
+        assert!(!set.insert(1000));
+        assert!(set.contains(1000));
+        assert_eq!(1, set.len());
+        assert!(set.remove(1000));
+        assert!(!set.remove(1000));
+        assert!(set.is_empty());
+    }
+    #[test]
+    fn with_range() {
+        let set = FreeSet::with_range(1000, 10);
+        assert_eq!(10, set.len());
+        assert!(set.contains(1000) && set.contains(1009));
+        assert!(!set.contains(999) && !set.contains(1010));
+        assert!(FreeSet::with_range(1000, 0).is_empty());
+
+        // The whole port space, clipped at 65535:
+
+        assert_eq!(65535, FreeSet::with_range(1, 65535).len());
+        assert_eq!(536, FreeSet::with_range(65000, 1000).len());
+    }
+    #[test]
+    fn first() {
+        let mut set = FreeSet::with_range(100, 200);
+        assert_eq!(Some(100), set.first());
+        set.remove(100);
+        assert_eq!(Some(101), set.first());
+        assert_eq!(None, FreeSet::new().first());
+        set = FreeSet::new();
+        set.insert(65535);
+        assert_eq!(Some(65535), set.first());
+    }
+    #[test]
+    fn find_run() {
+        let mut set = FreeSet::with_range(60, 200);
+        assert_eq!(Some(60), set.find_run(1));
+        assert_eq!(Some(60), set.find_run(200));
+        assert_eq!(None, set.find_run(201));
+        assert_eq!(None, set.find_run(0));
+
+        // Holes split runs:
+
+        set.remove(62);
+        set.remove(130);
+        assert_eq!(Some(60), set.find_run(2));
+        assert_eq!(Some(63), set.find_run(3));
+        assert_eq!(Some(63), set.find_run(67));
+        assert_eq!(Some(131), set.find_run(68));
+        assert_eq!(None, set.find_run(130));
+    }
+    #[test]
+    fn disjoint() {
+        let a = FreeSet::with_range(1000, 10);
+        assert!(a.is_disjoint(&FreeSet::with_range(1010, 10)));
+        assert!(!a.is_disjoint(&FreeSet::with_range(1009, 10)));
+    }
+}
@@ -1,3 +1,4 @@
 // Contains module definitions that pull in specific files
 
 pub mod ports;
+pub mod freeset;
@@ -1,25 +1,62 @@
+use super::freeset::{range_end, FreeSet};
+use crate::error::error::PortmanError;
+use crate::protocol::request::{quote, tokenize, MAX_NAME_LENGTH};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 
 // Contains definitions and implemntations for port pools.
 // A port pool consists of a free set of ports and a used set
 // of ports.
 // Used ports contain the port number, the port service
-// name and the port username.
+// name, the port username and the transport protocol the port is for.
 //
 
+// How many '.n' suffixes unique_name tries before giving up.
+
+const MAX_SUFFIX: usize = 1000;
+
+///
+/// Protocol
+///    The transport protocol a port is allocated for.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+// The protocol is rendered the way it appears in LIST output:
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct UsedPort {
     port_number: u16,
     port_service: String,
     port_user: String,
+    port_protocol: Protocol,
 }
 impl UsedPort {
     pub fn new(n: u16, service: &str, user: &str) -> UsedPort {
+        UsedPort::with_protocol(n, service, user, Protocol::Tcp)
+    }
+    pub fn with_protocol(n: u16, service: &str, user: &str, protocol: Protocol) -> UsedPort {
         UsedPort {
             port_number: n,
             port_service: String::from(service),
             port_user: String::from(user),
+            port_protocol: protocol,
         }
     }
     pub fn port(&self) -> u16 {
@@ -31,123 +68,515 @@ impl UsedPort {
     pub fn user(&self) -> String {
         String::from(self.port_user.as_str())
     }
+    pub fn protocol(&self) -> Protocol {
+        self.port_protocol
+    }
 }
 
 // So we can produce a formatted UsedPort:
-// Output format is the same as what is produced
-// from the LIST requrest to the port manager:
+// Output format is the same as a line of the LIST PROTOCOL
+// (and FIND) reply; plain LIST leaves the protocol out.
+// Names are quoted if they need to be (e.g. contain spaces):
 
 impl fmt::Display for UsedPort {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {} {}", self.port(), self.service(), self.user())
+        write!(
+            f,
+            "{} {} {} {}",
+            self.port(),
+            quote(&self.port_service),
+            quote(&self.port_user),
+            self.protocol()
+        )
     }
 }
 
-// Unused ports are just the port number:
+// ... and read back from a LIST line, e.g. one another portman sent:
 
-type UnusedPort = u16;
+impl FromStr for UsedPort {
+    type Err = PortmanError;
+
+    fn from_str(line: &str) -> Result<UsedPort, PortmanError> {
+        let invalid = || PortmanError::Invalid(format!("Not an allocation: {}", line));
+        let words = tokenize(line)?;
+        if words.len() != 4 {
+            return Err(invalid());
+        }
+        let protocol = match words[3].as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return Err(invalid()),
+        };
+        let port = words[0].parse().map_err(|_| invalid())?;
+        Ok(UsedPort::with_protocol(port, &words[1], &words[2], protocol))
+    }
+}
+
+///
+/// PortRequest
+///    Describes which port(s) an allocation wants:
+///
+///  *   Any         - any single free port.
+///  *   Specific(n) - port *n*, which must be a free port in the pool.
+///  *   Block(k)    - *k* consecutive free ports advertised under the same name.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortRequest {
+    Any,
+    Specific(u16),
+    Block(u16),
+}
 
 // A port pool requires collections of both the available
-// and used ports.
+// and used ports.  Normally TCP and UDP ports share the same
+// free set.  If the pool is given a separate UDP range, UDP
+// ports come from udp_unused instead.  The ranges may not overlap
+// so a port number always identifies a single allocation.
+//
+// So that the cost of operations doesn't grow with the number of
+// allocations:
+//
+// -  The free sets are bitmaps over the port space (see FreeSet).
+// -  Used ports are kept ordered by port number so LIST needs no sort.
+// -  The ports allocated to each service/user/protocol are indexed
+//    so duplicate checks are a lookup rather than a scan.
+// -  The usage is kept as a shared snapshot that's rebuilt only when
+//    the allocations have changed since it was last asked for.
+
+///
+/// Snapshot
+///    The allocations at some point in time in port order.  It's shared, so
+///  copying it is cheap, and it doesn't change as the pool does.
+///
+pub type Snapshot = Arc<Vec<UsedPort>>;
+
+// The key of the name index:
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Name {
+    service: String,
+    user: String,
+    protocol: Protocol,
+}
 
+impl Name {
+    fn new(service: &str, user: &str, protocol: Protocol) -> Name {
+        Name {
+            service: String::from(service),
+            user: String::from(user),
+            protocol,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct PortPool {
-    used: HashMap<u16, UsedPort>,
-    unused: HashSet<UnusedPort>,
+    used: BTreeMap<u16, UsedPort>,
+    names: HashMap<Name, Vec<u16>>, // Ports of each name, ascending.
+    unused: FreeSet,
+    udp_unused: Option<FreeSet>,
+    range: (u16, u16), // First port, number of ports.
+    udp_range: Option<(u16, u16)>,
+    snapshot: RefCell<Option<Snapshot>>,
 }
 
 impl PortPool {
     ///
     /// Create a new port pool:
     ///  start is the starting port.  n is the number of ports in the pool.
+    ///  TCP and UDP allocations share the pool.  Ports past 65535 are not
+    ///  included.
     ///
     pub fn new(start: u16, n: u16) -> PortPool {
-        // Generate the unused port pool
-
-        let mut unused = HashSet::new();
-        for p in start..(start + n) {
-            unused.insert(p);
-        }
         PortPool {
-            used: HashMap::new(),
-            unused: unused,
+            used: BTreeMap::new(),
+            names: HashMap::new(),
+            unused: FreeSet::with_range(start, n),
+            udp_unused: None,
+            range: (start, n),
+            udp_range: None,
+            snapshot: RefCell::new(None),
         }
     }
-    // Mark 'port' as used.
+    ///
+    /// Create a port pool with separate TCP and UDP ranges:
+    ///  start, n are the first port and number of ports for TCP.
+    ///  udp_start, udp_n are the same for UDP.  The two ranges must not overlap.
+    ///
+    pub fn with_udp_range(
+        start: u16,
+        n: u16,
+        udp_start: u16,
+        udp_n: u16,
+    ) -> Result<PortPool, PortmanError> {
+        let mut pool = PortPool::new(start, n);
+        let udp = FreeSet::with_range(udp_start, udp_n);
+        if !pool.unused.is_disjoint(&udp) {
+            return Err(PortmanError::Invalid(String::from(
+                "The TCP and UDP port ranges overlap",
+            )));
+        }
+        pool.udp_unused = Some(udp);
+        pool.udp_range = Some((udp_start, udp_n));
+        Ok(pool)
+    }
+    // The free set ports for 'protocol' come from.
     //
-    fn mark_used(&mut self, port: u16) {
-        self.unused.remove(&port);
+    fn free_set(&self, protocol: Protocol) -> &FreeSet {
+        match (protocol, &self.udp_unused) {
+            (Protocol::Udp, Some(udp)) => udp,
+            _ => &self.unused,
+        }
     }
-    // Return a port, any port that is not yet in use.
+    fn free_set_mut(&mut self, protocol: Protocol) -> &mut FreeSet {
+        match (protocol, &mut self.udp_unused) {
+            (Protocol::Udp, Some(udp)) => udp,
+            _ => &mut self.unused,
+        }
+    }
+    // The range of ports 'protocol' allocates from.
     //
-    fn get_unused(&self) -> u16 {
-        let pport = self
-            .unused
-            .iter()
-            .next()
-            .expect("Bug non-empty free port pool iterator failed");
-        *pport
-    }
-    // Return true if there's an allocated port already with the service/user pair.
+    fn range_of(&self, protocol: Protocol) -> (u16, u16) {
+        match (protocol, self.udp_range) {
+            (Protocol::Udp, Some(udp)) => udp,
+            _ => self.range,
+        }
+    }
+    // Mark 'port' as used by 'service'/'user' for 'protocol'.
     //
-    fn in_use(&self, service: &str, user: &str) -> bool {
-        for (_, value) in self.used.iter() {
-            if value.port_service == service && value.port_user == user {
-                return true;
+    fn mark_used(&mut self, port: u16, service: &str, user: &str, protocol: Protocol) {
+        self.free_set_mut(protocol).remove(port);
+        self.used
+            .insert(port, UsedPort::with_protocol(port, service, user, protocol));
+        let ports = self
+            .names
+            .entry(Name::new(service, user, protocol))
+            .or_default();
+        let at = ports.partition_point(|p| *p < port);
+        ports.insert(at, port);
+        self.snapshot.replace(None);
+    }
+    // Return a port, any port that is not yet in use.
+    //
+    fn get_unused(&self, protocol: Protocol) -> u16 {
+        self.free_set(protocol)
+            .first()
+            .expect("Bug non-empty free port pool iterator failed")
+    }
+    ///
+    /// Return true if there's an allocated port already with the
+    /// service/user pair for 'protocol'.  The same names may be used
+    /// once for each protocol.
+    ///
+    pub fn in_use(&self, service: &str, user: &str, protocol: Protocol) -> bool {
+        self.names.contains_key(&Name::new(service, user, protocol))
+    }
+    ///
+    /// Return the ports allocated to the service/user pair for 'protocol'
+    /// in ascending order.  This is empty if the name is not in use.
+    ///
+    pub fn ports_for(&self, service: &str, user: &str, protocol: Protocol) -> Vec<u16> {
+        self.names
+            .get(&Name::new(service, user, protocol))
+            .cloned()
+            .unwrap_or_default()
+    }
+    ///
+    /// Return a service name based on 'service' that is not in use for 'user'
+    /// and 'protocol'.  As the Tcl port manager did, if 'service' is taken,
+    /// the name is made unique by appending '.n' for the smallest n that works.
+    /// 'service' is shortened as needed for the result to fit in
+    /// MAX_NAME_LENGTH.  Fails as a duplicate if none of the first
+    /// MAX_SUFFIX suffixes is free.
+    ///
+    pub fn unique_name(
+        &self,
+        service: &str,
+        user: &str,
+        protocol: Protocol,
+    ) -> Result<String, PortmanError> {
+        if !self.in_use(service, user, protocol) {
+            return Ok(String::from(service));
+        }
+        for n in 1..=MAX_SUFFIX {
+            let suffix = format!(".{}", n);
+            let mut end = service.len().min(MAX_NAME_LENGTH - suffix.len());
+            while !service.is_char_boundary(end) {
+                end -= 1;
+            }
+            let candidate = format!("{}{}", &service[..end], suffix);
+            if !self.in_use(&candidate, user, protocol) {
+                return Ok(candidate);
             }
         }
-        return false;
+        Err(duplicate())
+    }
+    ///
+    /// Return the number of ports that are still available for allocation
+    /// over all protocols.
+    ///
+    pub fn available(&self) -> usize {
+        self.unused.len() + self.udp_unused.as_ref().map_or(0, FreeSet::len)
     }
     ///
-    /// Allocate a port from the pool.  The port will be advertised with the
+    /// Allocate a TCP port from the pool.  The port will be advertised with the
     /// service name 'servie' qualified by the user 'user'.  The return value will be
     /// a UsedPort describing the allocated port on success or a failure reason string
     /// on failure.
     ///
-    pub fn allocate(&mut self, service: &str, user: &str) -> Result<UsedPort, String> {
-        if self.unused.len() == 0 {
-            return Err(String::from("No free ports available"));
+    pub fn allocate(&mut self, service: &str, user: &str) -> Result<UsedPort, PortmanError> {
+        self.allocate_any(service, user, Protocol::Tcp)
+    }
+    ///
+    /// Allocate any free port for 'protocol'.  Otherwise, this is the same as
+    /// allocate.
+    ///
+    pub fn allocate_any(
+        &mut self,
+        service: &str,
+        user: &str,
+        protocol: Protocol,
+    ) -> Result<UsedPort, PortmanError> {
+        if self.free_set(protocol).is_empty() {
+            Err(PortmanError::PoolExhausted(String::from(
+                "No free ports available",
+            )))
         } else {
-            if self.in_use(service, user) {
-                return Err(String::from("Duplicate port allocation attempted"));
+            if self.in_use(service, user, protocol) {
+                return Err(duplicate());
             }
-            let port = self.get_unused();
+            let port = self.get_unused(protocol);
 
-            self.mark_used(port);
-            self.used.insert(port, UsedPort::new(port, service, user));
-            Ok(UsedPort::new(port, service, user))
+            self.mark_used(port, service, user, protocol);
+            Ok(UsedPort::with_protocol(port, service, user, protocol))
         }
     }
     ///
-    /// return a vector of the used ports.
+    /// Return true if 'port' is one of the ports managed by the pool
+    /// whether or not it is allocated.
     ///
-    pub fn usage(&self) -> Vec<UsedPort> {
-        let mut result: Vec<UsedPort> = Vec::new();
-        for (_, value) in &self.used {
-            let u = UsedPort::new(
-                value.port(),
-                value.service().as_str(),
-                value.user().as_str(),
-            );
-            result.push(u);
+    pub fn contains(&self, port: u16) -> bool {
+        self.in_range(port, Protocol::Tcp) || self.in_range(port, Protocol::Udp)
+    }
+    ///
+    /// Return the allocation of 'port' or None if it's not allocated.
+    ///
+    pub fn allocation(&self, port: u16) -> Option<&UsedPort> {
+        self.used.get(&port)
+    }
+    // Return true if 'port' belongs to the range 'protocol' allocates from.
+    //
+    fn in_range(&self, port: u16, protocol: Protocol) -> bool {
+        let (start, n) = self.range_of(protocol);
+        range_end(start, n).is_some_and(|end| port >= start && port <= end)
+    }
+    // Return the number of ports in the range 'protocol' allocates from.
+    //
+    fn range_size(&self, protocol: Protocol) -> usize {
+        let (start, n) = self.range_of(protocol);
+        range_end(start, n).map_or(0, |end| usize::from(end - start) + 1)
+    }
+    // Return the lowest port of the first run of 'count' consecutive free ports.
+    //
+    fn find_block(&self, count: u16, protocol: Protocol) -> Option<u16> {
+        self.free_set(protocol).find_run(count)
+    }
+    ///
+    /// Return true if the ports described by 'request' are free right now.
+    ///
+    pub fn satisfiable(&self, request: PortRequest, protocol: Protocol) -> bool {
+        match request {
+            PortRequest::Any => !self.free_set(protocol).is_empty(),
+            PortRequest::Specific(port) => self.free_set(protocol).contains(port),
+            PortRequest::Block(count) => self.find_block(count, protocol).is_some(),
+        }
+    }
+    ///
+    /// Return true if the ports described by 'request' would be free were
+    /// the allocated ports in 'freed' given back first.
+    ///
+    pub fn satisfiable_after(
+        &self,
+        request: PortRequest,
+        protocol: Protocol,
+        freed: &[u16],
+    ) -> bool {
+        if freed.is_empty() {
+            return self.satisfiable(request, protocol);
+        }
+        let mut free = self.free_set(protocol).clone();
+        for port in freed {
+            if self.used.contains_key(port) {
+                free.insert(*port);
+            }
+        }
+        match request {
+            PortRequest::Any => !free.is_empty(),
+            PortRequest::Specific(port) => free.contains(port),
+            PortRequest::Block(count) => free.find_run(count).is_some(),
         }
-        result.sort_by_key(|k| k.port_number);
-        result
+    }
+    ///
+    /// Return true if 'request' could ever be satisfied by this pool, that is
+    /// the port it names is in the pool or the block it asks for fits.
+    ///
+    pub fn possible(&self, request: PortRequest, protocol: Protocol) -> bool {
+        match request {
+            PortRequest::Any => true,
+            PortRequest::Specific(port) => self.in_range(port, protocol),
+            PortRequest::Block(count) => {
+                count > 0 && usize::from(count) <= self.range_size(protocol)
+            }
+        }
+    }
+    ///
+    /// Allocate the specific 'port' from the pool for 'protocol', advertising it as
+    /// 'service' qualified by 'user'.  The port must be a free port in the pool.
+    ///
+    pub fn allocate_port(
+        &mut self,
+        port: u16,
+        service: &str,
+        user: &str,
+        protocol: Protocol,
+    ) -> Result<UsedPort, PortmanError> {
+        if self.used.contains_key(&port) {
+            return Err(PortmanError::Unavailable(format!(
+                "Port {} is already allocated",
+                port
+            )));
+        }
+        if !self.free_set(protocol).contains(port) {
+            return Err(PortmanError::Unavailable(format!(
+                "Port {} is not in the {} port pool",
+                port, protocol
+            )));
+        }
+        if self.in_use(service, user, protocol) {
+            return Err(duplicate());
+        }
+        self.mark_used(port, service, user, protocol);
+        Ok(UsedPort::with_protocol(port, service, user, protocol))
+    }
+    ///
+    /// Atomically allocate 'count' consecutive ports for 'protocol' that are all
+    /// advertised as 'service' qualified by 'user'.  Either all of the ports are
+    /// allocated or none are.  The ports are returned in ascending order.
+    ///
+    pub fn allocate_block(
+        &mut self,
+        count: u16,
+        service: &str,
+        user: &str,
+        protocol: Protocol,
+    ) -> Result<Vec<UsedPort>, PortmanError> {
+        if count == 0 {
+            return Err(PortmanError::Invalid(String::from(
+                "A block must contain at least one port",
+            )));
+        }
+        if self.in_use(service, user, protocol) {
+            return Err(duplicate());
+        }
+        let base = match self.find_block(count, protocol) {
+            Some(base) => base,
+            None => {
+                return Err(PortmanError::PoolExhausted(format!(
+                    "No block of {} free ports available",
+                    count
+                )))
+            }
+        };
+        let mut result = Vec::new();
+        for port in base..=base + (count - 1) {
+            self.mark_used(port, service, user, protocol);
+            result.push(UsedPort::with_protocol(port, service, user, protocol));
+        }
+        Ok(result)
+    }
+    ///
+    /// Put back an allocation 'used' that was made from a pool like this one,
+    /// e.g. when the pool is being rebuilt.  Unlike allocate_port, the
+    /// names need not be unique so that each port of a block can be restored.
+    /// The port must be a free port in the pool.
+    ///
+    pub fn restore(&mut self, used: &UsedPort) -> Result<(), PortmanError> {
+        let port = used.port();
+        if !self.free_set(used.protocol()).contains(port) {
+            return Err(PortmanError::Unavailable(format!(
+                "Port {} is not free in the {} port pool",
+                port,
+                used.protocol()
+            )));
+        }
+        self.mark_used(port, &used.port_service, &used.port_user, used.port_protocol);
+        Ok(())
+    }
+    ///
+    /// Allocate whatever 'request' describes for 'protocol'.  This is a
+    /// convenience that dispatches to allocate_any, allocate_port or allocate_block.
+    ///
+    pub fn allocate_request(
+        &mut self,
+        request: PortRequest,
+        protocol: Protocol,
+        service: &str,
+        user: &str,
+    ) -> Result<Vec<UsedPort>, PortmanError> {
+        match request {
+            PortRequest::Any => self.allocate_any(service, user, protocol).map(|p| vec![p]),
+            PortRequest::Specific(port) => self
+                .allocate_port(port, service, user, protocol)
+                .map(|p| vec![p]),
+            PortRequest::Block(count) => self.allocate_block(count, service, user, protocol),
+        }
+    }
+    ///
+    /// return a vector of the used ports in port order.
+    ///
+    pub fn usage(&self) -> Vec<UsedPort> {
+        self.snapshot().as_ref().clone()
+    }
+    ///
+    /// Return the used ports in port order as a shared snapshot.  This is
+    /// cheap unless the allocations have changed since the last snapshot.
+    ///
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot
+            .borrow_mut()
+            .get_or_insert_with(|| Arc::new(self.used.values().cloned().collect()))
+            .clone()
     }
     ///
     ///  Given a used 'port' number return it to the unused port pool.
     ///  The result is eithert the original port number for Ok or an
     ///  a string describing the failure.
     ///
-    pub fn free(&mut self, port: u16) -> Result<u16, String> {
+    pub fn free(&mut self, port: u16) -> Result<u16, PortmanError> {
         match self.used.remove(&port) {
-            Some(_) => {
-                self.unused.insert(port);
+            Some(used) => {
+                let name = Name::new(&used.port_service, &used.port_user, used.port_protocol);
+                if let Some(ports) = self.names.get_mut(&name) {
+                    ports.retain(|p| *p != port);
+                    if ports.is_empty() {
+                        self.names.remove(&name);
+                    }
+                }
+                self.free_set_mut(used.port_protocol).insert(port);
+                self.snapshot.replace(None);
                 Ok(port)
             }
-            None => Err(String::from("Port is not allocated")),
+            None => Err(PortmanError::NotAllocated(port)),
         }
     }
 }
+
+///
+/// The error for a service/user/protocol that's already allocated.
+///
+pub fn duplicate() -> PortmanError {
+    PortmanError::Duplicate(String::from("Duplicate port allocation attempted"))
+}
 //
 // Unit tests:
 //
@@ -209,7 +638,7 @@ mod tests {
         // This one should fail:
 
         let result = pool.allocate("Fails", "fox");
-        assert!(result.is_err());
+        assert_eq!("E_EXHAUSTED", result.unwrap_err().code());
     }
     #[test]
     fn portpool_allocate_3() {
@@ -239,6 +668,230 @@ mod tests {
 
         assert_ne!(port1.port_number, port2.port_number);
     }
+    #[test]
+    fn portpool_available() {
+        let mut pool = PortPool::new(1000, 2);
+        assert_eq!(2, pool.available());
+        let port = pool.allocate("Service", "fox").unwrap();
+        assert_eq!(1, pool.available());
+        pool.free(port.port_number).unwrap();
+        assert_eq!(2, pool.available());
+    }
+    #[test]
+    fn portpool_in_use() {
+        let mut pool = PortPool::new(1000, 2);
+        assert!(!pool.in_use("Service", "fox", Protocol::Tcp));
+        pool.allocate("Service", "fox").unwrap();
+        assert!(pool.in_use("Service", "fox", Protocol::Tcp));
+        assert!(!pool.in_use("Service", "cerizza", Protocol::Tcp));
+    }
+    // PortPool type - specific port allocation.
+    #[test]
+    fn allocate_port_1() {
+        let mut pool = PortPool::new(1000, 10);
+        let port = pool.allocate_port(1005, "Service", "fox", Protocol::Tcp).unwrap();
+        assert_eq!(1005, port.port_number);
+        assert!(!pool.unused.contains(1005));
+        assert_eq!(9, pool.available());
+    }
+    #[test]
+    fn allocate_port_2() {
+        // Already allocated or not in the pool fail:
+        let mut pool = PortPool::new(1000, 10);
+        pool.allocate_port(1005, "Service", "fox", Protocol::Tcp).unwrap();
+        assert!(pool.allocate_port(1005, "Other", "fox", Protocol::Tcp).is_err());
+        assert!(pool.allocate_port(2000, "Other", "fox", Protocol::Tcp).is_err());
+    }
+    #[test]
+    fn allocate_port_3() {
+        // Duplicate names still fail:
+        let mut pool = PortPool::new(1000, 10);
+        pool.allocate("Service", "fox").unwrap();
+        assert!(pool.allocate_port(1005, "Service", "fox", Protocol::Tcp).is_err());
+    }
+    // PortPool type - block allocation.
+    #[test]
+    fn allocate_block_1() {
+        let mut pool = PortPool::new(1000, 10);
+        let block = pool.allocate_block(3, "Service", "fox", Protocol::Tcp).unwrap();
+        let ports: Vec<u16> = block.iter().map(|p| p.port()).collect();
+        assert_eq!(vec![1000, 1001, 1002], ports);
+        for p in block {
+            assert_eq!(String::from("Service"), p.service());
+            assert_eq!(String::from("fox"), p.user());
+        }
+        assert_eq!(7, pool.available());
+    }
+    #[test]
+    fn allocate_block_2() {
+        // Skips runs that are too short:
+        let mut pool = PortPool::new(1000, 6);
+        pool.allocate_port(1002, "a", "fox", Protocol::Tcp).unwrap();
+        pool.allocate_port(1004, "b", "fox", Protocol::Tcp).unwrap();
+        assert!(pool.satisfiable(PortRequest::Block(2), Protocol::Tcp));
+        assert!(!pool.satisfiable(PortRequest::Block(3), Protocol::Tcp));
+        assert!(pool.allocate_block(3, "c", "fox", Protocol::Tcp).is_err());
+        assert_eq!(4, pool.available()); // Nothing was taken.
+        let block = pool.allocate_block(2, "c", "fox", Protocol::Tcp).unwrap();
+        assert_eq!(1000, block[0].port());
+    }
+    #[test]
+    fn allocate_block_3() {
+        // Zero length, oversize and duplicates fail:
+        let mut pool = PortPool::new(1000, 4);
+        assert!(pool.allocate_block(0, "a", "fox", Protocol::Tcp).is_err());
+        assert!(pool.allocate_block(5, "a", "fox", Protocol::Tcp).is_err());
+        assert!(!pool.possible(PortRequest::Block(5), Protocol::Tcp));
+        pool.allocate("a", "fox").unwrap();
+        assert!(pool.allocate_block(2, "a", "fox", Protocol::Tcp).is_err());
+    }
+    #[test]
+    fn allocate_block_4() {
+        // A block that ends at the top of the port space:
+        let mut pool = PortPool::new(65534, 2);
+        let block = pool.allocate_block(2, "a", "fox", Protocol::Tcp).unwrap();
+        let ports: Vec<u16> = block.iter().map(|p| p.port()).collect();
+        assert_eq!(vec![65534, 65535], ports);
+        assert_eq!(0, pool.available());
+    }
+    #[test]
+    fn satisfiable_after_1() {
+        let mut pool = PortPool::new(1000, 3);
+        pool.allocate_port(1000, "a", "fox", Protocol::Tcp).unwrap();
+        pool.allocate_port(1002, "b", "fox", Protocol::Tcp).unwrap();
+        assert!(!pool.satisfiable(PortRequest::Block(2), Protocol::Tcp));
+        assert!(pool.satisfiable_after(PortRequest::Block(2), Protocol::Tcp, &[1000]));
+        assert!(pool.satisfiable_after(PortRequest::Specific(1002), Protocol::Tcp, &[1002]));
+        assert!(!pool.satisfiable_after(PortRequest::Specific(1002), Protocol::Tcp, &[1000]));
+        assert!(!pool.satisfiable_after(PortRequest::Block(3), Protocol::Tcp, &[1000]));
+    }
+    #[test]
+    fn possible_1() {
+        let pool = PortPool::new(1000, 4);
+        assert!(pool.possible(PortRequest::Any, Protocol::Tcp));
+        assert!(pool.possible(PortRequest::Specific(1003), Protocol::Tcp));
+        assert!(!pool.possible(PortRequest::Specific(1004), Protocol::Tcp));
+        assert!(pool.possible(PortRequest::Block(4), Protocol::Tcp));
+        assert!(!pool.possible(PortRequest::Block(0), Protocol::Tcp));
+    }
+    // Protocols:
+    #[test]
+    fn uport_protocol() {
+        let u = UsedPort::new(100, "Mytest", "Fox");
+        assert_eq!(Protocol::Tcp, u.protocol());
+        let u = UsedPort::with_protocol(100, "Mytest", "Fox", Protocol::Udp);
+        assert_eq!(Protocol::Udp, u.protocol());
+        assert_eq!(String::from("100 Mytest Fox udp"), u.to_string());
+        let u = UsedPort::new(100, "My test", "Fox");
+        assert_eq!(String::from("100 \"My test\" Fox tcp"), u.to_string());
+        assert_eq!(Ok(u), "100 \"My test\" Fox tcp".parse());
+        assert!("100 Mytest Fox sctp".parse::<UsedPort>().is_err());
+        assert!("100 Mytest Fox".parse::<UsedPort>().is_err());
+    }
+    #[test]
+    fn protocol_shared_1() {
+        // Shared pool - TCP and UDP draw from the same ports:
+        let mut pool = PortPool::new(1000, 2);
+        let tcp = pool.allocate_any("Service", "fox", Protocol::Tcp).unwrap();
+        let udp = pool.allocate_any("Service", "fox", Protocol::Udp).unwrap();
+        assert_ne!(tcp.port(), udp.port());
+        assert_eq!(0, pool.available());
+        assert!(pool.allocate_any("Other", "fox", Protocol::Udp).is_err());
+    }
+    #[test]
+    fn protocol_shared_2() {
+        // Duplicate check is per protocol:
+        let mut pool = PortPool::new(1000, 4);
+        pool.allocate_any("Service", "fox", Protocol::Udp).unwrap();
+        assert!(pool.allocate_any("Service", "fox", Protocol::Udp).is_err());
+        assert!(pool.allocate_any("Service", "fox", Protocol::Tcp).is_ok());
+    }
+    #[test]
+    fn protocol_separate_1() {
+        let mut pool = PortPool::with_udp_range(1000, 2, 2000, 2).unwrap();
+        assert_eq!(4, pool.available());
+        let udp = pool.allocate_any("Service", "fox", Protocol::Udp).unwrap();
+        assert!(udp.port() >= 2000);
+        let tcp = pool.allocate("Service", "fox").unwrap();
+        assert!(tcp.port() < 2000);
+
+        // Each range only serves its own protocol:
+
+        assert!(pool.allocate_port(2001, "x", "fox", Protocol::Tcp).is_err());
+        assert!(pool.allocate_port(1001, "x", "fox", Protocol::Udp).is_err());
+        assert!(!pool.possible(PortRequest::Block(3), Protocol::Udp));
+
+        // Freeing returns the port to the right range:
+
+        pool.free(udp.port()).unwrap();
+        assert!(pool.udp_unused.as_ref().unwrap().contains(udp.port()));
+        assert!(!pool.unused.contains(udp.port()));
+    }
+    #[test]
+    fn protocol_separate_2() {
+        // Overlapping ranges are refused:
+        assert!(PortPool::with_udp_range(1000, 10, 1005, 10).is_err());
+    }
+    #[test]
+    fn protocol_usage() {
+        let mut pool = PortPool::with_udp_range(1000, 2, 2000, 2).unwrap();
+        pool.allocate_any("Service", "fox", Protocol::Udp).unwrap();
+        let usage = pool.usage();
+        assert_eq!(1, usage.len());
+        assert_eq!(Protocol::Udp, usage[0].protocol());
+    }
+    // Name collision helpers:
+    #[test]
+    fn ports_for_1() {
+        let mut pool = PortPool::new(1000, 10);
+        assert!(pool.ports_for("Service", "fox", Protocol::Tcp).is_empty());
+        pool.allocate_block(3, "Service", "fox", Protocol::Tcp).unwrap();
+        pool.allocate("Other", "fox").unwrap();
+        assert_eq!(vec![1000, 1001, 1002], pool.ports_for("Service", "fox", Protocol::Tcp));
+        assert!(pool.ports_for("Service", "fox", Protocol::Udp).is_empty());
+    }
+    #[test]
+    fn unique_name_1() {
+        let mut pool = PortPool::new(1000, 10);
+        let unique = |pool: &PortPool, service: &str, user: &str, protocol: Protocol| {
+            pool.unique_name(service, user, protocol).unwrap()
+        };
+        assert_eq!("Service", unique(&pool, "Service", "fox", Protocol::Tcp));
+        pool.allocate("Service", "fox").unwrap();
+        assert_eq!("Service.1", unique(&pool, "Service", "fox", Protocol::Tcp));
+        pool.allocate("Service.1", "fox").unwrap();
+        assert_eq!("Service.2", unique(&pool, "Service", "fox", Protocol::Tcp));
+
+        // Other users and protocols don't collide:
+
+        assert_eq!(
+            "Service",
+            unique(&pool, "Service", "cerizza", Protocol::Tcp)
+        );
+        assert_eq!("Service", unique(&pool, "Service", "fox", Protocol::Udp));
+
+        // Long names are shortened to fit the suffix:
+
+        let long = "x".repeat(MAX_NAME_LENGTH);
+        pool.allocate(&long, "fox").unwrap();
+        let name = unique(&pool, &long, "fox", Protocol::Tcp);
+        assert_eq!(MAX_NAME_LENGTH, name.len());
+        assert!(name.ends_with("x.1"));
+    }
+    #[test]
+    fn unique_name_gives_up() {
+        let mut pool = PortPool::new(1000, MAX_SUFFIX as u16 + 1);
+        pool.allocate("Service", "fox").unwrap();
+        for n in 1..=MAX_SUFFIX {
+            pool.allocate(&format!("Service.{}", n), "fox").unwrap();
+        }
+        assert_eq!(
+            "E_DUPLICATE",
+            pool.unique_name("Service", "fox", Protocol::Tcp)
+                .unwrap_err()
+                .code()
+        );
+    }
     // PortPool type usage listing.
     #[test]
     fn usage_1() {
@@ -257,6 +910,7 @@ mod tests {
         assert_eq!(port1.port_user, usage[0].port_user);
     }
     #[test]
+    #[allow(clippy::useless_vec)]
     fn usage_3() {
         // Use a couple of ports:
         let mut pool = PortPool::new(1000, 2);
@@ -272,6 +926,64 @@ mod tests {
             assert_eq!(allocated[i].port_user, used[i].port_user);
         }
     }
+    #[test]
+    fn snapshot_1() {
+        // Snapshots are shared until the allocations change:
+        let mut pool = PortPool::new(1000, 10);
+        pool.allocate("b", "fox").unwrap();
+        pool.allocate("a", "fox").unwrap();
+        let first = pool.snapshot();
+        assert!(Arc::ptr_eq(&first, &pool.snapshot()));
+        assert_eq!(vec![1000, 1001], first.iter().map(|u| u.port()).collect::<Vec<_>>());
+        pool.free(1000).unwrap();
+        let second = pool.snapshot();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(2, first.len()); // Unchanged by the free.
+        assert_eq!(1, second.len());
+    }
+    #[test]
+    fn index_1() {
+        // The name index follows frees:
+        let mut pool = PortPool::new(1000, 10);
+        pool.allocate_block(3, "Service", "fox", Protocol::Tcp).unwrap();
+        pool.free(1001).unwrap();
+        assert_eq!(vec![1000, 1002], pool.ports_for("Service", "fox", Protocol::Tcp));
+        assert_eq!(None, pool.allocation(1001));
+        assert_eq!("Service", pool.allocation(1002).unwrap().service());
+        pool.free(1000).unwrap();
+        pool.free(1002).unwrap();
+        assert!(!pool.in_use("Service", "fox", Protocol::Tcp));
+        assert!(pool.allocate("Service", "fox").is_ok());
+    }
+    #[test]
+    fn full_port_space() {
+        // A pool can cover every port:
+        let mut pool = PortPool::new(1, 65535);
+        assert_eq!(65535, pool.available());
+        assert!(pool.contains(65535));
+        assert!(pool.possible(PortRequest::Block(65535), Protocol::Tcp));
+        pool.allocate_port(65535, "last", "fox", Protocol::Tcp).unwrap();
+        assert_eq!(1, pool.allocate("first", "fox").unwrap().port());
+
+        // Ranges that would run past 65535 are clipped:
+
+        let pool = PortPool::new(65000, 1000);
+        assert_eq!(536, pool.available());
+        assert!(!pool.possible(PortRequest::Block(537), Protocol::Tcp));
+    }
+    #[test]
+    fn restore_1() {
+        let mut pool = PortPool::new(1000, 10);
+        let block = pool.allocate_block(2, "Service", "fox", Protocol::Tcp).unwrap();
+        let mut rebuilt = PortPool::new(1000, 10);
+        for u in &block {
+            rebuilt.restore(u).unwrap();
+        }
+        assert_eq!(pool.usage(), rebuilt.usage());
+        assert!(rebuilt.in_use("Service", "fox", Protocol::Tcp));
+        assert!(rebuilt.restore(&block[0]).is_err()); // Already used.
+        assert!(rebuilt.restore(&UsedPort::new(2000, "x", "fox")).is_err());
+    }
     // PortPool type: free pool.
     #[test]
     fn free_1() {
@@ -279,7 +991,7 @@ mod tests {
         // any free gives err:
 
         let result = pool.free(1000);
-        assert!(result.is_err());
+        assert_eq!(Err(PortmanError::NotAllocated(1000)), result);
     }
     #[test]
     fn free_2() {
@@ -298,6 +1010,6 @@ mod tests {
 
         pool.free(port.port_number).unwrap();
         assert_eq!(1, pool.unused.len());
-        assert!(pool.unused.contains(&port.port_number));
+        assert!(pool.unused.contains(port.port_number));
     }
 }
@@ -0,0 +1,239 @@
+// Contains the framing of client requests:  Splitting the byte stream
+// from a connection into request lines.
+//
+// Each connection has a LineReader that lives as long as the connection.
+// Bytes are added to it as they arrive and complete lines are taken out
+// of it, so any number of requests can be sent (pipelined) without
+// waiting for replies and nothing received after a newline is lost.
+//
+// Lines are terminated by a newline (an optional carriage return before
+// it is dropped), must be valid UTF-8 and are limited in length so a
+// client can't make us buffer without limit.
+
+use crate::error::error::PortmanError;
+use std::str;
+
+///
+/// The longest request line (not counting its line ending) we accept.
+/// The longest legal request, GIMME with two quoted, fully escaped names
+/// and all of its options, is well within this.
+///
+pub const MAX_LINE_LENGTH: usize = 1024;
+
+///
+/// The most received but unprocessed data a connection may have.
+/// Requests are not processed while a client waits for a port, so this
+/// bounds how much a client can pipeline behind a GIMME ... WAIT.
+///
+pub const MAX_BUFFERED: usize = 64 * 1024;
+
+///
+/// LineReader
+///    Accumulates the bytes received from a connection and hands them out
+/// as request lines.
+///
+pub struct LineReader {
+    buffer: Vec<u8>,
+    max_line: usize,
+    max_buffered: usize,
+    scanned: usize, // No newline in buffer[..scanned].
+}
+
+impl LineReader {
+    ///
+    /// A reader with the default limits.
+    ///
+    pub fn new() -> LineReader {
+        LineReader::with_limits(MAX_LINE_LENGTH, MAX_BUFFERED)
+    }
+    ///
+    /// A reader that accepts lines of at most max_line bytes and buffers
+    /// at most max_buffered bytes.
+    ///
+    pub fn with_limits(max_line: usize, max_buffered: usize) -> LineReader {
+        LineReader {
+            buffer: Vec::new(),
+            max_line,
+            max_buffered,
+            scanned: 0,
+        }
+    }
+    ///
+    /// Add received bytes.
+    ///
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+    ///
+    /// True if there's nothing buffered.
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+    ///
+    /// Check the limits on what's buffered.  Returns an error once the
+    /// client has sent more than we're willing to hold, in which case the
+    /// connection should not be read further.
+    ///
+    pub fn check(&self) -> Result<(), PortmanError> {
+        if self.buffer.len() > self.max_buffered {
+            Err(PortmanError::Framing(format!(
+                "More than {} bytes of unprocessed requests",
+                self.max_buffered
+            )))
+        } else {
+            Ok(())
+        }
+    }
+    ///
+    /// Take the next line out of the buffer.
+    ///
+    /// ### Returns:
+    /// -  None if there's no complete line (yet).
+    /// -  Some(Ok(line)) the line without its line ending.
+    /// -  Some(Err(PortmanError::Framing)) if the line is too long or not UTF-8.  The stream
+    ///    can't be resynchronized reliably after this so the connection
+    ///    should be closed.
+    ///
+    pub fn next_line(&mut self) -> Option<Result<String, PortmanError>> {
+        let end = match self.buffer[self.scanned..].iter().position(|b| *b == b'\n') {
+            Some(offset) => self.scanned + offset,
+            None => {
+                self.scanned = self.buffer.len();
+                return if self.buffer.len() > self.max_line {
+                    Some(Err(self.too_long()))
+                } else {
+                    None
+                };
+            }
+        };
+        let line: Vec<u8> = self.buffer.drain(..=end).collect();
+        self.scanned = 0;
+        let mut line = &line[..line.len() - 1];
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        if line.len() > self.max_line {
+            return Some(Err(self.too_long()));
+        }
+        Some(match str::from_utf8(line) {
+            Ok(line) => Ok(line.to_string()),
+            Err(_) => Err(PortmanError::Framing(String::from(
+                "Request is not valid UTF-8",
+            ))),
+        })
+    }
+    ///
+    /// Take everything that's buffered, e.g. what a client sent after a
+    /// CONNECT, which is for the service rather than for us.
+    ///
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        self.scanned = 0;
+        std::mem::take(&mut self.buffer)
+    }
+    ///
+    /// What's buffered, left in place.
+    ///
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+    ///
+    /// Called when the peer has closed its side of the connection.
+    /// Returns an error if it left a partial request behind.
+    ///
+    pub fn finish(&self) -> Result<(), PortmanError> {
+        if self.buffer.is_empty() {
+            Ok(())
+        } else {
+            Err(PortmanError::Framing(String::from(
+                "Request not terminated by a newline",
+            )))
+        }
+    }
+    fn too_long(&self) -> PortmanError {
+        PortmanError::Framing(format!("Request longer than {} bytes", self.max_line))
+    }
+}
+
+impl Default for LineReader {
+    fn default() -> LineReader {
+        LineReader::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipelined() {
+        let mut r = LineReader::new();
+        r.extend(b"LIST\nFIND a b\r\nGIM");
+        assert_eq!(r.next_line(), Some(Ok(String::from("LIST"))));
+        assert_eq!(r.next_line(), Some(Ok(String::from("FIND a b"))));
+        assert_eq!(r.next_line(), None);
+        r.extend(b"ME a b\n");
+        assert_eq!(r.next_line(), Some(Ok(String::from("GIMME a b"))));
+        assert_eq!(r.next_line(), None);
+        assert!(r.is_empty());
+    }
+    #[test]
+    fn too_long() {
+        let mut r = LineReader::with_limits(8, 100);
+        r.extend(b"12345678\n123456789\n");
+        assert_eq!(r.next_line(), Some(Ok(String::from("12345678"))));
+        assert_eq!(
+            r.next_line(),
+            Some(Err(PortmanError::Framing(String::from(
+                "Request longer than 8 bytes"
+            ))))
+        );
+
+        // Detected before the newline arrives:
+
+        let mut r = LineReader::with_limits(8, 100);
+        r.extend(b"1234567");
+        assert_eq!(r.next_line(), None);
+        r.extend(b"89");
+        assert!(r.next_line().unwrap().is_err());
+    }
+    #[test]
+    fn buffered_limit() {
+        let mut r = LineReader::with_limits(8, 10);
+        r.extend(b"a\nb\nc\nd\ne\n");
+        assert!(r.check().is_ok());
+        r.extend(b"f\n");
+        assert!(r.check().is_err());
+    }
+    #[test]
+    fn not_utf8() {
+        let mut r = LineReader::new();
+        r.extend(b"GIMME \xff\xfe me\nLIST\n");
+        assert_eq!(
+            r.next_line(),
+            Some(Err(PortmanError::Framing(String::from(
+                "Request is not valid UTF-8"
+            ))))
+        );
+    }
+    #[test]
+    fn unterminated() {
+        let mut r = LineReader::new();
+        r.extend(b"LIST\nLI");
+        assert_eq!(r.next_line(), Some(Ok(String::from("LIST"))));
+        assert_eq!(r.next_line(), None);
+        assert!(r.finish().is_err());
+        r.extend(b"ST\n");
+        r.next_line();
+        assert!(r.finish().is_ok());
+    }
+    #[test]
+    fn take_buffered() {
+        let mut r = LineReader::new();
+        r.extend(b"CONNECT web fox\nGET / HTTP/1.0\r\n");
+        assert_eq!(r.next_line(), Some(Ok(String::from("CONNECT web fox"))));
+        assert_eq!(b"GET / HTTP/1.0\r\n".to_vec(), r.take_buffered());
+        assert!(r.is_empty());
+        assert_eq!(r.next_line(), None);
+    }
+}
@@ -0,0 +1,528 @@
+use crate::error::error::PortmanError;
+use crate::portpool::ports::{PortRequest, Protocol};
+use std::time::Duration;
+
+// Contains the decoding of client requests.
+// A request is a line of words separated by spaces or tabs.
+// A word can be double quoted so that it can contain whitespace,
+// and a backslash makes the character that follows it literal, both
+// inside and outside of quotes, e.g. these are the same word:
+//
+//     "my service"      my\ service
+//
+// Service and user names are then validated (see validate_name) so
+// that nothing malformed reaches the responder.
+
+///
+/// The longest service or user name a client may supply.
+///
+pub const MAX_NAME_LENGTH: usize = 64;
+
+///
+/// The longest reclaim or transfer token a client may supply.  Tokens are
+/// letters and digits.
+///
+pub const MAX_TOKEN_LENGTH: usize = 64;
+
+// Every decoding failure is an invalid request:
+
+fn invalid(msg: impl Into<String>) -> PortmanError {
+    PortmanError::Invalid(msg.into())
+}
+
+///
+/// Allocation
+///    What a GIMME request asks for.
+///
+#[derive(Debug, PartialEq)]
+pub struct Allocation {
+    pub service_name: String,
+    pub user_name: String,
+    pub request: PortRequest,
+    pub protocol: Protocol,
+    pub wait: Option<Duration>,
+}
+
+///
+/// ClientRequest
+///    The requests a client can make.
+///
+#[derive(Debug, PartialEq)]
+pub enum ClientRequest {
+    Gimme(Allocation),
+    Find {
+        service_name: String,
+        user_name: String,
+    },
+    List,
+    ListProtocols,
+    ListAll,
+    FindAnyHost {
+        service_name: String,
+        user_name: String,
+    },
+    Health,
+    Terminate,
+    Connect {
+        service_name: String,
+        user_name: String,
+    },
+    Reclaim {
+        token: String,
+    },
+    Adopt {
+        port: u16,
+        token: String,
+    },
+}
+
+///
+/// Split a request line into words, honoring quotes and backslash escapes.
+/// The result is the words or a description of why the line can't be split.
+///
+pub fn tokenize(line: &str) -> Result<Vec<String>, PortmanError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false; // Distinguishes "" from no word at all.
+    let mut in_quotes = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => {
+                    word.push(escaped);
+                    in_word = true;
+                }
+                None => return Err(invalid("Incomplete escape at the end of the request")),
+            },
+            '"' => {
+                in_quotes = !in_quotes;
+                in_word = true;
+            }
+            ' ' | '\t' if !in_quotes => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            _ => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_quotes {
+        return Err(invalid("Unterminated quoted string"));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// Characters other than letters and digits that may appear in a name.
+
+const NAME_PUNCTUATION: &str = " ._-:@+/";
+
+///
+/// Check that 'name' is an acceptable service or user name.  Names are
+/// 1 to MAX_NAME_LENGTH characters of ASCII letters, digits, spaces and
+/// the punctuation ._-:@+/ and may not begin or end with a space.
+/// 'what' names the field (e.g. "service name") in the error message.
+///
+pub fn validate_name(what: &str, name: &str) -> Result<(), PortmanError> {
+    if name.is_empty() {
+        return Err(invalid(format!("The {} is empty", what)));
+    }
+    let length = name.chars().count();
+    if length > MAX_NAME_LENGTH {
+        return Err(invalid(format!(
+            "The {} is {} characters long; the limit is {}",
+            what, length, MAX_NAME_LENGTH
+        )));
+    }
+    for (i, c) in name.chars().enumerate() {
+        if !(c.is_ascii_alphanumeric() || NAME_PUNCTUATION.contains(c)) {
+            return Err(invalid(format!(
+                "The {} contains the invalid character '{}' at position {}",
+                what,
+                c.escape_default(),
+                i + 1
+            )));
+        }
+    }
+    if name.starts_with(' ') || name.ends_with(' ') {
+        return Err(invalid(format!("The {} begins or ends with a space", what)));
+    }
+    Ok(())
+}
+
+///
+/// Render a name so that tokenize gives it back as a single word.  Names
+/// that need it are double quoted with '"' and '\' escaped.
+///
+pub fn quote(name: &str) -> String {
+    let plain = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| !c.is_whitespace() && c != '"' && c != '\\');
+    if plain {
+        String::from(name)
+    } else {
+        let mut result = String::from("\"");
+        for c in name.chars() {
+            if c == '"' || c == '\\' {
+                result.push('\\');
+            }
+            result.push(c);
+        }
+        result.push('"');
+        result
+    }
+}
+
+// Pull the validated service and user names from a request's words.
+
+fn names(keyword: &str, words: &[String]) -> Result<(String, String), PortmanError> {
+    if words.len() < 3 {
+        return Err(invalid(format!("{} requires a service name and a user name", keyword)));
+    }
+    validate_name("service name", &words[1])?;
+    validate_name("user name", &words[2])?;
+    Ok((words[1].clone(), words[2].clone()))
+}
+
+// Decode the GIMME request.  The service and user names can be
+// followed by the options:
+//
+//   PORT n   - allocate port n.
+//   BLOCK k  - allocate k consecutive ports.
+//   WAIT s   - wait up to s seconds for the port(s) to be free.
+//   UDP      - allocate UDP rather than TCP port(s).
+//
+// PORT and BLOCK are mutually exclusive.
+
+fn decode_gimme(words: &[String]) -> Result<ClientRequest, PortmanError> {
+    let (service_name, user_name) = names("GIMME", words)?;
+    let mut request = PortRequest::Any;
+    let mut protocol = Protocol::Tcp;
+    let mut wait = None;
+    let mut options = words[3..].iter();
+    while let Some(option) = options.next() {
+        if option == "UDP" {
+            if protocol == Protocol::Udp {
+                return Err(invalid("UDP was given more than once"));
+            }
+            protocol = Protocol::Udp;
+            continue;
+        }
+        if option != "PORT" && option != "BLOCK" && option != "WAIT" {
+            return Err(invalid(format!("Unknown GIMME option '{}'", option.escape_default())));
+        }
+        let value = match options.next() {
+            Some(value) => value,
+            None => return Err(invalid(format!("{} requires a value", option))),
+        };
+        match option.as_str() {
+            "WAIT" => {
+                if wait.is_some() {
+                    return Err(invalid("WAIT was given more than once"));
+                }
+                match value.parse::<u32>() {
+                    Ok(seconds) => wait = Some(Duration::from_secs(u64::from(seconds))),
+                    Err(_) => {
+                        return Err(invalid(format!(
+                            "Invalid WAIT time '{}'; it must be a number of seconds",
+                            value.escape_default()
+                        )))
+                    }
+                }
+            }
+            _ => {
+                if request != PortRequest::Any {
+                    return Err(invalid("Only one of PORT or BLOCK can be given"));
+                }
+                let number = match value.parse::<u16>() {
+                    Ok(number) => number,
+                    Err(_) => {
+                        return Err(invalid(format!(
+                            "Invalid {} value '{}'",
+                            option,
+                            value.escape_default()
+                        )))
+                    }
+                };
+                request = if option == "PORT" {
+                    PortRequest::Specific(number)
+                } else if number == 0 {
+                    return Err(invalid("BLOCK must be at least 1"));
+                } else {
+                    PortRequest::Block(number)
+                };
+            }
+        }
+    }
+    Ok(ClientRequest::Gimme(Allocation {
+        service_name,
+        user_name,
+        request,
+        protocol,
+        wait,
+    }))
+}
+
+// A reclaim or transfer token, which we never made if it's not letters
+// and digits.
+
+fn token(what: &str, word: &str) -> Result<String, PortmanError> {
+    if word.is_empty()
+        || word.len() > MAX_TOKEN_LENGTH
+        || !word.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return Err(invalid(format!(
+            "Invalid {} token '{}'",
+            what,
+            word.escape_default()
+        )));
+    }
+    Ok(String::from(word))
+}
+
+///
+/// Decode a request line into a ClientRequest.  If the request is not valid,
+/// the error says exactly what was wrong with it.
+///
+pub fn decode_request(request_line: &str) -> Result<ClientRequest, PortmanError> {
+    let words = tokenize(request_line)?;
+    if words.is_empty() {
+        return Err(invalid("Empty request"));
+    }
+    match words[0].as_str() {
+        "GIMME" => decode_gimme(&words),
+        "FIND" => {
+            let (service_name, user_name) = names("FIND", &words)?;
+            match words.get(3).map(String::as_str) {
+                None => Ok(ClientRequest::Find {
+                    service_name,
+                    user_name,
+                }),
+                Some("ANYHOST") if words.len() == 4 => Ok(ClientRequest::FindAnyHost {
+                    service_name,
+                    user_name,
+                }),
+                _ => Err(invalid(
+                    "FIND takes only a service name, a user name and ANYHOST",
+                )),
+            }
+        }
+        "CONNECT" => {
+            let (service_name, user_name) = names("CONNECT", &words)?;
+            if words.len() > 3 {
+                return Err(invalid("CONNECT takes only a service name and a user name"));
+            }
+            Ok(ClientRequest::Connect {
+                service_name,
+                user_name,
+            })
+        }
+        "RECLAIM" if words.len() == 2 => Ok(ClientRequest::Reclaim {
+            token: token("reclaim", &words[1])?,
+        }),
+        "RECLAIM" => Err(invalid("RECLAIM takes only a reclaim token")),
+        "ADOPT" if words.len() == 3 => match words[1].parse::<u16>() {
+            Ok(port) => Ok(ClientRequest::Adopt {
+                port,
+                token: token("transfer", &words[2])?,
+            }),
+            Err(_) => Err(invalid(format!(
+                "Invalid port '{}'",
+                words[1].escape_default()
+            ))),
+        },
+        "ADOPT" => Err(invalid("ADOPT takes a port and its transfer token")),
+        "LIST" if words.len() == 1 => Ok(ClientRequest::List),
+        "LIST" if words.len() == 2 && words[1] == "PROTOCOL" => Ok(ClientRequest::ListProtocols),
+        "LIST" if words.len() == 2 && words[1] == "ALL" => Ok(ClientRequest::ListAll),
+        "HEALTH" if words.len() == 1 => Ok(ClientRequest::Health),
+        "TERMINATE" if words.len() == 1 => Ok(ClientRequest::Terminate),
+        "LIST" => Err(invalid("LIST takes no arguments but PROTOCOL or ALL")),
+        "HEALTH" | "TERMINATE" => {
+            Err(invalid(format!("{} takes no arguments", words[0])))
+        }
+        other => Err(invalid(format!("Unknown request '{}'", other.escape_default()))),
+    }
+}
+//
+// Unit tests:
+//
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gimme(line: &str) -> Allocation {
+        match decode_request(line) {
+            Ok(ClientRequest::Gimme(a)) => a,
+            other => panic!("Not a GIMME: {:?}", other),
+        }
+    }
+
+    // Tokenizer:
+
+    #[test]
+    fn tokenize_1() {
+        assert_eq!(
+            vec!["GIMME", "svc", "fox"],
+            tokenize("  GIMME\tsvc   fox ").unwrap()
+        );
+        assert!(tokenize("").unwrap().is_empty());
+    }
+    #[test]
+    fn tokenize_2() {
+        // Quotes and escapes:
+        assert_eq!(
+            vec!["GIMME", "service 1", "fox"],
+            tokenize("GIMME \"service 1\" fox").unwrap()
+        );
+        assert_eq!(vec!["service 1"], tokenize("service\\ 1").unwrap());
+        assert_eq!(vec!["a\"b\\c"], tokenize("\"a\\\"b\\\\c\"").unwrap());
+        assert_eq!(vec!["", "x"], tokenize("\"\" x").unwrap());
+        assert_eq!(vec!["abcd"], tokenize("ab\"cd\"").unwrap());
+    }
+    #[test]
+    fn tokenize_3() {
+        // Errors:
+        assert!(tokenize("GIMME \"svc fox").is_err());
+        assert!(tokenize("GIMME svc fox\\").is_err());
+    }
+
+    // Name validation:
+
+    #[test]
+    fn names_1() {
+        assert!(validate_name("service name", "service 1").is_ok());
+        assert!(validate_name("service name", "a.b-c_d:e@f+g/h").is_ok());
+        assert!(validate_name("service name", &"x".repeat(MAX_NAME_LENGTH)).is_ok());
+    }
+    #[test]
+    fn names_2() {
+        assert!(validate_name("service name", "").is_err());
+        assert!(validate_name("service name", &"x".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        assert!(validate_name("service name", " leading").is_err());
+        assert!(validate_name("service name", "trailing ").is_err());
+        let msg = validate_name("user name", "fo\u{7}x").unwrap_err().to_string();
+        assert!(msg.contains("user name"));
+        assert!(msg.contains("\\u{7}"));
+        assert!(msg.contains("position 3"));
+        assert!(validate_name("user name", "fox\"").is_err());
+        assert!(validate_name("user name", "f\u{e9}").is_err());
+    }
+
+    // Quoting:
+
+    #[test]
+    fn quote_1() {
+        assert_eq!("svc", quote("svc"));
+        assert_eq!("\"service 1\"", quote("service 1"));
+        assert_eq!("\"\"", quote(""));
+        for name in ["svc", "service 1", "a\"b", "a\\b", ""] {
+            assert_eq!(vec![name], tokenize(&quote(name)).unwrap());
+        }
+    }
+
+    // Requests:
+
+    #[test]
+    fn decode_gimme_1() {
+        let a = gimme("GIMME \"service 1\" fox");
+        assert_eq!("service 1", a.service_name);
+        assert_eq!("fox", a.user_name);
+        assert_eq!(PortRequest::Any, a.request);
+        assert_eq!(Protocol::Tcp, a.protocol);
+        assert_eq!(None, a.wait);
+    }
+    #[test]
+    fn decode_gimme_2() {
+        let a = gimme("GIMME svc fox BLOCK 3 UDP WAIT 10");
+        assert_eq!(PortRequest::Block(3), a.request);
+        assert_eq!(Protocol::Udp, a.protocol);
+        assert_eq!(Some(Duration::from_secs(10)), a.wait);
+        assert_eq!(PortRequest::Specific(31000), gimme("GIMME svc fox PORT 31000").request);
+    }
+    #[test]
+    fn decode_gimme_3() {
+        for bad in [
+            "GIMME",
+            "GIMME svc",
+            "GIMME svc fox PORT",
+            "GIMME svc fox PORT x",
+            "GIMME svc fox PORT 1 BLOCK 2",
+            "GIMME svc fox BLOCK 0",
+            "GIMME svc fox WAIT 1 WAIT 2",
+            "GIMME svc fox UDP UDP",
+            "GIMME svc fox EXTRA",
+            "GIMME \"\" fox",
+            "GIMME svc \"fo\tx\"",
+        ] {
+            assert!(decode_request(bad).is_err(), "{}", bad);
+        }
+    }
+    #[test]
+    fn decode_others() {
+        assert_eq!(Ok(ClientRequest::List), decode_request("LIST"));
+        assert_eq!(Ok(ClientRequest::Health), decode_request("HEALTH"));
+        assert_eq!(Ok(ClientRequest::Terminate), decode_request("TERMINATE"));
+        assert_eq!(
+            Ok(ClientRequest::Find {
+                service_name: String::from("service 1"),
+                user_name: String::from("fox"),
+            }),
+            decode_request("FIND 'service 1' fox".replace('\'', "\"").as_str())
+        );
+        assert!(decode_request("LIST extra").is_err());
+        assert!(decode_request("HEALTH extra").is_err());
+        assert!(decode_request("FIND svc fox extra").is_err());
+        assert_eq!(Ok(ClientRequest::ListAll), decode_request("LIST ALL"));
+        assert_eq!(Ok(ClientRequest::ListProtocols), decode_request("LIST PROTOCOL"));
+        assert!(decode_request("LIST PROTOCOL extra").is_err());
+        assert_eq!(
+            Ok(ClientRequest::FindAnyHost {
+                service_name: String::from("svc"),
+                user_name: String::from("fox"),
+            }),
+            decode_request("FIND svc fox ANYHOST")
+        );
+        assert!(decode_request("LIST ALL extra").is_err());
+        assert!(decode_request("FIND svc fox ANYHOST extra").is_err());
+        assert_eq!(
+            Ok(ClientRequest::Connect {
+                service_name: String::from("webui"),
+                user_name: String::from("fox"),
+            }),
+            decode_request("CONNECT webui fox")
+        );
+        assert!(decode_request("CONNECT webui").is_err());
+        assert!(decode_request("CONNECT webui fox extra").is_err());
+        assert_eq!(
+            Ok(ClientRequest::Reclaim {
+                token: String::from("0f1e2d3c"),
+            }),
+            decode_request("RECLAIM 0f1e2d3c")
+        );
+        assert!(decode_request("RECLAIM").is_err());
+        assert!(decode_request("RECLAIM 0f1e 2d3c").is_err());
+        assert!(decode_request("RECLAIM 0f1e-2d3c").is_err());
+        assert_eq!(
+            Ok(ClientRequest::Adopt {
+                port: 31000,
+                token: String::from("4b5a6978"),
+            }),
+            decode_request("ADOPT 31000 4b5a6978")
+        );
+        assert!(decode_request("ADOPT 31000").is_err());
+        assert!(decode_request("ADOPT port 4b5a6978").is_err());
+        assert!(decode_request("ADOPT 31000 4b5a_6978").is_err());
+        assert!(decode_request("ADOPT 31000 4b5a6978 extra").is_err());
+        assert!(decode_request("").is_err());
+        assert!(decode_request("HELLO").is_err());
+    }
+}
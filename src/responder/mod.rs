@@ -1,3 +1,5 @@
 // contains module definitions that pull in specific files:
 
+#[allow(clippy::module_inception)]
 pub mod responder;
+pub mod state;
@@ -1,41 +1,947 @@
+use super::state::{StateFile, StateWriter};
+use crate::error::error::PortmanError;
+use crate::logging::audit::{Audit, Event, Record};
 use crate::portpool::ports;
-use std::sync::mpsc;
+use crate::{log_error, log_info, log_warn};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// ClientId
+///    Identifies the client connection on whose behalf a request is
+///  made.  The server hands these out; the responder uses them
+///  to find a client's place in the queue of waiting allocations and
+///  to know which client owns each allocated port.
+///
+pub type ClientId = u64;
+
+///
+/// How long the request functions below wait for the responder to reply
+/// before reporting an error rather than hanging.
+///
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// Notify
+///    A function the responder calls to say it has sent a reply, e.g. to
+///  wake an event loop that polls for replies rather than waiting for them.
+///
+pub type Notify = Arc<dyn Fn() + Send + Sync>;
+
+// If the responder fails more than MAX_FAILURES times in FAILURE_WINDOW
+// something is badly wrong and it gives up rather than fail forever.
+
+const MAX_FAILURES: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Holder
+///    Describes the client that will hold an allocation:  its id, the
+///  uid of the process behind the connection and its address, if known
+///  (for the audit log), how to close that connection should another client take the allocation
+///  over (see CollisionPolicy) and how to tell the client that the reply
+///  to its allocation request has been sent.  The last is needed by
+///  servers that don't block waiting for replies to queued requests.
+///  A holder given a reclaim token has its allocations saved in the state
+///  file, if there is one, so that they can be reclaimed with the token.
+///  The transfer token of an allocation lets another client adopt it.
+///
+#[derive(Clone)]
+pub struct Holder {
+    client: ClientId,
+    uid: Option<u32>,
+    peer: Option<SocketAddr>,
+    token: Option<String>,
+    transfer: Option<String>,
+    disconnect: Arc<dyn Fn() + Send + Sync>,
+    notify: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl Holder {
+    ///
+    /// A holder for 'client' whose uid is not known and whose connection
+    /// can't be closed by the responder.
+    ///
+    pub fn new(client: ClientId) -> Holder {
+        Holder {
+            client,
+            uid: None,
+            peer: None,
+            token: None,
+            transfer: None,
+            disconnect: Arc::new(|| {}),
+            notify: Arc::new(|| {}),
+        }
+    }
+    ///
+    /// Set the uid of the process making the request.
+    ///
+    pub fn with_uid(mut self, uid: Option<u32>) -> Holder {
+        self.uid = uid;
+        self
+    }
+    ///
+    /// Set the address of the holder's end of its connection.
+    ///
+    pub fn with_peer(mut self, peer: Option<SocketAddr>) -> Holder {
+        self.peer = peer;
+        self
+    }
+    ///
+    /// Set the token with which the holder's allocations can be reclaimed.
+    ///
+    pub fn with_token(mut self, token: Option<String>) -> Holder {
+        self.token = token;
+        self
+    }
+    ///
+    /// Set the token with which another client can adopt the allocation
+    /// made for the holder.
+    ///
+    pub fn with_transfer(mut self, transfer: Option<String>) -> Holder {
+        self.transfer = transfer;
+        self
+    }
+    ///
+    /// Set the function that closes the holder's connection.
+    ///
+    pub fn with_disconnect<F>(mut self, disconnect: F) -> Holder
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.disconnect = Arc::new(disconnect);
+        self
+    }
+    ///
+    /// Set the function called after the reply to the holder's allocation
+    /// request has been sent.
+    ///
+    pub fn with_notify<F>(mut self, notify: F) -> Holder
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.notify = Arc::new(notify);
+        self
+    }
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+    pub fn transfer(&self) -> Option<&str> {
+        self.transfer.as_deref()
+    }
+}
+
+/// CollisionPolicy
+///    What to do when a service/user pair asks for a port while that
+///  name is already advertised for the same protocol:
+///
+///  *   Reject          - Fail the request (the default).
+///  *   Uniquify        - Advertise the new port under a unique variant of the
+///      service name, as the Tcl port manager did.
+///  *   Takeover        - The new request takes over the name: the previous
+///      holder's connection is closed and its port is reused.  This only
+///      happens if the new request can then be granted at once.
+///  *   TakeoverSameUid - As Takeover but only if both requests come from the
+///      same uid.  Otherwise the request is rejected.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    Reject,
+    Uniquify,
+    Takeover,
+    TakeoverSameUid,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = PortmanError;
+    fn from_str(s: &str) -> Result<CollisionPolicy, PortmanError> {
+        match s {
+            "reject" => Ok(CollisionPolicy::Reject),
+            "uniquify" => Ok(CollisionPolicy::Uniquify),
+            "takeover" => Ok(CollisionPolicy::Takeover),
+            "takeover-same-uid" => Ok(CollisionPolicy::TakeoverSameUid),
+            _ => Err(PortmanError::Invalid(format!(
+                "Invalid collision policy: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CollisionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CollisionPolicy::Reject => "reject",
+            CollisionPolicy::Uniquify => "uniquify",
+            CollisionPolicy::Takeover => "takeover",
+            CollisionPolicy::TakeoverSameUid => "takeover-same-uid",
+        };
+        write!(f, "{}", name)
+    }
+}
 
 /// ReplyMessage
 ///    Each RequestMessage has  corresponding reply message type
 ///    that's sent along the reply channel that's supplied  in
 ///    the message request (if provided).
 ///    The actual message sent to a channels is Result where Ok contains
-///    a reply message and Err contains an error message string.
+///    a reply message and Err contains the PortmanError that says what failed.
 ///    Note that FreePort requests don't need a reply.
 ///
 pub enum ReplyMessage {
-    AllocatePort(u16),
-    ListAllocations(Vec<ports::UsedPort>),
+    AllocatePort(Vec<u16>),
+    Adopt { from: ClientId, ports: Vec<u16> },
+    CancelWait,
+    ListAllocations(ports::Snapshot),
+    Health(Health),
 }
 
-type Reply = Result<ReplyMessage, String>;
+///
+/// Health
+///    What the responder reports about itself when asked if it's alive:
+///  the number of times it has been restarted after failing, the number of
+///  ports allocated and available, how many of those allocated are waiting
+///  to be reclaimed and the number of queued requests.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    pub restarts: u32,
+    pub allocated: usize,
+    pub available: usize,
+    pub unclaimed: usize,
+    pub waiting: usize,
+}
+
+pub type Reply = Result<ReplyMessage, PortmanError>;
 
 /// RequestMessage
 ///    This enum defines the set of messages that can be sent
-///  to us, the responder to perform operations.  There are three
-///  operations currently provided:
+///  to us, the responder to perform operations.  The operations
+///  currently provided are:
 ///
-///  *   AllocatePort - allocates a new port.
+///  *   AllocatePort - allocates the port(s) described by *request* to *holder*.
+///      If *wait* is provided and the ports are not free, the request is queued
+///      until ports are freed or the wait time expires.
+///  *   CancelWait   - removes a client's queued allocation request.  The
+///      reply is sent once the request has been removed.
+///  *   Leave        - a client is going away:  removes its queued allocation
+///      request and frees all of the ports it holds, including any granted
+///      to it whose reply it hasn't read.  There's no reply.
 ///  *   FreePort     - frees a port that's been allocated.
+///  *   FreePorts    - frees the ports of a client, e.g. when its connection
+///      closes.  Ports that no longer belong to the client are left alone.
 ///  *   ListAllocations - Provides a list of all allocations:
+///  *   Health       - Replies with the responder's Health.
+///  *   RestorePorts - gives *holder* the allocations *ports* made by an
+///      earlier server process.  All of them are restored or none are.
+///  *   Reclaim      - gives *holder* the allocations saved in the state file
+///      with *token* that haven't been reclaimed yet.  The reply lists their
+///      ports.
+///  *   Adopt        - gives *holder* the allocation that includes *port*, all
+///      of its ports at once, if *transfer* is its transfer token.  The ports
+///      stay allocated throughout.  The reply says which client held them.
+///  *   Notify       - handles *request* and then calls *notify*.
 ///
 pub enum RequestMessage {
     AllocatePort {
         service_name: String,
         user_name: String,
+        request: ports::PortRequest,
+        protocol: ports::Protocol,
+        holder: Holder,
+        wait: Option<Duration>,
         reply_chan: mpsc::Sender<Reply>,
     },
+    CancelWait {
+        client: ClientId,
+        reply_chan: mpsc::Sender<Reply>,
+    },
+    Leave {
+        client: ClientId,
+    },
     FreePort(u16),
+    FreePorts {
+        client: ClientId,
+        ports: Vec<u16>,
+    },
     ListAllocations(mpsc::Sender<Reply>),
+    Health(mpsc::Sender<Reply>),
+    RestorePorts {
+        ports: Vec<ports::UsedPort>,
+        holder: Holder,
+        reply_chan: mpsc::Sender<Reply>,
+    },
+    Reclaim {
+        token: String,
+        holder: Holder,
+        reply_chan: mpsc::Sender<Reply>,
+    },
+    Adopt {
+        port: u16,
+        transfer: String,
+        holder: Holder,
+        reply_chan: mpsc::Sender<Reply>,
+    },
+    Notify {
+        request: Box<RequestMessage>,
+        notify: Notify,
+    },
     Terminate,
 }
 
+// An allocation request being worked on.
+
+struct Pending {
+    service_name: String,
+    user_name: String,
+    request: ports::PortRequest,
+    protocol: ports::Protocol,
+    holder: Holder,
+    reply_chan: mpsc::Sender<Reply>,
+}
+
+// What the collision policy makes of a request:  the service name to
+// advertise, the request to allocate and, for a takeover, the previous
+// holder and the ports that would be taken from it.
+
+struct Resolution {
+    service_name: String,
+    request: ports::PortRequest,
+    takeover: Option<(Holder, Vec<u16>)>,
+}
+
+// A queued allocation request that's waiting for a free port and what
+// the collision policy made of it when it was queued.
+
+struct Waiter {
+    pending: Pending,
+    resolution: Resolution,
+    deadline: Instant,
+}
+
+// The responder's state: the pool, who owns each allocated port,
+// the queue of waiting requests and where grants, releases and rejected
+// requests are recorded.  With a state file there are also the saved
+// allocations that haven't been reclaimed (they're allocated in the pool
+// but have no owner) and until when they may be.
+
+struct Responder {
+    pool: ports::PortPool,
+    policy: CollisionPolicy,
+    owners: HashMap<u16, Holder>,
+    waiters: VecDeque<Waiter>,
+    restarts: u32,
+    audit: Audit,
+    state: Option<StateWriter>,
+    unclaimed: HashMap<String, Vec<ports::UsedPort>>,
+    reclaim_deadline: Option<Instant>,
+    changed: bool, // The allocations changed since the state file was saved.
+}
+
+impl Responder {
+    fn new(pool: ports::PortPool, policy: CollisionPolicy, audit: Audit) -> Responder {
+        Responder {
+            pool,
+            policy,
+            owners: HashMap::new(),
+            waiters: VecDeque::new(),
+            restarts: 0,
+            audit,
+            state: None,
+            unclaimed: HashMap::new(),
+            reclaim_deadline: None,
+            changed: false,
+        }
+    }
+    // Fail a request, recording why in the audit log.
+    //
+    fn reject(&self, p: &Pending, error: PortmanError) {
+        self.audit.record(
+            &Record::rejected(&error)
+                .with_names(&p.service_name, &p.user_name, p.protocol)
+                .with_peer(p.holder.peer)
+                .with_client(p.holder.client),
+        );
+        let _ = p.reply_chan.send(Err(error));
+        (p.holder.notify)();
+    }
+    // Free a port, recording who held it and why it was freed.
+    //
+    fn release(&mut self, port: u16, reason: &str) {
+        if let Some(used) = self.pool.allocation(port) {
+            let mut record = Record::new(Event::Release, reason).with_port(used);
+            if let Some(holder) = self.owners.get(&port) {
+                record = record.with_peer(holder.peer).with_client(holder.client);
+            }
+            self.audit.record(&record);
+        }
+        let _ = self.pool.free(port);
+        self.owners.remove(&port);
+        for unclaimed in self.unclaimed.values_mut() {
+            unclaimed.retain(|u| u.port() != port);
+        }
+        self.changed = true;
+    }
+    // Apply the collision policy to a request.  Nothing is changed:  a
+    // takeover is only described, with the request rewritten to reuse the
+    // previous holder's port.  See grant.
+    //
+    fn resolve_collision(&self, p: &Pending) -> Result<Resolution, PortmanError> {
+        let resolution = |service_name: String| Resolution {
+            service_name,
+            request: p.request,
+            takeover: None,
+        };
+        if !self.pool.in_use(&p.service_name, &p.user_name, p.protocol) {
+            return Ok(resolution(p.service_name.clone()));
+        }
+        let duplicate = Err(ports::duplicate());
+        match self.policy {
+            CollisionPolicy::Reject => duplicate,
+            CollisionPolicy::Uniquify => Ok(resolution(self.pool.unique_name(
+                &p.service_name,
+                &p.user_name,
+                p.protocol,
+            )?)),
+            CollisionPolicy::Takeover | CollisionPolicy::TakeoverSameUid => {
+                let taken = self
+                    .pool
+                    .ports_for(&p.service_name, &p.user_name, p.protocol);
+                let previous = match self.owners.get(&taken[0]) {
+                    Some(h) => h.clone(),
+                    None => return duplicate,
+                };
+                if previous.client == p.holder.client {
+                    return duplicate; // Can't take over from ourself.
+                }
+                if self.policy == CollisionPolicy::TakeoverSameUid
+                    && (p.holder.uid.is_none() || p.holder.uid != previous.uid)
+                {
+                    return Err(PortmanError::Denied(String::from(
+                        "Duplicate port allocation attempted by a different uid",
+                    )));
+                }
+                let request = match p.request {
+                    ports::PortRequest::Any => ports::PortRequest::Specific(taken[0]),
+                    other => other,
+                };
+                Ok(Resolution {
+                    service_name: p.service_name.clone(),
+                    request,
+                    takeover: Some((previous, taken)),
+                })
+            }
+        }
+    }
+    // Grant a resolved request.  A takeover only goes ahead if the request
+    // can then be granted at once, in which case the previous holder's ports
+    // are released, it's disconnected and the ports allocated in one step.
+    // Otherwise the request fails and the previous holder keeps its ports.
+    //
+    fn grant(&mut self, p: &Pending, resolution: Resolution) {
+        if let Some((previous, taken)) = &resolution.takeover {
+            if !self
+                .pool
+                .satisfiable_after(resolution.request, p.protocol, taken)
+            {
+                let error = match resolution.request {
+                    ports::PortRequest::Specific(port) => PortmanError::Unavailable(format!(
+                        "Port {} is not free so nothing was taken over",
+                        port
+                    )),
+                    _ => PortmanError::PoolExhausted(String::from(
+                        "The ports asked for are not free so nothing was taken over",
+                    )),
+                };
+                self.reject(p, error);
+                return;
+            }
+            let reason = format!("taken over by conn {}", p.holder.client);
+            for port in taken {
+                self.release(*port, &reason);
+            }
+            (previous.disconnect)();
+        }
+        self.allocate_and_reply(p, &resolution.service_name, resolution.request);
+    }
+    // Allocate a request's ports and reply with them.  If the requester has
+    // gone away the ports go right back to the pool.
+    //
+    fn allocate_and_reply(&mut self, p: &Pending, service_name: &str, request: ports::PortRequest) {
+        match self
+            .pool
+            .allocate_request(request, p.protocol, service_name, &p.user_name)
+        {
+            Ok(allocs) => {
+                for alloc in &allocs {
+                    self.owners.insert(alloc.port(), p.holder.clone());
+                    self.audit.record(
+                        &Record::new(Event::Grant, "allocated")
+                            .with_port(alloc)
+                            .with_peer(p.holder.peer)
+                            .with_client(p.holder.client),
+                    );
+                }
+                self.changed = true;
+                self.save(); // Queued before the holder has its token.
+                let allocated: Vec<u16> = allocs.iter().map(|a| a.port()).collect();
+                if let Err(mpsc::SendError(Ok(ReplyMessage::AllocatePort(unwanted)))) =
+                    p.reply_chan.send(Ok(ReplyMessage::AllocatePort(allocated)))
+                {
+                    for port in unwanted {
+                        self.release(port, "requester went away");
+                    }
+                }
+                (p.holder.notify)();
+            }
+            Err(msg) => self.reject(p, msg),
+        }
+    }
+    // Handle a new allocation request:  Only requests that fail for want
+    // of free ports and could be satisfied later are queued.  A takeover
+    // is never queued; it's granted now or not at all.
+    //
+    fn allocate(&mut self, p: Pending, wait: Option<Duration>) {
+        let resolution = match self.resolve_collision(&p) {
+            Ok(resolution) => resolution,
+            Err(msg) => {
+                self.reject(&p, msg);
+                return;
+            }
+        };
+        let request = resolution.request;
+        let queue = resolution.takeover.is_none()
+            && !self.pool.satisfiable(request, p.protocol)
+            && self.pool.possible(request, p.protocol);
+        match wait {
+            Some(wait) if queue => self.waiters.push_back(Waiter {
+                pending: p,
+                resolution,
+                deadline: Instant::now() + wait,
+            }),
+            _ => self.grant(&p, resolution),
+        }
+    }
+    // Free the ports 'client' still owns from 'ports'.
+    //
+    fn free_owned(&mut self, client: ClientId, ports: Vec<u16>) {
+        for port in ports {
+            if self.owners.get(&port).map(|h| h.client) == Some(client) {
+                self.release(port, "released");
+            }
+        }
+    }
+    // Give 'holder' allocations made by an earlier server process.  If
+    // any of the ports isn't free none are restored.
+    //
+    fn restore(
+        &mut self,
+        ports: Vec<ports::UsedPort>,
+        holder: Holder,
+        reply_chan: mpsc::Sender<Reply>,
+    ) {
+        let mut restored = Vec::new();
+        for used in &ports {
+            if let Err(msg) = self.pool.restore(used) {
+                for port in &restored {
+                    let _ = self.pool.free(*port);
+                }
+                let _ = reply_chan.send(Err(msg));
+                return;
+            }
+            restored.push(used.port());
+        }
+        for used in &ports {
+            self.owners.insert(used.port(), holder.clone());
+            self.audit.record(
+                &Record::new(Event::Grant, "restored")
+                    .with_port(used)
+                    .with_peer(holder.peer)
+                    .with_client(holder.client),
+            );
+        }
+        self.changed = true;
+        let _ = reply_chan.send(Ok(ReplyMessage::AllocatePort(restored)));
+    }
+    // Give 'holder' the unclaimed allocations saved with 'token'.
+    //
+    fn reclaim(&mut self, token: &str, holder: Holder, reply_chan: mpsc::Sender<Reply>) {
+        let reclaimed = match self.unclaimed.remove(token) {
+            Some(reclaimed) if !reclaimed.is_empty() => reclaimed,
+            _ => {
+                let _ = reply_chan.send(Err(PortmanError::Denied(String::from(
+                    "Nothing can be reclaimed with that token",
+                ))));
+                return;
+            }
+        };
+        for used in &reclaimed {
+            self.owners.insert(used.port(), holder.clone());
+            self.audit.record(
+                &Record::new(Event::Transfer, "reclaimed")
+                    .with_port(used)
+                    .with_peer(holder.peer)
+                    .with_client(holder.client),
+            );
+        }
+        self.changed = true;
+        self.save();
+        let ports = reclaimed.iter().map(|u| u.port()).collect();
+        let _ = reply_chan.send(Ok(ReplyMessage::AllocatePort(ports)));
+    }
+    // Move the allocation that 'port' is part of, that is the ports its
+    // holder was granted with the same transfer token, to 'holder'.  They're
+    // never freed along the way so no one else can be given them.  If the
+    // new holder has gone away by the time we reply they're released.
+    //
+    fn adopt(
+        &mut self,
+        port: u16,
+        transfer: &str,
+        holder: Holder,
+        reply_chan: mpsc::Sender<Reply>,
+    ) {
+        let from = match self.owners.get(&port) {
+            Some(h) if h.transfer.as_deref() == Some(transfer) => h.client,
+            _ => {
+                let _ = reply_chan.send(Err(PortmanError::Denied(format!(
+                    "Port {} can't be adopted with that token",
+                    port
+                ))));
+                return;
+            }
+        };
+        if from == holder.client {
+            let _ = reply_chan.send(Err(PortmanError::Invalid(String::from(
+                "The allocation is already held on this connection",
+            ))));
+            return;
+        }
+        let mut ports: Vec<u16> = self
+            .owners
+            .iter()
+            .filter(|(_, h)| h.client == from && h.transfer.as_deref() == Some(transfer))
+            .map(|(port, _)| *port)
+            .collect();
+        ports.sort_unstable();
+        let reason = format!("adopted from conn {}", from);
+        for port in &ports {
+            self.owners.insert(*port, holder.clone());
+            if let Some(used) = self.pool.allocation(*port) {
+                self.audit.record(
+                    &Record::new(Event::Transfer, reason.as_str())
+                        .with_port(used)
+                        .with_peer(holder.peer)
+                        .with_client(holder.client),
+                );
+            }
+        }
+        self.changed = true;
+        self.save();
+        if let Err(mpsc::SendError(Ok(ReplyMessage::Adopt { ports, .. }))) =
+            reply_chan.send(Ok(ReplyMessage::Adopt { from, ports }))
+        {
+            for port in ports {
+                self.release(port, "adopter went away");
+            }
+        }
+    }
+    // Once the grace period is over, return the allocations that weren't
+    // reclaimed to the pool.
+    //
+    fn expire_reclaims(&mut self) {
+        if self.reclaim_deadline.is_some_and(|d| d <= Instant::now()) {
+            self.reclaim_deadline = None;
+            let unclaimed: Vec<u16> = self
+                .unclaimed
+                .drain()
+                .flat_map(|(_, u)| u)
+                .map(|u| u.port())
+                .collect();
+            for port in unclaimed {
+                self.release(port, "not reclaimed");
+            }
+            self.grant_waiters();
+        }
+    }
+    // Have the state file rewritten if the allocations have changed.  The
+    // writer's thread does the writing so we don't wait for the disk;
+    // failure is logged and we try again after the next change.
+    //
+    fn save(&mut self) {
+        let state = match &self.state {
+            Some(state) if self.changed => state,
+            _ => return,
+        };
+        self.changed = false;
+        let mut held: Vec<(String, ports::UsedPort)> = Vec::new();
+        let usage = self.pool.snapshot();
+        for used in usage.iter() {
+            if let Some(token) = self.owners.get(&used.port()).and_then(Holder::token) {
+                held.push((String::from(token), used.clone()));
+            }
+        }
+        for (token, unclaimed) in &self.unclaimed {
+            held.extend(unclaimed.iter().map(|u| (token.clone(), u.clone())));
+        }
+        held.sort_by_key(|(_, used)| used.port());
+        state.save(held);
+    }
+    // Hand free ports to waiters in the order they arrived.  Waiters
+    // whose request can't yet be satisfied (e.g. a block that doesn't fit)
+    // keep their place in the queue.  Each keeps the resolution it was
+    // queued with, so it never becomes a takeover; see requeued_name.
+    //
+    fn grant_waiters(&mut self) {
+        let mut i = 0;
+        while i < self.waiters.len() && self.pool.available() > 0 {
+            let p = &self.waiters[i].pending;
+            if self.pool.satisfiable(p.request, p.protocol) {
+                let waiter = self
+                    .waiters
+                    .remove(i)
+                    .expect("Bug waiter index out of range");
+                let p = waiter.pending;
+                match self.requeued_name(&p, waiter.resolution) {
+                    Ok(resolution) => self.grant(&p, resolution),
+                    Err(msg) => self.reject(&p, msg),
+                }
+            } else {
+                i += 1;
+            }
+        }
+    }
+    // The resolution of a waiter about to be granted.  If its name was
+    // taken while it waited it's made unique again or, under the other
+    // policies, fails as a duplicate.
+    //
+    fn requeued_name(
+        &self,
+        p: &Pending,
+        resolution: Resolution,
+    ) -> Result<Resolution, PortmanError> {
+        if !self
+            .pool
+            .in_use(&resolution.service_name, &p.user_name, p.protocol)
+        {
+            return Ok(resolution);
+        }
+        match self.policy {
+            CollisionPolicy::Uniquify => Ok(Resolution {
+                service_name: self
+                    .pool
+                    .unique_name(&p.service_name, &p.user_name, p.protocol)?,
+                ..resolution
+            }),
+            _ => Err(ports::duplicate()),
+        }
+    }
+    // Reject the waiters whose wait time has run out.
+    //
+    fn expire_waiters(&mut self) {
+        let now = Instant::now();
+        let (expired, waiting) = std::mem::take(&mut self.waiters)
+            .into_iter()
+            .partition(|w| w.deadline <= now);
+        self.waiters = waiting;
+        for w in expired {
+            let error = PortmanError::TimedOut(String::from("Timed out waiting for a free port"));
+            self.reject(&w.pending, error);
+        }
+    }
+    // Process requests until told to terminate or there's nobody left
+    // to make them.
+    //
+    fn serve(&mut self, request_chan: &mpsc::Receiver<RequestMessage>) {
+        loop {
+            self.save();
+
+            // With waiters queued, or allocations waiting to be reclaimed,
+            // we can only block until the earliest of their deadlines:
+
+            let next_deadline = self
+                .waiters
+                .iter()
+                .map(|w| w.deadline)
+                .chain(self.reclaim_deadline)
+                .min();
+            let request = if let Some(deadline) = next_deadline {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match request_chan.recv_timeout(timeout) {
+                    Ok(request) => request,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        self.expire_waiters();
+                        self.expire_reclaims();
+                        continue;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            } else {
+                match request_chan.recv() {
+                    Ok(request) => request,
+                    Err(_) => return, // Nobody left to make requests.
+                }
+            };
+            if !self.handle(request) {
+                return;
+            }
+            self.expire_waiters();
+            self.expire_reclaims();
+        }
+    }
+    // Handle a request.  Returns false if we're to stop.
+    //
+    fn handle(&mut self, request: RequestMessage) -> bool {
+        match request {
+            RequestMessage::AllocatePort {
+                service_name,
+                user_name,
+                request,
+                protocol,
+                holder,
+                wait,
+                reply_chan,
+            } => self.allocate(
+                Pending {
+                    service_name,
+                    user_name,
+                    request,
+                    protocol,
+                    holder,
+                    reply_chan,
+                },
+                wait,
+            ),
+            RequestMessage::CancelWait { client, reply_chan } => {
+                self.waiters.retain(|w| w.pending.holder.client != client);
+                let _ = reply_chan.send(Ok(ReplyMessage::CancelWait));
+            }
+            RequestMessage::Leave { client } => {
+                self.waiters.retain(|w| w.pending.holder.client != client);
+                let mut ports: Vec<u16> = self
+                    .owners
+                    .iter()
+                    .filter(|(_, h)| h.client == client)
+                    .map(|(port, _)| *port)
+                    .collect();
+                ports.sort_unstable();
+                self.free_owned(client, ports);
+                self.grant_waiters();
+            }
+            RequestMessage::FreePort(p) => {
+                self.release(p, "released");
+                self.grant_waiters();
+            }
+            RequestMessage::FreePorts { client, ports } => {
+                self.free_owned(client, ports);
+                self.grant_waiters();
+            }
+            RequestMessage::ListAllocations(reply_chan) => {
+                let snapshot = self.pool.snapshot();
+                let _ = reply_chan.send(Ok(ReplyMessage::ListAllocations(snapshot)));
+            }
+            RequestMessage::Health(reply_chan) => {
+                let _ = reply_chan.send(Ok(ReplyMessage::Health(self.health())));
+            }
+            RequestMessage::RestorePorts {
+                ports,
+                holder,
+                reply_chan,
+            } => self.restore(ports, holder, reply_chan),
+            RequestMessage::Reclaim {
+                token,
+                holder,
+                reply_chan,
+            } => self.reclaim(&token, holder, reply_chan),
+            RequestMessage::Adopt {
+                port,
+                transfer,
+                holder,
+                reply_chan,
+            } => {
+                self.adopt(port, &transfer, holder, reply_chan);
+                self.grant_waiters();
+            }
+            RequestMessage::Notify { request, notify } => {
+                let more = self.handle(*request);
+                notify();
+                return more;
+            }
+            RequestMessage::Terminate => {
+                self.save();
+                return false;
+            }
+        }
+        true
+    }
+    fn health(&self) -> Health {
+        let unclaimed = self.unclaimed.values().map(Vec::len).sum::<usize>();
+        Health {
+            restarts: self.restarts,
+            allocated: self.owners.len() + unclaimed,
+            available: self.pool.available(),
+            unclaimed,
+            waiting: self.waiters.len(),
+        }
+    }
+    // Rebuild the pool after a failure, which may have left it inconsistent
+    // with the owners of record, from the ports held by the holders and
+    // those waiting to be reclaimed.  Holders whose ports can't all be
+    // restored are disconnected so that they know to ask again.  Queued
+    // requests keep their place.
+    //
+    fn rebuild(&mut self, initial: &ports::PortPool) {
+        let usage = self.pool.usage();
+        let owners = std::mem::take(&mut self.owners);
+        self.pool = initial.clone();
+        self.changed = true;
+        let pool = &mut self.pool;
+        for unclaimed in self.unclaimed.values_mut() {
+            unclaimed.retain(|u| pool.restore(u).is_ok());
+        }
+        let mut lost: HashMap<ClientId, Holder> = HashMap::new();
+        for used in &usage {
+            if let Some(holder) = owners.get(&used.port()) {
+                if self.pool.restore(used).is_ok() {
+                    self.owners.insert(used.port(), holder.clone());
+                } else {
+                    lost.insert(holder.client, holder.clone());
+                }
+            }
+        }
+        for (port, holder) in &owners {
+            if !self.owners.contains_key(port) {
+                let mut record = Record::new(Event::Release, "lost when the pool was rebuilt")
+                    .with_peer(holder.peer)
+                    .with_client(holder.client);
+                match usage.iter().find(|u| u.port() == *port) {
+                    Some(used) => record = record.with_port(used),
+                    None => record.port = Some(*port),
+                }
+                self.audit.record(&record);
+                lost.insert(holder.client, holder.clone());
+            }
+        }
+        for holder in lost.values() {
+            (holder.disconnect)();
+        }
+        self.grant_waiters();
+    }
+}
+
 ///
 /// responder
 ///    This handles the logic of getting a request, dispatching it
@@ -47,32 +953,138 @@ pub enum RequestMessage {
 ///    *   request_chan - channel over which the requests are received.
 ///
 pub fn responder(base: u16, num: u16, request_chan: mpsc::Receiver<RequestMessage>) {
-    let mut pool = ports::PortPool::new(base, num);
-    loop {
-        let request = request_chan.recv().unwrap();
-        match request {
-            RequestMessage::AllocatePort {
-                service_name,
-                user_name,
-                reply_chan,
-            } => match pool.allocate(&service_name, &user_name) {
-                Ok(alloc) => reply_chan
-                    .send(Ok(ReplyMessage::AllocatePort(alloc.port())))
-                    .unwrap(),
-                Err(msg) => reply_chan.send(Err(msg)).unwrap(),
-            },
-            RequestMessage::FreePort(p) => {
-                let _ = pool.free(p).is_ok(); // We can't really handle errors.
+    responder_with_pool(
+        ports::PortPool::new(base, num),
+        CollisionPolicy::Reject,
+        request_chan,
+    )
+}
+///
+/// responder_with_pool
+///    Same as responder but manages a port pool the caller has already
+///    created, e.g. one with separate TCP and UDP ranges, and applies
+///    *policy* when a service name is already in use.
+///
+///    The responder is supervised:  if processing a request panics, the
+///    panic is caught and logged, the pool is rebuilt from the ports held
+///    by the current holders and requests are processed again.  The
+///    request being processed is lost; its requester sees the reply channel
+///    close.  If failures keep happening the responder gives up and returns
+///    so the caller can shut down rather than accept requests it can't serve.
+///
+pub fn responder_with_pool(
+    pool: ports::PortPool,
+    policy: CollisionPolicy,
+    request_chan: mpsc::Receiver<RequestMessage>,
+) {
+    responder_with_audit(pool, policy, Audit::default(), request_chan)
+}
+///
+/// responder_with_audit
+///    Same as responder_with_pool but records the ports granted and
+///    released and the allocation requests rejected in *audit*.
+///
+pub fn responder_with_audit(
+    pool: ports::PortPool,
+    policy: CollisionPolicy,
+    audit: Audit,
+    request_chan: mpsc::Receiver<RequestMessage>,
+) {
+    let initial = pool.clone();
+    supervise(Responder::new(pool, policy, audit), initial, request_chan)
+}
+///
+/// responder_with_state
+///    Same as responder_with_audit but keeps the allocations of holders
+///    with reclaim tokens in *state*.  The allocations saved there by an
+///    earlier server are allocated again, without holders, and for *grace*
+///    can be reclaimed with their tokens.  Then those that haven't been
+///    are released.  Each is audited as granted when it's allocated again
+///    and as transferred when it's reclaimed.
+///
+pub fn responder_with_state(
+    pool: ports::PortPool,
+    policy: CollisionPolicy,
+    audit: Audit,
+    mut state: StateFile,
+    grace: Duration,
+    request_chan: mpsc::Receiver<RequestMessage>,
+) {
+    let mut responder = Responder::new(pool, policy, audit);
+    let mut pool = responder.pool.clone();
+    for (token, used) in state.take_saved() {
+        match pool.restore(&used) {
+            Ok(()) => {
+                responder
+                    .audit
+                    .record(&Record::new(Event::Grant, "saved before restart").with_port(&used));
+                responder.unclaimed.entry(token).or_default().push(used);
             }
-            RequestMessage::ListAllocations(reply_chan) => {
-                reply_chan
-                    .send(Ok(ReplyMessage::ListAllocations(pool.usage())))
-                    .unwrap();
+            Err(e) => log_warn!("Dropping saved allocation {}: {}", used, e),
+        }
+    }
+    if !responder.unclaimed.is_empty() {
+        log_info!(
+            "{} saved allocations can be reclaimed for {:?}",
+            responder.unclaimed.values().map(Vec::len).sum::<usize>(),
+            grace
+        );
+        responder.reclaim_deadline = Some(Instant::now() + grace);
+    }
+
+    // The pool rebuilt after a failure is the one without the saved
+    // allocations; they're restored from 'unclaimed':
+
+    let initial = std::mem::replace(&mut responder.pool, pool);
+    responder.state = Some(StateWriter::start(state));
+    responder.changed = true;
+    supervise(responder, initial, request_chan)
+}
+// Serve requests, rebuilding the pool from 'initial' should serving fail.
+
+fn supervise(
+    mut state: Responder,
+    initial: ports::PortPool,
+    request_chan: mpsc::Receiver<RequestMessage>,
+) {
+    let mut failures: VecDeque<Instant> = VecDeque::new();
+    loop {
+        match panic::catch_unwind(AssertUnwindSafe(|| state.serve(&request_chan))) {
+            Ok(()) => return,
+            Err(cause) => {
+                let now = Instant::now();
+                failures.retain(|t| now.duration_since(*t) < FAILURE_WINDOW);
+                failures.push_back(now);
+                if failures.len() > MAX_FAILURES {
+                    log_error!(
+                        "Port pool responder failed ({}) {} times in {:?}, giving up",
+                        panic_message(&cause),
+                        failures.len(),
+                        FAILURE_WINDOW
+                    );
+                    return;
+                }
+                log_error!(
+                    "Port pool responder failed ({}), rebuilding the pool",
+                    panic_message(&cause)
+                );
+                state.rebuild(&initial);
+                state.restarts += 1;
             }
-            RequestMessage::Terminate => break,
         }
     }
 }
+// The message a panic was raised with, if it has one.
+
+fn panic_message(cause: &Box<dyn Any + Send>) -> String {
+    if let Some(msg) = cause.downcast_ref::<&str>() {
+        String::from(*msg)
+    } else if let Some(msg) = cause.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown cause")
+    }
+}
 ///
 /// request_port
 ///    Interacts with the service thread to obtain a new port.
@@ -81,55 +1093,284 @@ pub fn responder(base: u16, num: u16, request_chan: mpsc::Receiver<RequestMessag
 ///
 ///   *  service_name   - Name of service to advertise.
 ///   *  user_name      - Name of user advertising service.
+///   *  client         - Id of the client connection making the request.
 ///   *  request        - Sender side of the request channel.
 ///
-///    The return value is a Result<u16, String> decoded from the actual
-/// raw server reply.
+///    The port is a TCP port.  The return value is a Result<u16, PortmanError>
+/// decoded from the actual raw server reply.
 ///
 pub fn request_port(
     service_name: &str,
     user_name: &str,
+    client: ClientId,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<u16, PortmanError> {
+    let ports = request_ports(
+        service_name,
+        user_name,
+        ports::PortRequest::Any,
+        ports::Protocol::Tcp,
+        Holder::new(client),
+        request,
+    )?;
+    Ok(ports[0])
+}
+///
+/// request_ports
+///    Like request_port but allocates the port(s) described by *what*:
+/// any port, a specific port or a block of consecutive ports, for
+/// *protocol* and on behalf of *holder*.  The allocated ports are returned
+/// in ascending order.
+///
+pub fn request_ports(
+    service_name: &str,
+    user_name: &str,
+    what: ports::PortRequest,
+    protocol: ports::Protocol,
+    holder: Holder,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<Vec<u16>, PortmanError> {
+    let reply_receiver = queue_port_request(
+        service_name,
+        user_name,
+        what,
+        protocol,
+        holder,
+        None,
+        request,
+    )?;
+    decode_port_reply(reply_receiver.recv_timeout(REPLY_TIMEOUT)?)
+}
+///
+/// queue_port_request
+///    Sends a port allocation request without waiting for the reply.
+/// If *wait* is supplied and the ports are not free, the responder holds the
+/// request for up to that long, granting it in FIFO order as ports
+/// are freed.  The reply arrives on the returned receiver and can be
+/// decoded with decode_port_reply.  A client that gives up should
+/// call cancel_wait and then check the receiver for a port granted
+/// in the meantime.
+///
+pub fn queue_port_request(
+    service_name: &str,
+    user_name: &str,
+    what: ports::PortRequest,
+    protocol: ports::Protocol,
+    holder: Holder,
+    wait: Option<Duration>,
     request: &mpsc::Sender<RequestMessage>,
-) -> Result<u16, String> {
+) -> Result<mpsc::Receiver<Reply>, PortmanError> {
     let (reply_sender, reply_receiver) = mpsc::channel();
-
-    // Send the request:
-
     request
         .send(RequestMessage::AllocatePort {
             service_name: String::from(service_name),
             user_name: String::from(user_name),
+            request: what,
+            protocol,
+            holder,
+            wait,
             reply_chan: reply_sender,
-        })
-        .unwrap();
-
-    // Get the reply:
-
-    match reply_receiver.recv().unwrap() {
-        Ok(msg) => match msg {
-            ReplyMessage::AllocatePort(port) => Ok(port),
-            _ => Err(String::from("Invalid reply message type")),
+        })?;
+    Ok(reply_receiver)
+}
+///
+/// decode_port_reply
+///    Turns the responder's reply to an allocation request into the
+/// allocated ports or the reason the allocation failed.
+///
+pub fn decode_port_reply(reply: Reply) -> Result<Vec<u16>, PortmanError> {
+    match reply? {
+        ReplyMessage::AllocatePort(ports) => Ok(ports),
+        _ => Err(invalid_reply()),
+    }
+}
+///
+/// queue_request
+///    Sends the request *make* builds around a reply channel without
+/// waiting for the reply, which arrives on the returned receiver.  If
+/// *notify* is given the responder calls it once it has handled the
+/// request.  The decode functions below make sense of the reply.
+///
+pub fn queue_request<F>(
+    make: F,
+    notify: Option<Notify>,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<mpsc::Receiver<Reply>, PortmanError>
+where
+    F: FnOnce(mpsc::Sender<Reply>) -> RequestMessage,
+{
+    let (reply_sender, reply_receiver) = mpsc::channel();
+    let message = make(reply_sender);
+    request.send(match notify {
+        Some(notify) => RequestMessage::Notify {
+            request: Box::new(message),
+            notify,
         },
-        Err(msg) => Err(msg),
+        None => message,
+    })?;
+    Ok(reply_receiver)
+}
+///
+/// decode_adopt_reply
+///    Turns the reply to an Adopt request into the client that held the
+/// allocation and its ports.
+///
+pub fn decode_adopt_reply(reply: Reply) -> Result<(ClientId, Vec<u16>), PortmanError> {
+    match reply? {
+        ReplyMessage::Adopt { from, ports } => Ok((from, ports)),
+        _ => Err(invalid_reply()),
+    }
+}
+///
+/// decode_allocations_reply
+///    Turns the reply to a ListAllocations request into the allocations.
+///
+pub fn decode_allocations_reply(reply: Reply) -> Result<ports::Snapshot, PortmanError> {
+    match reply? {
+        ReplyMessage::ListAllocations(allocs) => Ok(allocs),
+        _ => Err(invalid_reply()),
     }
 }
 ///
+/// decode_health_reply
+///    Turns the reply to a Health request into the responder's Health.
+///
+pub fn decode_health_reply(reply: Reply) -> Result<Health, PortmanError> {
+    match reply? {
+        ReplyMessage::Health(health) => Ok(health),
+        _ => Err(invalid_reply()),
+    }
+}
+///
+/// cancel_wait
+///    Removes a client's queued allocation request.  When this returns
+/// the responder has processed the cancellation so any port granted
+/// before it is already waiting in the request's reply channel.
+///
+pub fn cancel_wait(
+    client: ClientId,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<(), PortmanError> {
+    let receiver = queue_request(
+        |reply_chan| RequestMessage::CancelWait { client, reply_chan },
+        None,
+        request,
+    )?;
+    receiver.recv_timeout(REPLY_TIMEOUT)?.map(|_| ())
+}
+///
+/// leave
+///    Tells the responder that *client* is going away without waiting for
+/// it:  its queued request is withdrawn and every port it holds is freed,
+/// including those granted to it in replies it won't read.
+///
+pub fn leave(client: ClientId, request: &mpsc::Sender<RequestMessage>) -> Result<(), PortmanError> {
+    Ok(request.send(RequestMessage::Leave { client })?)
+}
+///
 /// release_port
 ///     Release an allocated port.
 ///
 /// - port is the port to release and
 /// - request is the sender side of the channel on which we make requests
-///    of the responder.
+///   of the responder.
 ///
 pub fn release_port(
     port: u16,
     request: &mpsc::Sender<RequestMessage>,
-) -> Result<(), mpsc::SendError<RequestMessage>> {
-    request.send(RequestMessage::FreePort(port))
+) -> Result<(), PortmanError> {
+    Ok(request.send(RequestMessage::FreePort(port))?)
+}
+///
+/// release_ports
+///     Release the ports a client holds in one request so that, for example,
+/// a block is returned to the pool as a unit.  Ports that have since been
+/// taken over by another client are not released.
+///
+/// - client is the client releasing the ports.
+/// - ports are the ports to release and
+/// - request is the sender side of the channel on which we make requests
+///   of the responder.
+///
+pub fn release_ports(
+    client: ClientId,
+    ports: Vec<u16>,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<(), PortmanError> {
+    Ok(request.send(RequestMessage::FreePorts { client, ports })?)
+}
+///
+/// restore_ports
+///    Gives *holder* allocations made by an earlier server process, e.g.
+/// the one we took over from, as they were.  Either all of *ports* are
+/// restored or, if any of them isn't free, none are.  The restored ports
+/// are returned.
+///
+pub fn restore_ports(
+    ports: Vec<ports::UsedPort>,
+    holder: Holder,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<Vec<u16>, PortmanError> {
+    let receiver = queue_request(
+        |reply_chan| RequestMessage::RestorePorts {
+            ports,
+            holder,
+            reply_chan,
+        },
+        None,
+        request,
+    )?;
+    decode_port_reply(receiver.recv_timeout(REPLY_TIMEOUT)?)
+}
+///
+/// reclaim_ports
+///    Gives *holder* the allocations saved with *token* by an earlier
+/// server, if the grace period for reclaiming them hasn't ended and
+/// they haven't been reclaimed already.  Their ports are returned.
+///
+pub fn reclaim_ports(
+    token: &str,
+    holder: Holder,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<Vec<u16>, PortmanError> {
+    let receiver = queue_request(
+        |reply_chan| RequestMessage::Reclaim {
+            token: String::from(token),
+            holder,
+            reply_chan,
+        },
+        None,
+        request,
+    )?;
+    decode_port_reply(receiver.recv_timeout(REPLY_TIMEOUT)?)
+}
+///
+/// adopt_ports
+///    Gives *holder* the allocation that includes *port*, provided
+/// *transfer* is its transfer token.  The client that held it and the
+/// allocation's ports are returned.
+///
+pub fn adopt_ports(
+    port: u16,
+    transfer: &str,
+    holder: Holder,
+    request: &mpsc::Sender<RequestMessage>,
+) -> Result<(ClientId, Vec<u16>), PortmanError> {
+    let receiver = queue_request(
+        |reply_chan| RequestMessage::Adopt {
+            port,
+            transfer: String::from(transfer),
+            holder,
+            reply_chan,
+        },
+        None,
+        request,
+    )?;
+    decode_adopt_reply(receiver.recv_timeout(REPLY_TIMEOUT)?)
 }
 /// get_allocations
-///    Returns the vector of allocations (it's up to the caller to decide
-/// how to format them).
+///    Returns a snapshot of the allocations in port order (it's up to the
+/// caller to decide how to format them).
 ///
 /// ### Parameters:
 ///
@@ -137,25 +1378,526 @@ pub fn release_port(
 ///
 ///  ### Returns:
 ///
-///    Result<Vec<UsedPort>, String>
+///    Result<Snapshot, PortmanError>
 pub fn get_allocations(
     request: &mpsc::Sender<RequestMessage>,
-) -> Result<Vec<ports::UsedPort>, String> {
-    let (reply_sender, reply_receiver) = mpsc::channel();
-    request
-        .send(RequestMessage::ListAllocations(reply_sender))
+) -> Result<ports::Snapshot, PortmanError> {
+    let receiver = queue_request(RequestMessage::ListAllocations, None, request)?;
+    decode_allocations_reply(receiver.recv_timeout(REPLY_TIMEOUT)?)
+}
+///
+/// check_health
+///    Asks the responder how it is.  An error means it's not responding.
+///
+pub fn check_health(request: &mpsc::Sender<RequestMessage>) -> Result<Health, PortmanError> {
+    let receiver = queue_request(RequestMessage::Health, None, request)?;
+    decode_health_reply(receiver.recv_timeout(REPLY_TIMEOUT)?)
+}
+// The responder answered with the wrong kind of reply.
+
+fn invalid_reply() -> PortmanError {
+    PortmanError::Internal(String::from("Invalid reply from port manager"))
+}
+//
+// Unit tests:
+//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portpool::ports::{PortRequest, Protocol, UsedPort};
+    use std::thread;
+
+    fn start(num: u16) -> mpsc::Sender<RequestMessage> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || responder(1000, num, receiver));
+        sender
+    }
+    // Queue a request of fox's for TCP ports that waits up to 'wait'.
+
+    fn queue(
+        service: &str,
+        what: PortRequest,
+        holder: Holder,
+        wait: Duration,
+        req: &mpsc::Sender<RequestMessage>,
+    ) -> mpsc::Receiver<Reply> {
+        queue_port_request(service, "fox", what, Protocol::Tcp, holder, Some(wait), req).unwrap()
+    }
+
+    #[test]
+    fn wait_granted_on_free() {
+        let req = start(1);
+        let port = request_port("first", "fox", 0, &req).unwrap();
+        let reply = queue(
+            "second",
+            PortRequest::Any,
+            Holder::new(1),
+            Duration::from_secs(10),
+            &req,
+        );
+        assert!(reply.try_recv().is_err()); // still queued.
+        release_port(port, &req).unwrap();
+        assert_eq!(Ok(vec![port]), decode_port_reply(reply.recv().unwrap()));
+    }
+    #[test]
+    fn wait_fifo() {
+        let req = start(1);
+        let port = request_port("first", "fox", 0, &req).unwrap();
+        let wait = Duration::from_secs(10);
+        let second = queue("second", PortRequest::Any, Holder::new(1), wait, &req);
+        let third = queue("third", PortRequest::Any, Holder::new(2), wait, &req);
+        release_port(port, &req).unwrap();
+        assert_eq!(Ok(vec![port]), decode_port_reply(second.recv().unwrap()));
+        assert!(third.try_recv().is_err());
+    }
+    #[test]
+    fn wait_times_out() {
+        let req = start(1);
+        request_port("first", "fox", 0, &req).unwrap();
+        let reply = queue(
+            "second",
+            PortRequest::Any,
+            Holder::new(1),
+            Duration::from_millis(50),
+            &req,
+        );
+        let error = decode_port_reply(reply.recv().unwrap()).unwrap_err();
+        assert_eq!("E_TIMEOUT", error.code());
+    }
+    #[test]
+    fn wait_cancelled() {
+        let req = start(1);
+        let port = request_port("first", "fox", 0, &req).unwrap();
+        let wait = Duration::from_secs(10);
+        let second = queue("second", PortRequest::Any, Holder::new(1), wait, &req);
+        let third = queue("third", PortRequest::Any, Holder::new(2), wait, &req);
+        cancel_wait(1, &req).unwrap();
+        release_port(port, &req).unwrap();
+        assert_eq!(Ok(vec![port]), decode_port_reply(third.recv().unwrap()));
+        assert!(second.recv().is_err()); // Dropped without a reply.
+    }
+    #[test]
+    fn wait_not_needed() {
+        // With ports free, WAIT requests are granted at once:
+        let req = start(1);
+        let reply = queue(
+            "first",
+            PortRequest::Any,
+            Holder::new(0),
+            Duration::from_secs(10),
+            &req,
+        );
+        assert_eq!(Ok(vec![1000]), decode_port_reply(reply.recv().unwrap()));
+    }
+    #[test]
+    fn block_waits_for_fit() {
+        let req = start(3);
+        request_ports(
+            "first",
+            "fox",
+            PortRequest::Specific(1000),
+            Protocol::Tcp,
+            Holder::new(0),
+            &req,
+        )
         .unwrap();
-    match reply_receiver.recv() {
-        Ok(msg) => match msg {
-            Ok(replyok) => {
-                if let ReplyMessage::ListAllocations(allocs) = replyok {
-                    Ok(allocs)
-                } else {
-                    Err(String::from("Invalid reply from port manager"))
-                }
+        request_ports(
+            "second",
+            "fox",
+            PortRequest::Specific(1001),
+            Protocol::Tcp,
+            Holder::new(1),
+            &req,
+        )
+        .unwrap();
+        let wait = Duration::from_secs(10);
+        let block = queue("block", PortRequest::Block(3), Holder::new(2), wait, &req);
+        let single = queue("single", PortRequest::Any, Holder::new(3), wait, &req);
+
+        // The single port request can go ahead of the block that doesn't fit:
+
+        assert_eq!(Ok(vec![1002]), decode_port_reply(single.recv().unwrap()));
+        release_ports(0, vec![1000], &req).unwrap();
+        release_ports(1, vec![1001], &req).unwrap();
+        release_ports(3, vec![1002], &req).unwrap();
+        assert_eq!(
+            Ok(vec![1000, 1001, 1002]),
+            decode_port_reply(block.recv().unwrap())
+        );
+    }
+    #[test]
+    fn specific_port() {
+        let req = start(3);
+        assert_eq!(
+            Ok(vec![1001]),
+            request_ports(
+                "svc",
+                "fox",
+                PortRequest::Specific(1001),
+                Protocol::Tcp,
+                Holder::new(0),
+                &req
+            )
+        );
+        assert!(request_ports(
+            "other",
+            "fox",
+            PortRequest::Specific(1001),
+            Protocol::Tcp,
+            Holder::new(1),
+            &req
+        )
+        .is_err());
+        assert!(request_ports(
+            "other",
+            "fox",
+            PortRequest::Specific(2000),
+            Protocol::Tcp,
+            Holder::new(1),
+            &req
+        )
+        .is_err());
+    }
+    #[test]
+    fn restores() {
+        let req = start(4);
+        let block = vec![
+            UsedPort::new(1001, "svc", "fox"),
+            UsedPort::new(1002, "svc", "fox"),
+        ];
+        assert_eq!(Ok(vec![1001, 1002]), restore_ports(block, Holder::new(7), &req));
+
+        // All or nothing:
+
+        let taken = vec![
+            UsedPort::new(1000, "other", "fox"),
+            UsedPort::new(1002, "other", "fox"),
+        ];
+        assert!(restore_ports(taken, Holder::new(8), &req).is_err());
+        assert_eq!(2, get_allocations(&req).unwrap().len());
+
+        // The restored ports belong to the holder:
+
+        release_ports(8, vec![1001], &req).unwrap();
+        release_ports(7, vec![1001, 1002], &req).unwrap();
+        assert!(get_allocations(&req).unwrap().is_empty());
+    }
+    #[test]
+    fn adopts() {
+        let req = start(4);
+        let transfer = |token: &str| Some(String::from(token));
+        let launcher = Holder::new(7).with_transfer(transfer("4b5a"));
+        let block = request_ports("daq", "fox", PortRequest::Block(2), Protocol::Tcp, launcher, &req)
+            .unwrap();
+        let ring = Holder::new(7).with_transfer(transfer("6978"));
+        assert_eq!(Ok(vec![1002]), any(ring, &req));
+
+        // Only with the allocation's own token and not by its holder:
+
+        let daemon = || Holder::new(8).with_transfer(transfer("8a9b"));
+        assert!(adopt_ports(1000, "6978", daemon(), &req).is_err());
+        assert!(adopt_ports(1003, "4b5a", daemon(), &req).is_err());
+        assert!(adopt_ports(1000, "4b5a", Holder::new(7), &req).is_err());
+
+        // The whole block whichever of its ports is named, once:
+
+        assert_eq!(Ok((7, block)), adopt_ports(1001, "4b5a", daemon(), &req));
+        assert!(adopt_ports(1001, "4b5a", daemon(), &req).is_err());
+
+        // They're no longer the launcher's to release:
+
+        release_ports(7, vec![1000, 1001, 1002], &req).unwrap();
+        assert_eq!(2, get_allocations(&req).unwrap().len());
+        assert_eq!(Ok((8, vec![1000, 1001])), adopt_ports(1000, "8a9b", Holder::new(9), &req));
+        release_ports(9, vec![1000, 1001], &req).unwrap();
+        assert!(get_allocations(&req).unwrap().is_empty());
+    }
+    #[test]
+    fn reclaims() {
+        let path = std::env::temp_dir().join(format!("portman-{}-responder-state", std::process::id()));
+        std::fs::write(
+            &path,
+            "0a1b 1001 svc fox tcp\n0a1b 1002 svc fox tcp\n2c3d 1000 other fox tcp\n4e5f 2000 gone fox tcp\n",
+        )
+        .unwrap();
+        let state = StateFile::open(&path).unwrap();
+        let metrics = Arc::new(crate::metrics::metrics::Metrics::new());
+        let audit = Audit::default().with_recorder(metrics.clone());
+        let (req, receiver) = mpsc::channel();
+        let responder = thread::spawn(move || {
+            responder_with_state(
+                ports::PortPool::new(1000, 4),
+                CollisionPolicy::Reject,
+                audit,
+                state,
+                Duration::from_millis(300),
+                receiver,
+            )
+        });
+
+        // What's saved is allocated again, but a port no longer in the
+        // pool is dropped:
+
+        assert_eq!(3, get_allocations(&req).unwrap().len());
+        let health = check_health(&req).unwrap();
+        assert_eq!(
+            (3, 1, 3),
+            (health.allocated, health.available, health.unclaimed)
+        );
+        assert_eq!(
+            Ok(vec![1001, 1002]),
+            reclaim_ports("0a1b", Holder::new(7).with_token(Some(String::from("0a1b"))), &req)
+        );
+        assert!(reclaim_ports("0a1b", Holder::new(8), &req).is_err());
+        assert!(reclaim_ports("9999", Holder::new(8), &req).is_err());
+
+        // New holders' allocations are saved with their tokens:
+
+        let holder = Holder::new(9).with_token(Some(String::from("6a7b")));
+        assert_eq!(
+            Ok(vec![1003]),
+            request_ports("new", "fox", PortRequest::Any, Protocol::Tcp, holder, &req)
+        );
+
+        // The file is written by another thread:
+
+        let saved = || std::fs::read_to_string(&path).unwrap();
+        for _ in 0..100 {
+            if saved().ends_with("6a7b 1003 new fox tcp\n") {
+                break;
             }
-            Err(msg) => Err(msg),
-        },
-        Err(msg) => Err(msg.to_string()),
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(saved().ends_with("6a7b 1003 new fox tcp\n"), "{}", saved());
+
+        // What isn't reclaimed in time goes back to the pool:
+
+        thread::sleep(Duration::from_millis(400));
+        let ports: Vec<u16> = get_allocations(&req).unwrap().iter().map(|u| u.port()).collect();
+        assert_eq!(vec![1001, 1002, 1003], ports);
+        release_ports(7, vec![1001, 1002], &req).unwrap();
+        assert_eq!(1, get_allocations(&req).unwrap().len());
+
+        // Every release was audited as a grant first:
+
+        let text = metrics.render(None);
+        assert!(text.contains("portman_ports_granted_total{protocol=\"tcp\"} 4"));
+        assert!(text.contains("portman_ports_released_total{protocol=\"tcp\"} 3"));
+
+        // It's up to date once the responder has stopped:
+
+        req.send(RequestMessage::Terminate).unwrap();
+        responder.join().unwrap();
+        assert_eq!("6a7b 1003 new fox tcp\n", saved());
+        let _ = std::fs::remove_file(&path);
+    }
+    fn start_with_policy(num: u16, policy: CollisionPolicy) -> mpsc::Sender<RequestMessage> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            responder_with_pool(ports::PortPool::new(1000, num), policy, receiver)
+        });
+        sender
+    }
+    fn any(holder: Holder, req: &mpsc::Sender<RequestMessage>) -> Result<Vec<u16>, PortmanError> {
+        request_ports("svc", "fox", PortRequest::Any, Protocol::Tcp, holder, req)
+    }
+    #[test]
+    fn policy_reject() {
+        let req = start_with_policy(2, CollisionPolicy::Reject);
+        any(Holder::new(0), &req).unwrap();
+        assert_eq!("E_DUPLICATE", any(Holder::new(1), &req).unwrap_err().code());
+    }
+    #[test]
+    fn policy_uniquify() {
+        let req = start_with_policy(2, CollisionPolicy::Uniquify);
+        any(Holder::new(0), &req).unwrap();
+        any(Holder::new(1), &req).unwrap();
+        let names: Vec<String> = get_allocations(&req)
+            .unwrap()
+            .iter()
+            .map(|u| u.service())
+            .collect();
+        assert!(names.contains(&String::from("svc")));
+        assert!(names.contains(&String::from("svc.1")));
+    }
+    #[test]
+    fn policy_takeover() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let req = start_with_policy(2, CollisionPolicy::Takeover);
+        let closed = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&closed);
+        let first = any(
+            Holder::new(0).with_disconnect(move || flag.store(true, Ordering::SeqCst)),
+            &req,
+        )
+        .unwrap();
+        let second = any(Holder::new(1), &req).unwrap();
+        assert_eq!(first, second); // Port is reused
+        assert!(closed.load(Ordering::SeqCst)); // and the old holder is disconnected.
+
+        // When the old holder goes away, the port stays with the new one:
+
+        release_ports(0, first, &req).unwrap();
+        assert_eq!(1, get_allocations(&req).unwrap().len());
+    }
+    #[test]
+    fn policy_takeover_not_queued() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let req = start_with_policy(3, CollisionPolicy::Takeover);
+        let closed = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&closed);
+        let first = any(
+            Holder::new(0).with_disconnect(move || flag.store(true, Ordering::SeqCst)),
+            &req,
+        )
+        .unwrap();
+        let other = request_ports(
+            "other",
+            "fox",
+            PortRequest::Specific(1001),
+            Protocol::Tcp,
+            Holder::new(1),
+            &req,
+        )
+        .unwrap();
+
+        // Taking the name over with requests that would have to wait for
+        // another port fails at once and leaves the old holder alone:
+
+        let wait = Duration::from_secs(10);
+        for request in [PortRequest::Specific(1001), PortRequest::Block(2)] {
+            let reply = queue("svc", request, Holder::new(2), wait, &req);
+            let reply = reply.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert!(decode_port_reply(reply).is_err());
+            assert!(!closed.load(Ordering::SeqCst));
+        }
+        assert_eq!(0, check_health(&req).unwrap().waiting);
+        assert_eq!(2, get_allocations(&req).unwrap().len());
+
+        // Once the block fits with the old holder's port, the takeover
+        // goes ahead:
+
+        release_ports(1, other, &req).unwrap();
+        let taken = request_ports(
+            "svc",
+            "fox",
+            PortRequest::Block(2),
+            Protocol::Tcp,
+            Holder::new(2),
+            &req,
+        )
+        .unwrap();
+        assert_eq!(vec![first[0], first[0] + 1], taken);
+        assert!(closed.load(Ordering::SeqCst));
+    }
+    #[test]
+    fn policy_takeover_decided_when_queued() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let req = start_with_policy(2, CollisionPolicy::Takeover);
+        let x = request_port("x", "fox", 5, &req).unwrap();
+        let y = request_port("y", "fox", 6, &req).unwrap();
+        let closed = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&closed);
+        let wait = Duration::from_secs(10);
+        let first = queue(
+            "svc",
+            PortRequest::Any,
+            Holder::new(0).with_disconnect(move || flag.store(true, Ordering::SeqCst)),
+            wait,
+            &req,
+        );
+        let second = queue("svc", PortRequest::Any, Holder::new(1), wait, &req);
+
+        // Neither collided when queued, so the second doesn't take the
+        // name over from the first once it's granted:
+
+        release_port(x, &req).unwrap();
+        assert_eq!(Ok(vec![x]), decode_port_reply(first.recv().unwrap()));
+        release_port(y, &req).unwrap();
+        let error = decode_port_reply(second.recv().unwrap()).unwrap_err();
+        assert_eq!("E_DUPLICATE", error.code());
+        assert!(!closed.load(Ordering::SeqCst));
+    }
+    #[test]
+    fn policy_takeover_same_uid() {
+        let req = start_with_policy(2, CollisionPolicy::TakeoverSameUid);
+        any(Holder::new(0).with_uid(Some(100)), &req).unwrap();
+        let error = any(Holder::new(1).with_uid(Some(200)), &req).unwrap_err();
+        assert_eq!("E_DENIED", error.code());
+        assert!(any(Holder::new(1), &req).is_err());
+        assert!(any(Holder::new(1).with_uid(Some(100)), &req).is_ok());
+    }
+    #[test]
+    fn notify_on_grant() {
+        let req = start(1);
+        let (sender, receiver) = mpsc::channel();
+        let sender = std::sync::Mutex::new(sender);
+        let port = request_port("first", "fox", 0, &req).unwrap();
+        let holder = Holder::new(1).with_notify(move || sender.lock().unwrap().send(()).unwrap());
+        let wait = Duration::from_secs(10);
+        let reply = queue("second", PortRequest::Any, holder, wait, &req);
+        release_port(port, &req).unwrap();
+        receiver.recv().unwrap(); // Notified after
+        assert!(reply.try_recv().is_ok()); // the reply was sent.
+    }
+    #[test]
+    fn leave_frees_unseen_grants() {
+        let req = start(2);
+        let (sender, receiver) = mpsc::channel();
+        let sender = std::sync::Mutex::new(sender);
+        let notify: Notify = Arc::new(move || sender.lock().unwrap().send(()).unwrap());
+        let make = |reply_chan| RequestMessage::AllocatePort {
+            service_name: String::from("svc"),
+            user_name: String::from("fox"),
+            request: PortRequest::Block(2),
+            protocol: Protocol::Tcp,
+            holder: Holder::new(1),
+            wait: None,
+            reply_chan,
+        };
+        let reply = queue_request(make, Some(notify), &req).unwrap();
+        receiver.recv().unwrap(); // Notified once answered.
+        assert_eq!(0, check_health(&req).unwrap().available);
+
+        // The client goes without reading its grant:
+
+        drop(reply);
+        leave(1, &req).unwrap();
+        assert_eq!(2, check_health(&req).unwrap().available);
+    }
+    #[test]
+    fn survives_panic() {
+        let req = start(3);
+        request_ports("a", "fox", PortRequest::Block(2), Protocol::Tcp, Holder::new(0), &req)
+            .unwrap();
+        assert_eq!(0, check_health(&req).unwrap().restarts);
+
+        // A holder whose notify panics takes the responder down after
+        // it's been given its port:
+
+        let holder = Holder::new(1).with_notify(|| panic!("notify failed"));
+        let ports =
+            request_ports("b", "fox", PortRequest::Any, Protocol::Tcp, holder, &req).unwrap();
+
+        // The pool is rebuilt with both allocations:
+
+        let health = check_health(&req).unwrap();
+        assert_eq!(1, health.restarts);
+        assert_eq!(3, health.allocated);
+        assert_eq!(0, health.available);
+        assert_eq!(3, get_allocations(&req).unwrap().len());
+        release_ports(1, ports, &req).unwrap();
+        assert_eq!(1, check_health(&req).unwrap().available);
+    }
+    #[test]
+    fn policy_parse() {
+        assert_eq!(Ok(CollisionPolicy::Reject), "reject".parse());
+        assert_eq!(Ok(CollisionPolicy::Uniquify), "uniquify".parse());
+        assert_eq!(Ok(CollisionPolicy::Takeover), "takeover".parse());
+        assert_eq!(
+            Ok(CollisionPolicy::TakeoverSameUid),
+            "takeover-same-uid".parse()
+        );
+        assert!("junk".parse::<CollisionPolicy>().is_err());
     }
 }
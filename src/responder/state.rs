@@ -0,0 +1,243 @@
+use crate::error::error::PortmanError;
+use crate::log_warn;
+use crate::portpool::ports::UsedPort;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+// The state file keeps the allocations of clients that were given reclaim
+// tokens so that, should the server stop without releasing them (e.g. it
+// crashed), they can be reclaimed when it restarts.  Each line is an
+// allocation as LIST shows it preceded by its token:
+//
+//    token port service-name user-name protocol
+//
+// The file is replaced whole (a new file is written and renamed over it)
+// each time the allocations change, so it's never seen half written.
+// The tokens let anyone who can read the file take the allocations, so
+// only its owner can.  It's written by a StateWriter's thread so that
+// changing the allocations doesn't wait for the disk.
+
+///
+/// The number of random bytes in a reclaim token, which is written as
+/// twice as many hex digits.
+///
+const TOKEN_BYTES: usize = 16;
+
+///
+/// new_token
+///    A new, unguessable reclaim token.
+///
+pub fn new_token() -> Result<String, PortmanError> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .map_err(|e| PortmanError::Io(format!("Unable to make a reclaim token: {}", e)))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+///
+/// StateFile
+///    Where the allocations with reclaim tokens are kept, along with those
+/// found there when it was opened.
+///
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    saved: Vec<(String, UsedPort)>,
+}
+
+impl StateFile {
+    ///
+    /// open
+    ///    Read the allocations saved in *path* by an earlier server.  A file
+    ///    that doesn't exist yet has none; one that can't be read or parsed
+    ///    is an error.
+    ///
+    pub fn open(path: &Path) -> Result<StateFile, PortmanError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(PortmanError::Io(format!(
+                    "Unable to read {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        let mut saved = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let parsed = line
+                .split_once(' ')
+                .ok_or_else(|| PortmanError::Invalid(String::from("No allocation")))
+                .and_then(|(token, used)| Ok((String::from(token), used.parse::<UsedPort>()?)));
+            match parsed {
+                Ok(entry) => saved.push(entry),
+                Err(e) => {
+                    return Err(PortmanError::Invalid(format!(
+                        "{} line {}: {}",
+                        path.display(),
+                        number + 1,
+                        e
+                    )))
+                }
+            }
+        }
+        Ok(StateFile {
+            path: path.to_path_buf(),
+            saved,
+        })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    ///
+    /// The allocations that were saved, each with its token.
+    ///
+    pub fn saved(&self) -> &[(String, UsedPort)] {
+        &self.saved
+    }
+    ///
+    /// Keep only the saved allocations whose tokens *keep* accepts, e.g.
+    /// to leave out those restored some other way.
+    ///
+    pub fn retain<F>(&mut self, keep: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.saved.retain(|(token, _)| keep(token));
+    }
+    pub(crate) fn take_saved(&mut self) -> Vec<(String, UsedPort)> {
+        std::mem::take(&mut self.saved)
+    }
+    ///
+    /// save
+    ///    Replace the file's contents with *allocations*.  The file is
+    ///    readable only by its owner.
+    ///
+    pub fn save(&self, allocations: &[(&str, &UsedPort)]) -> io::Result<()> {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".new");
+        let temporary = PathBuf::from(name);
+
+        // The mode only applies to a file we create, so one left behind,
+        // perhaps by someone else, is removed first:
+
+        match fs::remove_file(&temporary) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        for (token, used) in allocations {
+            writeln!(file, "{} {}", token, used)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+///
+/// StateWriter
+///    Saves allocations to a StateFile from a thread of its own.  Saves
+/// asked for while an earlier one is being written are coalesced:  only
+/// the latest allocations are written.  Dropping the writer waits for
+/// what it was last given to be saved.
+///
+pub(crate) struct StateWriter {
+    sender: Option<mpsc::Sender<Vec<(String, UsedPort)>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StateWriter {
+    pub(crate) fn start(state: StateFile) -> StateWriter {
+        let (sender, receiver) = mpsc::channel::<Vec<(String, UsedPort)>>();
+        let thread = thread::spawn(move || {
+            while let Ok(mut allocations) = receiver.recv() {
+                while let Ok(newer) = receiver.try_recv() {
+                    allocations = newer;
+                }
+                let held: Vec<(&str, &UsedPort)> =
+                    allocations.iter().map(|(t, u)| (t.as_str(), u)).collect();
+                if let Err(e) = state.save(&held) {
+                    log_warn!("Unable to save {}: {}", state.path.display(), e);
+                }
+            }
+        });
+        StateWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+    ///
+    /// Have *allocations* saved, replacing what was saved before.
+    ///
+    pub(crate) fn save(&self, allocations: Vec<(String, UsedPort)>) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(allocations);
+        }
+    }
+}
+
+impl Drop for StateWriter {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let token = new_token().unwrap();
+        assert_eq!(2 * TOKEN_BYTES, token.len());
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, new_token().unwrap());
+    }
+    #[test]
+    fn saves_and_opens() {
+        let path =
+            std::env::temp_dir().join(format!("portman-{}-state", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(StateFile::open(&path).unwrap().saved().is_empty());
+
+        let state = StateFile::open(&path).unwrap();
+        let daq = UsedPort::new(31000, "event builder", "fox");
+        let ring = UsedPort::new(31001, "ring", "fox");
+
+        // A temporary file left readable by others isn't reused:
+
+        use std::os::unix::fs::PermissionsExt;
+        let mut leftover = path.clone().into_os_string();
+        leftover.push(".new");
+        fs::write(&leftover, "").unwrap();
+        fs::set_permissions(&leftover, fs::Permissions::from_mode(0o644)).unwrap();
+        state.save(&[("0a1b", &daq), ("2c3d", &ring)]).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        let mut reopened = StateFile::open(&path).unwrap();
+        assert_eq!(
+            vec![(String::from("0a1b"), daq), (String::from("2c3d"), ring)],
+            reopened.saved()
+        );
+        reopened.retain(|token| token != "0a1b");
+        assert_eq!(1, reopened.take_saved().len());
+        assert!(reopened.saved().is_empty());
+
+        fs::write(&path, "0a1b 31000 daq\n").unwrap();
+        assert!(StateFile::open(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,1065 @@
+use super::event_loop::Control;
+use super::federation::{Federation, Question};
+use super::peer::{is_local, may_list, peer_uid};
+use super::relay::{Relays, CONNECT_TIMEOUT};
+use super::replication::Replica;
+use super::server::Limits;
+use super::takeover::Handed;
+use crate::error::error::PortmanError;
+use crate::logging::audit::{Audit, Event, Record};
+use crate::metrics::metrics::Metrics;
+use crate::portpool::ports::{Protocol, UsedPort};
+use crate::protocol::framing::LineReader;
+use crate::protocol::request::{self, quote, Allocation, ClientRequest};
+use crate::responder::responder;
+use crate::responder::state;
+use crate::{log_debug, log_info, log_warn};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How long a TERMINATE waits for its replies to be taken before the
+// server stops anyway.
+
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What every connection needs to reach the rest of the server.
+
+pub(crate) struct Context {
+    pub(crate) requests: mpsc::Sender<responder::RequestMessage>,
+    pub(crate) control: Arc<Control>,
+    pub(crate) audit: Audit,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) relays: Option<Relays>, // None if CONNECT isn't allowed.
+    pub(crate) federation: Federation,
+    pub(crate) replica: Option<Replica>, // Some if we're a read-only replica.
+    pub(crate) reclaims: bool,           // Holders get reclaim tokens.
+}
+
+// The state of a client connection:
+
+pub(crate) struct Connection {
+    pub(crate) stream: TcpStream,
+    pub(crate) client: responder::ClientId,
+    pub(crate) peer: SocketAddr,
+    pub(crate) active: Instant,       // Connected or last request.
+    pub(crate) requested: bool,       // Made at least one request.
+    pub(crate) input: LineReader,     // Received, not yet processed.
+    pub(crate) output: Vec<u8>,       // Waiting to be sent.
+    pub(crate) ports: Vec<u16>,       // Ports we hold for the client.
+    pub(crate) token: Option<String>, // Reclaims them after a restart.
+    pub(crate) transfers: HashMap<u16, String>, // Each port's transfer token.
+    pub(crate) transfer: Option<String>, // For the allocation being asked for.
+    pub(crate) adopted: Vec<(responder::ClientId, Vec<u16>)>, // Taken from other clients.
+    pub(crate) answers: VecDeque<Answer>, // Requests the responder is answering.
+    pub(crate) abandoned: bool,       // Gave up on an answer that may have granted ports.
+    pub(crate) waiting: Option<mpsc::Receiver<responder::Reply>>, // Queued GIMME ... WAIT.
+    pub(crate) queued: Option<(String, Instant)>, // Its request line and when the wait ends.
+    withdrawn: Option<mpsc::Receiver<responder::Reply>>, // Queued GIMME ... WAIT, withdrawn.
+    pub(crate) gathering: Option<mpsc::Receiver<String>>, // LIST ALL or FIND ... ANYHOST.
+    pub(crate) connecting: Option<Connecting>, // CONNECT to the service in progress.
+    pub(crate) relay: Option<TcpStream>, // Relay to this service once output is sent.
+    pub(crate) terminating: Option<Instant>, // TERMINATE:  stop once output is sent or by then.
+    pub(crate) closing: bool,         // Close once output is sent.
+    pub(crate) settling: bool,        // Being handed over:  requests wait.
+    pub(crate) eof: bool,             // Peer closed its side.
+    pub(crate) writable: bool,        // Registered for writability.
+    pub(crate) counted: bool,         // Counted against the connection limits.
+}
+
+// A request the responder has yet to answer:  what was asked, which says
+// what's done with the answer, and when it was sent.  The responder wakes
+// the event loop once it has answered.
+
+pub(crate) struct Answer {
+    receiver: mpsc::Receiver<responder::Reply>,
+    asked: Asked,
+    sent: Instant,
+}
+
+enum Asked {
+    Gimme,
+    Reclaim,
+    Adopt,
+    Restore { transfer: String },
+    Health,
+    List { protocols: bool },
+    Find { service: String, user: String },
+    Gather(Question),
+    Connect { service: String, user: String },
+}
+
+// A CONNECT whose connection to the service hasn't completed yet.  The
+// event loop watches the stream for writability.
+
+pub(crate) struct Connecting {
+    pub(crate) stream: TcpStream,
+    pub(crate) registered: bool, // With the event loop.
+    port: u16,
+    service: String,
+    user: String,
+    deadline: Instant,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        stream: TcpStream,
+        client: responder::ClientId,
+        peer: SocketAddr,
+    ) -> Connection {
+        Connection {
+            stream,
+            client,
+            peer,
+            active: Instant::now(),
+            requested: false,
+            input: LineReader::new(),
+            output: Vec::new(),
+            ports: Vec::new(),
+            token: None,
+            transfers: HashMap::new(),
+            transfer: None,
+            adopted: Vec::new(),
+            answers: VecDeque::new(),
+            abandoned: false,
+            waiting: None,
+            withdrawn: None,
+            queued: None,
+            gathering: None,
+            connecting: None,
+            relay: None,
+            terminating: None,
+            closing: false,
+            settling: false,
+            eof: false,
+            writable: false,
+            counted: true,
+        }
+    }
+    // Read all that's available.  Returns false if the connection failed.
+    // Once we've decided to close the connection what's read is ignored and
+    // a client that sends more than we'll buffer is failed.
+    //
+    pub(crate) fn read(&mut self, ctx: &Context) -> bool {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.eof = true;
+                    return true;
+                }
+                Ok(_) if self.closing => {}
+                Ok(n) => {
+                    self.input.extend(&buffer[..n]);
+                    if let Err(msg) = self.input.check() {
+                        self.refuse(ctx, &msg, None);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+    // Write as much pending output as we can.  Returns false if the
+    // connection failed.
+    //
+    pub(crate) fn flush(&mut self) -> bool {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+    // Whether we're waiting for the responder or the peers to answer.
+    //
+    pub(crate) fn is_waiting(&self) -> bool {
+        !self.answers.is_empty() || self.waiting.is_some() || self.gathering.is_some()
+    }
+    // Connections holding or waiting for ports are exempt from timeouts.
+    //
+    fn is_holder(&self) -> bool {
+        !self.ports.is_empty() || self.waiting.is_some()
+    }
+    // When the connection times out, if it's not a holder.
+    //
+    pub(crate) fn deadline(&self, limits: &Limits) -> Option<Instant> {
+        if self.is_holder() {
+            return None;
+        }
+        let limit = if self.requested {
+            limits.idle
+        } else {
+            limits.first_request
+        };
+        limit.map(|limit| self.active + limit)
+    }
+    fn reply(&mut self, text: &str) {
+        self.output.extend_from_slice(text.as_bytes());
+    }
+    // Reply with a failure and close the connection.
+    //
+    pub(crate) fn fail(&mut self, error: &PortmanError) {
+        log_info!(client = self.client; "Failed: {} - {}", error.code(), error);
+        self.reply(&error.reply());
+        self.closing = true;
+    }
+    // Refuse a request the responder never sees (it's malformed or not
+    // allowed from this peer), recording it in the audit log, and close
+    // the connection.
+    //
+    fn refuse(
+        &mut self,
+        ctx: &Context,
+        error: &PortmanError,
+        names: Option<(&str, &str, Protocol)>,
+    ) {
+        let mut record = Record::rejected(error)
+            .with_peer(Some(self.peer))
+            .with_client(self.client);
+        if let Some((service, user, protocol)) = names {
+            record = record.with_names(service, user, protocol);
+        }
+        ctx.audit.record(&record);
+        self.fail(error);
+    }
+    // Process the complete request lines we have.  Processing stops while
+    // an allocation request is queued or the peers are being asked so that
+    // requests stay in order, and after a CONNECT since what follows it is
+    // for the service.
+    // Malformed framing (overlong lines, invalid UTF-8 or a partial request
+    // left when the client closes its side) fails the connection.
+    //
+    pub(crate) fn process(&mut self, ctx: &Context) {
+        while !self.closing && !self.is_blocked() {
+            match self.input.next_line() {
+                Some(Ok(line)) => {
+                    self.active = Instant::now();
+                    self.requested = true;
+                    self.request(&line, ctx);
+                }
+                Some(Err(msg)) => self.refuse(ctx, &msg, None),
+                None => break,
+            }
+        }
+        if self.eof && !self.closing && !self.is_blocked() {
+            if let Err(msg) = self.input.finish() {
+                self.refuse(ctx, &msg, None);
+            }
+        }
+    }
+    // Whether requests must wait for a reply or the relay.
+    //
+    fn is_blocked(&self) -> bool {
+        self.is_waiting() || self.connecting.is_some() || self.relay.is_some() || self.settling
+    }
+    fn request(&mut self, request_line: &str, ctx: &Context) {
+        log_debug!(client = self.client; "Request: {}", request_line);
+        let request = match request::decode_request(request_line) {
+            Ok(request) => request,
+            Err(msg) => {
+                self.refuse(ctx, &msg, None); // only allow one.
+                return;
+            }
+        };
+        let lists = matches!(
+            request,
+            ClientRequest::List
+                | ClientRequest::ListProtocols
+                | ClientRequest::ListAll
+                | ClientRequest::Find { .. }
+                | ClientRequest::FindAnyHost { .. }
+        );
+        if lists {
+            if let Err(msg) = may_list(&self.peer) {
+                self.refuse(ctx, &msg, None);
+                return;
+            }
+        }
+        match request {
+            ClientRequest::Gimme(allocation) => {
+                self.create_allocation(&allocation, ctx);
+                if let (Some(_), Some(wait)) = (&self.waiting, allocation.wait) {
+                    self.queued = Some((String::from(request_line), Instant::now() + wait));
+                }
+            }
+            ClientRequest::Find {
+                service_name,
+                user_name,
+            } => self.find_allocations(ctx, &service_name, &user_name),
+            ClientRequest::List => self.list_allocations(ctx, false),
+            ClientRequest::ListProtocols => self.list_allocations(ctx, true),
+            ClientRequest::ListAll => self.gather(ctx, Question::List),
+            ClientRequest::FindAnyHost {
+                service_name,
+                user_name,
+            } => self.gather(
+                ctx,
+                Question::Find {
+                    service: service_name,
+                    user: user_name,
+                },
+            ),
+            ClientRequest::Health => self.health(ctx),
+            ClientRequest::Connect {
+                service_name,
+                user_name,
+            } => self.connect(ctx, &service_name, &user_name),
+            ClientRequest::Reclaim { token } => self.reclaim(ctx, token),
+            ClientRequest::Adopt { port, token } => self.adopt_allocation(ctx, port, &token),
+            ClientRequest::Terminate => {
+                log_info!(client = self.client; "Client requested shutdown");
+                ctx.audit.record(
+                    &Record::new(Event::Admin, "TERMINATE requested")
+                        .with_peer(Some(self.peer))
+                        .with_client(self.client),
+                );
+
+                // Replies to requests pipelined ahead of this one
+                // are sent before we stop (see EventLoop::remove) unless
+                // the client won't take them in time:
+
+                self.closing = true;
+                self.terminating = Some(Instant::now() + TERMINATE_TIMEOUT);
+            }
+        }
+    }
+    // See if the responder has answered our requests, in the order they
+    // were made, or the reply to a queued allocation request, or from the
+    // peers, has arrived.
+    //
+    pub(crate) fn check_wait(&mut self, ctx: &Context) {
+        while let Some(answer) = self.answers.front() {
+            let reply = match answer.receiver.try_recv() {
+                Ok(reply) => reply,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => Err(PortmanError::Internal(String::from(
+                    "Lost contact with the port pool",
+                ))),
+            };
+            let answer = self.answers.pop_front().unwrap();
+            ctx.metrics.round_trip(answer.sent.elapsed());
+            self.answered(ctx, answer.asked, reply);
+        }
+        if let Some(receiver) = &self.gathering {
+            match receiver.try_recv() {
+                Ok(reply) => {
+                    self.gathering = None;
+                    self.reply(&reply);
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.gathering = None;
+                    self.fail(&PortmanError::Internal(String::from(
+                        "Lost contact with the peers",
+                    )));
+                }
+            }
+        }
+        let reply = match &self.waiting {
+            Some(receiver) => receiver.try_recv(),
+            None => return,
+        };
+        match reply {
+            Ok(reply) => {
+                self.waiting = None;
+                self.granted(reply);
+            }
+            Err(mpsc::TryRecvError::Empty) => {}
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.waiting = None;
+                self.fail(&PortmanError::Internal(String::from(
+                    "Lost contact with the port pool",
+                )));
+            }
+        }
+    }
+    // Give up on the responder if the oldest of our requests hasn't been
+    // answered by 'now'.  Returns true if we did.
+    //
+    pub(crate) fn check_overdue(&mut self, now: Instant) -> bool {
+        match self.answers.front() {
+            Some(answer) if now.duration_since(answer.sent) >= responder::REPLY_TIMEOUT => {}
+            _ => return false,
+        }
+        self.answers.clear();
+        self.abandoned = true;
+        self.fail(&mpsc::RecvTimeoutError::Timeout.into());
+        true
+    }
+    // Send the responder the request 'make' builds and handle its answer
+    // as 'asked' once it arrives.
+    //
+    fn ask<F>(&mut self, ctx: &Context, asked: Asked, make: F)
+    where
+        F: FnOnce(mpsc::Sender<responder::Reply>) -> responder::RequestMessage,
+    {
+        let control = Arc::clone(&ctx.control);
+        let notify: responder::Notify = Arc::new(move || control.wake());
+        match responder::queue_request(make, Some(notify), &ctx.requests) {
+            Ok(receiver) => self.expect(receiver, asked),
+            Err(msg) => self.fail(&msg),
+        }
+    }
+    fn expect(&mut self, receiver: mpsc::Receiver<responder::Reply>, asked: Asked) {
+        self.answers.push_back(Answer {
+            receiver,
+            asked,
+            sent: Instant::now(),
+        });
+    }
+    // Ask for the allocations to be handled as 'asked'.  A replica has
+    // its copy at hand.
+    //
+    fn ask_allocations(&mut self, ctx: &Context, asked: Asked) {
+        match &ctx.replica {
+            Some(replica) => {
+                let reply = Ok(responder::ReplyMessage::ListAllocations(
+                    replica.allocations(),
+                ));
+                self.answered(ctx, asked, reply);
+            }
+            None => self.ask(ctx, asked, responder::RequestMessage::ListAllocations),
+        }
+    }
+    // Do what's needed with the responder's 'reply' to what we 'asked'.
+    //
+    fn answered(&mut self, ctx: &Context, asked: Asked, reply: responder::Reply) {
+        match asked {
+            Asked::Gimme => self.granted(reply),
+            Asked::Reclaim => {
+                if reply.is_err() {
+                    self.token = None;
+                }
+                self.granted(reply);
+            }
+            Asked::Adopt => match responder::decode_adopt_reply(reply) {
+                Ok((from, ports)) => {
+                    log_info!(client = self.client; "Adopted {:?} from conn {}", ports, from);
+                    self.adopted.push((from, ports.clone()));
+                    self.granted(Ok(responder::ReplyMessage::AllocatePort(ports)));
+                }
+                Err(msg) => {
+                    self.transfer = None;
+                    self.fail(&msg);
+                }
+            },
+            Asked::Restore { transfer } => match responder::decode_port_reply(reply) {
+                Ok(ports) => {
+                    log_info!(client = self.client; "Restored {:?}", ports);
+                    for port in &ports {
+                        self.transfers.insert(*port, transfer.clone());
+                    }
+                    self.ports.extend(ports);
+                }
+                Err(msg) => self.fail(&msg),
+            },
+            Asked::Health => match responder::decode_health_reply(reply) {
+                Ok(health) => self.report_health(ctx, health),
+                Err(msg) => self.fail(&msg),
+            },
+            Asked::List { protocols } => match responder::decode_allocations_reply(reply) {
+                Ok(allocations) => self.write_allocations(ctx, &allocations, protocols),
+                Err(msg) => self.fail(&msg),
+            },
+            Asked::Find { service, user } => match responder::decode_allocations_reply(reply) {
+                Ok(allocations) => self.found(ctx, &allocations, &service, &user),
+                Err(msg) => self.fail(&msg),
+            },
+            Asked::Gather(question) => {
+                let control = Arc::clone(&ctx.control);
+                let gathering =
+                    responder::decode_allocations_reply(reply).and_then(|allocations| {
+                        ctx.federation
+                            .gather(question, &allocations, move || control.wake())
+                    });
+                match gathering {
+                    Ok(receiver) => self.gathering = Some(receiver),
+                    Err(msg) => self.fail(&msg),
+                }
+            }
+            Asked::Connect { service, user } => match responder::decode_allocations_reply(reply) {
+                Ok(allocations) => self.connect_to(ctx, &allocations, &service, &user),
+                Err(msg) => self.fail(&msg),
+            },
+        }
+    }
+    // Reply to an allocation request with the ports, the token that
+    // reclaims them if we have one and the token that transfers them.
+    // Failure closes the connection.
+    //
+    fn granted(&mut self, reply: responder::Reply) {
+        let transfer = self.transfer.take();
+        match responder::decode_port_reply(reply) {
+            Ok(ports) => {
+                let mut reply: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+                log_info!(client = self.client; "Allocated {}", reply.join(" "));
+                if let Some(token) = &self.token {
+                    reply.push(format!("reclaim={}", token));
+                }
+                if let Some(transfer) = transfer {
+                    for port in &ports {
+                        self.transfers.insert(*port, transfer.clone());
+                    }
+                    reply.push(format!("transfer={}", transfer));
+                }
+                self.ports.extend(ports);
+                self.reply(&format!("OK {}\n", reply.join(" ")));
+            }
+            Err(msg) => self.fail(&msg), // exit regardless...
+        }
+    }
+    // Make the tokens an allocation is granted with:  a transfer token for
+    // it and, if holders get them and we don't have one yet, our reclaim
+    // token.
+    //
+    fn new_tokens(&mut self, ctx: &Context) -> Result<(), PortmanError> {
+        if ctx.reclaims && self.token.is_none() {
+            self.token = Some(state::new_token()?);
+        }
+        self.transfer = Some(state::new_token()?);
+        Ok(())
+    }
+    //
+    // ## create_allocation
+    //
+    //    Given an allocation request, asks the responder for the port(s).
+    //    The ports are released when the connection closes.  This request
+    //    is only allowed from local connections.
+    //
+    //    The allocation says whether any port, a specific port or a block of
+    //    ports is wanted and for which protocol.  We keep serving other
+    //    connections until the responder answers, which wakes the event loop.
+    //    If the request has a wait time and the ports are not free, it's
+    //    queued in the responder until they are.
+    //
+    fn create_allocation(&mut self, allocation: &Allocation, ctx: &Context) {
+        let names = (
+            allocation.service_name.as_str(),
+            allocation.user_name.as_str(),
+            allocation.protocol,
+        );
+        if let Some(replica) = &ctx.replica {
+            let error = PortmanError::Denied(format!(
+                "This is a read-only replica of {}; ask the primary for ports",
+                replica.primary()
+            ));
+            self.refuse(ctx, &error, Some(names));
+            return;
+        }
+        if !is_local(&self.stream) {
+            self.refuse(ctx, &PortmanError::NotLocal, Some(names));
+            return;
+        }
+        if let Err(e) = self.new_tokens(ctx) {
+            self.fail(&e);
+            return;
+        }
+        match responder::queue_port_request(
+            &allocation.service_name,
+            &allocation.user_name,
+            allocation.request,
+            allocation.protocol,
+            self.holder(ctx),
+            allocation.wait,
+            &ctx.requests,
+        ) {
+            Ok(receiver) if allocation.wait.is_some() => self.waiting = Some(receiver),
+            Ok(receiver) => self.expect(receiver, Asked::Gimme),
+            Err(msg) => self.fail(&msg),
+        }
+    }
+    // Describe ourself as the holder so that a takeover can close
+    // this connection and we're woken when a queued request is answered.
+    //
+    fn holder(&self, ctx: &Context) -> responder::Holder {
+        let mut holder = responder::Holder::new(self.client)
+            .with_uid(peer_uid(&self.stream))
+            .with_peer(Some(self.peer))
+            .with_token(self.token.clone())
+            .with_transfer(self.transfer.clone());
+        if let Ok(stream) = self.stream.try_clone() {
+            holder = holder.with_disconnect(move || {
+                let _ = stream.shutdown(net::Shutdown::Both);
+            });
+        }
+        let control = Arc::clone(&ctx.control);
+        holder.with_notify(move || control.wake())
+    }
+    //
+    // ## reclaim
+    //    After a restart, take back the allocations saved with 'token' and
+    //    reply as GIMME did.  The token then reclaims them again should we
+    //    restart once more, so a connection can only reclaim before it
+    //    holds anything (and so has a token of its own).  Like GIMME this
+    //    is only allowed from local connections.  The reclaimed allocation
+    //    gets a new transfer token.
+    //
+    fn reclaim(&mut self, ctx: &Context, token: String) {
+        if !ctx.reclaims {
+            let error = PortmanError::Denied(String::from("There is no state to reclaim from"));
+            self.refuse(ctx, &error, None);
+            return;
+        }
+        if !is_local(&self.stream) {
+            self.refuse(ctx, &PortmanError::NotLocal, None);
+            return;
+        }
+        if self.token.is_some() {
+            let error = PortmanError::Invalid(String::from(
+                "RECLAIM must come before any GIMME or ADOPT on a connection",
+            ));
+            self.refuse(ctx, &error, None);
+            return;
+        }
+        self.token = Some(token);
+        if let Err(e) = self.new_tokens(ctx) {
+            self.fail(&e);
+            return;
+        }
+        let (token, holder) = (self.token.clone().unwrap(), self.holder(ctx));
+        self.ask(ctx, Asked::Reclaim, |reply_chan| {
+            responder::RequestMessage::Reclaim {
+                token,
+                holder,
+                reply_chan,
+            }
+        });
+    }
+    //
+    // ## adopt_allocation
+    //    Take the allocation that 'port' is part of from the connection it
+    //    was granted on, e.g. a launcher's, given its transfer token.  The
+    //    ports stay allocated throughout and the reply is as GIMME's with a
+    //    new transfer token.  The event loop tells the previous holder
+    //    (see disown).  Like GIMME this is only allowed from local
+    //    connections.
+    //
+    fn adopt_allocation(&mut self, ctx: &Context, port: u16, token: &str) {
+        if !is_local(&self.stream) {
+            self.refuse(ctx, &PortmanError::NotLocal, None);
+            return;
+        }
+        if let Err(e) = self.new_tokens(ctx) {
+            self.fail(&e);
+            return;
+        }
+        let (transfer, holder) = (String::from(token), self.holder(ctx));
+        self.ask(ctx, Asked::Adopt, |reply_chan| {
+            responder::RequestMessage::Adopt {
+                port,
+                transfer,
+                holder,
+                reply_chan,
+            }
+        });
+    }
+    // Forget the 'ports' that client 'to' adopted from us.  They're no
+    // longer ours to release.
+    //
+    pub(crate) fn disown(&mut self, ports: &[u16], to: responder::ClientId) {
+        log_info!(client = self.client; "Conn {} adopted {:?}", to, ports);
+        self.ports.retain(|p| !ports.contains(p));
+        for port in ports {
+            self.transfers.remove(port);
+        }
+    }
+    //
+    // ## list_allocations
+    //    Produce a list of allocations to the output.  The lines only say
+    //    which protocol each allocation is for if *protocols* is true
+    //    (LIST PROTOCOL) so that plain LIST replies keep their three fields.
+    //
+    fn list_allocations(&mut self, ctx: &Context, protocols: bool) {
+        self.ask_allocations(ctx, Asked::List { protocols });
+    }
+    //
+    // ## gather
+    //    Answer LIST ALL or FIND ... ANYHOST:  ask the peers the question
+    //    and reply with their allocations and ours once they've answered.
+    //    Meanwhile the event loop serves other connections.
+    //
+    fn gather(&mut self, ctx: &Context, question: Question) {
+        self.ask_allocations(ctx, Asked::Gather(question));
+    }
+    //
+    // ## health
+    //    Report on the health of the responder.  A replica reports the
+    //    allocations in its copy, which primary it's a replica of and, if
+    //    it's out of contact with the primary, how stale the copy is.
+    //
+    fn health(&mut self, ctx: &Context) {
+        self.ask(ctx, Asked::Health, responder::RequestMessage::Health);
+    }
+    fn report_health(&mut self, ctx: &Context, mut health: responder::Health) {
+        let mut replica = String::new();
+        if let Some(r) = &ctx.replica {
+            health.allocated = r.allocations().len();
+            health.available = 0;
+            replica = format!(" replica-of={}{}", r.primary(), staleness(ctx));
+        }
+        self.reply(&format!(
+            "OK restarts={} allocated={} available={} waiting={} relays={}{}\n",
+            health.restarts,
+            health.allocated,
+            health.available,
+            health.waiting,
+            ctx.metrics.relays(),
+            replica
+        ))
+    }
+    //
+    // ## connect
+    //    Connect the client to the (lowest) TCP port of a service on this
+    //    host, if the relay rules allow it.  Once the OK is sent the event
+    //    loop hands the connection to the relay.  Connections that hold
+    //    ports can't be relayed as they must stay with us to keep them.
+    //    Any failure closes the connection.
+    //
+    fn connect(&mut self, ctx: &Context, service: &str, user: &str) {
+        let names = Some((service, user, Protocol::Tcp));
+        let relays = match &ctx.relays {
+            Some(relays) => relays,
+            None => {
+                let error = PortmanError::Denied(String::from("CONNECT is not enabled"));
+                self.refuse(ctx, &error, names);
+                return;
+            }
+        };
+        if let Err(e) = relays.permit(self.peer.ip(), service, user) {
+            self.refuse(ctx, &e, names);
+            return;
+        }
+        if self.is_holder() {
+            let error = PortmanError::Invalid(String::from(
+                "CONNECT can't be used on a connection that holds ports",
+            ));
+            self.refuse(ctx, &error, names);
+            return;
+        }
+        let asked = Asked::Connect {
+            service: String::from(service),
+            user: String::from(user),
+        };
+        self.ask(ctx, asked, responder::RequestMessage::ListAllocations);
+    }
+    // Start connecting to the service a CONNECT asked for, given the
+    // 'allocations'.  The event loop sees the connection through.
+    //
+    fn connect_to(&mut self, ctx: &Context, allocations: &[UsedPort], service: &str, user: &str) {
+        let names = Some((service, user, Protocol::Tcp));
+        let port = allocations
+            .iter()
+            .find(|a| a.protocol() == Protocol::Tcp && a.service() == service && a.user() == user)
+            .map(|a| a.port());
+        let port = match port {
+            Some(port) => port,
+            None => {
+                let error = PortmanError::NotAdvertised {
+                    service: String::from(service),
+                    user: String::from(user),
+                };
+                self.refuse(ctx, &error, names);
+                return;
+            }
+        };
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        match mio::net::TcpStream::connect(address) {
+            Ok(stream) => {
+                self.connecting = Some(Connecting {
+                    stream: TcpStream::from(stream),
+                    registered: false,
+                    port,
+                    service: String::from(service),
+                    user: String::from(user),
+                    deadline: Instant::now() + CONNECT_TIMEOUT,
+                });
+            }
+            Err(e) => {
+                let error = PortmanError::Io(format!("Unable to connect to port {}: {}", port, e));
+                self.refuse(ctx, &error, names);
+            }
+        }
+    }
+    // See if the connection a CONNECT started has completed, failed or, by
+    // 'now', timed out.  Once it's connected the OK is sent and the event
+    // loop hands us to the relay.
+    //
+    pub(crate) fn check_connect(&mut self, ctx: &Context, now: Instant) {
+        let connecting = match &self.connecting {
+            Some(connecting) => connecting,
+            None => return,
+        };
+        let result = match connecting.stream.take_error() {
+            Ok(None) => match connecting.stream.peer_addr() {
+                Ok(_) => Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotConnected => {
+                    if now < connecting.deadline {
+                        return;
+                    }
+                    Err(io::Error::from(ErrorKind::TimedOut))
+                }
+                Err(e) => Err(e),
+            },
+            Ok(Some(e)) | Err(e) => Err(e),
+        };
+        let connecting = self.connecting.take().unwrap();
+        let port = connecting.port;
+
+        // The relay sets the stream up the way it wants it:
+
+        match result.and_then(|()| connecting.stream.set_nonblocking(false)) {
+            Ok(()) => {
+                log_info!(client = self.client; "Relaying to port {}", port);
+                self.reply(&format!("OK {}\n", port));
+                self.relay = Some(connecting.stream);
+            }
+            Err(e) => {
+                let error = PortmanError::Io(format!("Unable to connect to port {}: {}", port, e));
+                let names = (connecting.service.as_str(), connecting.user.as_str());
+                self.refuse(ctx, &error, Some((names.0, names.1, Protocol::Tcp)));
+            }
+        }
+    }
+    //
+    // ## find_allocations
+    //    Produce the allocations for a service/user pair to the output.
+    //    This is the subset of the LIST output for the service and user, so there's
+    //    one line for each protocol (and each port of a block).
+    //
+    fn find_allocations(&mut self, ctx: &Context, service: &str, user: &str) {
+        let asked = Asked::Find {
+            service: String::from(service),
+            user: String::from(user),
+        };
+        self.ask_allocations(ctx, asked);
+    }
+    fn found(&mut self, ctx: &Context, allocations: &[UsedPort], service: &str, user: &str) {
+        let allocations: Vec<_> = allocations
+            .iter()
+            .filter(|a| a.service() == service && a.user() == user)
+            .cloned()
+            .collect();
+        if allocations.is_empty() {
+            let error = PortmanError::NotAdvertised {
+                service: String::from(service),
+                user: String::from(user),
+            };
+            self.reply(&error.reply());
+        } else {
+            self.write_allocations(ctx, &allocations, true);
+        }
+    }
+    // Write a set of allocations in LIST format, with or without their
+    // protocols.  A replica out of contact with its primary says how stale
+    // they may be.
+    //
+    fn write_allocations(&mut self, ctx: &Context, allocations: &[UsedPort], protocols: bool) {
+        self.reply(&format!("OK {}{}\n", allocations.len(), staleness(ctx)));
+        for aloc in allocations {
+            if protocols {
+                self.reply(&format!("{}\n", aloc));
+            } else {
+                self.reply(&format!(
+                    "{} {} {}\n",
+                    aloc.port(),
+                    quote(&aloc.service()),
+                    quote(&aloc.user())
+                ));
+            }
+        }
+    }
+    // Once the OK to a CONNECT has been sent, give the connection to the
+    // relay.  It's no longer ours.
+    //
+    pub(crate) fn hand_off(mut self, ctx: &Context) {
+        if let (Some(relays), Some(service)) = (&ctx.relays, self.relay.take()) {
+            relays.start(
+                self.client,
+                self.stream,
+                service,
+                self.input.take_buffered(),
+            );
+        }
+    }
+    // Start getting ready to be handed to another process (see
+    // takeover.rs):  hold further requests and withdraw a queued GIMME ...
+    // WAIT so that whoever serves us next can resume it.  We don't wait
+    // for the responder; once it's answered a later request a grant made
+    // before the withdrawal is waiting for us.
+    //
+    pub(crate) fn withdraw(&mut self, ctx: &Context) {
+        self.settling = true;
+        if let Some(receiver) = self.waiting.take() {
+            let client = self.client;
+            self.withdrawn = Some(receiver);
+            let _ = responder::queue_request(
+                |reply_chan| responder::RequestMessage::CancelWait { client, reply_chan },
+                None,
+                &ctx.requests,
+            );
+        }
+    }
+    // Whether the responder and the peers have answered everything we
+    // asked.
+    //
+    pub(crate) fn is_settled(&self) -> bool {
+        self.answers.is_empty() && self.gathering.is_none()
+    }
+    // Finish getting ready to be handed over, after withdraw and once the
+    // responder has answered a later request:  take a grant made before
+    // the withdrawal and send what we can.  The peers' answer to a LIST
+    // ALL or FIND ... ANYHOST we gave up waiting for is reported as
+    // failed.  Returns false if the connection failed or can't be handed
+    // over as the responder hasn't answered it.
+    //
+    pub(crate) fn settle(&mut self, ctx: &Context) -> bool {
+        self.settling = false;
+        self.check_wait(ctx);
+        if !self.answers.is_empty() {
+            return false;
+        }
+        if self.gathering.take().is_some() {
+            self.fail(&PortmanError::Internal(String::from(
+                "Lost contact with the peers",
+            )));
+        }
+        if let Some(receiver) = self.withdrawn.take() {
+            if let Ok(reply) = receiver.try_recv() {
+                self.queued = None;
+                self.granted(reply);
+            }
+        } else {
+            self.queued = None;
+        }
+        self.flush()
+    }
+    // Queue the GIMME ... WAIT that settle withdrew again, for what's
+    // left of its wait.
+    //
+    pub(crate) fn resume(&mut self, ctx: &Context) {
+        if self.closing {
+            return;
+        }
+        if let Some((line, deadline)) = self.queued.take() {
+            if let Ok(ClientRequest::Gimme(mut allocation)) = request::decode_request(&line) {
+                allocation.wait = Some(deadline.saturating_duration_since(Instant::now()));
+                self.create_allocation(&allocation, ctx);
+                if self.waiting.is_some() {
+                    self.queued = Some((line, deadline));
+                }
+            }
+        }
+    }
+    // Describe the connection for the process taking over from us.  The
+    // ports we hold are looked up in 'allocations' for their names.
+    //
+    pub(crate) fn handed(&self, allocations: &[UsedPort]) -> io::Result<Handed> {
+        let now = Instant::now();
+        let mut ports = Vec::new();
+        for used in allocations
+            .iter()
+            .filter(|a| self.ports.contains(&a.port()))
+        {
+            match self.transfers.get(&used.port()) {
+                Some(transfer) => ports.push((transfer.clone(), used.clone())),
+                None => log_warn!(
+                    client = self.client;
+                    "Port {} has no transfer token, not handing it over", used.port()
+                ),
+            }
+        }
+        Ok(Handed {
+            stream: self.stream.try_clone()?,
+            client: self.client,
+            peer: self.peer,
+            token: self.token.clone(),
+            requested: self.requested,
+            eof: self.eof,
+            ports,
+            input: self.input.buffered().to_vec(),
+            output: self.output.clone(),
+            queued: self
+                .queued
+                .as_ref()
+                .map(|(line, deadline)| (line.clone(), deadline.saturating_duration_since(now))),
+        })
+    }
+    // A connection handed to us by the process we took over from.  Its
+    // ports are restored and its queued request resumed by adopt.
+    //
+    pub(crate) fn from_handed(handed: Handed) -> Connection {
+        let mut conn = Connection::new(handed.stream, handed.client, handed.peer);
+        conn.token = handed.token;
+        conn.requested = handed.requested;
+        conn.eof = handed.eof;
+        conn.input.extend(&handed.input);
+        conn.output = handed.output;
+        conn.queued = handed.queued.map(|(line, wait)| (line, conn.active + wait));
+        conn
+    }
+    // Take back the ports 'used' we held in the process we took over from,
+    // each allocation with its transfer token.  If they can't all be
+    // restored the client is told and the connection closed:  its
+    // allocations are gone.
+    //
+    pub(crate) fn adopt(&mut self, ctx: &Context, used: Vec<(String, UsedPort)>) {
+        let mut allocations: BTreeMap<String, Vec<UsedPort>> = BTreeMap::new();
+        for (transfer, used) in used {
+            allocations.entry(transfer).or_default().push(used);
+        }
+        for (transfer, ports) in allocations {
+            let holder = self.holder(ctx).with_transfer(Some(transfer.clone()));
+            self.ask(ctx, Asked::Restore { transfer }, |reply_chan| {
+                responder::RequestMessage::RestorePorts {
+                    ports,
+                    holder,
+                    reply_chan,
+                }
+            });
+        }
+    }
+    // The connection is done: Give up any queued request, release the
+    // ports we hold and close the socket.
+    //
+    pub(crate) fn close(self, ctx: &Context) {
+        if self.waiting.is_some()
+            || self.withdrawn.is_some()
+            || !self.answers.is_empty()
+            || self.abandoned
+        {
+            // Withdraw from the queue and give back anything granted that
+            // we haven't seen, without waiting for the responder:
+
+            log_info!(client = self.client; "Closed, releasing everything held");
+            let _ = responder::leave(self.client, &ctx.requests);
+        } else if self.ports.is_empty() {
+            log_info!(client = self.client; "Closed");
+        } else {
+            log_info!(client = self.client; "Closed, releasing {:?}", self.ports);
+            let _ = responder::release_ports(self.client, self.ports, &ctx.requests);
+        }
+        let _ = self.stream.shutdown(net::Shutdown::Both);
+    }
+}
+
+// What's added to replies when we're a replica that's out of contact with
+// its primary:  how many seconds it is since we last heard from it.
+
+fn staleness(ctx: &Context) -> String {
+    match ctx.replica.as_ref().and_then(Replica::stale) {
+        Some(stale) => format!(" stale={}", stale.as_secs()),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,804 @@
+use super::connection::{Connection, Context};
+use super::federation::Federation;
+use super::relay::Relays;
+use super::replication::Replica;
+use super::server::Limits;
+use super::takeover::{self, Handed, HandoffListener, Link, PoolRanges};
+use crate::error::error::PortmanError;
+use crate::logging::audit::{Audit, Event, Record};
+use crate::metrics::metrics::Metrics;
+use crate::responder::responder;
+use crate::{log_info, log_warn};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::net;
+use std::net::{IpAddr, TcpListener};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+//
+// The server is a single event loop.  All sockets are non-blocking and
+// registered with a mio Poll:  the listener, every client connection and
+// a Waker the responder uses (through each Holder's notify function) to
+// tell us that a queued allocation request has been answered.  Each
+// connection only costs us its Connection struct and buffers rather
+// than a thread.  If another process may take over from us, so is the
+// handoff socket it connects to.
+//
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const HANDOFF: Token = Token(2);
+
+// Connection tokens are the client id offset past the fixed tokens.
+// The connection a CONNECT makes to its service uses its client's token
+// with this bit set.
+
+const FIRST_CLIENT: usize = 3;
+const CONNECTING: usize = 1 << (usize::BITS - 1);
+
+// How often connections are checked for timeouts.
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often the metrics textfile is rewritten.
+
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(15);
+
+///
+/// Control
+///    Shared by the event loop and whatever needs to get its attention from
+///    other threads:  the responder (to say a queued request was answered or
+///    that it has given up), a TERMINATE request and RunningServer::shutdown.
+///
+pub(crate) struct Control {
+    waker: Waker,
+    stop: AtomicBool,
+    failed: AtomicBool,
+    successor: Mutex<Option<Link>>, // The process we've handed over to.
+}
+
+impl Control {
+    pub(crate) fn wake(&self) {
+        let _ = self.waker.wake();
+    }
+    // Ask the event loop to close all connections and return.
+    //
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+    pub(crate) fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+    // The responder has given up; the event loop stops with an error.
+    //
+    pub(crate) fn fail(&self) {
+        self.failed.store(true, Ordering::SeqCst);
+        self.stop();
+    }
+    fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+    // Whether we've handed our connections to another process.
+    //
+    fn handed_over(&self) -> bool {
+        self.successor.lock().unwrap().is_some()
+    }
+    // Once we've finished stopping, let the process we handed over to
+    // know that it has the ports to itself.  Exiting does the same.
+    //
+    pub(crate) fn finish_handover(&self) {
+        self.successor.lock().unwrap().take();
+    }
+}
+
+// A handover waiting for the connections to settle (see
+// Connection::withdraw):  the successor, its process, the responder's
+// answer to the request for the allocations made after the withdrawals,
+// once it's arrived, and when we stop waiting.
+
+struct Handing {
+    link: Link,
+    pid: libc::pid_t,
+    allocations: mpsc::Receiver<responder::Reply>,
+    answer: Option<responder::Reply>,
+    deadline: Instant,
+}
+
+pub(crate) struct EventLoop {
+    poll: Poll,
+    listener: TcpListener,
+    connections: HashMap<Token, Connection>,
+    counted: usize,                      // Connections counted against the limits
+    per_address: HashMap<IpAddr, usize>, // and how many are from each address.
+    next_client: responder::ClientId,
+    limits: Limits,
+    next_sweep: Instant,
+    textfile: Option<(PathBuf, Instant)>, // Metrics file and when to next write it.
+    health: Option<(mpsc::Receiver<responder::Reply>, Instant)>, // Health for it, when asked.
+    handoff: Option<(HandoffListener, PoolRanges)>, // Where a successor connects and the pool it gets.
+    handing: Option<Handing>, // Handover waiting for the connections to settle.
+    ctx: Context,
+}
+
+impl EventLoop {
+    pub(crate) fn new(
+        listener: TcpListener,
+        requests: mpsc::Sender<responder::RequestMessage>,
+        limits: Limits,
+        audit: Audit,
+        metrics: Arc<Metrics>,
+        federation: Federation,
+    ) -> std::io::Result<EventLoop> {
+        let poll = Poll::new()?;
+        let control = Arc::new(Control {
+            waker: Waker::new(poll.registry(), WAKER)?,
+            stop: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+            successor: Mutex::new(None),
+        });
+        listener.set_nonblocking(true)?;
+        poll.registry().register(
+            &mut SourceFd(&listener.as_raw_fd()),
+            LISTENER,
+            Interest::READABLE,
+        )?;
+        Ok(EventLoop {
+            poll,
+            listener,
+            connections: HashMap::new(),
+            counted: 0,
+            per_address: HashMap::new(),
+            next_client: 0,
+            limits,
+            next_sweep: Instant::now() + SWEEP_INTERVAL,
+            textfile: None,
+            health: None,
+            handing: None,
+            handoff: None,
+            ctx: Context {
+                requests,
+                control,
+                audit,
+                metrics,
+                relays: None,
+                federation,
+                replica: None,
+                reclaims: false,
+            },
+        })
+    }
+    // Let clients CONNECT to services through 'relays'.
+    //
+    pub(crate) fn with_relays(mut self, relays: Relays) -> EventLoop {
+        self.ctx.relays = Some(relays);
+        self
+    }
+    // Answer LIST and FIND from 'replica' and refuse GIMME.
+    //
+    pub(crate) fn with_replica(mut self, replica: Replica) -> EventLoop {
+        self.ctx.replica = Some(replica);
+        self
+    }
+    // Give holders tokens with which to reclaim their ports after a restart.
+    //
+    pub(crate) fn with_reclaims(mut self) -> EventLoop {
+        self.ctx.reclaims = true;
+        self
+    }
+    // Also write the metrics to 'path' every so often.
+    //
+    pub(crate) fn with_textfile(mut self, path: PathBuf) -> EventLoop {
+        self.textfile = Some((path, Instant::now()));
+        self
+    }
+    // Let another process take over from us through 'listener', handing it
+    // a pool with 'ranges'.
+    //
+    pub(crate) fn with_handoff(
+        mut self,
+        listener: HandoffListener,
+        ranges: PoolRanges,
+    ) -> std::io::Result<EventLoop> {
+        self.poll.registry().register(
+            &mut SourceFd(&listener.as_raw_fd()),
+            HANDOFF,
+            Interest::READABLE,
+        )?;
+        self.handoff = Some((listener, ranges));
+        Ok(self)
+    }
+    // Serve the connections handed to us by the process we took over
+    // from, giving them back their ports and then, in the order the
+    // clients connected, their queued requests.  The responder must be
+    // running.  New clients get ids from 'next_client'.
+    //
+    pub(crate) fn adopt(&mut self, connections: Vec<Handed>, next_client: responder::ClientId) {
+        self.next_client = next_client;
+        let mut tokens = Vec::new();
+        for mut handed in connections {
+            let token = Token(handed.client as usize + FIRST_CLIENT);
+            let ports = std::mem::take(&mut handed.ports);
+            let mut conn = Connection::from_handed(handed);
+            if conn.stream.set_nonblocking(true).is_err()
+                || self
+                    .poll
+                    .registry()
+                    .register(
+                        &mut SourceFd(&conn.stream.as_raw_fd()),
+                        token,
+                        Interest::READABLE,
+                    )
+                    .is_err()
+            {
+                continue;
+            }
+            conn.adopt(&self.ctx, ports);
+            log_info!(client = conn.client; "Taken over, connected from {}", conn.peer);
+            self.ctx.metrics.connected();
+            self.count(conn.peer.ip());
+            self.connections.insert(token, conn);
+            tokens.push(token);
+        }
+        tokens.sort();
+        for token in &tokens {
+            if let Some(conn) = self.connections.get_mut(token) {
+                conn.resume(&self.ctx);
+            }
+        }
+        for token in tokens {
+            self.service(token, true);
+        }
+    }
+    pub(crate) fn control(&self) -> Arc<Control> {
+        Arc::clone(&self.ctx.control)
+    }
+    // Serve connections until asked to stop.  Then all connections are
+    // closed, which releases their ports, and the responder is told to exit.
+    // If we've handed the connections to another process they're left open
+    // and their ports are not released.
+    //
+    pub(crate) fn run(mut self) -> Result<(), PortmanError> {
+        let mut events = Events::with_capacity(1024);
+        let mut result = Ok(());
+
+        while !self.ctx.control.stopping() {
+            let timeout = self.next_sweep.saturating_duration_since(Instant::now());
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                result = Err(PortmanError::Io(format!("Event loop failed: {}", e)));
+                break;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.check_waiters(),
+                    HANDOFF => self.accept_successor(),
+                    token if token.0 & CONNECTING != 0 => {
+                        self.connected(Token(token.0 & !CONNECTING), Instant::now())
+                    }
+                    token => self.service(token, event.is_readable()),
+                }
+            }
+            if Instant::now() >= self.next_sweep {
+                self.sweep();
+                self.next_sweep = Instant::now() + SWEEP_INTERVAL;
+            }
+            self.check_handing();
+        }
+        if self.ctx.control.handed_over() {
+            self.connections.clear();
+        }
+        let tokens: Vec<Token> = self.connections.keys().copied().collect();
+        for token in tokens {
+            self.close(token);
+        }
+        let _ = self.ctx.requests.send(responder::RequestMessage::Terminate);
+
+        if self.ctx.control.failed() {
+            result = Err(PortmanError::Internal(String::from(
+                "The port pool responder has stopped",
+            )));
+        }
+        result
+    }
+    // Accept all pending connections.  Connections over the limits
+    // are told so and closed.
+    //
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, peer)) => {
+                    if let Err(msg) = self.check_limits(peer.ip()) {
+                        log_warn!("Refused connection from {}: {}", peer, msg);
+                        self.ctx
+                            .audit
+                            .record(&Record::rejected(&msg).with_peer(Some(peer)));
+                        let _ = stream.set_nonblocking(true);
+                        let _ = stream.write_all(msg.reply().as_bytes());
+                        let _ = stream.shutdown(net::Shutdown::Both);
+                        continue;
+                    }
+                    let client = self.next_client;
+                    self.next_client += 1;
+                    let token = Token(client as usize + FIRST_CLIENT);
+                    if stream.set_nonblocking(true).is_err()
+                        || self
+                            .poll
+                            .registry()
+                            .register(
+                                &mut SourceFd(&stream.as_raw_fd()),
+                                token,
+                                Interest::READABLE,
+                            )
+                            .is_err()
+                    {
+                        continue;
+                    }
+                    log_info!(client = client; "Connected from {}", peer);
+                    self.ctx.metrics.connected();
+                    self.count(peer.ip());
+                    let mut conn = Connection::new(stream, client, peer);
+                    conn.settling = self.handing.is_some();
+                    self.connections.insert(token, conn);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return, // e.g. out of file descriptors - try again later.
+            }
+        }
+    }
+    // A process connected to the handoff socket to take over from us.
+    // It must be run by our user (or root).
+    //
+    fn accept_successor(&mut self) {
+        loop {
+            let accepted = match &self.handoff {
+                Some((listener, _)) => listener.accept(),
+                None => return,
+            };
+            let link = match accepted {
+                Ok(Some(link)) => link,
+                Ok(None) => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            let uid = unsafe { libc::geteuid() };
+            match link.peer() {
+                Ok((pid, _)) if self.handing.is_some() => {
+                    log_warn!(
+                        "Refused a takeover by process {}: already handing over",
+                        pid
+                    )
+                }
+                Ok((pid, peer_uid)) if peer_uid == uid || peer_uid == 0 => {
+                    self.start_hand_over(link, pid)
+                }
+                Ok((pid, _)) => log_warn!("Refused a takeover by process {} of another user", pid),
+                Err(e) => log_warn!("Refused a takeover: {}", e),
+            }
+        }
+    }
+    // Start handing our listener and connections to the process at the
+    // other end of 'link'.  The connections hold their requests and
+    // withdraw those queued, and then one request for the allocations
+    // tells us when the responder has dealt with them all.  Meanwhile we
+    // carry on serving, see check_handing.
+    //
+    fn start_hand_over(&mut self, link: Link, pid: libc::pid_t) {
+        log_info!("Handing over to process {}", pid);
+        for conn in self.connections.values_mut() {
+            conn.withdraw(&self.ctx);
+        }
+        let control = Arc::clone(&self.ctx.control);
+        let notify: responder::Notify = Arc::new(move || control.wake());
+        let asked = responder::queue_request(
+            responder::RequestMessage::ListAllocations,
+            Some(notify),
+            &self.ctx.requests,
+        );
+        let (allocations, answer) = match asked {
+            Ok(receiver) => (receiver, None),
+            Err(e) => (mpsc::channel().1, Some(Err(e))),
+        };
+        self.handing = Some(Handing {
+            link,
+            pid,
+            allocations,
+            answer,
+            deadline: Instant::now() + responder::REPLY_TIMEOUT,
+        });
+    }
+    // Hand over once the responder has answered the handover's request
+    // and the connections have had the answers to theirs, or we've waited
+    // long enough.
+    //
+    fn check_handing(&mut self) {
+        let handing = match &mut self.handing {
+            Some(handing) => handing,
+            None => return,
+        };
+        if handing.answer.is_none() {
+            handing.answer = match handing.allocations.try_recv() {
+                Ok(reply) => Some(reply),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => Some(Err(PortmanError::Internal(
+                    String::from("Lost contact with the port pool"),
+                ))),
+            };
+        }
+        let answered = handing.answer.is_some();
+        let overdue = Instant::now() >= handing.deadline;
+        if answered && !overdue {
+            self.check_waiters();
+        }
+        let settled = answered && self.connections.values().all(Connection::is_settled);
+        if !(settled || overdue) {
+            return;
+        }
+        let handing = self.handing.take().unwrap();
+        let answer = handing
+            .answer
+            .unwrap_or_else(|| Err(mpsc::RecvTimeoutError::Timeout.into()));
+        self.hand_over(handing.link, handing.pid, answer);
+    }
+    // Hand our listener and connections to the process at the other end
+    // of 'link' and stop.  'allocations' is the responder's answer to the
+    // handover's request.  Connections that are about to close or be
+    // relayed are closed first, as are those that haven't settled or that
+    // have too much output to hand over.  If the handover fails we carry
+    // on.
+    //
+    fn hand_over(&mut self, link: Link, pid: libc::pid_t, allocations: responder::Reply) {
+        let mut tokens: Vec<Token> = self.connections.keys().copied().collect();
+        tokens.sort();
+        for token in &tokens {
+            let conn = self.connections.get_mut(token).unwrap();
+            let alive = conn.settle(&self.ctx);
+            if !alive
+                || conn.closing
+                || conn.connecting.is_some()
+                || conn.relay.is_some()
+                || (conn.eof && conn.output.is_empty())
+            {
+                self.close(*token);
+            }
+        }
+        let mut result = responder::decode_allocations_reply(allocations)
+            .map_err(|e| std::io::Error::other(e.to_string()));
+        let mut handed = Vec::new();
+        if let Ok(allocations) = &result {
+            for token in &tokens {
+                let conn = match self.connections.get(token) {
+                    Some(conn) => conn,
+                    None => continue,
+                };
+                match conn.handed(allocations) {
+                    Ok(h) if h.fits() => handed.push(h),
+                    Ok(_) => {
+                        log_warn!(client = conn.client; "Too much output to hand over, closing");
+                        self.close(*token);
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+        }
+        let result = result.and_then(|_| {
+            let ranges = self.handoff.as_ref().map(|(_, ranges)| *ranges).unwrap();
+            takeover::hand_over(&link, ranges, self.next_client, &self.listener, &handed)
+        });
+        match result {
+            Ok(()) => {
+                log_info!(
+                    "Handed {} connections over to process {}",
+                    self.connections.len(),
+                    pid
+                );
+                self.ctx.audit.record(&Record::new(
+                    Event::Admin,
+                    format!("handed over to process {}", pid),
+                ));
+                *self.ctx.control.successor.lock().unwrap() = Some(link);
+                self.ctx.control.stop();
+            }
+            Err(e) => {
+                log_warn!("Unable to hand over to process {}: {}", pid, e);
+                let mut tokens: Vec<Token> = self.connections.keys().copied().collect();
+                tokens.sort();
+                for token in tokens {
+                    if let Some(conn) = self.connections.get_mut(&token) {
+                        conn.resume(&self.ctx);
+                    }
+                    self.service(token, false);
+                }
+            }
+        }
+    }
+    fn check_limits(&self, peer: IpAddr) -> Result<(), PortmanError> {
+        if let Some(max) = self.limits.max_connections {
+            if self.counted >= max {
+                return Err(PortmanError::QuotaExceeded(String::from(
+                    "Too many connections",
+                )));
+            }
+        }
+        if let Some(max) = self.limits.max_per_address {
+            if self.per_address.get(&peer).copied().unwrap_or(0) >= max {
+                return Err(PortmanError::QuotaExceeded(format!(
+                    "Too many connections from {}",
+                    peer
+                )));
+            }
+        }
+        Ok(())
+    }
+    // Fail connections that have timed out.  If one we've already
+    // failed still hasn't taken the reply after another timeout period
+    // it's just closed.  We also stop if a TERMINATE's replies haven't
+    // been taken in time, time out CONNECTs and look for replies in case
+    // a wakeup was missed.
+    //
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        self.write_textfile(now);
+        if self
+            .connections
+            .values()
+            .any(|c| c.terminating.is_some_and(|d| d <= now))
+        {
+            self.ctx.control.stop();
+        }
+        let connecting: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.connecting.is_some())
+            .map(|(t, _)| *t)
+            .collect();
+        for token in connecting {
+            self.connected(token, now);
+        }
+        self.check_waiters();
+        let overdue: Vec<Token> = self
+            .connections
+            .iter_mut()
+            .filter_map(|(t, c)| if c.check_overdue(now) { Some(*t) } else { None })
+            .collect();
+        for token in overdue {
+            self.service(token, false);
+        }
+        let expired: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.deadline(&self.limits).is_some_and(|d| d <= now))
+            .map(|(t, _)| *t)
+            .collect();
+        for token in expired {
+            let conn = self.connections.get_mut(&token).unwrap();
+            if conn.closing {
+                self.close(token);
+            } else {
+                let msg = if conn.requested {
+                    "Idle timeout"
+                } else {
+                    "Timed out waiting for a request"
+                };
+                conn.fail(&PortmanError::TimedOut(String::from(msg)));
+                conn.active = now;
+                self.service(token, false);
+            }
+        }
+    }
+    // Rewrite the metrics textfile if it's time.  We ask the responder
+    // for its health and write the file at a later sweep, once it's
+    // answered or given up on.  A failure is logged and we try again next
+    // time.
+    //
+    fn write_textfile(&mut self, now: Instant) {
+        let (path, due) = match &mut self.textfile {
+            Some(textfile) => textfile,
+            None => return,
+        };
+        let health = match &self.health {
+            Some((receiver, asked)) => match receiver.try_recv() {
+                Ok(reply) => responder::decode_health_reply(reply).ok(),
+                Err(mpsc::TryRecvError::Empty) if now < *asked + responder::REPLY_TIMEOUT => return,
+                Err(_) => None,
+            },
+            None => {
+                if now >= *due {
+                    *due = now + TEXTFILE_INTERVAL;
+                    let asked = responder::queue_request(
+                        responder::RequestMessage::Health,
+                        None,
+                        &self.ctx.requests,
+                    );
+                    self.health = asked.ok().map(|receiver| (receiver, now));
+                }
+                return;
+            }
+        };
+        self.health = None;
+        if let Err(e) = self.ctx.metrics.write_textfile(path, health.as_ref()) {
+            log_warn!("Unable to write {}: {}", path.display(), e);
+        }
+    }
+    // The responder answered one or more queued requests or the peers
+    // were asked.
+    //
+    fn check_waiters(&mut self) {
+        let waiting: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.is_waiting())
+            .map(|(t, _)| *t)
+            .collect();
+        for token in waiting {
+            self.service(token, false);
+        }
+    }
+    // The connection a CONNECT is making to its service may have completed
+    // or failed, or by 'now' timed out.
+    //
+    fn connected(&mut self, token: Token, now: Instant) {
+        if let Some(conn) = self.connections.get_mut(&token) {
+            let fd = match &conn.connecting {
+                Some(connecting) => connecting.stream.as_raw_fd(),
+                None => return,
+            };
+            conn.check_connect(&self.ctx, now);
+
+            // A stream that failed to connect has been closed, which
+            // deregisters it:
+
+            if conn.relay.is_some() {
+                let _ = self.poll.registry().deregister(&mut SourceFd(&fd));
+            }
+        }
+        self.service(token, false);
+    }
+    // Do whatever a connection needs: read, process requests, write
+    // replies and close it if it's done.
+    //
+    fn service(&mut self, token: Token, readable: bool) {
+        let mut conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut alive = !readable || conn.read(&self.ctx);
+        conn.check_wait(&self.ctx);
+        conn.process(&self.ctx);
+        if !conn.adopted.is_empty() {
+            let (client, adopted) = (conn.client, std::mem::take(&mut conn.adopted));
+            self.disown(client, adopted);
+            conn = self.connections.get_mut(&token).unwrap();
+        }
+        if let Some(connecting) = &mut conn.connecting {
+            if !connecting.registered {
+                connecting.registered = true;
+                let _ = self.poll.registry().register(
+                    &mut SourceFd(&connecting.stream.as_raw_fd()),
+                    Token(token.0 | CONNECTING),
+                    Interest::WRITABLE,
+                );
+            }
+        }
+        alive = alive && conn.flush();
+        if alive && conn.relay.is_some() && conn.output.is_empty() {
+            if let Some(conn) = self.remove(token) {
+                conn.hand_off(&self.ctx);
+            }
+            return;
+        }
+        // A client that's sent its last request may still be owed the
+        // peers' answer to it:
+
+        let done =
+            conn.closing || (conn.eof && conn.gathering.is_none() && conn.answers.is_empty());
+        if !alive || (done && conn.output.is_empty()) {
+            self.close(token);
+            return;
+        }
+
+        // Only ask to hear about writability while there's output pending:
+
+        let want_write = !conn.output.is_empty();
+        if want_write != conn.writable {
+            let interest = if want_write {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            conn.writable = want_write;
+            let _ = self.poll.registry().reregister(
+                &mut SourceFd(&conn.stream.as_raw_fd()),
+                token,
+                interest,
+            );
+        }
+        self.recount(token);
+    }
+    // Connections that hold ports aren't counted against the connection
+    // limits:  one local address may hold as many allocations as the pool
+    // has.  A connection is counted again if it gives up all its ports.
+    //
+    fn recount(&mut self, token: Token) {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let counted = conn.ports.is_empty();
+        if counted != conn.counted {
+            conn.counted = counted;
+            let peer = conn.peer.ip();
+            if counted {
+                self.count(peer);
+            } else {
+                self.uncount(peer);
+            }
+        }
+    }
+    fn count(&mut self, peer: IpAddr) {
+        self.counted += 1;
+        *self.per_address.entry(peer).or_insert(0) += 1;
+    }
+    fn uncount(&mut self, peer: IpAddr) {
+        self.counted -= 1;
+        if let Some(count) = self.per_address.get_mut(&peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_address.remove(&peer);
+            }
+        }
+    }
+    // Tell the connections that 'client' adopted allocations from that
+    // their ports are no longer theirs.
+    //
+    fn disown(
+        &mut self,
+        client: responder::ClientId,
+        adopted: Vec<(responder::ClientId, Vec<u16>)>,
+    ) {
+        for (from, ports) in adopted {
+            let token = Token(from as usize + FIRST_CLIENT);
+            if let Some(conn) = self.connections.get_mut(&token) {
+                conn.disown(&ports, client);
+            }
+            self.recount(token);
+        }
+    }
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.remove(token) {
+            conn.close(&self.ctx);
+        }
+    }
+    // Stop serving a connection, which is to be closed or relayed.  If it
+    // asked us to TERMINATE we stop now whether or not it got its replies.
+    //
+    fn remove(&mut self, token: Token) -> Option<Connection> {
+        let conn = self.connections.remove(&token)?;
+        if conn.terminating.is_some() {
+            self.ctx.control.stop();
+        }
+        if conn.counted {
+            self.uncount(conn.peer.ip());
+        }
+        let _ = self
+            .poll
+            .registry()
+            .deregister(&mut SourceFd(&conn.stream.as_raw_fd()));
+        self.ctx.metrics.disconnected();
+        Some(conn)
+    }
+}
@@ -0,0 +1,745 @@
+use crate::dns::mdns::bind_shared;
+use crate::error::error::PortmanError;
+use crate::portpool::ports::UsedPort;
+use crate::protocol::request::quote;
+use crate::{log_debug, log_info};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Federation:  LIST ALL and FIND ... ANYHOST ask the other portman
+// instances we know of as well as ourselves.  The peers are those given
+// with --peer and those heard from by the beacon.  Each peer is asked
+// with a LIST PROTOCOL or FIND (so the question goes no further) over its
+// own connection, up to MAX_ASKING of them at once, and a peer that
+// hasn't answered in time is reported as failed rather than holding up
+// the reply.  The asking is done in a thread of its own so the event loop
+// keeps serving other clients; the connection waits for the reply as a
+// queued GIMME does.  No more than MAX_GATHERS are done at once; requests
+// beyond that are refused.  Configured peers' names are resolved when the
+// server starts so that asking never waits for the resolver.
+//
+// Results are tagged with the host they came from:  its name and, if it
+// isn't listening on the default port, the port, e.g. daq2 or daq2:30100.
+//
+// The beacon is a UDP datagram each instance sends every 10 seconds,
+// usually to the LAN's broadcast address:
+//
+//    PORTMAN nonce listen-port host-name
+//
+// Instances that hear it ask the sender's address at that port.  The
+// nonce lets an instance ignore its own beacon.  Peers not heard from for
+// three intervals are forgotten, and no more than MAX_HEARD are remembered
+// so that a flood of beacons can't make us ask ever more hosts.
+
+///
+/// How long a peer has to answer.
+///
+pub(crate) const PEER_TIMEOUT: Duration = Duration::from_secs(2);
+
+///
+/// The port portman listens on by default, which host tags leave out.
+///
+pub(crate) const DEFAULT_PORT: u16 = 30000;
+
+const BEACON_INTERVAL: Duration = Duration::from_secs(10);
+const BEACON_LIFETIME: Duration = Duration::from_secs(30);
+const MAX_HEARD: usize = 256;
+
+// The most peers a gather asks at once, each from a thread of its own,
+// and the most gathers done at once.
+
+const MAX_ASKING: usize = 16;
+const MAX_GATHERS: usize = 8;
+
+///
+/// Question
+///    What to ask each host.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Question {
+    List,
+    Find { service: String, user: String },
+}
+
+impl Question {
+    fn request(&self) -> String {
+        match self {
+            Question::List => String::from("LIST PROTOCOL\n"),
+            Question::Find { service, user } => {
+                format!("FIND {} {}\n", quote(service), quote(user))
+            }
+        }
+    }
+    fn wants(&self, used: &UsedPort) -> bool {
+        match self {
+            Question::List => true,
+            Question::Find { service, user } => used.service() == *service && used.user() == *user,
+        }
+    }
+}
+
+///
+/// tag
+///    The tag for a host:  its name and its port, unless it's the default.
+///
+pub(crate) fn tag(host: &str, port: u16) -> String {
+    if port == DEFAULT_PORT {
+        String::from(host)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// The peers the beacon has heard from, by tag:  their address and when.
+
+type Heard = Arc<Mutex<BTreeMap<String, (SocketAddr, Instant)>>>;
+
+///
+/// resolve
+///    The tag and address of a configured *peer* (host or host:port).
+///
+pub(crate) fn resolve(peer: &str) -> Result<(String, SocketAddr), PortmanError> {
+    let address = if peer.parse::<SocketAddr>().is_ok() || has_port(peer) {
+        String::from(peer)
+    } else {
+        format!("{}:{}", peer, DEFAULT_PORT)
+    };
+    let unresolved = |e: &dyn std::fmt::Display| {
+        PortmanError::Invalid(format!("Unable to resolve peer {}: {}", peer, e))
+    };
+    let address = address
+        .to_socket_addrs()
+        .map_err(|e| unresolved(&e))?
+        .next()
+        .ok_or_else(|| unresolved(&"no address"))?;
+    Ok((String::from(peer), address))
+}
+
+///
+/// Federation
+///    The peers of the instance tagged *host*:  the configured *peers*
+/// (tag and address, see resolve) and those the beacon hears from.
+///
+#[derive(Clone)]
+pub(crate) struct Federation {
+    host: String,
+    peers: Vec<(String, SocketAddr)>,
+    heard: Heard,
+    gathers: Arc<AtomicUsize>, // Gathers in progress.
+    timeout: Duration,
+}
+
+// Counts a gather in progress while it's alive.
+
+struct Gathering(Arc<AtomicUsize>);
+
+impl Drop for Gathering {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Federation {
+    pub(crate) fn new(host: &str, peers: Vec<(String, SocketAddr)>) -> Federation {
+        Federation {
+            host: String::from(host),
+            peers,
+            heard: Arc::new(Mutex::new(BTreeMap::new())),
+            gathers: Arc::new(AtomicUsize::new(0)),
+            timeout: PEER_TIMEOUT,
+        }
+    }
+    #[cfg(test)]
+    pub(crate) fn with_timeout(mut self, timeout: Duration) -> Federation {
+        self.timeout = timeout;
+        self
+    }
+    // The peers to ask:  their tags and addresses.  A configured peer's
+    // tag is as given; one the beacon heard of that's also configured is
+    // only asked once.
+
+    fn peers(&self) -> Vec<(String, SocketAddr)> {
+        let mut peers = self.peers.clone();
+        let heard = self.heard.lock().unwrap_or_else(|e| e.into_inner());
+        for (tag, (address, when)) in heard.iter() {
+            if when.elapsed() < BEACON_LIFETIME && !peers.iter().any(|(t, _)| t == tag) {
+                peers.push((tag.clone(), *address));
+            }
+        }
+        peers
+    }
+    ///
+    /// gather
+    ///    Ask every peer *question* and return a receiver that gets the
+    ///    reply for the client:  *local* (our own allocations) and what the
+    ///    peers said.  *notify* is called once the reply has been sent.
+    ///    Fails if MAX_GATHERS are already in progress.
+    ///
+    pub(crate) fn gather<F>(
+        &self,
+        question: Question,
+        local: &[UsedPort],
+        notify: F,
+    ) -> Result<mpsc::Receiver<String>, PortmanError>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let gathering = Gathering(Arc::clone(&self.gathers));
+        if self.gathers.fetch_add(1, Ordering::SeqCst) >= MAX_GATHERS {
+            return Err(PortmanError::QuotaExceeded(String::from(
+                "Too many requests are asking the peers; try again later",
+            )));
+        }
+        let mut results = vec![(
+            self.host.clone(),
+            Ok(local
+                .iter()
+                .filter(|a| question.wants(a))
+                .map(|a| a.to_string())
+                .collect()),
+        )];
+        let peers = self.peers();
+        let deadline = Instant::now() + self.timeout;
+        let (send, receive) = mpsc::channel();
+        thread::spawn(move || {
+            let _gathering = gathering;
+            let request = question.request();
+            let mut answers: Vec<Option<Result<Vec<String>, String>>> = vec![None; peers.len()];
+            let next = AtomicUsize::new(0);
+            let (answered, answered_receiver) = mpsc::channel();
+            thread::scope(|scope| {
+                let askers: Vec<_> = (0..peers.len().min(MAX_ASKING))
+                    .map(|_| {
+                        let (peers, request, next) = (&peers, &request, &next);
+                        let answered = answered.clone();
+                        scope.spawn(move || loop {
+                            let i = next.fetch_add(1, Ordering::SeqCst);
+                            let (_, address) = match peers.get(i) {
+                                Some(peer) => peer,
+                                None => break,
+                            };
+                            let _ = answered.send((i, ask(address, request, deadline)));
+                        })
+                    })
+                    .collect();
+                drop(answered);
+                for asker in askers {
+                    let _ = asker.join(); // One that panicked leaves its peer failed.
+                }
+            });
+            for (i, answer) in answered_receiver.try_iter() {
+                answers[i] = Some(answer);
+            }
+            for ((tag, _), answer) in peers.into_iter().zip(answers) {
+                let answer = answer.unwrap_or_else(|| Err(String::from("failed")));
+                if let Err(e) = &answer {
+                    log_info!("Peer {} didn't answer: {}", tag, e);
+                }
+                results.push((tag, answer));
+            }
+            let _ = send.send(reply(&question, &results));
+            notify();
+        });
+        Ok(receive)
+    }
+}
+
+// Whether a peer as given includes a port.
+
+fn has_port(peer: &str) -> bool {
+    match peer.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty() && !host.ends_with(':') && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+// Ask the peer at 'address' a LIST or FIND request, which it must have
+// answered by 'deadline'.  The result is the allocation lines of its
+// reply; a FIND that matches nothing has none.
+
+fn ask(address: &SocketAddr, request: &str, deadline: Instant) -> Result<Vec<String>, String> {
+    let remaining = || {
+        deadline
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| String::from("timed out"))
+    };
+    let stream = TcpStream::connect_timeout(address, remaining()?).map_err(|e| e.to_string())?;
+    stream
+        .set_write_timeout(Some(remaining()?))
+        .map_err(|e| e.to_string())?;
+    (&stream)
+        .write_all(request.as_bytes())
+        .map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(&stream);
+    let mut read_line = || -> Result<String, String> {
+        stream
+            .set_read_timeout(Some(remaining()?))
+            .map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => Err(String::from("closed the connection")),
+            Ok(_) => Ok(String::from(line.trim_end_matches(&['\r', '\n'][..]))),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                Err(String::from("timed out"))
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    };
+    let first = read_line()?;
+    let count = match first.strip_prefix("OK ") {
+        Some(count) => count.parse::<usize>().map_err(|_| first.clone())?,
+        None if first.starts_with("FAIL E_NOT_FOUND") => return Ok(Vec::new()),
+        None => return Err(first),
+    };
+    (0..count).map(|_| read_line()).collect()
+}
+
+// The reply to the client given each host's results:  the count of the
+// lines that follow and the hosts that failed, then a line for each
+// allocation tagged with its host.  A FIND that matched nothing anywhere
+// gets the FAIL a FIND does.
+
+fn reply(question: &Question, results: &[(String, Result<Vec<String>, String>)]) -> String {
+    let mut lines = Vec::new();
+    let mut failed = Vec::new();
+    for (tag, result) in results {
+        match result {
+            Ok(allocations) => {
+                lines.extend(allocations.iter().map(|a| format!("{} {}", tag, a)));
+            }
+            Err(_) => failed.push(tag.as_str()),
+        }
+    }
+    if let Question::Find { service, user } = question {
+        if lines.is_empty() {
+            return PortmanError::NotAdvertised {
+                service: service.clone(),
+                user: user.clone(),
+            }
+            .reply();
+        }
+    }
+    let mut reply = format!("OK {}", lines.len());
+    if !failed.is_empty() {
+        reply.push_str(&format!(" failed={}", failed.join(",")));
+    }
+    reply.push('\n');
+    for line in lines {
+        reply.push_str(&line);
+        reply.push('\n');
+    }
+    reply
+}
+
+///
+/// BeaconStop
+///    Stops a Beacon from another thread.
+///
+pub(crate) struct BeaconStop {
+    stop: AtomicBool,
+    waker: Waker,
+}
+
+impl BeaconStop {
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+const SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+
+///
+/// Beacon
+///    Sends our beacon and listens for those of our peers, which it adds to
+/// a Federation's.
+///
+pub(crate) struct Beacon {
+    poll: Poll,
+    socket: UdpSocket,
+    send_to: SocketAddr,
+    message: String,
+    nonce: String,
+    heard: Heard,
+    stop: Arc<BeaconStop>,
+}
+
+impl Beacon {
+    ///
+    /// bind
+    ///    Listen for beacons on *listen_port*, which other instances on
+    ///    this host may share, and send ours, saying we listen on *port* of
+    ///    host *host*, to *send_to*.  Peers heard from are added to
+    ///    *federation*.
+    ///
+    pub(crate) fn bind(
+        listen_port: u16,
+        send_to: SocketAddr,
+        host: &str,
+        port: u16,
+        federation: &Federation,
+    ) -> io::Result<Beacon> {
+        let socket = bind_shared(listen_port)?;
+        socket.set_broadcast(true)?;
+        let poll = Poll::new()?;
+        poll.registry().register(
+            &mut SourceFd(&socket.as_raw_fd()),
+            SOCKET,
+            Interest::READABLE,
+        )?;
+        let stop = Arc::new(BeaconStop {
+            stop: AtomicBool::new(false),
+            waker: Waker::new(poll.registry(), WAKER)?,
+        });
+        let nonce = format!(
+            "{:x}{:x}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0)
+        );
+        Ok(Beacon {
+            poll,
+            socket,
+            send_to,
+            message: format!("PORTMAN {} {} {}", nonce, port, host),
+            nonce,
+            heard: Arc::clone(&federation.heard),
+            stop,
+        })
+    }
+    pub(crate) fn stopper(&self) -> Arc<BeaconStop> {
+        Arc::clone(&self.stop)
+    }
+    ///
+    /// Beacon and listen until stopped.
+    ///
+    pub(crate) fn run(mut self) {
+        let mut events = Events::with_capacity(16);
+        let mut buffer = [0u8; 512];
+        let mut next = Instant::now();
+        while !self.stop.stop.load(Ordering::SeqCst) {
+            if Instant::now() >= next {
+                if let Err(e) = self.socket.send_to(self.message.as_bytes(), self.send_to) {
+                    log_debug!("Unable to send the beacon to {}: {}", self.send_to, e);
+                }
+                next = Instant::now() + BEACON_INTERVAL;
+            }
+            let timeout = next.saturating_duration_since(Instant::now());
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+            loop {
+                match self.socket.recv_from(&mut buffer) {
+                    Ok((n, from)) => self.heard(&buffer[..n], from),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => break, // WouldBlock or e.g. ICMP unreachable.
+                }
+            }
+        }
+    }
+    // Remember the peer whose beacon came from 'from', forgetting those
+    // we've stopped hearing from.
+
+    fn heard(&self, beacon: &[u8], from: SocketAddr) {
+        let beacon = String::from_utf8_lossy(beacon);
+        let words: Vec<&str> = beacon.split_ascii_whitespace().collect();
+        if let ["PORTMAN", nonce, port, host] = words.as_slice() {
+            if *nonce == self.nonce {
+                return;
+            }
+            if let Ok(port) = port.parse::<u16>() {
+                let tag = tag(host, port);
+                let mut heard = self.heard.lock().unwrap_or_else(|e| e.into_inner());
+                heard.retain(|_, (_, when)| when.elapsed() < BEACON_LIFETIME);
+                if !heard.contains_key(&tag) {
+                    if heard.len() >= MAX_HEARD {
+                        log_debug!("Ignoring portman peer {}: heard from too many", tag);
+                        return;
+                    }
+                    log_info!("Heard from portman peer {} at {}", tag, from.ip());
+                }
+                heard.insert(tag, (SocketAddr::new(from.ip(), port), Instant::now()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portpool::ports::Protocol;
+    use std::net::TcpListener;
+
+    fn local() -> Vec<UsedPort> {
+        vec![
+            UsedPort::new(31000, "daq", "fox"),
+            UsedPort::with_protocol(31001, "my ring", "owl", Protocol::Udp),
+        ]
+    }
+    // A peer that answers one request with 'reply'.
+
+    fn peer(reply: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            (&stream).write_all(reply.as_bytes()).unwrap();
+        });
+        address
+    }
+    fn resolved(peers: Vec<String>) -> Vec<(String, SocketAddr)> {
+        peers.iter().map(|peer| resolve(peer).unwrap()).collect()
+    }
+
+    #[test]
+    fn tags_and_peers() {
+        assert_eq!("daq1", tag("daq1", 30000));
+        assert_eq!("daq1:30100", tag("daq1", 30100));
+        assert!(has_port("daq1:30100"));
+        assert!(!has_port("daq1"));
+        assert!(!has_port("::1"));
+        let address = |a: &str| a.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            Ok((String::from("127.0.0.1"), address("127.0.0.1:30000"))),
+            resolve("127.0.0.1")
+        );
+        assert_eq!(30000, resolve("localhost").unwrap().1.port());
+        assert_eq!(
+            Ok((String::from("[::1]:30200"), address("[::1]:30200"))),
+            resolve("[::1]:30200")
+        );
+        assert_eq!(
+            "E_INVALID",
+            resolve("no.such.host.invalid").unwrap_err().code()
+        );
+        let federation = Federation::new(
+            "daq1",
+            vec![
+                (String::from("daq2"), address("10.0.0.12:30000")),
+                (String::from("daq3:30100"), address("10.0.0.3:30100")),
+            ],
+        );
+        federation.heard.lock().unwrap().insert(
+            String::from("daq4"),
+            ("10.0.0.4:30000".parse().unwrap(), Instant::now()),
+        );
+        federation.heard.lock().unwrap().insert(
+            String::from("daq2"),
+            ("10.0.0.2:30000".parse().unwrap(), Instant::now()),
+        );
+        assert_eq!(
+            vec![
+                (String::from("daq2"), address("10.0.0.12:30000")),
+                (String::from("daq3:30100"), address("10.0.0.3:30100")),
+                (String::from("daq4"), address("10.0.0.4:30000")),
+            ],
+            federation.peers()
+        );
+    }
+    #[test]
+    fn replies() {
+        let results = vec![
+            (
+                String::from("daq1"),
+                Ok(local().iter().map(|a| a.to_string()).collect()),
+            ),
+            (String::from("daq2"), Err(String::from("timed out"))),
+            (
+                String::from("daq3"),
+                Ok(vec![String::from("31005 daq fox tcp")]),
+            ),
+        ];
+        assert_eq!(
+            "OK 3 failed=daq2\n\
+             daq1 31000 daq fox tcp\n\
+             daq1 31001 \"my ring\" owl udp\n\
+             daq3 31005 daq fox tcp\n",
+            reply(&Question::List, &results)
+        );
+        let find = Question::Find {
+            service: String::from("daq"),
+            user: String::from("fox"),
+        };
+        assert_eq!("FIND daq fox\n", find.request());
+        assert!(
+            reply(&find, &[(String::from("daq1"), Ok(vec![]))]).starts_with("FAIL E_NOT_FOUND ")
+        );
+    }
+    #[test]
+    fn gathers() {
+        let answers = peer("OK 1\n31005 daq fox tcp\n");
+        let not_found = peer("FAIL E_NOT_FOUND daq fox is not advertised\n");
+        let refuses = peer("FAIL E_INTERNAL oops\n");
+
+        // A peer that never answers and one that isn't there:
+
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let federation = Federation::new(
+            "daq1",
+            resolved(vec![
+                answers.clone(),
+                not_found.clone(),
+                refuses.clone(),
+                silent.local_addr().unwrap().to_string(),
+                gone.to_string(),
+            ]),
+        )
+        .with_timeout(Duration::from_millis(300));
+        let (woken_send, woken) = mpsc::channel();
+        let started = Instant::now();
+        let reply = federation
+            .gather(
+                Question::Find {
+                    service: String::from("daq"),
+                    user: String::from("fox"),
+                },
+                &local(),
+                move || woken_send.send(()).unwrap(),
+            )
+            .unwrap()
+            .recv()
+            .unwrap();
+        woken.recv().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(
+            format!(
+                "OK 2 failed={},{},{}\ndaq1 31000 daq fox tcp\n{} 31005 daq fox tcp\n",
+                refuses,
+                silent.local_addr().unwrap(),
+                gone,
+                answers
+            ),
+            reply
+        );
+    }
+    #[test]
+    fn asks_a_few_at_a_time() {
+        let peers: Vec<String> = (0..2 * MAX_ASKING).map(|_| peer("OK 0\n")).collect();
+        let federation = Federation::new("daq1", resolved(peers));
+        let reply = federation
+            .gather(Question::List, &local(), || {})
+            .unwrap()
+            .recv()
+            .unwrap();
+        assert_eq!(
+            "OK 2\ndaq1 31000 daq fox tcp\ndaq1 31001 \"my ring\" owl udp\n",
+            reply
+        );
+    }
+    #[test]
+    fn gathers_a_few_at_a_time() {
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peers = resolved(vec![silent.local_addr().unwrap().to_string()]);
+        let federation = Federation::new("daq1", peers).with_timeout(Duration::from_millis(300));
+        let gathers: Vec<_> = (0..MAX_GATHERS)
+            .map(|_| federation.gather(Question::List, &local(), || {}).unwrap())
+            .collect();
+        let refused = federation.gather(Question::List, &local(), || {});
+        assert_eq!("E_QUOTA", refused.unwrap_err().code());
+
+        // There's room again once they're done:
+
+        for gather in gathers {
+            gather.recv().unwrap();
+        }
+        for _ in 0..100 {
+            if federation.gathers.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(federation.gather(Question::List, &local(), || {}).is_ok());
+    }
+    #[test]
+    fn forgets_peers() {
+        let federation = Federation::new("daq1", Vec::new());
+        let beacon = Beacon::bind(
+            0,
+            "127.0.0.1:9".parse().unwrap(),
+            "daq1",
+            30000,
+            &federation,
+        )
+        .unwrap();
+        let from: SocketAddr = "10.0.0.2:30002".parse().unwrap();
+        federation.heard.lock().unwrap().insert(
+            String::from("old"),
+            (from, Instant::now() - BEACON_LIFETIME),
+        );
+
+        // Those we've stopped hearing from go when another is heard and
+        // there's a limit to how many are remembered:
+
+        for i in 0..MAX_HEARD + 10 {
+            beacon.heard(format!("PORTMAN 1234 30000 daq{}", i).as_bytes(), from);
+        }
+        let heard = federation.heard.lock().unwrap();
+        assert_eq!(MAX_HEARD, heard.len());
+        assert!(!heard.contains_key("old"));
+        assert!(heard.contains_key("daq0"));
+    }
+    #[test]
+    fn beacons() {
+        let first = Federation::new("daq1", Vec::new());
+        let second = Federation::new("daq2", Vec::new());
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (a_address, b_address) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        drop((a, b));
+        let beacons = vec![
+            Beacon::bind(a_address.port(), b_address, "daq1", 30000, &first).unwrap(),
+            Beacon::bind(b_address.port(), a_address, "daq2", 30100, &second).unwrap(),
+        ];
+        let stops: Vec<_> = beacons.iter().map(Beacon::stopper).collect();
+        let threads: Vec<_> = beacons
+            .into_iter()
+            .map(|beacon| thread::spawn(move || beacon.run()))
+            .collect();
+        for _ in 0..200 {
+            if !first.peers().is_empty() && !second.peers().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            vec![(
+                String::from("daq2:30100"),
+                "127.0.0.1:30100".parse().unwrap()
+            )],
+            first.peers()
+        );
+        assert_eq!(
+            vec![(String::from("daq1"), "127.0.0.1:30000".parse().unwrap())],
+            second.peers()
+        );
+        for stop in stops {
+            stop.stop();
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// Contains module definitions that pull in specific files
+
+mod connection;
+mod event_loop;
+mod federation;
+pub(crate) mod peer;
+mod relay;
+mod replication;
+#[allow(clippy::module_inception)]
+pub mod server;
+mod takeover;
+
+pub use self::relay::RelayRule;
+pub use self::server::{Limits, RunningServer, Server};
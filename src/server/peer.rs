@@ -0,0 +1,80 @@
+use crate::error::error::PortmanError;
+use std::fs;
+use std::net;
+use std::net::{SocketAddr, TcpStream};
+
+// What we can find out about the process at the other end of a connection.
+
+///
+/// ## is_local
+///
+///   Determine if a socket is connected to a local peer.
+///
+pub(crate) fn is_local(socket: &TcpStream) -> bool {
+    if let Ok(peer) = socket.peer_addr() {
+        if peer.is_ipv4() {
+            peer.ip() == net::Ipv4Addr::new(127, 0, 0, 1)
+        } else if peer.is_ipv6() {
+            peer.ip() == net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)
+        } else {
+            false
+        }
+    } else {
+        false
+    }
+}
+
+///
+/// ## may_list
+///
+///   Check that a client at *peer* may see what's advertised:  LIST, FIND
+///   and the read-only HTTP API.  Advertising ports is the point, so anyone
+///   may (unlike GIMME, which must come from the local host).  The check is
+///   here so that the line protocol and the HTTP API can't drift apart.
+///
+pub(crate) fn may_list(_peer: &SocketAddr) -> Result<(), PortmanError> {
+    Ok(())
+}
+
+///
+/// ## peer_uid
+///
+///   Determine the uid of the local process at the other end of a socket.
+///   The kernel's socket tables in /proc/net/tcp(6) list the owning uid of
+///   every socket, so we look for the peer's end of our connection there.
+///   None is returned if it can't be found (e.g. not on Linux).
+///
+pub(crate) fn peer_uid(socket: &TcpStream) -> Option<u32> {
+    let peer = socket.peer_addr().ok()?;
+    let local = socket.local_addr().ok()?;
+    let table = if peer.is_ipv4() {
+        "/proc/net/tcp"
+    } else {
+        "/proc/net/tcp6"
+    };
+    let (peer, local) = (proc_net_address(&peer), proc_net_address(&local));
+    let contents = fs::read_to_string(table).ok()?;
+    for line in contents.lines().skip(1) {
+        let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+        if fields.len() > 7 && fields[1] == peer && fields[2] == local {
+            return fields[7].parse().ok();
+        }
+    }
+    None
+}
+
+// Format an address the way /proc/net/tcp(6) does: the address as
+// native-endian 32 bit words in hex, a colon and the port in hex.
+
+fn proc_net_address(addr: &SocketAddr) -> String {
+    let words: Vec<String> = match addr {
+        SocketAddr::V4(a) => vec![format!("{:08X}", u32::from_ne_bytes(a.ip().octets()))],
+        SocketAddr::V6(a) => a
+            .ip()
+            .octets()
+            .chunks(4)
+            .map(|c| format!("{:08X}", u32::from_ne_bytes([c[0], c[1], c[2], c[3]])))
+            .collect(),
+    };
+    format!("{}:{:04X}", words.concat(), addr.port())
+}
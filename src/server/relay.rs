@@ -0,0 +1,578 @@
+use crate::error::error::PortmanError;
+use crate::log_info;
+use crate::metrics::metrics::Metrics;
+use crate::protocol::request::quote;
+use crate::responder::responder::ClientId;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+// CONNECT relays.  A client that's allowed to may ask us to connect it to
+// a service by name; once it has the OK its connection is handed to the
+// relay thread, which shuttles bytes between it and the service's port on
+// localhost until both sides are done.  That way only our listen port need
+// be open to remote machines.
+//
+// Data is moved with splice(2) through a pipe for each direction so it's
+// never copied into our address space.  Like the event loop, the relay
+// thread serves all of its sessions from one mio Poll with non-blocking
+// sockets.  What the client sent after its CONNECT goes into its pipe
+// ahead of the rest.  When one side closes its end, the other side's write
+// half is shut down once everything it sent has been passed on, so
+// half-closed protocols work.
+//
+// Who may CONNECT to what is given by RelayRules.  With none, CONNECT
+// is refused.
+
+const WAKER: Token = Token(0);
+
+// The most moved by one splice:  the default capacity of a pipe.
+
+const CHUNK: usize = 64 * 1024;
+
+// How long we give a service to accept the connection.
+
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+///
+/// RelayRule
+///    Permission to CONNECT.  Written NETWORK[/PREFIX][=SERVICE,USER]:
+/// clients on the network may CONNECT to services advertised under the
+/// service and user names, either of which may be * to match any.  With no
+/// names any service may be reached, e.g.:
+///
+///  *   127.0.0.1                 - Local clients, to any service.
+///  *   10.1.0.0/16=spectcl,*     - Clients on 10.1, to spectcl of any user.
+///  *   ::/0=*,fox                - Anyone, to fox's services.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayRule {
+    network: IpAddr,
+    prefix: u8,
+    service: Option<String>, // None matches any.
+    user: Option<String>,
+}
+
+impl RelayRule {
+    ///
+    /// Whether the rule lets a client at *peer* CONNECT to *service* of *user*.
+    ///
+    pub fn permits(&self, peer: IpAddr, service: &str, user: &str) -> bool {
+        let name_matches = |pattern: &Option<String>, name: &str| {
+            pattern.as_deref().is_none_or(|pattern| pattern == name)
+        };
+        in_network(peer, self.network, self.prefix)
+            && name_matches(&self.service, service)
+            && name_matches(&self.user, user)
+    }
+}
+
+impl FromStr for RelayRule {
+    type Err = PortmanError;
+    fn from_str(s: &str) -> Result<RelayRule, PortmanError> {
+        let invalid = |why: &str| PortmanError::Invalid(format!("Relay rule '{}': {}", s, why));
+        let (address, names) = match s.split_once('=') {
+            Some((address, names)) => (address, Some(names)),
+            None => (s, None),
+        };
+        let (network, prefix) = match address.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (address, None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .map_err(|_| invalid("not an IP address or network"))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= bits => prefix,
+                _ => return Err(invalid("bad prefix length")),
+            },
+            None => bits,
+        };
+        let pattern = |name: &str| {
+            if name == "*" {
+                None
+            } else {
+                Some(String::from(name))
+            }
+        };
+        let (service, user) = match names {
+            None => (None, None),
+            Some(names) => match names.split_once(',') {
+                Some((service, user)) if !service.is_empty() && !user.is_empty() => {
+                    (pattern(service), pattern(user))
+                }
+                _ => return Err(invalid("names must be given as SERVICE,USER")),
+            },
+        };
+        Ok(RelayRule {
+            network,
+            prefix,
+            service,
+            user,
+        })
+    }
+}
+
+// Whether 'address' is in the network with 'prefix' leading bits of
+// 'network'.  IPv4 clients that reach an IPv6 socket (::ffff:a.b.c.d)
+// are treated as the IPv4 addresses they are.
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let bits = |address: IpAddr| -> u128 {
+        match address {
+            IpAddr::V4(v4) => u128::from(u32::from(v4)),
+            IpAddr::V6(v6) => u128::from(v6),
+        }
+    };
+    let address = address.to_canonical();
+    if address.is_ipv4() != network.is_ipv4() {
+        return false;
+    }
+    let width = if network.is_ipv4() { 32 } else { 128 };
+    if prefix == 0 {
+        return true;
+    }
+    let shift = width - u32::from(prefix);
+    bits(address) >> shift == bits(network) >> shift
+}
+
+///
+/// Relays
+///    The event loop's side of the relay:  who may CONNECT and how to hand
+/// a connection over once it has been told OK.
+///
+pub(crate) struct Relays {
+    rules: Vec<RelayRule>,
+    sessions: mpsc::Sender<Handoff>,
+    control: Arc<RelayControl>,
+}
+
+impl Relays {
+    pub(crate) fn permit(
+        &self,
+        peer: IpAddr,
+        service: &str,
+        user: &str,
+    ) -> Result<(), PortmanError> {
+        if self.rules.iter().any(|r| r.permits(peer, service, user)) {
+            Ok(())
+        } else {
+            Err(PortmanError::Denied(format!(
+                "{} may not CONNECT to {} {}",
+                peer,
+                quote(service),
+                quote(user)
+            )))
+        }
+    }
+    // Relay between a client and the service we connected it to.  'pending'
+    // is what the client sent after its CONNECT request.
+    //
+    pub(crate) fn start(
+        &self,
+        client_id: ClientId,
+        client: TcpStream,
+        service: TcpStream,
+        pending: Vec<u8>,
+    ) {
+        let _ = self.sessions.send(Handoff {
+            client_id,
+            client,
+            service,
+            pending,
+        });
+        let _ = self.control.waker.wake();
+    }
+}
+
+///
+/// RelayControl
+///    Stops the relay thread from another thread.
+///
+pub(crate) struct RelayControl {
+    stop: AtomicBool,
+    waker: Waker,
+}
+
+impl RelayControl {
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+// A connection being handed to the relay thread.
+
+struct Handoff {
+    client_id: ClientId,
+    client: TcpStream,
+    service: TcpStream,
+    pending: Vec<u8>,
+}
+
+// One direction of a session:  a pipe and what's in it.
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    queued: Vec<u8>, // Bytes to put in the pipe before reading more.
+    pending: usize,  // Bytes in the pipe.
+    eof: bool,       // The sending side has closed.
+    shut: bool,      // We've shut down the receiving side's writing.
+}
+
+impl Pipe {
+    fn new(queued: Vec<u8>) -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: unsafe { OwnedFd::from_raw_fd(fds[0]) },
+            write: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+            queued,
+            pending: 0,
+            eof: false,
+            shut: false,
+        })
+    }
+    fn done(&self) -> bool {
+        self.eof && self.pending == 0 && self.queued.is_empty()
+    }
+    // Move what we can from 'from' to 'to'.  Err if either side failed.
+    //
+    fn pump(&mut self, from: &TcpStream, to: &TcpStream) -> io::Result<()> {
+        loop {
+            if self.pending > 0 {
+                match splice(self.read.as_raw_fd(), to.as_raw_fd(), self.pending)? {
+                    Some(n) => self.pending -= n,
+                    None => return Ok(()), // 'to' is full.
+                }
+            } else if !self.queued.is_empty() {
+                match put(self.write.as_raw_fd(), &self.queued)? {
+                    Some(n) => {
+                        self.queued.drain(..n);
+                        self.pending += n;
+                    }
+                    None => return Ok(()),
+                }
+            } else if self.eof {
+                if !self.shut {
+                    self.shut = true;
+                    let _ = to.shutdown(Shutdown::Write);
+                }
+                return Ok(());
+            } else {
+                match splice(from.as_raw_fd(), self.write.as_raw_fd(), CHUNK)? {
+                    Some(0) => self.eof = true,
+                    Some(n) => self.pending += n,
+                    None => return Ok(()), // Nothing to read.
+                }
+            }
+        }
+    }
+}
+
+// Splice up to 'len' bytes.  None if it would block.
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<Option<usize>> {
+    loop {
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if n >= 0 {
+            return Ok(Some(n as usize));
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            ErrorKind::Interrupted => continue,
+            ErrorKind::WouldBlock => return Ok(None),
+            _ => return Err(e),
+        }
+    }
+}
+
+// Write what we can of 'bytes' to 'to'.  None if it would block.
+
+fn put(to: RawFd, bytes: &[u8]) -> io::Result<Option<usize>> {
+    loop {
+        let n = unsafe { libc::write(to, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+        if n >= 0 {
+            return Ok(Some(n as usize));
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            ErrorKind::Interrupted => continue,
+            ErrorKind::WouldBlock => return Ok(None),
+            _ => return Err(e),
+        }
+    }
+}
+
+// A relay session.  ends[0] is the client, ends[1] the service and
+// pipes[i] carries what ends[i] sends to the other end.
+
+struct Session {
+    client_id: ClientId,
+    ends: [TcpStream; 2],
+    pipes: [Pipe; 2],
+}
+
+impl Session {
+    // Move all that can be moved.  Returns false once the session is over.
+    //
+    fn pump(&mut self) -> bool {
+        for i in 0..2 {
+            if self.pipes[i]
+                .pump(&self.ends[i], &self.ends[1 - i])
+                .is_err()
+            {
+                return false;
+            }
+        }
+        !(self.pipes[0].done() && self.pipes[1].done())
+    }
+}
+
+///
+/// Relay
+///    The relay thread's state.  Sessions are numbered; session n's client
+/// and service sockets have the tokens 2n + 1 and 2n + 2.
+///
+pub(crate) struct Relay {
+    poll: Poll,
+    handoffs: mpsc::Receiver<Handoff>,
+    sessions: HashMap<usize, Session>,
+    next_session: usize,
+    control: Arc<RelayControl>,
+    metrics: Arc<Metrics>,
+}
+
+///
+/// relay
+///    Create a relay for the *rules*:  the Relays the event loop uses and
+///    the Relay to run in a thread of its own.
+///
+pub(crate) fn relay(rules: Vec<RelayRule>, metrics: Arc<Metrics>) -> io::Result<(Relays, Relay)> {
+    let poll = Poll::new()?;
+    let control = Arc::new(RelayControl {
+        stop: AtomicBool::new(false),
+        waker: Waker::new(poll.registry(), WAKER)?,
+    });
+    let (sessions, handoffs) = mpsc::channel();
+    Ok((
+        Relays {
+            rules,
+            sessions,
+            control: Arc::clone(&control),
+        },
+        Relay {
+            poll,
+            handoffs,
+            sessions: HashMap::new(),
+            next_session: 0,
+            control,
+            metrics,
+        },
+    ))
+}
+
+impl Relay {
+    pub(crate) fn control(&self) -> Arc<RelayControl> {
+        Arc::clone(&self.control)
+    }
+    ///
+    /// Relay until stopped.  The sessions still open are then closed.
+    ///
+    pub(crate) fn run(mut self) {
+        let mut events = Events::with_capacity(256);
+        while !self.control.stop.load(Ordering::SeqCst) {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                break;
+            }
+            let mut ready: Vec<usize> = Vec::new();
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.accept(),
+                    Token(token) => ready.push((token - 1) / 2),
+                }
+            }
+            ready.sort_unstable();
+            ready.dedup();
+            for session in ready {
+                self.service(session);
+            }
+        }
+        let sessions: Vec<usize> = self.sessions.keys().copied().collect();
+        for session in sessions {
+            self.close(session);
+        }
+    }
+    // Take on the sessions the event loop has handed us.
+    //
+    fn accept(&mut self) {
+        while let Ok(handoff) = self.handoffs.try_recv() {
+            let client_id = handoff.client_id;
+            self.metrics.relay_started();
+            match self.add(handoff) {
+                Ok(session) => self.service(session),
+                Err(e) => {
+                    log_info!(client = client_id; "Relay failed: {}", e);
+                    self.metrics.relay_ended();
+                }
+            }
+        }
+    }
+    fn add(&mut self, handoff: Handoff) -> io::Result<usize> {
+        let session = self.next_session;
+        self.next_session += 1;
+        let ends = [handoff.client, handoff.service];
+        for (i, end) in ends.iter().enumerate() {
+            end.set_nonblocking(true)?;
+            self.poll.registry().register(
+                &mut SourceFd(&end.as_raw_fd()),
+                Token(2 * session + 1 + i),
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+        }
+        self.sessions.insert(
+            session,
+            Session {
+                client_id: handoff.client_id,
+                ends,
+                pipes: [Pipe::new(handoff.pending)?, Pipe::new(Vec::new())?],
+            },
+        );
+        Ok(session)
+    }
+    fn service(&mut self, session: usize) {
+        let alive = match self.sessions.get_mut(&session) {
+            Some(s) => s.pump(),
+            None => return,
+        };
+        if !alive {
+            self.close(session);
+        }
+    }
+    fn close(&mut self, session: usize) {
+        if let Some(s) = self.sessions.remove(&session) {
+            for end in &s.ends {
+                let _ = self
+                    .poll
+                    .registry()
+                    .deregister(&mut SourceFd(&end.as_raw_fd()));
+                let _ = end.shutdown(Shutdown::Both);
+            }
+            log_info!(client = s.client_id; "Relay closed");
+            self.metrics.relay_ended();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn rule(s: &str) -> RelayRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "10.1.2.3".parse().unwrap();
+        let mapped: IpAddr = "::ffff:10.1.2.3".parse().unwrap();
+
+        assert!(rule("127.0.0.1").permits(local, "webui", "fox"));
+        assert!(!rule("127.0.0.1").permits(remote, "webui", "fox"));
+        assert!(rule("10.1.0.0/16").permits(remote, "webui", "fox"));
+        assert!(rule("10.1.0.0/16").permits(mapped, "webui", "fox"));
+        assert!(!rule("10.2.0.0/16").permits(remote, "webui", "fox"));
+        assert!(rule("0.0.0.0/0").permits(remote, "webui", "fox"));
+        assert!(!rule("::/0").permits(remote, "webui", "fox"));
+
+        assert!(rule("10.0.0.0/8=webui,*").permits(remote, "webui", "owl"));
+        assert!(!rule("10.0.0.0/8=webui,*").permits(remote, "daq", "fox"));
+        assert!(rule("10.0.0.0/8=*,fox").permits(remote, "daq", "fox"));
+        assert!(!rule("10.0.0.0/8=*,fox").permits(remote, "daq", "owl"));
+        assert!(rule("10.0.0.0/8=my daq,fox").permits(remote, "my daq", "fox"));
+
+        for bad in &[
+            "10.0.0",
+            "10.0.0.0/33",
+            "10.0.0.0/x",
+            "10.0.0.0=webui",
+            "10.0.0.0=,fox",
+        ] {
+            assert!(bad.parse::<RelayRule>().is_err(), "{}", bad);
+        }
+    }
+    #[test]
+    fn relays() {
+        let metrics = Arc::new(Metrics::new());
+        let (relays, relay) = relay(vec![rule("127.0.0.1")], Arc::clone(&metrics)).unwrap();
+        let control = relay.control();
+        let thread = thread::spawn(move || relay.run());
+
+        // An echo service that says when it has seen the end of its input:
+
+        let service = TcpListener::bind("127.0.0.1:0").unwrap();
+        let service_addr = service.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = service.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+            stream.write_all(b" - done").unwrap();
+        });
+
+        // A client connection as the event loop would hand it over:
+
+        let front = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(front.local_addr().unwrap()).unwrap();
+        let (accepted, _) = front.accept().unwrap();
+        let to_service = TcpStream::connect(service_addr).unwrap();
+        relays.start(1, accepted, to_service, b"pipelined ".to_vec());
+
+        let big = vec![b'x'; 1024 * 1024];
+        client.write_all(&big).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(
+            b"pipelined ".len() + big.len() + b" - done".len(),
+            reply.len()
+        );
+        assert!(reply.starts_with(b"pipelined xxx"));
+        assert!(reply.ends_with(b"x - done"));
+        echo.join().unwrap();
+
+        control.stop();
+        thread.join().unwrap();
+        assert_eq!(0, metrics.relays());
+    }
+}
@@ -0,0 +1,689 @@
+use super::peer::may_list;
+use crate::error::error::PortmanError;
+use crate::logging::audit::{Event, Record, Recorder};
+use crate::portpool::ports::{Snapshot, UsedPort};
+use crate::responder::responder::{self, RequestMessage};
+use crate::{log_debug, log_info, log_warn};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Replication:  a replica is a second portman that keeps a copy of a
+// primary's allocations so that LIST and FIND can still be answered when
+// the primary can't be reached.  The primary serves a replication stream
+// on a listener of its own (--replication-listen) and the replica
+// (--replica-of) follows it.  The replica refuses GIMME:  only the
+// primary allocates ports.
+//
+// The stream is lines of text with names quoted as in LIST replies.  It
+// starts with a snapshot of the allocations and goes on with each grant
+// and release as it's audited, and a heartbeat every 5 seconds:
+//
+//    SNAPSHOT n
+//    port service-name user-name protocol          (n of these)
+//    GRANT port service-name user-name protocol
+//    RELEASE port service-name user-name protocol
+//    ALIVE
+//
+// A change audited while the snapshot is being taken may also be sent
+// after it.  That's harmless as the replica applies each change as "this
+// port is (or isn't) allocated", so the last word on a port wins.
+//
+// A replica whose stream closes or that misses three heartbeats is out of
+// contact:  it keeps answering from its copy, says how stale the copy is
+// and keeps trying to reconnect.
+//
+// The primary never waits for a replica:  what's to be sent to each is
+// queued and written as its socket takes it.  A replica that falls too
+// far behind is dropped; it catches up with a new snapshot when it
+// reconnects.
+
+const HEARTBEAT: Duration = Duration::from_secs(5);
+const LIVENESS: Duration = Duration::from_secs(15);
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY: Duration = Duration::from_secs(2);
+
+// The most that may be queued for a replica before it's dropped:  well
+// over a snapshot of the largest pool.
+
+const MAX_BACKLOG: usize = 16 * 1024 * 1024;
+
+// How often a replica waiting on the stream checks whether it's stopped.
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+///
+/// ReplicationControl
+///    Wakes a Replicator when there are changes to send and stops it.
+///
+pub(crate) struct ReplicationControl {
+    stop: AtomicBool,
+    waker: Waker,
+}
+
+impl ReplicationControl {
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+///
+/// Feed
+///    Passes the grants and releases audited on the primary to its
+/// Replicator as stream lines.
+///
+pub(crate) struct Feed {
+    changes: mpsc::Sender<String>,
+    control: Arc<ReplicationControl>,
+}
+
+impl Recorder for Feed {
+    fn record(&self, record: &Record) {
+        let kind = match record.event {
+            Event::Grant => "GRANT",
+            Event::Release => "RELEASE",
+            _ => return,
+        };
+        if let (Some(port), Some(service), Some(user), Some(protocol)) = (
+            &record.port,
+            &record.service,
+            &record.user,
+            &record.protocol,
+        ) {
+            let used = UsedPort::with_protocol(*port, service, user, *protocol);
+            if self.changes.send(format!("{} {}\n", kind, used)).is_ok() {
+                let _ = self.control.waker.wake();
+            }
+        }
+    }
+}
+
+///
+/// Replicator
+///    Serves the replication stream on the primary:  it accepts replicas,
+/// starts each off with a snapshot from the responder and then sends them
+/// what the Feed passes on.
+///
+pub(crate) struct Replicator {
+    poll: Poll,
+    listener: TcpListener,
+    changes: mpsc::Receiver<String>,
+    requests: mpsc::Sender<RequestMessage>,
+    replicas: HashMap<Token, Downstream>,
+    next_token: usize,
+    control: Arc<ReplicationControl>,
+}
+
+// A replica we're feeding and what's still to be written to it.
+
+struct Downstream {
+    peer: SocketAddr,
+    stream: TcpStream,
+    queue: Vec<u8>,
+}
+
+impl Downstream {
+    // Write what the socket will take.  Err if the replica can't be fed.
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.queue.is_empty() {
+            match self.stream.write(&self.queue) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.queue.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// replicator
+///    Listen for replicas on *address*.  The snapshots come from the
+///    responder *requests* go to; the Feed must be added to the audit
+///    handle the responder records with.
+///
+pub(crate) fn replicator(
+    address: SocketAddr,
+    requests: mpsc::Sender<RequestMessage>,
+) -> io::Result<(Feed, Replicator)> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let poll = Poll::new()?;
+    poll.registry().register(
+        &mut SourceFd(&listener.as_raw_fd()),
+        LISTENER,
+        Interest::READABLE,
+    )?;
+    let control = Arc::new(ReplicationControl {
+        stop: AtomicBool::new(false),
+        waker: Waker::new(poll.registry(), WAKER)?,
+    });
+    let (send, receive) = mpsc::channel();
+    Ok((
+        Feed {
+            changes: send,
+            control: Arc::clone(&control),
+        },
+        Replicator {
+            poll,
+            listener,
+            changes: receive,
+            requests,
+            replicas: HashMap::new(),
+            next_token: 2,
+            control,
+        },
+    ))
+}
+
+impl Replicator {
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+    pub(crate) fn control(&self) -> Arc<ReplicationControl> {
+        Arc::clone(&self.control)
+    }
+    ///
+    /// Serve replicas until stopped.  Stopping closes their streams.
+    ///
+    pub(crate) fn run(mut self) {
+        let mut events = Events::with_capacity(16);
+        let mut next_heartbeat = Instant::now() + HEARTBEAT;
+        while !self.control.stop.load(Ordering::SeqCst) {
+            let timeout = next_heartbeat.saturating_duration_since(Instant::now());
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+            self.pass_on();
+            for event in events.iter() {
+                let token = event.token();
+                let flushed = match self.replicas.get_mut(&token) {
+                    Some(replica) => replica.flush(),
+                    None => continue,
+                };
+                if let Err(e) = flushed {
+                    self.drop_replica(token, &e.to_string());
+                }
+            }
+            self.accept();
+            if Instant::now() >= next_heartbeat {
+                self.send("ALIVE\n");
+                next_heartbeat = Instant::now() + HEARTBEAT;
+            }
+        }
+    }
+    // Send the changes audited since we last looked to every replica.
+
+    fn pass_on(&mut self) {
+        let text: String = self.changes.try_iter().collect();
+        if !text.is_empty() {
+            self.send(&text);
+        }
+    }
+    // Queue 'text' for every replica and send what we can, dropping those
+    // that can't take it (e.g. they've gone away) or have fallen too far
+    // behind.
+
+    fn send(&mut self, text: &str) {
+        let tokens: Vec<Token> = self.replicas.keys().copied().collect();
+        for token in tokens {
+            let replica = self.replicas.get_mut(&token).expect("Bug: replica token");
+            replica.queue.extend_from_slice(text.as_bytes());
+            let sent = if replica.queue.len() > MAX_BACKLOG {
+                Err(String::from("it has fallen too far behind"))
+            } else {
+                replica.flush().map_err(|e| e.to_string())
+            };
+            if let Err(e) = sent {
+                self.drop_replica(token, &e);
+            }
+        }
+    }
+    fn drop_replica(&mut self, token: Token, why: &str) {
+        if let Some(replica) = self.replicas.remove(&token) {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&replica.stream.as_raw_fd()));
+            log_info!("Dropped replica {}: {}", replica.peer, why);
+        }
+    }
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => self.add(stream, peer),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break, // WouldBlock or e.g. out of descriptors.
+            }
+        }
+    }
+    // Start a new replica off with a snapshot.  The changes so far go
+    // to the replicas we already have first as the snapshot includes them.
+
+    fn add(&mut self, stream: TcpStream, peer: SocketAddr) {
+        if let Err(e) = may_list(&peer) {
+            log_warn!("Refused replica {}: {}", peer, e);
+            return;
+        }
+        self.pass_on();
+        let allocations = match responder::get_allocations(&self.requests) {
+            Ok(allocations) => allocations,
+            Err(e) => {
+                log_warn!("No snapshot for replica {}: {}", peer, e);
+                return;
+            }
+        };
+        let mut text = format!("SNAPSHOT {}\n", allocations.len());
+        for used in allocations.iter() {
+            text.push_str(&format!("{}\n", used));
+        }
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        let mut replica = Downstream {
+            peer,
+            stream,
+            queue: text.into_bytes(),
+        };
+        let added = replica
+            .stream
+            .set_nonblocking(true)
+            .and_then(|_| {
+                self.poll.registry().register(
+                    &mut SourceFd(&replica.stream.as_raw_fd()),
+                    token,
+                    Interest::WRITABLE,
+                )
+            })
+            .and_then(|_| replica.flush());
+        match added {
+            Ok(()) => {
+                log_info!("Replica {} is following us", peer);
+                self.replicas.insert(token, replica);
+            }
+            Err(e) => {
+                let _ = self
+                    .poll
+                    .registry()
+                    .deregister(&mut SourceFd(&replica.stream.as_raw_fd()));
+                log_info!("Dropped replica {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+// The replica's copy of the primary's allocations and what it knows of
+// the stream.
+
+struct Mirror {
+    allocations: BTreeMap<u16, UsedPort>,
+    incoming: Option<(usize, BTreeMap<u16, UsedPort>)>, // Snapshot lines still to come and those read.
+    following: bool,                                    // Have a snapshot from the current stream.
+    heard: Instant,                                     // Last heard from the primary (or started).
+}
+
+impl Mirror {
+    // Apply a line of the stream.  Returns true if it completed a
+    // snapshot and the error if the line makes no sense.
+
+    fn apply(&mut self, line: &str) -> Result<bool, String> {
+        self.heard = Instant::now();
+        if let Some((remaining, snapshot)) = &mut self.incoming {
+            let used: UsedPort = line.parse().map_err(|e: PortmanError| e.to_string())?;
+            snapshot.insert(used.port(), used);
+            *remaining -= 1;
+            return Ok(self.complete());
+        }
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match kind {
+            "SNAPSHOT" => {
+                let count = rest
+                    .parse::<usize>()
+                    .map_err(|_| format!("Bad snapshot count: {}", line))?;
+                self.incoming = Some((count, BTreeMap::new()));
+                Ok(self.complete())
+            }
+            "GRANT" | "RELEASE" if self.following => {
+                let used: UsedPort = rest.parse().map_err(|e: PortmanError| e.to_string())?;
+                if kind == "GRANT" {
+                    self.allocations.insert(used.port(), used);
+                } else {
+                    self.allocations.remove(&used.port());
+                }
+                Ok(false)
+            }
+            "ALIVE" => Ok(false),
+            _ => Err(format!("Unexpected line: {}", line)),
+        }
+    }
+    // Take the incoming snapshot as the copy if all of it has arrived.
+
+    fn complete(&mut self) -> bool {
+        match self.incoming.take() {
+            Some((0, snapshot)) => {
+                self.allocations = snapshot;
+                self.following = true;
+                true
+            }
+            incoming => {
+                self.incoming = incoming;
+                false
+            }
+        }
+    }
+}
+
+///
+/// Replica
+///    The copy of the allocations of the primary at *primary* (host:port of
+/// its replication listener) that a replica answers LIST and FIND from.
+/// It's shared by the event loop and the Follower that keeps it up to date.
+///
+#[derive(Clone)]
+pub(crate) struct Replica {
+    primary: String,
+    mirror: Arc<Mutex<Mirror>>,
+}
+
+impl Replica {
+    pub(crate) fn new(primary: &str) -> Replica {
+        Replica {
+            primary: String::from(primary),
+            mirror: Arc::new(Mutex::new(Mirror {
+                allocations: BTreeMap::new(),
+                incoming: None,
+                following: false,
+                heard: Instant::now(),
+            })),
+        }
+    }
+    pub(crate) fn primary(&self) -> &str {
+        &self.primary
+    }
+    fn mirror(&self) -> MutexGuard<'_, Mirror> {
+        self.mirror.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    ///
+    /// The allocations as the primary last told us, in port order.
+    ///
+    pub(crate) fn allocations(&self) -> Snapshot {
+        Arc::new(self.mirror().allocations.values().cloned().collect())
+    }
+    ///
+    /// How long it is since we last heard from the primary if we're out of
+    /// contact with it (and so the copy may be stale).  None while we're
+    /// following its stream.
+    ///
+    pub(crate) fn stale(&self) -> Option<Duration> {
+        let mirror = self.mirror();
+        let since = mirror.heard.elapsed();
+        if mirror.following && since < LIVENESS {
+            None
+        } else {
+            Some(since)
+        }
+    }
+    // We've lost the stream.  Returns true if we were following it.
+
+    fn lose(&self) -> bool {
+        let mut mirror = self.mirror();
+        mirror.incoming = None;
+        std::mem::replace(&mut mirror.following, false)
+    }
+}
+
+///
+/// FollowerStop
+///    Stops a Follower from another thread.
+///
+pub(crate) struct FollowerStop {
+    stop: AtomicBool,
+}
+
+impl FollowerStop {
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
+
+///
+/// Follower
+///    Keeps a Replica up to date with its primary's stream, reconnecting
+/// whenever the stream is lost.
+///
+pub(crate) struct Follower {
+    replica: Replica,
+    stop: Arc<FollowerStop>,
+}
+
+impl Follower {
+    pub(crate) fn new(replica: &Replica) -> Follower {
+        Follower {
+            replica: replica.clone(),
+            stop: Arc::new(FollowerStop {
+                stop: AtomicBool::new(false),
+            }),
+        }
+    }
+    pub(crate) fn stopper(&self) -> Arc<FollowerStop> {
+        Arc::clone(&self.stop)
+    }
+    ///
+    /// Follow the primary until stopped.
+    ///
+    pub(crate) fn run(self) {
+        let primary = self.replica.primary().to_string();
+        while !self.stop.stopping() {
+            if let Err(e) = self.follow() {
+                if self.replica.lose() {
+                    log_warn!(
+                        "Lost contact with the primary {}: {}; answering from a copy that's going stale",
+                        primary,
+                        e
+                    );
+                } else {
+                    log_debug!("Can't follow the primary {}: {}", primary, e);
+                }
+            }
+            let retry = Instant::now() + RETRY;
+            while !self.stop.stopping() && Instant::now() < retry {
+                thread::sleep(CHECK_INTERVAL);
+            }
+        }
+    }
+    // Connect to the primary and apply its stream until it fails or we're
+    // stopped.
+
+    fn follow(&self) -> Result<(), String> {
+        let address = self
+            .replica
+            .primary()
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| String::from("no address"))?;
+        let stream = TcpStream::connect_timeout(&address, IO_TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(CHECK_INTERVAL))
+            .map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        let mut heard = Instant::now();
+        while !self.stop.stopping() {
+            // A read that times out keeps what it has read of the line in
+            // 'line' for the next one to finish.
+
+            match reader.read_line(&mut line) {
+                Ok(0) => return Err(String::from("it closed the stream")),
+                Ok(_) => {
+                    heard = Instant::now();
+                    let text = line.trim_end_matches(&['\r', '\n'][..]);
+                    if self.replica.mirror().apply(text)? {
+                        log_info!(
+                            "Following the primary {}: {} allocations",
+                            self.replica.primary(),
+                            self.replica.mirror().allocations.len()
+                        );
+                    }
+                    line.clear();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    if heard.elapsed() >= LIVENESS {
+                        return Err(String::from("it has stopped sending heartbeats"));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirrors() {
+        let replica = Replica::new("daq1:30001");
+        assert!(replica.stale().is_some());
+        let mut mirror = replica.mirror();
+        assert!(mirror.apply("GRANT 31000 daq fox tcp").is_err());
+        assert_eq!(Ok(false), mirror.apply("SNAPSHOT 2"));
+        assert_eq!(Ok(false), mirror.apply("31001 \"event builder\" fox udp"));
+        assert!(!mirror.following);
+        assert_eq!(Ok(true), mirror.apply("31000 daq fox tcp"));
+        assert_eq!(Ok(false), mirror.apply("GRANT 31002 ring fox tcp"));
+        assert_eq!(Ok(false), mirror.apply("RELEASE 31000 daq fox tcp"));
+        assert_eq!(Ok(false), mirror.apply("ALIVE"));
+        assert!(mirror.apply("GRANT 31003 ring fox sctp").is_err());
+        assert!(mirror.apply("HELLO").is_err());
+        drop(mirror);
+
+        assert_eq!(None, replica.stale());
+        let listed: Vec<String> = replica
+            .allocations()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            vec!["31001 \"event builder\" fox udp", "31002 ring fox tcp"],
+            listed
+        );
+
+        // Losing the stream keeps the copy but it's stale until there's a
+        // new snapshot:
+
+        assert!(replica.lose());
+        assert!(!replica.lose());
+        assert!(replica.stale().is_some());
+        assert_eq!(2, replica.allocations().len());
+        assert_eq!(Ok(true), replica.mirror().apply("SNAPSHOT 0"));
+        assert_eq!(None, replica.stale());
+        assert!(replica.allocations().is_empty());
+    }
+    #[test]
+    fn follows() {
+        let (requests, receive) = mpsc::channel();
+        let responder = thread::spawn(move || responder::responder(31000, 10, receive));
+        responder::request_port("daq", "fox", 1, &requests).unwrap();
+
+        let (feed, replicator) =
+            replicator("127.0.0.1:0".parse().unwrap(), requests.clone()).unwrap();
+        let primary = replicator.local_addr().unwrap().to_string();
+        let control = replicator.control();
+        let replicating = thread::spawn(move || replicator.run());
+
+        let replica = Replica::new(&primary);
+        let follower = Follower::new(&replica);
+        let stop = follower.stopper();
+        let following = thread::spawn(move || follower.run());
+        let listed = |n: usize| {
+            for _ in 0..200 {
+                if replica.stale().is_none() && replica.allocations().len() == n {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            false
+        };
+        assert!(listed(1));
+
+        // Changes are passed on as they're audited:
+
+        let used = UsedPort::new(31001, "ring", "fox");
+        feed.record(&Record::new(Event::Grant, "allocated").with_port(&used));
+        assert!(listed(2));
+        feed.record(&Record::new(Event::Release, "released").with_port(&used));
+        assert!(listed(1));
+        assert_eq!("31000 daq fox tcp", replica.allocations()[0].to_string());
+
+        // If the primary goes away, the copy is kept but it's stale:
+
+        control.stop();
+        replicating.join().unwrap();
+        for _ in 0..200 {
+            if replica.stale().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(replica.stale().is_some());
+        assert_eq!(1, replica.allocations().len());
+
+        stop.stop();
+        following.join().unwrap();
+        requests.send(RequestMessage::Terminate).unwrap();
+        responder.join().unwrap();
+    }
+    #[test]
+    fn drops_laggards() {
+        let (requests, receive) = mpsc::channel();
+        let responder = thread::spawn(move || responder::responder(31000, 10, receive));
+        let (_feed, mut replicator) =
+            replicator("127.0.0.1:0".parse().unwrap(), requests.clone()).unwrap();
+
+        // A replica that never reads doesn't hold us up; it's dropped once
+        // it's too far behind:
+
+        let _idle = TcpStream::connect(replicator.local_addr().unwrap()).unwrap();
+        for _ in 0..200 {
+            replicator.accept();
+            if !replicator.replicas.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, replicator.replicas.len());
+        let change = format!("{}\n", "x".repeat(1024 * 1024));
+        for _ in 0..(MAX_BACKLOG / change.len() + 64) {
+            replicator.send(&change);
+        }
+        assert!(replicator.replicas.is_empty());
+
+        requests.send(RequestMessage::Terminate).unwrap();
+        responder.join().unwrap();
+    }
+}
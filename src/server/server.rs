@@ -0,0 +1,1307 @@
+use super::event_loop::{Control, EventLoop};
+use super::federation::{self, Beacon, Federation};
+use super::relay::{self, RelayRule};
+use super::replication::{self, Follower, Replica};
+use super::takeover::{self, HandoffListener, PoolRanges};
+use crate::dns::mdns::{self, MDNS_GROUP};
+use crate::dns::server::DnsServer;
+use crate::dns::zone::{self, Zone};
+use crate::error::error::PortmanError;
+use crate::logging::audit::{Audit, Event, Record};
+use crate::metrics::metrics::Metrics;
+use crate::portpool::ports::PortPool;
+use crate::responder::responder::{self, CollisionPolicy};
+use crate::responder::state::StateFile;
+use crate::web::http::HttpServer;
+use crate::web::routes::Routes;
+use crate::{log_error, log_warn};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// A port manager that can be run in-process.  The portman program is a thin
+// wrapper around this: it turns its command line into a Server and runs it.
+// Tests and other programs can do the same, e.g.:
+//
+//    let server = Server::new()
+//        .with_listen_address("127.0.0.1:0".parse().unwrap())
+//        .with_port_range(31000, 100)
+//        .start()?;
+//    let address = server.local_addr();    // The port the system picked.
+//    ...
+//    server.shutdown()?;
+//
+
+///
+/// Limits
+///    Limits on client connections.  Connections that hold (or are waiting
+/// for) ports are exempt from the timeouts so that their allocations are
+/// not dropped.  Those that hold ports don't count against the connection
+/// limits either, so the limits don't cap the number of allocations.
+/// None means there is no limit.
+///
+///  *   max_connections - Connections in total that don't hold ports.
+///  *   max_per_address - Connections from any one address that don't hold ports.
+///  *   first_request   - How long a new connection has to send its first request.
+///  *   idle            - How long a connection may go without making a request.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_connections: Option<usize>,
+    pub max_per_address: Option<usize>,
+    pub first_request: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_connections: Some(4096),
+            max_per_address: Some(1024),
+            first_request: Some(Duration::from_secs(10)),
+            idle: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+///
+/// Server
+///    Describes a port manager server:  where it listens, the ports it
+/// manages and how it treats clients.  The defaults are those of the
+/// portman program:  listen on port 30000 of all interfaces and manage
+/// the 1000 ports from 31000 shared by TCP and UDP.
+///
+#[derive(Debug, Clone)]
+pub struct Server {
+    listen_address: SocketAddr,
+    port_base: u16,
+    num_ports: u16,
+    udp_range: Option<(u16, u16)>,
+    collision_policy: CollisionPolicy,
+    limits: Limits,
+    audit: Audit,
+    http_address: Option<SocketAddr>,
+    metrics_textfile: Option<PathBuf>,
+    relay_rules: Vec<RelayRule>,
+    dns_address: Option<SocketAddr>,
+    dns_domain: String,
+    dns_target: Option<String>,
+    mdns_interface: Option<Ipv4Addr>,
+    peers: Vec<String>,
+    beacon: Option<SocketAddr>,
+    replication_address: Option<SocketAddr>,
+    primary: Option<String>,
+    handoff_socket: Option<PathBuf>,
+    takeover: bool,
+    state_file: Option<PathBuf>,
+    reclaim_grace: Duration,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            listen_address: SocketAddr::from(([0, 0, 0, 0], 30000)),
+            port_base: 31000,
+            num_ports: 1000,
+            udp_range: None,
+            collision_policy: CollisionPolicy::Reject,
+            limits: Limits::default(),
+            audit: Audit::default(),
+            http_address: None,
+            metrics_textfile: None,
+            relay_rules: Vec::new(),
+            dns_address: None,
+            dns_domain: String::from("portman.local"),
+            dns_target: None,
+            mdns_interface: None,
+            peers: Vec::new(),
+            beacon: None,
+            replication_address: None,
+            primary: None,
+            handoff_socket: None,
+            takeover: false,
+            state_file: None,
+            reclaim_grace: Duration::from_secs(60),
+        }
+    }
+    /// Listen on *address*.  A port of 0 lets the system pick a free port;
+    /// RunningServer::local_addr says which.
+    pub fn with_listen_address(mut self, address: SocketAddr) -> Server {
+        self.listen_address = address;
+        self
+    }
+    /// Listen on *port* of the current listen address.
+    pub fn with_listen_port(mut self, port: u16) -> Server {
+        self.listen_address.set_port(port);
+        self
+    }
+    /// Allocate ports from the *num* ports starting at *base*.
+    pub fn with_port_range(mut self, base: u16, num: u16) -> Server {
+        self.port_base = base;
+        self.num_ports = num;
+        self
+    }
+    /// Allocate UDP ports from their own range, which must not overlap the
+    /// TCP range.
+    pub fn with_udp_range(mut self, base: u16, num: u16) -> Server {
+        self.udp_range = Some((base, num));
+        self
+    }
+    /// What to do when GIMME names a service that's already advertised.
+    pub fn with_collision_policy(mut self, policy: CollisionPolicy) -> Server {
+        self.collision_policy = policy;
+        self
+    }
+    pub fn with_limits(mut self, limits: Limits) -> Server {
+        self.limits = limits;
+        self
+    }
+    /// Record grants, releases, rejected requests and administrative
+    /// actions in *audit*.
+    pub fn with_audit(mut self, audit: Audit) -> Server {
+        self.audit = audit;
+        self
+    }
+    /// Serve HTTP on *address*:  the allocations and status as JSON and
+    /// HTML and, at /metrics, the server's metrics for Prometheus (see
+    /// portman::metrics).  As with the listen address, a
+    /// port of 0 lets the system pick; RunningServer::http_addr says which.
+    pub fn with_http_address(mut self, address: SocketAddr) -> Server {
+        self.http_address = Some(address);
+        self
+    }
+    /// Write the metrics to *path* every 15 seconds for node_exporter's
+    /// textfile collector.
+    pub fn with_metrics_textfile(mut self, path: &Path) -> Server {
+        self.metrics_textfile = Some(path.to_path_buf());
+        self
+    }
+    /// Let the clients *rule* allows CONNECT to services through us.
+    /// Without any rules CONNECT is refused.
+    pub fn with_relay_rule(mut self, rule: RelayRule) -> Server {
+        self.relay_rules.push(rule);
+        self
+    }
+    /// Answer DNS SRV and TXT queries for the allocations on *address*
+    /// (UDP and TCP).  A port of 0 lets the system pick;
+    /// RunningServer::dns_addr says which.
+    pub fn with_dns_address(mut self, address: SocketAddr) -> Server {
+        self.dns_address = Some(address);
+        self
+    }
+    /// The domain the DNS names are in (portman.local by default).
+    pub fn with_dns_domain(mut self, domain: &str) -> Server {
+        self.dns_domain = String::from(domain);
+        self
+    }
+    /// The host SRV records name (this host's name by default).
+    pub fn with_dns_target(mut self, target: &str) -> Server {
+        self.dns_target = Some(String::from(target));
+        self
+    }
+    /// Publish the allocations with mDNS/DNS-SD on the interface with
+    /// address *interface* (UNSPECIFIED lets the system choose).
+    pub fn with_mdns(mut self, interface: Ipv4Addr) -> Server {
+        self.mdns_interface = Some(interface);
+        self
+    }
+    /// Include the portman at *peer* (host or host:port) in LIST ALL and
+    /// FIND ... ANYHOST.  Its name is resolved when the server starts.
+    pub fn with_peer(mut self, peer: &str) -> Server {
+        self.peers.push(String::from(peer));
+        self
+    }
+    /// Send a beacon to *address* (usually the LAN's broadcast address)
+    /// and listen for those of other instances on its port, which then
+    /// become peers.
+    pub fn with_beacon(mut self, address: SocketAddr) -> Server {
+        self.beacon = Some(address);
+        self
+    }
+    /// Serve the replication stream replicas follow on *address*.  A port
+    /// of 0 lets the system pick; RunningServer::replication_addr says which.
+    pub fn with_replication_address(mut self, address: SocketAddr) -> Server {
+        self.replication_address = Some(address);
+        self
+    }
+    /// Be a read-only replica of the primary whose replication stream is
+    /// at *primary* (host:port):  answer LIST and FIND from a copy of its
+    /// allocations and refuse GIMME.
+    pub fn with_primary(mut self, primary: &str) -> Server {
+        self.primary = Some(String::from(primary));
+        self
+    }
+    /// Listen on the Unix socket *path* for a new server process (e.g.
+    /// after an upgrade) to take over from this one:  it's handed the
+    /// listen socket and all the client connections with their ports, and
+    /// this server stops without dropping any of them.
+    pub fn with_handoff_socket(mut self, path: &Path) -> Server {
+        self.handoff_socket = Some(path.to_path_buf());
+        self
+    }
+    /// Rather than bind the listen socket, take over from the server
+    /// listening on the handoff socket.  The new server then listens on
+    /// the handoff socket itself.
+    pub fn with_takeover(mut self) -> Server {
+        self.takeover = true;
+        self
+    }
+    /// Keep the allocations in the file at *path* and give each holder a
+    /// token with which, should the server restart without releasing
+    /// them, it can RECLAIM them from the next server to use the file.
+    pub fn with_state_file(mut self, path: &Path) -> Server {
+        self.state_file = Some(path.to_path_buf());
+        self
+    }
+    /// How long after starting the allocations in the state file can be
+    /// reclaimed (60 seconds by default).  Then those that haven't been
+    /// are released.
+    pub fn with_reclaim_grace(mut self, grace: Duration) -> Server {
+        self.reclaim_grace = grace;
+        self
+    }
+    ///
+    /// start
+    ///    Bind the listen socket and serve clients in threads of our own.
+    ///    The returned RunningServer reports the address we're listening on
+    ///    and stops the server.  Failure to create the pool or bind the
+    ///    socket is reported here.
+    ///
+    pub fn start(self) -> Result<RunningServer, PortmanError> {
+        let audit = self.audit.clone();
+        let launched = self.launch()?;
+        let event_loop = launched.event_loop;
+        let control = event_loop.control();
+        let event_loop = thread::spawn(move || event_loop.run());
+        Ok(RunningServer {
+            local_addr: launched.local_addr,
+            control,
+            event_loop: Some(event_loop),
+            responder: Some(launched.responder),
+            http_addr: launched.http_addr,
+            dns_addr: launched.dns_addr,
+            replication_addr: launched.replication_addr,
+            helpers: launched.helpers,
+            audit,
+        })
+    }
+    ///
+    /// run
+    ///    As start, but serve clients in this thread, returning once a client
+    ///    asks us to TERMINATE.  An error is returned if the server can't be
+    ///    started or can't keep going.
+    ///
+    pub fn run(self) -> Result<(), PortmanError> {
+        let audit = self.audit.clone();
+        let launched = self.launch()?;
+        let control = launched.event_loop.control();
+        let result = launched.event_loop.run();
+        for helper in launched.helpers {
+            helper.stop();
+        }
+        let _ = launched.responder.join();
+        stopped(&audit, launched.local_addr);
+        control.finish_handover();
+        result
+    }
+    // Create the pool, start its responder, the relay, the HTTP, DNS and
+    // replication listeners, the mDNS publisher and the replica's follower
+    // (if they're wanted) and set up the event loop.  When taking over,
+    // the pool's ranges, the listen socket and the connections are those
+    // of the server we take over from and the rest is only set up once it
+    // has exited, freeing its listeners.
+
+    fn launch(self) -> Result<Launched, PortmanError> {
+        if self.primary.is_some() && self.replication_address.is_some() {
+            return Err(PortmanError::Invalid(String::from(
+                "A replica can't serve a replication stream of its own",
+            )));
+        }
+        if self.primary.is_some() && self.state_file.is_some() {
+            return Err(PortmanError::Invalid(String::from(
+                "A replica has no allocations to keep in a state file",
+            )));
+        }
+        let mut ranges = PoolRanges {
+            base: self.port_base,
+            num: self.num_ports,
+            udp: self.udp_range,
+        };
+        let handover = match (&self.handoff_socket, self.takeover) {
+            (Some(path), true) => {
+                let handover = takeover::take_over(path)?;
+                if !handover.wait_for_exit() {
+                    log_warn!("The server we took over from hasn't exited");
+                }
+                if handover.ranges != ranges {
+                    log_warn!("Keeping the port ranges of the server we took over from");
+                    ranges = handover.ranges;
+                }
+                Some(handover)
+            }
+            (None, true) => {
+                return Err(PortmanError::Invalid(String::from(
+                    "A takeover needs the handoff socket",
+                )))
+            }
+            (_, false) => None,
+        };
+        let pool = match ranges.udp {
+            Some((udp_base, udp_num)) => {
+                PortPool::with_udp_range(ranges.base, ranges.num, udp_base, udp_num)?
+            }
+            None => PortPool::new(ranges.base, ranges.num),
+        };
+        let (listener, handed) = match handover {
+            Some(handover) => (
+                handover.listener,
+                Some((handover.connections, handover.next_client)),
+            ),
+            None => (TcpListener::bind(self.listen_address)?, None),
+        };
+        let local_addr = listener.local_addr()?;
+
+        // The allocations of the connections we've been handed are restored
+        // with them rather than from the state file:
+
+        let mut state = match &self.state_file {
+            Some(path) => Some(StateFile::open(path)?),
+            None => None,
+        };
+        if let (Some(state), Some((connections, _))) = (&mut state, &handed) {
+            state.retain(|token| {
+                !connections
+                    .iter()
+                    .any(|c| c.token.as_deref() == Some(token))
+            });
+        }
+
+        // Having been handed the connections we keep going without the
+        // handoff socket rather than drop them:
+
+        let handoff = match &self.handoff_socket {
+            Some(path) => match HandoffListener::bind(path) {
+                Ok(listener) => Some(listener),
+                Err(e) if handed.is_some() => {
+                    log_warn!("Unable to listen on {}: {}", path.display(), e);
+                    None
+                }
+                Err(e) => return Err(e.into()),
+            },
+            None => None,
+        };
+        let http_server = match self.http_address {
+            Some(address) => Some(HttpServer::bind(address)?),
+            None => None,
+        };
+        let dns_server = match self.dns_address {
+            Some(address) => Some(DnsServer::bind(address)?),
+            None => None,
+        };
+
+        // The metrics count grants, releases and rejections as they're
+        // audited:
+
+        let (request_send, request_receive) = mpsc::channel();
+        let metrics = Arc::new(Metrics::new());
+        let mut audit = self.audit.clone().with_recorder(metrics.clone());
+
+        // As is the mDNS publisher:
+
+        let mut helpers = Vec::new();
+        if let Some(interface) = self.mdns_interface {
+            let (publisher, mdns) = mdns::mdns(MDNS_GROUP, interface, &mdns::local_host())?;
+            audit = audit.with_recorder(Arc::new(publisher));
+            let stop = mdns.control();
+            helpers.push(Helper {
+                stop: Box::new(move || stop.stop()),
+                thread: thread::spawn(move || mdns.run()),
+            });
+        }
+
+        // And the replication stream:
+
+        let mut replication_addr = None;
+        if let Some(address) = self.replication_address {
+            let (feed, replicator) = replication::replicator(address, request_send.clone())?;
+            audit = audit.with_recorder(Arc::new(feed));
+            replication_addr = replicator.local_addr();
+            let stop = replicator.control();
+            helpers.push(Helper {
+                stop: Box::new(move || stop.stop()),
+                thread: thread::spawn(move || replicator.run()),
+            });
+        }
+
+        // Our peers for LIST ALL and FIND ... ANYHOST:
+
+        let host = zone::host_name();
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| federation::resolve(peer))
+            .collect::<Result<Vec<_>, _>>()?;
+        let federation = Federation::new(&federation::tag(&host, local_addr.port()), peers);
+        if let Some(address) = self.beacon {
+            let beacon = Beacon::bind(
+                address.port(),
+                address,
+                &host,
+                local_addr.port(),
+                &federation,
+            )?;
+            let stop = beacon.stopper();
+            helpers.push(Helper {
+                stop: Box::new(move || stop.stop()),
+                thread: thread::spawn(move || beacon.run()),
+            });
+        }
+
+        let mut event_loop = EventLoop::new(
+            listener,
+            request_send.clone(),
+            self.limits,
+            audit.clone(),
+            Arc::clone(&metrics),
+            federation,
+        )?;
+        if let Some(path) = self.metrics_textfile {
+            event_loop = event_loop.with_textfile(path);
+        }
+        if let Some(listener) = handoff {
+            event_loop = event_loop.with_handoff(listener, ranges)?;
+        }
+        if state.is_some() {
+            event_loop = event_loop.with_reclaims();
+        }
+        if let Some(primary) = &self.primary {
+            let replica = Replica::new(primary);
+            let follower = Follower::new(&replica);
+            event_loop = event_loop.with_replica(replica);
+            let stop = follower.stopper();
+            helpers.push(Helper {
+                stop: Box::new(move || stop.stop()),
+                thread: thread::spawn(move || follower.run()),
+            });
+        }
+        if !self.relay_rules.is_empty() {
+            let (relays, relay) = relay::relay(self.relay_rules, Arc::clone(&metrics))?;
+            event_loop = event_loop.with_relays(relays);
+            let stop = relay.control();
+            helpers.push(Helper {
+                stop: Box::new(move || stop.stop()),
+                thread: thread::spawn(move || relay.run()),
+            });
+        }
+        let control = event_loop.control();
+        let policy = self.collision_policy;
+        let grace = self.reclaim_grace;
+        let responder = thread::spawn(move || {
+            match state {
+                Some(state) => responder::responder_with_state(
+                    pool,
+                    policy,
+                    audit,
+                    state,
+                    grace,
+                    request_receive,
+                ),
+                None => responder::responder_with_audit(pool, policy, audit, request_receive),
+            }
+
+            // The responder only returns on its own if it can't keep going.
+            // Rather than accept connections we can't serve, stop:
+
+            if !control.stopping() {
+                log_error!("The port pool responder has stopped");
+                control.fail();
+            }
+        });
+        if let Some((connections, next_client)) = handed {
+            event_loop.adopt(connections, next_client);
+        }
+        let mut dns_addr = None;
+        if let Some(server) = dns_server {
+            dns_addr = server.local_addr();
+            let stop = server.stopper();
+            let target = self.dns_target.unwrap_or_else(zone::host_name);
+            let zone = Zone::new(&self.dns_domain, &target);
+            let requests = request_send.clone();
+            helpers.push(Helper {
+                stop: Box::new(move || stop.stop()),
+                thread: thread::spawn(move || {
+                    server.run(|query, peer| zone::resolve(&zone, &requests, query, peer))
+                }),
+            });
+        }
+        let mut http_addr = None;
+        if let Some(server) = http_server {
+            http_addr = server.local_addr();
+            let stop = server.stopper();
+            let routes = Routes::new(request_send, metrics);
+            helpers.push(Helper {
+                stop: Box::new(move || stop.stop()),
+                thread: thread::spawn(move || server.run(|request| routes.handle(request))),
+            });
+        }
+        self.audit.record(&Record::new(
+            Event::Admin,
+            format!("server started on {}", local_addr),
+        ));
+        Ok(Launched {
+            event_loop,
+            responder,
+            local_addr,
+            http_addr,
+            dns_addr,
+            replication_addr,
+            helpers,
+        })
+    }
+}
+
+// What launch started.
+
+struct Launched {
+    event_loop: EventLoop,
+    responder: thread::JoinHandle<()>,
+    local_addr: SocketAddr,
+    http_addr: Option<SocketAddr>,
+    dns_addr: Option<SocketAddr>,
+    replication_addr: Option<SocketAddr>,
+    helpers: Vec<Helper>,
+}
+
+// A thread that helps the event loop (e.g. the relay or the HTTP listener)
+// and how to stop it.
+
+struct Helper {
+    stop: Box<dyn Fn() + Send>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Helper {
+    fn stop(self) {
+        (self.stop)();
+        let _ = self.thread.join();
+    }
+}
+
+// Record that the server listening on 'address' has stopped.  This is
+// done once the responder has finished, so it follows the releases of
+// the ports held when the server stopped.
+
+fn stopped(audit: &Audit, address: SocketAddr) {
+    audit.record(&Record::new(
+        Event::Admin,
+        format!("server on {} stopped", address),
+    ));
+}
+
+///
+/// RunningServer
+///    A server started by Server::start.  Dropping it shuts the server down.
+///
+pub struct RunningServer {
+    local_addr: SocketAddr,
+    control: Arc<Control>,
+    event_loop: Option<thread::JoinHandle<Result<(), PortmanError>>>,
+    responder: Option<thread::JoinHandle<()>>,
+    http_addr: Option<SocketAddr>,
+    dns_addr: Option<SocketAddr>,
+    replication_addr: Option<SocketAddr>,
+    helpers: Vec<Helper>,
+    audit: Audit,
+}
+
+impl RunningServer {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    /// The address the HTTP listener is on, if there is one.
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+    /// The address the DNS listener is on, if there is one.
+    pub fn dns_addr(&self) -> Option<SocketAddr> {
+        self.dns_addr
+    }
+    /// The address the replication listener is on, if there is one.
+    pub fn replication_addr(&self) -> Option<SocketAddr> {
+        self.replication_addr
+    }
+    ///
+    /// shutdown
+    ///    Close all client connections, which releases their ports, and stop
+    ///    the server's threads.
+    ///
+    pub fn shutdown(mut self) -> Result<(), PortmanError> {
+        self.control.stop();
+        self.join()
+    }
+    ///
+    /// wait
+    ///    Wait for the server to stop, e.g. because a client asked it to
+    ///    TERMINATE.
+    ///
+    pub fn wait(mut self) -> Result<(), PortmanError> {
+        self.join()
+    }
+    fn join(&mut self) -> Result<(), PortmanError> {
+        let mut result = Ok(());
+        if let Some(event_loop) = self.event_loop.take() {
+            result = event_loop.join().unwrap_or_else(|_| {
+                Err(PortmanError::Internal(String::from(
+                    "The event loop failed",
+                )))
+            });
+        }
+        for helper in self.helpers.drain(..) {
+            helper.stop();
+        }
+        if let Some(responder) = self.responder.take() {
+            let _ = responder.join();
+            stopped(&self.audit, self.local_addr);
+        }
+        self.control.finish_handover();
+        result
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        if self.event_loop.is_some() {
+            self.control.stop();
+            let _ = self.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn start() -> RunningServer {
+        Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31000, 10)
+            .start()
+            .unwrap()
+    }
+    // Send a request and return the first line of the reply.
+
+    fn request(stream: &mut TcpStream, line: &str) -> String {
+        stream.write_all(line.as_bytes()).unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        reply
+    }
+    // An allocation's reply without the transfer token at its end.
+
+    fn without_transfer(reply: String) -> String {
+        let (reply, token) = reply.trim_end().rsplit_once(" transfer=").unwrap();
+        assert_eq!(32, token.len());
+        format!("{}\n", reply)
+    }
+
+    #[test]
+    fn start_and_shutdown() {
+        let server = start();
+        assert_ne!(0, server.local_addr().port());
+        let mut holder = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31000\n",
+            without_transfer(request(&mut holder, "GIMME test fox\n"))
+        );
+
+        let lister = TcpStream::connect(server.local_addr()).unwrap();
+        (&lister).write_all(b"LIST\n").unwrap();
+        let mut list = BufReader::new(&lister);
+        let mut line = String::new();
+        list.read_line(&mut line).unwrap();
+        list.read_line(&mut line).unwrap();
+        assert_eq!("OK 1\n31000 test fox\n", line);
+
+        // Asked for, the lines say which protocol the ports are for:
+
+        let mut lister = TcpStream::connect(server.local_addr()).unwrap();
+        lister.write_all(b"LIST PROTOCOL\n").unwrap();
+        lister.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut list = String::new();
+        lister.read_to_string(&mut list).unwrap();
+        assert_eq!("OK 1\n31000 test fox tcp\n", list);
+
+        server.shutdown().unwrap();
+
+        // Shutting down closed the holder's connection:
+
+        let mut rest = String::new();
+        assert_eq!(0, holder.read_to_string(&mut rest).unwrap_or(0));
+    }
+    #[test]
+    fn holders_not_limited() {
+        let server = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31000, 10)
+            .with_limits(Limits {
+                max_connections: Some(3),
+                max_per_address: Some(2),
+                ..Limits::default()
+            })
+            .start()
+            .unwrap();
+
+        // More holders than either limit, all from the one address:
+
+        let mut holders = Vec::new();
+        for i in 0..5 {
+            let mut holder = TcpStream::connect(server.local_addr()).unwrap();
+            let reply = request(&mut holder, &format!("GIMME svc{} fox\n", i));
+            assert!(reply.starts_with("OK "), "{}", reply);
+            holders.push(holder);
+        }
+
+        // Connections that don't hold ports are still limited:
+
+        let _idle: Vec<TcpStream> = (0..2)
+            .map(|_| TcpStream::connect(server.local_addr()).unwrap())
+            .collect();
+        let mut over = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reply = String::new();
+        BufReader::new(&mut over).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("FAIL E_QUOTA"), "{}", reply);
+
+        server.shutdown().unwrap();
+    }
+    #[test]
+    fn terminate() {
+        let server = start();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"TERMINATE\n").unwrap();
+        server.wait().unwrap();
+
+        // Requests pipelined ahead of the TERMINATE are answered first:
+
+        let server = start();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client
+            .write_all(b"GIMME test fox\nHEALTH\nTERMINATE\n")
+            .unwrap();
+        server.wait().unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        let replies: Vec<&str> = replies.lines().collect();
+        assert_eq!(2, replies.len(), "{:?}", replies);
+        assert!(replies[0].starts_with("OK 31000 transfer="));
+        assert!(replies[1].starts_with("OK "));
+    }
+    #[test]
+    fn audited() {
+        let path =
+            std::env::temp_dir().join(format!("portman-{}-server-audit.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31000, 1)
+            .with_audit(Audit::open(&path, Default::default()).unwrap())
+            .start()
+            .unwrap();
+        let mut holder = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31000\n",
+            without_transfer(request(&mut holder, "GIMME test fox\n"))
+        );
+        let mut other = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(request(&mut other, "GIMME other fox\n").starts_with("FAIL E_EXHAUSTED"));
+        server.shutdown().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<(&str, &str)> = log
+            .lines()
+            .map(|l| {
+                let words: Vec<&str> = l.split(' ').collect();
+                (words[1], words[2])
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("admin", "port=-"),
+                ("grant", "port=31000"),
+                ("reject", "port=-"),
+                ("release", "port=31000"),
+                ("admin", "port=-"),
+            ],
+            events
+        );
+        assert!(log
+            .lines()
+            .nth(2)
+            .unwrap()
+            .contains("service=other user=fox"));
+        let _ = std::fs::remove_file(&path);
+    }
+    #[test]
+    fn metrics() {
+        let server = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31000, 10)
+            .with_http_address("127.0.0.1:0".parse().unwrap())
+            .start()
+            .unwrap();
+        let mut holder = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31000\n",
+            without_transfer(request(&mut holder, "GIMME test fox\n"))
+        );
+
+        let mut http = TcpStream::connect(server.http_addr().unwrap()).unwrap();
+        http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in &[
+            "portman_up 1",
+            "portman_pool_used 1",
+            "portman_pool_free 9",
+            "portman_ports_granted_total{protocol=\"tcp\"} 1",
+            "portman_connections 1",
+        ] {
+            assert!(response.lines().any(|l| l == *line), "missing {}", line);
+        }
+        server.shutdown().unwrap();
+    }
+    #[test]
+    fn dns() {
+        use crate::dns::message::tests::query;
+        use crate::dns::message::{NOERROR, NXDOMAIN, TYPE_SRV};
+        use std::net::UdpSocket;
+
+        let server = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31000, 10)
+            .with_dns_address("127.0.0.1:0".parse().unwrap())
+            .with_dns_target("daq.example")
+            .start()
+            .unwrap();
+        let mut holder = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31000\n",
+            without_transfer(request(&mut holder, "GIMME daq fox\n"))
+        );
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let dns = server.dns_addr().unwrap();
+        let mut buffer = [0u8; 512];
+        socket
+            .send_to(&query(1, "_daq._fox.portman.local", TYPE_SRV, None), dns)
+            .unwrap();
+        let n = socket.recv(&mut buffer).unwrap();
+        assert_eq!([0x85, NOERROR, 0, 1, 0, 1], buffer[2..8]);
+        assert_eq!(
+            b"\x00\x00\x00\x00\x79\x18\x03daq\x07example\x00",
+            &buffer[n - 19..n]
+        );
+
+        // Once the holder goes, so does the name:
+
+        drop(holder);
+        let mut rcode = NOERROR;
+        for _ in 0..100 {
+            socket
+                .send_to(&query(2, "_daq._fox.portman.local", TYPE_SRV, None), dns)
+                .unwrap();
+            socket.recv(&mut buffer).unwrap();
+            rcode = buffer[3] & 0xf;
+            if rcode == NXDOMAIN {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(NXDOMAIN, rcode);
+        server.shutdown().unwrap();
+    }
+    #[test]
+    fn federated() {
+        let other = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31100, 10)
+            .start()
+            .unwrap();
+        let server = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31000, 10)
+            .with_peer(&other.local_addr().to_string())
+            .start()
+            .unwrap();
+        let mut holder = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31000\n",
+            without_transfer(request(&mut holder, "GIMME daq fox\n"))
+        );
+        let mut other_holder = TcpStream::connect(other.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31100\n",
+            without_transfer(request(&mut other_holder, "GIMME \"my daq\" fox\n"))
+        );
+
+        // The reply comes even though the client's done sending:
+
+        let host = federation::tag(&zone::host_name(), server.local_addr().port());
+        let peer = other.local_addr().to_string();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"LIST ALL\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(
+            format!(
+                "OK 2\n{} 31000 daq fox tcp\n{} 31100 \"my daq\" fox tcp\n",
+                host, peer
+            ),
+            reply
+        );
+
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(
+            "OK 1\n",
+            request(&mut client, "FIND \"my daq\" fox ANYHOST\n")
+        );
+        assert!(request(&mut client, "FIND nothing fox ANYHOST\n").starts_with("FAIL E_NOT_FOUND"));
+
+        // Once the peer's gone it's reported as failed:
+
+        drop(other_holder);
+        other.shutdown().unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(
+            format!("OK 1 failed={}\n", peer),
+            request(&mut client, "LIST ALL\n")
+        );
+        server.shutdown().unwrap();
+    }
+    #[test]
+    fn replicated() {
+        let primary = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31200, 10)
+            .with_replication_address("127.0.0.1:0".parse().unwrap())
+            .start()
+            .unwrap();
+        let mut holder = TcpStream::connect(primary.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31200\n",
+            without_transfer(request(&mut holder, "GIMME daq fox\n"))
+        );
+        let replica = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31300, 10)
+            .with_primary(&primary.replication_addr().unwrap().to_string())
+            .start()
+            .unwrap();
+        let list = |wanted: &str| {
+            let mut reply = String::new();
+            for _ in 0..200 {
+                let mut client = TcpStream::connect(replica.local_addr()).unwrap();
+                client.write_all(b"LIST\n").unwrap();
+                client.shutdown(std::net::Shutdown::Write).unwrap();
+                reply.clear();
+                client.read_to_string(&mut reply).unwrap();
+                if reply.starts_with(wanted) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            reply
+        };
+
+        // The replica lists what the primary has and what it grants later,
+        // but won't grant anything itself:
+
+        assert_eq!("OK 1\n31200 daq fox\n", list("OK 1\n"));
+        let mut other = TcpStream::connect(primary.local_addr()).unwrap();
+        assert_eq!(
+            "OK 31201\n",
+            without_transfer(request(&mut other, "GIMME ring fox\n"))
+        );
+        assert_eq!("OK 2\n31200 daq fox\n31201 ring fox\n", list("OK 2\n"));
+        let mut client = TcpStream::connect(replica.local_addr()).unwrap();
+        assert!(request(&mut client, "GIMME daq fox\n").starts_with("FAIL E_DENIED"));
+
+        // Releases are passed on too and, without the primary, it says how
+        // stale its copy is:
+
+        drop((holder, other));
+        assert_eq!("OK 0\n", list("OK 0\n"));
+        primary.shutdown().unwrap();
+        assert!(list("OK 0 stale=").starts_with("OK 0 stale="));
+        let mut client = TcpStream::connect(replica.local_addr()).unwrap();
+        let health = request(&mut client, "HEALTH\n");
+        assert!(health.contains(" allocated=0 available=0 "));
+        assert!(health.contains(" replica-of=127.0.0.1:"));
+        assert!(health.contains(" stale="));
+        replica.shutdown().unwrap();
+    }
+    #[test]
+    fn takeover() {
+        let path = std::env::temp_dir().join(format!(
+            "portman-{}-server-handoff.sock",
+            std::process::id()
+        ));
+        let server = || {
+            Server::new()
+                .with_listen_address("127.0.0.1:0".parse().unwrap())
+                .with_port_range(31400, 2)
+                .with_handoff_socket(&path)
+        };
+        let old = server().start().unwrap();
+        let address = old.local_addr();
+        let mut daq = TcpStream::connect(address).unwrap();
+        let reply = request(&mut daq, "GIMME daq fox\n");
+        let token = reply.trim_end().strip_prefix("OK 31400 transfer=").unwrap();
+        let mut ring = TcpStream::connect(address).unwrap();
+        assert_eq!(
+            "OK 31401\n",
+            without_transfer(request(&mut ring, "GIMME ring fox\n"))
+        );
+        let mut waiter = TcpStream::connect(address).unwrap();
+        waiter.write_all(b"GIMME wait fox WAIT 30\n").unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        while !request(&mut client, "HEALTH\n").contains(" waiting=1 ") {
+            thread::sleep(Duration::from_millis(10));
+        }
+        daq.write_all(b"LI").unwrap();
+
+        // The new server takes over once the old one has stopped:
+
+        let new = {
+            let server = server().with_takeover();
+            thread::spawn(move || server.start())
+        };
+        old.wait().unwrap();
+        let new = new.join().unwrap().unwrap();
+        assert_eq!(address, new.local_addr());
+
+        // The connections, their ports and the queued request are all
+        // still there:
+
+        daq.write_all(b"ST\n").unwrap();
+        let mut list = BufReader::new(&daq);
+        let mut lines = String::new();
+        for _ in 0..3 {
+            list.read_line(&mut lines).unwrap();
+        }
+        assert_eq!("OK 2\n31400 daq fox\n31401 ring fox\n", lines);
+        drop(ring);
+        let mut granted = String::new();
+        BufReader::new(&waiter).read_line(&mut granted).unwrap();
+        assert_eq!("OK 31401\n", without_transfer(granted));
+
+        // As is the connection that holds nothing:
+
+        assert!(request(&mut client, "HEALTH\n").contains(" allocated=2 "));
+
+        // And the allocations can still be adopted:
+
+        let mut daemon = TcpStream::connect(address).unwrap();
+        let adopt = format!("ADOPT 31400 {}\n", token);
+        assert_eq!("OK 31400\n", without_transfer(request(&mut daemon, &adopt)));
+
+        new.shutdown().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+    #[test]
+    fn reclaimed() {
+        let path =
+            std::env::temp_dir().join(format!("portman-{}-server-state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = || {
+            Server::new()
+                .with_listen_address("127.0.0.1:0".parse().unwrap())
+                .with_port_range(31500, 10)
+                .with_state_file(&path)
+                .with_reclaim_grace(Duration::from_millis(500))
+                .start()
+                .unwrap()
+        };
+        let first = server();
+        let mut daq = TcpStream::connect(first.local_addr()).unwrap();
+        let reply = without_transfer(request(&mut daq, "GIMME daq fox\n"));
+        let token = reply.strip_prefix("OK 31500 reclaim=").unwrap().trim_end();
+        let mut ring = TcpStream::connect(first.local_addr()).unwrap();
+        assert!(request(&mut ring, "GIMME ring fox\n").starts_with("OK 31501 reclaim="));
+
+        // Leave the state behind as if the server had crashed.  It's
+        // written by another thread:
+
+        let mut saved = String::new();
+        for _ in 0..100 {
+            saved = std::fs::read_to_string(&path).unwrap_or_default();
+            if saved.lines().count() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(2, saved.lines().count());
+        first.shutdown().unwrap();
+        assert_eq!("", std::fs::read_to_string(&path).unwrap());
+        std::fs::write(&path, saved).unwrap();
+
+        let second = server();
+        let mut client = TcpStream::connect(second.local_addr()).unwrap();
+        assert!(request(&mut client, "RECLAIM 0123\n").starts_with("FAIL E_DENIED"));
+        let mut daq = TcpStream::connect(second.local_addr()).unwrap();
+        assert_eq!(
+            format!("OK 31500 reclaim={}\n", token),
+            without_transfer(request(&mut daq, &format!("RECLAIM {}\n", token)))
+        );
+
+        // The ring service doesn't come back for its port so it's freed:
+
+        let mut list = String::new();
+        for _ in 0..200 {
+            let mut client = TcpStream::connect(second.local_addr()).unwrap();
+            client.write_all(b"LIST\n").unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            list.clear();
+            client.read_to_string(&mut list).unwrap();
+            if list.starts_with("OK 1\n") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!("OK 1\n31500 daq fox\n", list);
+        second.shutdown().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+    #[test]
+    fn adopted() {
+        let server = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31600, 10)
+            .start()
+            .unwrap();
+        let mut launcher = TcpStream::connect(server.local_addr()).unwrap();
+        let reply = request(&mut launcher, "GIMME daq fox BLOCK 2\n");
+        let (ports, token) = reply.trim_end().split_once(" transfer=").unwrap();
+        assert_eq!("OK 31600 31601", ports);
+        assert_eq!(
+            "OK 31602\n",
+            without_transfer(request(&mut launcher, "GIMME ring fox\n"))
+        );
+
+        let mut daemon = TcpStream::connect(server.local_addr()).unwrap();
+        let wrong = request(&mut daemon, "ADOPT 31600 0123\n");
+        assert!(wrong.starts_with("FAIL E_DENIED"));
+        let mut daemon = TcpStream::connect(server.local_addr()).unwrap();
+        let adopt = format!("ADOPT 31601 {}\n", token);
+        let reply = request(&mut daemon, &adopt);
+        assert!(!reply.contains(token));
+        assert_eq!("OK 31600 31601\n", without_transfer(reply));
+
+        // When the launcher's connection closes only its own port is freed:
+
+        launcher.write_all(b"HELLO\n").unwrap();
+        let mut failed = String::new();
+        launcher.read_to_string(&mut failed).unwrap();
+        assert!(failed.starts_with("FAIL E_INVALID"));
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(request(&mut client, "HEALTH\n").contains(" allocated=2 "));
+
+        // The token has been used:
+
+        let mut other = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(request(&mut other, &adopt).starts_with("FAIL E_DENIED"));
+        server.shutdown().unwrap();
+    }
+    #[test]
+    fn connect() {
+        // A service on a port the system picked, which is the pool:
+
+        let service = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = service.local_addr().unwrap().port();
+        let server = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(port, 1)
+            .with_relay_rule("127.0.0.1=web,*".parse().unwrap())
+            .start()
+            .unwrap();
+        let mut holder = TcpStream::connect(server.local_addr()).unwrap();
+        let gimme = format!("GIMME web fox PORT {}\n", port);
+        assert_eq!(
+            format!("OK {}\n", port),
+            without_transfer(request(&mut holder, &gimme))
+        );
+
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"CONNECT web fox\nhello").unwrap();
+        let (mut accepted, _) = service.accept().unwrap();
+        let mut reply = [0u8; 32];
+        let n = client.read(&mut reply).unwrap();
+        assert_eq!(format!("OK {}\n", port).as_bytes(), &reply[..n]);
+        let mut greeting = [0u8; 5];
+        accepted.read_exact(&mut greeting).unwrap();
+        assert_eq!(b"hello", &greeting);
+        accepted.write_all(b"world").unwrap();
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(b"world", &greeting);
+
+        let mut health = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(request(&mut health, "HEALTH\n").ends_with(" relays=1\n"));
+
+        // Closing the service's end closes the client's:
+
+        drop(accepted);
+        let mut rest = Vec::new();
+        assert_eq!(0, client.read_to_end(&mut rest).unwrap());
+
+        // Not allowed by the rules:
+
+        let mut other = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(request(&mut other, "CONNECT daq fox\n").starts_with("FAIL E_DENIED"));
+
+        // Advertised but no longer listening:
+
+        drop(service);
+        let mut other = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(request(&mut other, "CONNECT web fox\n").starts_with("FAIL E_IO"));
+        server.shutdown().unwrap();
+    }
+    #[test]
+    fn bind_failure() {
+        let server = start();
+        let e = Server::new()
+            .with_listen_address(server.local_addr())
+            .start()
+            .err()
+            .unwrap();
+        assert_eq!("E_IO", e.code());
+    }
+    #[test]
+    fn bad_udp_range() {
+        let e = Server::new()
+            .with_listen_address("127.0.0.1:0".parse().unwrap())
+            .with_port_range(31000, 10)
+            .with_udp_range(31005, 10)
+            .start()
+            .err()
+            .unwrap();
+        assert_eq!("E_INVALID", e.code());
+    }
+}
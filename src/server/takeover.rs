@@ -0,0 +1,698 @@
+use crate::error::error::PortmanError;
+use crate::portpool::ports::UsedPort;
+use crate::responder::responder::ClientId;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Takeover:  a new portman process (e.g. after an upgrade) takes over from
+// the running one without dropping a connection, and so without dropping
+// an allocation.  The running process listens on a Unix socket (the
+// handoff socket, --handoff-socket).  The new one (--takeover) connects to
+// it and is sent the listening socket, every client connection and the
+// state that goes with them, the descriptors passed with SCM_RIGHTS.  Once
+// the new process has said it has them all, the old one stops serving and
+// exits without closing the connections.  The new process waits for it to
+// exit (so that it can bind the other listeners) and carries on.
+//
+// The handoff socket is a SOCK_SEQPACKET socket so that each message,
+// with the descriptor it carries, arrives whole:
+//
+//    HANDOVER 1 next-client tcp-base tcp-count [udp-base udp-count]
+//    LISTENER                                  (with the listening socket)
+//    CONNECTION client peer [requested] [eof] [reclaim=token]
+//                                              (with the connection), then
+//      PORT transfer-token port service-name user-name protocol
+//                                 for each port it holds
+//      INPUT hex                  received but not yet processed
+//      OUTPUT hex                 not yet sent
+//      QUEUED milliseconds hex    a GIMME ... WAIT request and its wait left
+//    DONE
+//
+// to which the new process replies OK.  The lines describing a connection
+// are all in its CONNECTION message.  If anything goes wrong before the OK
+// the old process carries on serving.  Only processes of the same user
+// (or root) may take over.
+
+const VERSION: &str = "1";
+
+///
+/// How long each side of a handover waits for the other.
+///
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// How long the new process waits for the old one to exit.
+///
+const EXIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The largest message we'll receive.  A connection's buffered input is
+// at most 64KiB, which is twice that in hex.  Its output has no such
+// bound, so a connection whose message would be bigger isn't handed over.
+
+const MAX_MESSAGE: usize = 256 * 1024;
+
+///
+/// PoolRanges
+///    The port ranges of the pool being handed over:  the TCP (or shared)
+/// range and the separate UDP range if there is one.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PoolRanges {
+    pub(crate) base: u16,
+    pub(crate) num: u16,
+    pub(crate) udp: Option<(u16, u16)>,
+}
+
+///
+/// Handed
+///    A client connection as it's handed over.
+///
+#[derive(Debug)]
+pub(crate) struct Handed {
+    pub(crate) stream: TcpStream,
+    pub(crate) client: ClientId,
+    pub(crate) peer: SocketAddr,
+    pub(crate) token: Option<String>, // Reclaims its ports (see responder::state).
+    pub(crate) requested: bool,
+    pub(crate) eof: bool,
+    pub(crate) ports: Vec<(String, UsedPort)>, // With their transfer tokens.
+    pub(crate) input: Vec<u8>,
+    pub(crate) output: Vec<u8>,
+    pub(crate) queued: Option<(String, Duration)>, // GIMME ... WAIT line and the wait left.
+}
+
+impl Handed {
+    // Whether the message describing the connection can be sent.
+
+    pub(crate) fn fits(&self) -> bool {
+        self.encode().len() <= MAX_MESSAGE
+    }
+    // The message describing the connection (its stream goes with it).
+
+    fn encode(&self) -> String {
+        let mut text = format!("CONNECTION {} {}", self.client, self.peer);
+        if self.requested {
+            text.push_str(" requested");
+        }
+        if self.eof {
+            text.push_str(" eof");
+        }
+        if let Some(token) = &self.token {
+            let _ = write!(text, " reclaim={}", token);
+        }
+        for (transfer, used) in &self.ports {
+            let _ = write!(text, "\nPORT {} {}", transfer, used);
+        }
+        if !self.input.is_empty() {
+            let _ = write!(text, "\nINPUT {}", hex(&self.input));
+        }
+        if !self.output.is_empty() {
+            let _ = write!(text, "\nOUTPUT {}", hex(&self.output));
+        }
+        if let Some((line, wait)) = &self.queued {
+            let _ = write!(
+                text,
+                "\nQUEUED {} {}",
+                wait.as_millis(),
+                hex(line.as_bytes())
+            );
+        }
+        text
+    }
+    // The connection a message describes.
+
+    fn decode(text: &str, stream: TcpStream) -> Result<Handed, String> {
+        let bad = || format!("Bad connection: {}", text);
+        let mut lines = text.lines();
+        let words: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+        if words.len() < 3 || words[0] != "CONNECTION" {
+            return Err(bad());
+        }
+        let mut handed = Handed {
+            stream,
+            client: words[1].parse().map_err(|_| bad())?,
+            peer: words[2].parse().map_err(|_| bad())?,
+            token: words[3..]
+                .iter()
+                .find_map(|w| w.strip_prefix("reclaim="))
+                .map(String::from),
+            requested: words[3..].contains(&"requested"),
+            eof: words[3..].contains(&"eof"),
+            ports: Vec::new(),
+            input: Vec::new(),
+            output: Vec::new(),
+            queued: None,
+        };
+        for line in lines {
+            let (kind, rest) = line.split_once(' ').ok_or_else(bad)?;
+            match kind {
+                "PORT" => {
+                    let (transfer, used) = rest.split_once(' ').ok_or_else(bad)?;
+                    let used = used.parse().map_err(|e: PortmanError| e.to_string())?;
+                    handed.ports.push((String::from(transfer), used));
+                }
+                "INPUT" => handed.input = unhex(rest).ok_or_else(bad)?,
+                "OUTPUT" => handed.output = unhex(rest).ok_or_else(bad)?,
+                "QUEUED" => {
+                    let (wait, line) = rest.split_once(' ').ok_or_else(bad)?;
+                    let wait = Duration::from_millis(wait.parse().map_err(|_| bad())?);
+                    let line = unhex(line).and_then(|l| String::from_utf8(l).ok());
+                    handed.queued = Some((line.ok_or_else(bad)?, wait));
+                }
+                _ => return Err(bad()),
+            }
+        }
+        Ok(handed)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Turn the result of a system call into an io::Result.
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn seqpacket(flags: libc::c_int) -> io::Result<OwnedFd> {
+    let fd = check(unsafe {
+        libc::socket(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC | flags,
+            0,
+        )
+    })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn unix_address(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let bytes = path.as_os_str().as_bytes();
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if bytes.len() >= address.sun_path.len() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is too long for a socket", path.display()),
+        ));
+    }
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (to, from) in address.sun_path.iter_mut().zip(bytes) {
+        *to = *from as libc::c_char;
+    }
+    let length = mem::size_of::<libc::sa_family_t>() + bytes.len() + 1;
+    Ok((address, length as libc::socklen_t))
+}
+
+///
+/// Link
+///    One end of a connection over the handoff socket.
+///
+#[derive(Debug)]
+pub(crate) struct Link {
+    fd: OwnedFd,
+}
+
+impl Link {
+    fn connect(path: &Path) -> io::Result<Link> {
+        let fd = seqpacket(0)?;
+        let (address, length) = unix_address(path)?;
+        check(unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_un as *const libc::sockaddr,
+                length,
+            )
+        })?;
+        Ok(Link { fd })
+    }
+    ///
+    /// The pid and uid of the process at the other end.
+    ///
+    pub(crate) fn peer(&self) -> io::Result<(libc::pid_t, libc::uid_t)> {
+        let mut credentials: libc::ucred = unsafe { mem::zeroed() };
+        let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut length,
+            )
+        })?;
+        Ok((credentials.pid, credentials.uid))
+    }
+    ///
+    /// Send the message *text* and, if given, the descriptor *fd*.
+    ///
+    pub(crate) fn send(&self, text: &str, fd: Option<RawFd>) -> io::Result<()> {
+        let mut iov = libc::iovec {
+            iov_base: text.as_ptr() as *mut libc::c_void,
+            iov_len: text.len(),
+        };
+        let mut control = [0u64; 8]; // Aligned room for one descriptor.
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        if let Some(fd) = fd {
+            unsafe {
+                message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                message.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize;
+                let header = libc::CMSG_FIRSTHDR(&message);
+                (*header).cmsg_level = libc::SOL_SOCKET;
+                (*header).cmsg_type = libc::SCM_RIGHTS;
+                (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as usize;
+                std::ptr::write_unaligned(libc::CMSG_DATA(header) as *mut RawFd, fd);
+            }
+        }
+        let sent = unsafe { libc::sendmsg(self.fd.as_raw_fd(), &message, libc::MSG_NOSIGNAL) };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else if sent as usize != text.len() {
+            Err(io::Error::other("the message was cut short"))
+        } else {
+            Ok(())
+        }
+    }
+    ///
+    /// Receive a message and the descriptor that came with it, if any,
+    /// waiting up to *timeout* for it.  None means the other end has
+    /// closed the link.
+    ///
+    pub(crate) fn receive(
+        &self,
+        timeout: Duration,
+    ) -> io::Result<Option<(String, Option<OwnedFd>)>> {
+        let mut poll = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        if check(unsafe { libc::poll(&mut poll, 1, millis) })? == 0 {
+            return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+        }
+        let mut buffer = vec![0u8; MAX_MESSAGE];
+        let mut iov = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control);
+        let received =
+            unsafe { libc::recvmsg(self.fd.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Take ownership of what we were sent before anything can fail so
+        // that it's closed if it's not wanted:
+
+        let mut fds = Vec::new();
+        unsafe {
+            let mut header = libc::CMSG_FIRSTHDR(&message);
+            while !header.is_null() {
+                if (*header).cmsg_level == libc::SOL_SOCKET
+                    && (*header).cmsg_type == libc::SCM_RIGHTS
+                {
+                    let data = libc::CMSG_DATA(header) as *const RawFd;
+                    let count =
+                        ((*header).cmsg_len - libc::CMSG_LEN(0) as usize) / mem::size_of::<RawFd>();
+                    for i in 0..count {
+                        fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                    }
+                }
+                header = libc::CMSG_NXTHDR(&message, header);
+            }
+        }
+        if received == 0 {
+            return Ok(None);
+        }
+        if message.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
+            return Err(io::Error::other("the message was too big"));
+        }
+        buffer.truncate(received as usize);
+        let text = String::from_utf8(buffer)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "the message isn't text"))?;
+        Ok(Some((text, fds.into_iter().next())))
+    }
+}
+
+///
+/// HandoffListener
+///    The handoff socket of a running server, on which it waits for a new
+/// process to take over.
+///
+#[derive(Debug)]
+pub(crate) struct HandoffListener {
+    fd: OwnedFd,
+}
+
+impl HandoffListener {
+    ///
+    /// bind
+    ///    Listen on *path*, which only our user may connect to.  A socket
+    ///    left there by a process that's gone is replaced but one that's
+    ///    still served is an error:  that process should be taken over.
+    ///
+    pub(crate) fn bind(path: &Path) -> io::Result<HandoffListener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket", path.display()),
+                ));
+            }
+            if Link::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("A server is already listening on {}", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        let fd = seqpacket(libc::SOCK_NONBLOCK)?;
+        let (address, length) = unix_address(path)?;
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_un as *const libc::sockaddr,
+                length,
+            )
+        })?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        check(unsafe { libc::listen(fd.as_raw_fd(), 4) })?;
+        Ok(HandoffListener { fd })
+    }
+    ///
+    /// accept
+    ///    A pending connection or None if there isn't one.  The link blocks
+    ///    (with timeouts) as the handover is done all at once.
+    ///
+    pub(crate) fn accept(&self) -> io::Result<Option<Link>> {
+        let fd = unsafe {
+            libc::accept4(
+                self.fd.as_raw_fd(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                ErrorKind::WouldBlock => Ok(None),
+                _ => Err(error),
+            };
+        }
+        Ok(Some(Link {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        }))
+    }
+}
+
+impl AsRawFd for HandoffListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+///
+/// hand_over
+///    The old process's side:  send *ranges*, *listener* and *connections*
+///    over *link* and wait for the new process to say it has them.  If
+///    this fails, nothing has been given up.
+///
+pub(crate) fn hand_over(
+    link: &Link,
+    ranges: PoolRanges,
+    next_client: ClientId,
+    listener: &TcpListener,
+    connections: &[Handed],
+) -> io::Result<()> {
+    let mut header = format!(
+        "HANDOVER {} {} {} {}",
+        VERSION, next_client, ranges.base, ranges.num
+    );
+    if let Some((base, num)) = ranges.udp {
+        let _ = write!(header, " {} {}", base, num);
+    }
+    link.send(&header, None)?;
+    link.send("LISTENER", Some(listener.as_raw_fd()))?;
+    for handed in connections {
+        link.send(&handed.encode(), Some(handed.stream.as_raw_fd()))?;
+    }
+    link.send("DONE", None)?;
+    match link.receive(HANDOVER_TIMEOUT)? {
+        Some((reply, _)) if reply == "OK" => Ok(()),
+        Some((reply, _)) => Err(io::Error::other(format!("it said {}", reply))),
+        None => Err(io::Error::other("it went away")),
+    }
+}
+
+///
+/// Handover
+///    What a new process is handed by the one it takes over from.
+///
+#[derive(Debug)]
+pub(crate) struct Handover {
+    pub(crate) ranges: PoolRanges,
+    pub(crate) next_client: ClientId,
+    pub(crate) listener: TcpListener,
+    pub(crate) connections: Vec<Handed>,
+    link: Link,
+}
+
+///
+/// take_over
+///    The new process's side:  connect to the handoff socket at *path*
+///    and receive everything.  Once we've said we have it the old process
+///    stops serving.
+///
+pub(crate) fn take_over(path: &Path) -> Result<Handover, PortmanError> {
+    let failed = |e: &dyn std::fmt::Display| {
+        PortmanError::Io(format!(
+            "Unable to take over from {}: {}",
+            path.display(),
+            e
+        ))
+    };
+    let link = Link::connect(path).map_err(|e| failed(&e))?;
+    let receive = || -> Result<(String, Option<OwnedFd>), PortmanError> {
+        match link.receive(HANDOVER_TIMEOUT) {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => Err(failed(&"it closed the connection")),
+            Err(e) => Err(failed(&e)),
+        }
+    };
+    let (header, _) = receive()?;
+    let words: Vec<&str> = header.split(' ').collect();
+    let numbers: Vec<u64> = words
+        .iter()
+        .skip(2)
+        .filter_map(|w| w.parse().ok())
+        .collect();
+    let ranges = match (words.first(), words.get(1), numbers.len()) {
+        (Some(&"HANDOVER"), Some(&VERSION), n) if n == words.len() - 2 && (n == 3 || n == 5) => {
+            let port = |i: usize| u16::try_from(numbers[i]).map_err(|_| failed(&header));
+            PoolRanges {
+                base: port(1)?,
+                num: port(2)?,
+                udp: if n == 5 {
+                    Some((port(3)?, port(4)?))
+                } else {
+                    None
+                },
+            }
+        }
+        _ => return Err(failed(&format!("unexpected {}", header))),
+    };
+    let next_client = numbers[0];
+    let listener = match receive()? {
+        (message, Some(fd)) if message == "LISTENER" => TcpListener::from(fd),
+        (message, _) => return Err(failed(&format!("unexpected {}", message))),
+    };
+    let mut connections = Vec::new();
+    loop {
+        match receive()? {
+            (message, None) if message == "DONE" => break,
+            (message, Some(fd)) => {
+                let handed =
+                    Handed::decode(&message, TcpStream::from(fd)).map_err(|e| failed(&e))?;
+                connections.push(handed);
+            }
+            (message, None) => return Err(failed(&format!("unexpected {}", message))),
+        }
+    }
+    link.send("OK", None).map_err(|e| failed(&e))?;
+    Ok(Handover {
+        ranges,
+        next_client,
+        listener,
+        connections,
+        link,
+    })
+}
+
+impl Handover {
+    ///
+    /// Wait for the old process to exit, which closes its end of the link.
+    /// Returns false if it's still there after a while.
+    ///
+    pub(crate) fn wait_for_exit(&self) -> bool {
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.link.receive(remaining) {
+                Ok(None) => return true,
+                Ok(Some(_)) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    fn handed(stream: TcpStream) -> Handed {
+        Handed {
+            stream,
+            client: 12,
+            peer: "127.0.0.1:40312".parse().unwrap(),
+            token: Some(String::from("0a1b2c")),
+            requested: true,
+            eof: false,
+            ports: vec![
+                (
+                    String::from("3c4d"),
+                    UsedPort::new(31000, "event builder", "fox"),
+                ),
+                (
+                    String::from("3c4d"),
+                    UsedPort::new(31001, "event builder", "fox"),
+                ),
+            ],
+            input: b"LI".to_vec(),
+            output: b"OK 31000 31001\n".to_vec(),
+            queued: Some((
+                String::from("GIMME ring fox WAIT 30"),
+                Duration::from_millis(2500),
+            )),
+        }
+    }
+    #[test]
+    fn encodes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let original = handed(stream.try_clone().unwrap());
+        let text = original.encode();
+        assert!(text.starts_with(
+            "CONNECTION 12 127.0.0.1:40312 requested reclaim=0a1b2c\nPORT 3c4d 31000 \"event builder\" fox tcp\n"
+        ));
+        let copy = Handed::decode(&text, stream).unwrap();
+        assert_eq!(original.ports, copy.ports);
+        assert_eq!(original.input, copy.input);
+        assert_eq!(original.output, copy.output);
+        assert_eq!(original.queued, copy.queued);
+        assert_eq!(original.token, copy.token);
+        assert_eq!((12, true, false), (copy.client, copy.requested, copy.eof));
+
+        // Output can't be handed over without limit:
+
+        let mut original = original;
+        assert!(original.fits());
+        original.output = vec![b'x'; MAX_MESSAGE / 2];
+        assert!(!original.fits());
+
+        let stream = original.stream;
+        assert!(Handed::decode("CONNECTION 12", stream.try_clone().unwrap()).is_err());
+        assert!(Handed::decode(&format!("{}\nINPUT 4", text), stream).is_err());
+        assert_eq!(Some(vec![0, 0xab]), unhex("00ab"));
+    }
+    #[test]
+    fn hands_over() {
+        let path =
+            std::env::temp_dir().join(format!("portman-{}-handoff.sock", std::process::id()));
+        let handoff = HandoffListener::bind(&path).unwrap();
+
+        // Only one server per socket:
+
+        assert!(HandoffListener::bind(&path).is_err());
+        let probe = handoff.accept().unwrap().unwrap();
+        assert!(probe.receive(Duration::from_secs(1)).unwrap().is_none());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        let ranges = PoolRanges {
+            base: 31000,
+            num: 10,
+            udp: Some((32000, 5)),
+        };
+
+        let taking = {
+            let path = path.clone();
+            thread::spawn(move || take_over(&path))
+        };
+        let link = loop {
+            if let Some(link) = handoff.accept().unwrap() {
+                break link;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(unsafe { libc::getuid() }, link.peer().unwrap().1);
+        hand_over(&link, ranges, 13, &listener, &[handed(server_side)]).unwrap();
+        let handover = taking.join().unwrap().unwrap();
+        assert_eq!(ranges, handover.ranges);
+        assert_eq!(13, handover.next_client);
+        assert_eq!(1, handover.connections.len());
+
+        // The descriptors we got are the same sockets:
+
+        drop((listener, link));
+        assert!(handover.wait_for_exit());
+        assert_eq!(address, handover.listener.local_addr().unwrap());
+        let mut stream = &handover.connections[0].stream;
+        stream.write_all(b"hello\n").unwrap();
+        let mut greeting = [0u8; 6];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(b"hello\n", &greeting);
+
+        drop(handoff);
+        let _ = fs::remove_file(&path);
+    }
+}
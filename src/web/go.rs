@@ -0,0 +1,178 @@
+use super::http::{escape_html, percent_encode, Response};
+use super::routes::page;
+use crate::portpool::ports::{Protocol, UsedPort};
+use std::collections::BTreeSet;
+
+// Redirects to web services by name so that people can browse to
+// /go/{user}/{service} rather than look up the service's port first.
+// Only TCP allocations are considered; a service with several ports
+// (e.g. a block) is reached on the lowest.  When nothing matches, the
+// page lists the services with similar names in case it was mistyped.
+
+// The most similar names listed.
+
+const MAX_SIMILAR: usize = 10;
+
+///
+/// go
+///    The response to /go/{user}/{service}:  a redirect to the service on
+/// *host* (the host the request was sent to) or a page of similar names.
+/// *allocations* are all of the allocations, in port order.
+///
+pub(crate) fn go(allocations: &[UsedPort], user: &str, service: &str, host: &str) -> Response {
+    let tcp: Vec<&UsedPort> = allocations
+        .iter()
+        .filter(|a| a.protocol() == Protocol::Tcp)
+        .collect();
+    if let Some(used) = tcp
+        .iter()
+        .find(|a| a.user() == user && a.service() == service)
+    {
+        let location = format!("http://{}:{}/", host, used.port());
+        let body = format!(
+            "<p>{} is at <a href=\"{}\">{}</a></p>\n",
+            escape_html(service),
+            escape_html(&location),
+            escape_html(&location)
+        );
+        return Response::html(302, page(service, &body)).with_header("Location", &location);
+    }
+    let names: BTreeSet<(String, String)> = tcp.iter().map(|a| (a.user(), a.service())).collect();
+    let similar = similar(&names, user, service);
+    let mut body = format!(
+        "<p>No web service {} is advertised for {}.</p>\n",
+        escape_html(service),
+        escape_html(user)
+    );
+    if !similar.is_empty() {
+        body.push_str("<p>Did you mean:</p>\n<ul>\n");
+        for (u, s) in similar {
+            body.push_str(&format!(
+                "<li><a href=\"/go/{}/{}\">{} for {}</a></li>\n",
+                percent_encode(u),
+                percent_encode(s),
+                escape_html(s),
+                escape_html(u)
+            ));
+        }
+        body.push_str("</ul>\n");
+    }
+    Response::html(404, page("Not found", &body))
+}
+
+// The (user, service) names most like those asked for:  ones a few
+// typing mistakes away, the same service for other users and services
+// for the user whose names contain the one asked for (or vice versa).
+// Case is ignored.  The closest come first.
+
+fn similar<'a>(
+    names: &'a BTreeSet<(String, String)>,
+    user: &str,
+    service: &str,
+) -> Vec<(&'a str, &'a str)> {
+    let user = user.to_lowercase();
+    let service = service.to_lowercase();
+    let allowed = 2.max((user.chars().count() + service.chars().count()) / 4);
+    let mut scored: Vec<(usize, &str, &str)> = names
+        .iter()
+        .filter_map(|(u, s)| {
+            let (lu, ls) = (u.to_lowercase(), s.to_lowercase());
+            let distance = edit_distance(&lu, &user) + edit_distance(&ls, &service);
+            let close = distance <= allowed
+                || ls == service
+                || (lu == user && (ls.contains(&service) || service.contains(&ls)));
+            if close {
+                Some((distance, u.as_str(), s.as_str()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    scored.sort();
+    scored
+        .into_iter()
+        .take(MAX_SIMILAR)
+        .map(|(_, u, s)| (u, s))
+        .collect()
+}
+
+// The Levenshtein distance between two strings:  the number of characters
+// that must be inserted, deleted or changed to turn one into the other.
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let change = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(change.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocations() -> Vec<UsedPort> {
+        vec![
+            UsedPort::with_protocol(31000, "ring", "fox", Protocol::Udp),
+            UsedPort::new(31001, "ring", "fox"),
+            UsedPort::new(31002, "ring", "fox"),
+            UsedPort::new(31003, "webui", "fox"),
+            UsedPort::new(31004, "webui", "owl"),
+            UsedPort::new(31005, "daq web", "fox"),
+            UsedPort::new(31006, "spectcl", "owl"),
+        ]
+    }
+    fn location(response: &Response) -> Option<&str> {
+        response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Location")
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn distances() {
+        assert_eq!(0, edit_distance("webui", "webui"));
+        assert_eq!(1, edit_distance("webui", "webu"));
+        assert_eq!(1, edit_distance("webui", "wepui"));
+        assert_eq!(2, edit_distance("webui", "ewbui"));
+        assert_eq!(5, edit_distance("", "webui"));
+    }
+    #[test]
+    fn redirects() {
+        let response = go(&allocations(), "fox", "ring", "daq.example");
+        assert_eq!(302, response.status);
+        assert_eq!(Some("http://daq.example:31001/"), location(&response));
+        let response = go(&allocations(), "owl", "webui", "[::1]");
+        assert_eq!(Some("http://[::1]:31004/"), location(&response));
+    }
+    #[test]
+    fn not_found() {
+        let response = go(&allocations(), "fox", "WebUI", "daq.example");
+        assert_eq!(404, response.status);
+        assert_eq!(None, location(&response));
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("<li><a href=\"/go/fox/webui\">webui for fox</a></li>"));
+
+        let names: BTreeSet<(String, String)> = allocations()
+            .iter()
+            .map(|a| (a.user(), a.service()))
+            .collect();
+        assert_eq!(
+            vec![("fox", "webui"), ("fox", "daq web")],
+            similar(&names, "fox", "web")
+        );
+        assert_eq!(vec![("owl", "spectcl")], similar(&names, "owl", "spectlc"));
+        assert!(similar(&names, "bat", "nothing").is_empty());
+
+        let body = String::from_utf8(go(&[], "fox", "<ui>", "x").body).unwrap();
+        assert!(body.contains("No web service &lt;ui&gt; is advertised for fox."));
+        assert!(!body.contains("<ul>"));
+    }
+}
@@ -0,0 +1,686 @@
+use crate::error::error::PortmanError;
+use crate::log_debug;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Just enough HTTP/1.1 for the port manager's web pages and APIs.  Requests
+// are few and small, so a single thread serves them all from one mio Poll
+// with non-blocking connections:  each connection gets one response and is
+// then closed.  Clients that are slow to send their request or take the
+// response time out, and only so many are served at once, so none can
+// hold up the others.
+//
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+// The most we'll read of a request's head, and how long we'll wait for it
+// or for the response to be taken.
+
+const MAX_HEAD: usize = 8192;
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The most connections served at once.  Those over are told to come back
+// later.
+
+const MAX_CLIENTS: usize = 64;
+
+///
+/// Request
+///    The parts of an HTTP request we use.  The path has its query string
+/// removed and is not decoded.  The host is from the Host header or, if
+/// there isn't one, the address the request was sent to.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub host: Option<String>,
+    pub peer: SocketAddr,
+}
+
+impl Request {
+    ///
+    /// The path split into its segments, each percent-decoded.  None if
+    /// a segment doesn't decode to UTF-8.
+    ///
+    pub fn segments(&self) -> Option<Vec<String>> {
+        self.path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(percent_decode)
+            .collect()
+    }
+    ///
+    /// The host without its port, e.g. daq.example for daq.example:30001
+    /// or [::1] for [::1]:30001.
+    ///
+    pub fn host_name(&self) -> Option<&str> {
+        let host = self.host.as_deref()?;
+        let name = if host.starts_with('[') {
+            host.find(']').map(|end| &host[..=end]).unwrap_or(host)
+        } else {
+            host.split(':').next().unwrap_or(host)
+        };
+        Some(name).filter(|name| !name.is_empty())
+    }
+}
+
+///
+/// Response
+///    An HTTP response.  The Content-Length and Connection headers are
+/// added when it's sent.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status,
+            headers: vec![(String::from("Content-Type"), String::from(content_type))],
+            body: body.into(),
+        }
+    }
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", body)
+    }
+    pub fn json(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status, "application/json", body)
+    }
+    pub fn html(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status, "text/html; charset=utf-8", body)
+    }
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+    // The response as sent:  HEAD requests get the headers only.
+
+    fn to_bytes(&self, head_only: bool) -> Vec<u8> {
+        let mut result = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            result.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        result.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        let mut result = result.into_bytes();
+        if !head_only {
+            result.extend_from_slice(&self.body);
+        }
+        result
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        302 => "Found",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+///
+/// percent_decode
+///    Decode the %xx escapes in part of a URL.  A '+' is left alone since
+///    it only means a space in forms.  None if the result isn't UTF-8.
+///
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(b) => {
+                result.push(b);
+                i += 3;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+///
+/// percent_encode
+///    Escape what isn't unreserved in a URL path segment.
+///
+pub fn percent_encode(s: &str) -> String {
+    let mut result = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{:02X}", b));
+        }
+    }
+    result
+}
+
+///
+/// escape_html
+///    Make text safe to put in an HTML page.
+///
+pub fn escape_html(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// Whether a Host header is a host name or address with, optionally, a
+// port:  e.g. daq.example, 10.0.0.1:30001 or [::1]:30001.  What's in it
+// may end up in a Location header.
+
+fn valid_host(host: &str) -> bool {
+    let (name, port) = if let Some(rest) = host.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((address, port)) if address.parse::<Ipv6Addr>().is_ok() => (None, port),
+            _ => return false,
+        }
+    } else {
+        let end = host.find(':').unwrap_or(host.len());
+        (Some(&host[..end]), &host[end..])
+    };
+    let name_ok = name.is_none_or(|name| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    });
+    let port_ok = port.is_empty()
+        || port
+            .strip_prefix(':')
+            .is_some_and(|port| port.parse::<u16>().is_ok());
+    name_ok && port_ok
+}
+
+// Parse a request head.  Err is the response for a bad request.  Lines
+// are ended by CRLF; anything else that's a control character (but tab),
+// such as a bare LF, is refused rather than passed on.
+
+fn parse_head(head: &str, peer: SocketAddr) -> Result<Request, Response> {
+    if head
+        .split("\r\n")
+        .any(|line| line.chars().any(|c| c.is_control() && c != '\t'))
+    {
+        return Err(Response::text(400, "Control characters in the request\n"));
+    }
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let words: Vec<&str> = request_line.split(' ').collect();
+    if words.len() != 3 || !words[2].starts_with("HTTP/1.") {
+        return Err(Response::text(400, "Malformed request line\n"));
+    }
+    let (path, query) = match words[1].split_once('?') {
+        Some((path, query)) => (path, Some(String::from(query))),
+        None => (words[1], None),
+    };
+    if !path.starts_with('/') {
+        return Err(Response::text(400, "Only paths are accepted\n"));
+    }
+    let host = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| String::from(value.trim()));
+    if host.as_deref().is_some_and(|host| !valid_host(host)) {
+        return Err(Response::text(400, "Invalid Host\n"));
+    }
+    Ok(Request {
+        method: String::from(words[0]),
+        path: String::from(path),
+        query,
+        host,
+        peer,
+    })
+}
+
+// Read what's come of a request head from a stream into 'head'.
+
+enum Head {
+    Partial,                            // More is to come.
+    Complete(Result<String, Response>), // Err is the response to send.
+    Gone,                               // The client went away.
+}
+
+fn read_head(stream: &mut TcpStream, head: &mut Vec<u8>) -> Head {
+    let mut buffer = [0u8; 1024];
+    loop {
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            head.truncate(end);
+            let head = std::mem::take(head);
+            return Head::Complete(
+                String::from_utf8(head).map_err(|_| Response::text(400, "Request isn't UTF-8\n")),
+            );
+        }
+        if head.len() > MAX_HEAD {
+            return Head::Complete(Err(Response::text(431, "Request too large\n")));
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) => return Head::Gone,
+            Ok(n) => head.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Head::Partial,
+            Err(_) => return Head::Gone,
+        }
+    }
+}
+
+///
+/// HttpStop
+///    Stops an HttpServer from another thread.
+///
+pub(crate) struct HttpStop {
+    stop: AtomicBool,
+    waker: Waker,
+}
+
+impl HttpStop {
+    pub(crate) fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+///
+/// HttpServer
+///    Accepts HTTP connections and answers each request with what the
+/// handler given to run returns.  Only GET and HEAD are accepted.
+///
+pub(crate) struct HttpServer {
+    poll: Poll,
+    listener: TcpListener,
+    clients: HashMap<Token, Client>,
+    next_token: usize,
+    stop: Arc<HttpStop>,
+}
+
+impl HttpServer {
+    pub(crate) fn bind(address: SocketAddr) -> Result<HttpServer, PortmanError> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let poll = Poll::new()?;
+        poll.registry().register(
+            &mut SourceFd(&listener.as_raw_fd()),
+            LISTENER,
+            Interest::READABLE,
+        )?;
+        let stop = Arc::new(HttpStop {
+            stop: AtomicBool::new(false),
+            waker: Waker::new(poll.registry(), WAKER)?,
+        });
+        Ok(HttpServer {
+            poll,
+            listener,
+            clients: HashMap::new(),
+            next_token: 2,
+            stop,
+        })
+    }
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+    pub(crate) fn stopper(&self) -> Arc<HttpStop> {
+        Arc::clone(&self.stop)
+    }
+    ///
+    /// Serve requests until stopped.
+    ///
+    pub(crate) fn run<F>(mut self, handler: F)
+    where
+        F: Fn(&Request) -> Response,
+    {
+        let mut events = Events::with_capacity(64);
+        while !self.stop.stop.load(Ordering::SeqCst) {
+            let timeout = self
+                .clients
+                .values()
+                .map(|c| c.deadline)
+                .min()
+                .map(|d| d.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => {}
+                    token => {
+                        let done = match self.clients.get_mut(&token) {
+                            Some(client) => client.serve(&handler),
+                            None => false,
+                        };
+                        if done {
+                            self.close(token);
+                        }
+                    }
+                }
+            }
+            self.time_out();
+        }
+    }
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, peer)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    if self.clients.len() >= MAX_CLIENTS {
+                        let busy = Response::text(503, "Too many requests at once\n");
+                        let _ = stream.write(&busy.to_bytes(false));
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    if self
+                        .poll
+                        .registry()
+                        .register(
+                            &mut SourceFd(&stream.as_raw_fd()),
+                            token,
+                            Interest::READABLE | Interest::WRITABLE,
+                        )
+                        .is_ok()
+                    {
+                        self.clients.insert(token, Client::new(stream, peer));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break, // WouldBlock or e.g. out of descriptors.
+            }
+        }
+    }
+    // Those that haven't sent their request in time are told so; those
+    // that haven't taken their response are dropped.
+    //
+    fn time_out(&mut self) {
+        let now = Instant::now();
+        let overdue: Vec<Token> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in overdue {
+            let done = match self.clients.get_mut(&token) {
+                Some(client) if client.output.is_none() => {
+                    client.respond(
+                        Response::text(408, "Timed out reading the request\n"),
+                        false,
+                    );
+                    client.flush()
+                }
+                _ => true,
+            };
+            if done {
+                self.close(token);
+            }
+        }
+    }
+    fn close(&mut self, token: Token) {
+        if let Some(client) = self.clients.remove(&token) {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&client.stream.as_raw_fd()));
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// A connection being served:  its request head as it comes in, then its
+// response as it goes out.
+
+struct Client {
+    stream: TcpStream,
+    peer: SocketAddr,
+    head: Vec<u8>,
+    output: Option<Vec<u8>>, // The response, once there is one.
+    written: usize,
+    deadline: Instant,
+}
+
+impl Client {
+    fn new(stream: TcpStream, peer: SocketAddr) -> Client {
+        Client {
+            stream,
+            peer,
+            head: Vec::new(),
+            output: None,
+            written: 0,
+            deadline: Instant::now() + IO_TIMEOUT,
+        }
+    }
+    // Do what we can now.  True once the connection is done with.
+    //
+    fn serve<F>(&mut self, handler: &F) -> bool
+    where
+        F: Fn(&Request) -> Response,
+    {
+        if self.output.is_none() {
+            match read_head(&mut self.stream, &mut self.head) {
+                Head::Partial => return false,
+                Head::Gone => return true,
+                Head::Complete(head) => {
+                    let (response, head_only) = self.answer(head, handler);
+                    self.respond(response, head_only);
+                }
+            }
+        }
+        self.flush()
+    }
+    // The response to a request head and whether only its headers are
+    // sent.
+    //
+    fn answer<F>(&self, head: Result<String, Response>, handler: &F) -> (Response, bool)
+    where
+        F: Fn(&Request) -> Response,
+    {
+        let mut head_only = false;
+        let response = match head.and_then(|head| parse_head(&head, self.peer)) {
+            Err(response) => response,
+            Ok(mut request) => {
+                if request.host.is_none() {
+                    request.host = self.stream.local_addr().ok().map(|a| a.to_string());
+                }
+                log_debug!(
+                    "HTTP {} {} from {}",
+                    request.method,
+                    request.path,
+                    self.peer
+                );
+                match request.method.as_str() {
+                    "GET" => handler(&request),
+                    "HEAD" => {
+                        head_only = true;
+                        handler(&request)
+                    }
+                    _ => Response::text(405, "Only GET and HEAD are supported\n")
+                        .with_header("Allow", "GET, HEAD"),
+                }
+            }
+        };
+        (response, head_only)
+    }
+    fn respond(&mut self, response: Response, head_only: bool) {
+        self.output = Some(response.to_bytes(head_only));
+        self.deadline = Instant::now() + IO_TIMEOUT;
+    }
+    // Send what we can of the response.  True once it's all gone or the
+    // client has.
+    //
+    fn flush(&mut self) -> bool {
+        let output = match &self.output {
+            Some(output) => output,
+            None => return false,
+        };
+        while self.written < output.len() {
+            match self.stream.write(&output[self.written..]) {
+                Ok(0) => return true,
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(_) => return true,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    #[test]
+    fn heads() {
+        let request = parse_head(
+            "GET /allocations/my%20daq/fox?x=1 HTTP/1.1\r\nHost: daq.example:80",
+            peer(),
+        )
+        .unwrap();
+        assert_eq!("GET", request.method);
+        assert_eq!("/allocations/my%20daq/fox", request.path);
+        assert_eq!(Some(String::from("x=1")), request.query);
+        assert_eq!(Some(String::from("daq.example:80")), request.host);
+        assert_eq!(
+            Some(vec![
+                String::from("allocations"),
+                String::from("my daq"),
+                String::from("fox")
+            ]),
+            request.segments()
+        );
+        assert_eq!(Some("daq.example"), request.host_name());
+        let host = |host: &str| Request {
+            host: Some(String::from(host)),
+            ..request.clone()
+        };
+        assert_eq!(Some("daq"), host("daq").host_name());
+        assert_eq!(Some("[::1]"), host("[::1]:30001").host_name());
+        assert_eq!(None, host(":80").host_name());
+        assert_eq!(400, parse_head("GET /", peer()).unwrap_err().status);
+        assert_eq!(
+            400,
+            parse_head("GET x HTTP/1.1", peer()).unwrap_err().status
+        );
+
+        // Nothing that could split a header when it's sent back:
+
+        for bad in &[
+            "GET / HTTP/1.1\r\nHost: daq\nX-Injected: 1",
+            "GET / HTTP/1.1\r\nHost: daq\rX-Injected: 1",
+            "GET /\x00 HTTP/1.1\r\nHost: daq",
+            "GET / HTTP/1.1\r\nHost: daq/evil",
+            "GET / HTTP/1.1\r\nHost: daq:80x",
+            "GET / HTTP/1.1\r\nHost: [daq]:80",
+        ] {
+            assert_eq!(
+                400,
+                parse_head(bad, peer()).unwrap_err().status,
+                "{:?}",
+                bad
+            );
+        }
+        for good in &[
+            "daq",
+            "daq.example:30001",
+            "10.0.0.1",
+            "[::1]",
+            "[::1]:30001",
+        ] {
+            let head = format!("GET / HTTP/1.1\r\nUser-Agent: a\tb\r\nHost: {}", good);
+            assert!(parse_head(&head, peer()).is_ok(), "{}", good);
+        }
+    }
+    #[test]
+    fn escapes() {
+        assert_eq!(Some(String::from("a b%")), percent_decode("a%20b%"));
+        assert_eq!(Some(String::from("%zz")), percent_decode("%zz"));
+        assert_eq!(None, percent_decode("%ff"));
+        assert_eq!("my%20daq%2F1", percent_encode("my daq/1"));
+        assert_eq!("&lt;b&gt; &amp; &quot;", escape_html("<b> & \""));
+    }
+    #[test]
+    fn serves() {
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr().unwrap();
+        let stop = server.stopper();
+        let thread = thread::spawn(move || {
+            server.run(|r| Response::text(200, format!("You asked for {}\n", r.path)))
+        });
+        let fetch = |request: &[u8]| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        // A client that's slow to send its request doesn't hold up the
+        // others:
+
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"GET /slow HTTP/1.1\r\n").unwrap();
+        let response = fetch(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 23\r\n"));
+        assert!(response.ends_with("\r\n\r\nYou asked for /metrics\n"));
+        let response = fetch(b"HEAD / HTTP/1.0\r\n\r\n");
+        assert!(response.ends_with("Connection: close\r\n\r\n"));
+        assert!(fetch(b"POST / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+        slow.write_all(b"\r\n").unwrap();
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nYou asked for /slow\n"));
+        stop.stop();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use crate::error::error::PortmanError;
+use crate::portpool::ports::UsedPort;
+use std::fmt::Write;
+
+// The little JSON the HTTP API produces.  It's all flat objects and arrays
+// of them, so it's simpler to write them out here than to pull in a JSON
+// library.
+
+///
+/// string
+///    A JSON string literal holding *s*.
+///
+pub fn string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(result, "\\u{:04x}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+///
+/// allocation
+///    An allocation as a JSON object, e.g.
+///    {"port":31000,"protocol":"tcp","service":"daq","user":"fox"}
+///
+pub fn allocation(used: &UsedPort) -> String {
+    format!(
+        "{{\"port\":{},\"protocol\":{},\"service\":{},\"user\":{}}}",
+        used.port(),
+        string(&used.protocol().to_string()),
+        string(&used.service()),
+        string(&used.user())
+    )
+}
+
+///
+/// allocations
+///    A JSON array of allocations.
+///
+pub fn allocations(used: &[UsedPort]) -> String {
+    let objects: Vec<String> = used.iter().map(allocation).collect();
+    format!("[{}]", objects.join(","))
+}
+
+///
+/// error
+///    A failure as a JSON object with the code a FAIL reply would carry,
+///    e.g. {"code":"E_NOT_FOUND","message":"daq fox is not advertised"}
+///
+pub fn error(error: &PortmanError) -> String {
+    format!(
+        "{{\"code\":{},\"message\":{}}}",
+        string(error.code()),
+        string(&error.to_string())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portpool::ports::Protocol;
+
+    #[test]
+    fn strings() {
+        assert_eq!("\"daq\"", string("daq"));
+        assert_eq!(
+            "\"a \\\"b\\\" \\\\ \\n\\u0001\"",
+            string("a \"b\" \\ \n\u{1}")
+        );
+    }
+    #[test]
+    fn objects() {
+        let used = vec![
+            UsedPort::new(31000, "my daq", "fox"),
+            UsedPort::with_protocol(31001, "ring", "fox", Protocol::Udp),
+        ];
+        assert_eq!(
+            "[{\"port\":31000,\"protocol\":\"tcp\",\"service\":\"my daq\",\"user\":\"fox\"},\
+             {\"port\":31001,\"protocol\":\"udp\",\"service\":\"ring\",\"user\":\"fox\"}]",
+            allocations(&used)
+        );
+        assert_eq!("[]", allocations(&[]));
+        assert_eq!(
+            "{\"code\":\"E_NOT_LOCAL\",\"message\":\"Can only allocate to local senders\"}",
+            error(&PortmanError::NotLocal)
+        );
+    }
+}
@@ -0,0 +1,6 @@
+// Contains module definitions that pull in specific files
+
+mod go;
+pub mod http;
+pub mod json;
+pub(crate) mod routes;
@@ -0,0 +1,275 @@
+use super::go::go;
+use super::http::{escape_html, Request, Response};
+use super::json;
+use crate::error::error::PortmanError;
+use crate::metrics::metrics::Metrics;
+use crate::portpool::ports::UsedPort;
+use crate::responder::responder::{self, RequestMessage};
+use crate::server::peer::may_list;
+use std::sync::mpsc;
+use std::sync::Arc;
+
+// What the HTTP listener serves.  Like the event loop it only reaches the
+// port pool by asking the responder.
+//
+//   /                              - A page with the allocation table.
+//   /allocations                   - All allocations as JSON.
+//   /allocations/{service}/{user}  - Those of a service as JSON (as FIND).
+//   /go/{user}/{service}           - A redirect to a web service.
+//   /status                        - The state of the server as JSON.
+//   /metrics                       - Metrics for Prometheus.
+//
+// The allocations are shown to whoever may LIST them.
+
+pub(crate) struct Routes {
+    requests: mpsc::Sender<RequestMessage>,
+    metrics: Arc<Metrics>,
+}
+
+impl Routes {
+    pub(crate) fn new(requests: mpsc::Sender<RequestMessage>, metrics: Arc<Metrics>) -> Routes {
+        Routes { requests, metrics }
+    }
+    pub(crate) fn handle(&self, request: &Request) -> Response {
+        let segments = match request.segments() {
+            Some(segments) => segments,
+            None => return Response::text(400, "The path isn't UTF-8\n"),
+        };
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        match segments.as_slice() {
+            [] => self.page(request),
+            ["allocations"] => self.allocations(request, None),
+            ["allocations", service, user] => self.allocations(request, Some((service, user))),
+            ["go", user, service] => self.go(request, user, service),
+            ["status"] => self.status(),
+            ["metrics"] => self.metrics(),
+            _ => Response::text(404, "Not found\n"),
+        }
+    }
+    // The allocations, or just those of a service and user.  Like FIND
+    // it's an error if the service isn't advertised.
+
+    fn usage(
+        &self,
+        request: &Request,
+        names: Option<(&str, &str)>,
+    ) -> Result<Vec<UsedPort>, PortmanError> {
+        may_list(&request.peer)?;
+        let allocations = responder::get_allocations(&self.requests)?;
+        match names {
+            None => Ok(allocations.as_ref().clone()),
+            Some((service, user)) => {
+                let matching: Vec<UsedPort> = allocations
+                    .iter()
+                    .filter(|a| a.service() == service && a.user() == user)
+                    .cloned()
+                    .collect();
+                if matching.is_empty() {
+                    Err(PortmanError::NotAdvertised {
+                        service: String::from(service),
+                        user: String::from(user),
+                    })
+                } else {
+                    Ok(matching)
+                }
+            }
+        }
+    }
+    fn allocations(&self, request: &Request, names: Option<(&str, &str)>) -> Response {
+        match self.usage(request, names) {
+            Ok(allocations) => Response::json(200, json::allocations(&allocations)),
+            Err(e) => Response::json(status(&e), json::error(&e)),
+        }
+    }
+    fn status(&self) -> Response {
+        let version = json::string(env!("CARGO_PKG_VERSION"));
+        let connections = self.metrics.connections();
+        let relays = self.metrics.relays();
+        match responder::check_health(&self.requests) {
+            Ok(health) => Response::json(
+                200,
+                format!(
+                    "{{\"up\":true,\"version\":{},\"connections\":{},\"relays\":{},\
+                     \"restarts\":{},\"allocated\":{},\"available\":{},\"waiting\":{}}}",
+                    version,
+                    connections,
+                    relays,
+                    health.restarts,
+                    health.allocated,
+                    health.available,
+                    health.waiting
+                ),
+            ),
+            Err(e) => Response::json(
+                503,
+                format!(
+                    "{{\"up\":false,\"version\":{},\"connections\":{},\"relays\":{},\"error\":{}}}",
+                    version,
+                    connections,
+                    relays,
+                    json::error(&e)
+                ),
+            ),
+        }
+    }
+    // Send the browser to a web service by name.  Anyone who may LIST
+    // can see where it is anyway.
+
+    fn go(&self, request: &Request, user: &str, service: &str) -> Response {
+        let host = match request.host_name() {
+            Some(host) => host,
+            None => return Response::text(400, "A Host is needed to redirect\n"),
+        };
+        match self.usage(request, None) {
+            Ok(allocations) => go(&allocations, user, service, host),
+            Err(e) => Response::html(
+                status(&e),
+                page(
+                    "portman",
+                    &format!("<p>{}</p>", escape_html(&e.to_string())),
+                ),
+            ),
+        }
+    }
+    // The metrics are served even if the responder doesn't answer:
+    // portman_up says whether it did.
+
+    fn metrics(&self) -> Response {
+        let health = responder::check_health(&self.requests).ok();
+        Response::new(
+            200,
+            "text/plain; version=0.0.4; charset=utf-8",
+            self.metrics.render(health.as_ref()),
+        )
+    }
+    // A page for people:  the allocation table as LIST would give it.
+
+    fn page(&self, request: &Request) -> Response {
+        let allocations = match self.usage(request, None) {
+            Ok(allocations) => allocations,
+            Err(e) => {
+                return Response::html(
+                    status(&e),
+                    page(
+                        "portman",
+                        &format!("<p>{}</p>", escape_html(&e.to_string())),
+                    ),
+                )
+            }
+        };
+        let mut body = format!("<p>Ports allocated: {}</p>\n", allocations.len());
+        if !allocations.is_empty() {
+            body.push_str(
+                "<table>\n<tr><th>Port</th><th>Protocol</th><th>Service</th><th>User</th></tr>\n",
+            );
+            for used in &allocations {
+                body.push_str(&format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                    used.port(),
+                    used.protocol(),
+                    escape_html(&used.service()),
+                    escape_html(&used.user())
+                ));
+            }
+            body.push_str("</table>\n");
+        }
+        Response::html(200, page("portman allocations", &body))
+    }
+}
+
+// The HTTP status that goes with a failure.
+
+fn status(error: &PortmanError) -> u16 {
+    match error {
+        PortmanError::NotAdvertised { .. } => 404,
+        PortmanError::NotLocal | PortmanError::Denied(_) => 403,
+        _ => 503,
+    }
+}
+
+// An HTML page with 'body' under the heading 'title'.
+
+pub(super) fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n\
+         <style>td, th {{ padding: 0 1em; text-align: left; }}</style>\n\
+         </head>\n<body>\n<h1>{0}</h1>\n{1}</body>\n</html>\n",
+        escape_html(title),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portpool::ports::PortPool;
+    use crate::responder::responder::CollisionPolicy;
+    use std::thread;
+
+    // Routes with a responder for a pool holding 'allocations'.
+
+    fn routes(allocations: &[(&str, &str)]) -> Routes {
+        let mut pool = PortPool::new(31000, 10);
+        for (service, user) in allocations {
+            pool.allocate(service, user).unwrap();
+        }
+        let (requests, receive) = mpsc::channel();
+        thread::spawn(move || {
+            responder::responder_with_pool(pool, CollisionPolicy::Reject, receive)
+        });
+        Routes::new(requests, Arc::new(Metrics::new()))
+    }
+    fn get(routes: &Routes, path: &str) -> (u16, String) {
+        let response = routes.handle(&Request {
+            method: String::from("GET"),
+            path: String::from(path),
+            query: None,
+            host: None,
+            peer: "192.168.1.2:40000".parse().unwrap(),
+        });
+        (response.status, String::from_utf8(response.body).unwrap())
+    }
+
+    #[test]
+    fn allocations() {
+        let routes = routes(&[("daq", "fox"), ("my <ui>", "fox")]);
+        let (status, body) = get(&routes, "/allocations");
+        assert_eq!(200, status);
+        assert_eq!(
+            "[{\"port\":31000,\"protocol\":\"tcp\",\"service\":\"daq\",\"user\":\"fox\"},\
+             {\"port\":31001,\"protocol\":\"tcp\",\"service\":\"my <ui>\",\"user\":\"fox\"}]",
+            body
+        );
+        let (status, body) = get(&routes, "/allocations/my%20%3Cui%3E/fox");
+        assert_eq!(200, status);
+        assert!(body.starts_with("[{\"port\":31001,"));
+        let (status, body) = get(&routes, "/allocations/daq/owl");
+        assert_eq!(404, status);
+        assert!(body.starts_with("{\"code\":\"E_NOT_FOUND\","));
+        assert_eq!(404, get(&routes, "/allocations/daq").0);
+    }
+    #[test]
+    fn status_and_page() {
+        let routes = routes(&[("my <ui>", "fox")]);
+        let (status, body) = get(&routes, "/status");
+        assert_eq!(200, status);
+        assert!(body.starts_with("{\"up\":true,"));
+        assert!(body.ends_with("\"available\":9,\"waiting\":0}"));
+
+        let (status, body) = get(&routes, "/");
+        assert_eq!(200, status);
+        assert!(
+            body.contains("<tr><td>31000</td><td>tcp</td><td>my &lt;ui&gt;</td><td>fox</td></tr>")
+        );
+    }
+    #[test]
+    fn responder_gone() {
+        let (requests, _) = mpsc::channel();
+        let routes = Routes::new(requests, Arc::new(Metrics::new()));
+        let (status, body) = get(&routes, "/status");
+        assert_eq!(503, status);
+        assert!(body.starts_with("{\"up\":false,"));
+        assert_eq!(503, get(&routes, "/allocations").0);
+        assert_eq!(200, get(&routes, "/metrics").0);
+    }
+}